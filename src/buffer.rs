@@ -1,9 +1,17 @@
+use log::{debug, warn};
 use ringbuffer::RingBuffer;
 
-use crate::types::{Frame, FLAG};
+use crate::{
+    stats::{DropReason, DropStats},
+    types::{Frame, FrameRef, Framing, ParseError, TransparencyMode, ADVANCED_FLAG, FLAG},
+};
 
 pub const GSM0710_BUFFER_CAPACITY: usize = 2048;
 
+/// Octets of a basic option frame besides its information field: two flags, the
+/// address, control and FCS fields, a two octet length and a credit
+const FRAME_OVERHEAD: usize = 8;
+
 pub trait GSM0710Buffer {
     fn push_vec(&mut self, vec: Vec<u8>);
     /// Pop a GSM 07.10 frame from the buffer
@@ -11,11 +19,23 @@ pub trait GSM0710Buffer {
     /// If a frame is found, it is returned Some(Frame)
     /// If no frame is found, None is returned.
     fn pop_frame(&mut self) -> Option<Frame>;
+    /// Pop a GSM 07.10 frame from the buffer, telling why none could be popped.
+    ///
+    /// On [`ParseError::Incomplete`] the buffer is left untouched so that the rest of
+    /// the frame can be pushed. On other errors the bytes up to the next FLAG are discarded.
+    fn try_pop_frame(&mut self) -> Result<Frame, ParseError>;
+    /// [`GSM0710Buffer::try_pop_frame`] with the given framing.
+    ///
+    /// In advanced mode the closing flag stays in the buffer, it may open the next frame.
+    fn try_pop_frame_with(&mut self, framing: impl Into<Framing>) -> Result<Frame, ParseError>;
     /// Pop at least one frame from the buffer.
     ///
     /// If a frame is found, it is returned Some(Frame)
-    /// If buffer is empty, None is returned.
+    /// If buffer is empty or only holds the start of a frame, None is returned.
+    /// Bytes without a flag are kept, a later push may complete the frame.
     fn pop_frame1(&mut self) -> Option<Frame>;
+    /// [`GSM0710Buffer::pop_frame1`] with the given framing
+    fn pop_frame1_with(&mut self, framing: impl Into<Framing>) -> Option<Frame>;
 }
 
 impl<T: RingBuffer<u8>> GSM0710Buffer for T {
@@ -26,46 +46,266 @@ impl<T: RingBuffer<u8>> GSM0710Buffer for T {
     }
 
     fn pop_frame(&mut self) -> Option<Frame> {
-        let buf = self.to_vec();
-        match Frame::parse(&mut buf.into_iter()) {
-            Some((frame, len)) => {
+        self.try_pop_frame().ok()
+    }
+
+    fn try_pop_frame(&mut self) -> Result<Frame, ParseError> {
+        self.try_pop_frame_with(TransparencyMode::Basic)
+    }
+
+    fn try_pop_frame_with(&mut self, framing: impl Into<Framing>) -> Result<Frame, ParseError> {
+        let framing = framing.into();
+        // Parse in place, only the frame's content is copied
+        let parsed = Frame::parse_with(&mut self.iter().copied(), framing);
+        match parsed {
+            Ok((frame, len)) => {
                 for _ in 0..len {
                     self.skip();
                 }
-                Some(frame)
+                Ok(frame)
             }
-            None => {
-                // Discard all bytes until the next FLAG
-                while let Some(byte) = self.dequeue() {
-                    if byte == FLAG {
-                        break;
-                    }
+            Err(ParseError::Incomplete) => Err(ParseError::Incomplete),
+            Err(e) => {
+                for _ in 0..discard_len(self.iter().copied(), self.len(), framing.transparency) {
+                    self.skip();
                 }
-                None
+                Err(e)
             }
         }
     }
 
     fn pop_frame1(&mut self) -> Option<Frame> {
-        if self.is_empty() {
-            return None;
+        self.pop_frame1_with(TransparencyMode::Basic)
+    }
+
+    fn pop_frame1_with(&mut self, framing: impl Into<Framing>) -> Option<Frame> {
+        let framing = framing.into();
+        loop {
+            if self.is_empty() {
+                return None;
+            }
+            // Without a flag the bytes stay, the ring buffer drops the oldest when full
+            match self.try_pop_frame_with(framing) {
+                Ok(frame) => return Some(frame),
+                Err(ParseError::Incomplete) => return None,
+                Err(_) => {}
+            }
+        }
+    }
+}
+
+/// Octets discarded after a frame failed to parse: up to the first flag included, in
+/// advanced mode up to the closing flag of the bad frame as it may open the next one.
+///
+/// `octets` are the `len` octets of the buffer, scanned once.
+fn discard_len(
+    octets: impl Iterator<Item = u8>,
+    len: usize,
+    transparency: TransparencyMode,
+) -> usize {
+    let mut octets = octets.enumerate();
+    match transparency {
+        TransparencyMode::Basic => octets.find(|&(_, b)| b == FLAG).map_or(len, |(i, _)| i + 1),
+        TransparencyMode::Advanced => {
+            let opening = octets.find(|&(_, b)| b == ADVANCED_FLAG);
+            let body = opening.and_then(|_| octets.find(|&(_, b)| b != ADVANCED_FLAG));
+            body.and_then(|_| octets.find(|&(_, b)| b == ADVANCED_FLAG))
+                .map_or(len, |(i, _)| i)
+        }
+    }
+}
+
+/// Receive buffer of the serial stream, counting every octet it drops in [`DropStats`].
+///
+/// Octets leave it in a frame or as a drop: the oldest ones when a push overflows
+/// it, and those skipped while looking for a frame. Debug builds check that nothing
+/// else gets lost.
+///
+/// Its storage is contiguous, so that [`ReceiveBuffer::pop_frame_bytes`] can borrow
+/// the frames it parses.
+#[derive(Debug)]
+pub struct ReceiveBuffer {
+    /// Octets read from the serial port, those before `start` are already parsed
+    data: Vec<u8>,
+    start: usize,
+    capacity: usize,
+    pushed: u64,
+    framed: u64,
+    dropped: u64,
+}
+
+impl ReceiveBuffer {
+    pub fn new(capacity: usize) -> Self {
+        ReceiveBuffer {
+            data: Vec::with_capacity(capacity),
+            start: 0,
+            capacity,
+            pushed: 0,
+            framed: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len() - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Octets waiting to be parsed, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &u8> {
+        self.data[self.start..].iter()
+    }
+
+    /// Queue octets read from the serial port, counting the oldest ones overwritten
+    /// when it is full
+    pub fn push(&mut self, data: &[u8], drops: &mut DropStats) {
+        // Parsed octets make room for the new ones
+        self.data.drain(..self.start);
+        self.start = 0;
+        let overwritten = (self.data.len() + data.len()).saturating_sub(self.capacity);
+        if overwritten > 0 {
+            warn!("Receive buffer full, dropping {} bytes", overwritten);
+            self.drop_octets(DropReason::BufferOverflow, overwritten, drops);
         }
-        if !self.to_vec().iter().any(|&b| b == FLAG) {
-            self.clear();
-            return None;
+        self.data.extend_from_slice(data);
+        self.data.drain(..overwritten);
+        self.pushed += data.len() as u64;
+        self.check();
+    }
+
+    /// Pop the next frame, counting the octets discarded on the way.
+    ///
+    /// The content is copied into the frame, [`ReceiveBuffer::pop_frame_bytes`] borrows
+    /// it instead. Frames that can't fit in the buffer are rejected whatever `framing`
+    /// allows.
+    pub fn next_frame(&mut self, framing: Framing, drops: &mut DropStats) -> Option<Frame> {
+        match framing.transparency {
+            TransparencyMode::Basic => self
+                .pop_frame_bytes(framing, drops)
+                .map(|frame| frame.to_frame()),
+            TransparencyMode::Advanced => {
+                let framing = self.fitting(framing);
+                let parse =
+                    |pending: &[u8]| Frame::parse_with(&mut pending.iter().copied(), framing);
+                self.pop_with(framing, drops, parse).map(|(frame, _)| frame)
+            }
         }
-        let frame = self.pop_frame();
-        if frame.is_some() {
-            frame
-        } else {
-            self.pop_frame1()
+    }
+
+    /// Pop the next basic option frame without copying its content, counting the
+    /// octets discarded on the way.
+    ///
+    /// The content borrows the buffer until the next push. The transparency of
+    /// `framing` is ignored, advanced option frames are popped with
+    /// [`ReceiveBuffer::next_frame`].
+    pub fn pop_frame_bytes(
+        &mut self,
+        framing: Framing,
+        drops: &mut DropStats,
+    ) -> Option<FrameRef<'_>> {
+        let framing = Framing {
+            transparency: TransparencyMode::Basic,
+            ..self.fitting(framing)
+        };
+        let parse = |pending: &[u8]| {
+            FrameRef::parse_with(pending, framing).map(|(frame, len)| {
+                let header = (frame.address, frame.control, frame.length, frame.credit);
+                (header, len)
+            })
+        };
+        let ((address, control, length, credit), end) = self.pop_with(framing, drops, parse)?;
+        // The content precedes the FCS and the closing flag
+        let content_end = end - 2;
+        Some(FrameRef {
+            address,
+            control,
+            length,
+            content: &self.data[content_end - length as usize..content_end],
+            credit,
+        })
+    }
+
+    /// `framing` rejecting the frames longer than the buffer
+    fn fitting(&self, mut framing: Framing) -> Framing {
+        let fits = self.capacity.saturating_sub(FRAME_OVERHEAD);
+        framing.max_length = framing.max_length.min(fits as u16);
+        framing
+    }
+
+    /// Pop the next frame with `parse`, counting the octets discarded on the way.
+    ///
+    /// Returns the frame and the offset in the storage where it ends.
+    fn pop_with<T>(
+        &mut self,
+        framing: Framing,
+        drops: &mut DropStats,
+        parse: impl Fn(&[u8]) -> Result<(T, usize), ParseError>,
+    ) -> Option<(T, usize)> {
+        let flag = framing.transparency.flag();
+        loop {
+            let pending = &self.data[self.start..];
+            // Bytes without a flag stay until one arrives, or are overwritten when the buffer is full
+            let leading = pending.iter().position(|&b| b == flag)?;
+            let consumed = match parse(pending) {
+                Ok((frame, len)) => {
+                    self.start += len;
+                    self.drop_octets(DropReason::BadFrame, leading, drops);
+                    self.framed += (len - leading) as u64;
+                    self.check();
+                    return Some((frame, self.start));
+                }
+                Err(ParseError::Incomplete) => return None,
+                Err(e) => {
+                    let consumed =
+                        discard_len(pending.iter().copied(), pending.len(), framing.transparency);
+                    debug!("Discarding {} bytes of the serial stream: {}", consumed, e);
+                    consumed
+                }
+            };
+            self.start += consumed;
+            self.drop_octets(DropReason::BadFrame, consumed, drops);
+            self.check();
         }
     }
+
+    /// Drop everything waiting to be parsed
+    pub fn clear(&mut self, drops: &mut DropStats) {
+        let len = self.len();
+        self.data.clear();
+        self.start = 0;
+        self.drop_octets(DropReason::BadFrame, len, drops);
+        self.check();
+    }
+
+    fn drop_octets(&mut self, reason: DropReason, octets: usize, drops: &mut DropStats) {
+        drops.record(None, reason, octets);
+        self.dropped += octets as u64;
+    }
+
+    fn check(&self) {
+        debug_assert_eq!(
+            self.pushed,
+            self.framed + self.dropped + self.len() as u64,
+            "octets of the serial stream lost without being counted"
+        );
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_alloc::allocations;
+    use crate::types::{
+        serialize_batch, Address, AddressImpl, Control, ControlImpl, FlowControl, FrameCodec,
+        FrameType,
+    };
     use ringbuffer::AllocRingBuffer;
 
     #[test]
@@ -77,6 +317,29 @@ mod tests {
         assert_eq!(buffer.len(), 2);
     }
 
+    #[test]
+    fn gsm0710_buffer_drains_back_to_back_frames() {
+        let mut buffer = AllocRingBuffer::<u8>::new(GSM0710_BUFFER_CAPACITY);
+        let frames: Vec<Frame> = (0..20u8)
+            .map(|i| {
+                let content = vec![i; i as usize];
+                Frame::new(
+                    Address::new_address(true, true, i % 8),
+                    Control::new_control(FrameType::UIH, false),
+                    content,
+                )
+            })
+            .collect();
+        for frame in &frames {
+            buffer.push_vec(frame.try_to_bytes().unwrap());
+        }
+        for frame in &frames {
+            assert_eq!(buffer.pop_frame().as_ref(), Some(frame));
+        }
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.pop_frame(), None);
+    }
+
     #[test]
     fn gsm0710_buffer_push_vec() {
         let mut buffer = AllocRingBuffer::<u8>::new(GSM0710_BUFFER_CAPACITY);
@@ -89,8 +352,8 @@ mod tests {
     #[test]
     fn gsm0710_buffer_pop_frame_multiple_frames() {
         let mut buffer = AllocRingBuffer::<u8>::new(GSM0710_BUFFER_CAPACITY);
-        let frame1 = Frame::new(7, 239, 4, vec![0x41, 0x54, 0xD, 0xA]);
-        let frame2 = Frame::new(13, 239, 4, vec![0x44, 0x55, 0xD, 0xA]);
+        let frame1 = Frame::new(7, 239, vec![0x41, 0x54, 0xD, 0xA]);
+        let frame2 = Frame::new(13, 239, vec![0x44, 0x55, 0xD, 0xA]);
         let frame1_bytes = frame1.try_to_bytes().unwrap();
         let frame2_bytes = frame2.try_to_bytes().unwrap();
         buffer.push_vec(frame1_bytes.clone());
@@ -105,6 +368,28 @@ mod tests {
         assert_eq!(popped_frame3, Some(frame2));
     }
 
+    #[test]
+    fn gsm0710_buffer_pop_frame_consumes_leading_garbage() {
+        let frame = Frame::new(7, 239, vec![0x41, 0x54, 0xD, 0xA]);
+        let long = Frame::new(7, 239, vec![0x55; 200]);
+        let next = Frame::new(13, 239, vec![0x44, 0x55]);
+        let next_bytes = next.try_to_bytes().unwrap();
+        for garbage in [vec![], vec![0x00], vec![0x41, 0x54, 0x0D], vec![FLAG, FLAG]] {
+            for frame in [&frame, &long] {
+                let mut buffer = AllocRingBuffer::<u8>::new(GSM0710_BUFFER_CAPACITY);
+                buffer.push_vec(garbage.clone());
+                buffer.push_vec(frame.try_to_bytes().unwrap());
+                buffer.push_vec(next_bytes.clone());
+                // Stray flags are empty frames of their own, skipped by pop_frame1
+                assert_eq!(buffer.pop_frame1().as_ref(), Some(frame));
+                // Exactly the garbage and the frame are consumed
+                assert_eq!(buffer.to_vec(), next_bytes);
+                assert_eq!(buffer.pop_frame(), Some(next.clone()));
+                assert!(buffer.is_empty());
+            }
+        }
+    }
+
     #[test]
     fn gsm0710_buffer_pop_frame_no_frame() {
         let mut buffer = AllocRingBuffer::<u8>::new(GSM0710_BUFFER_CAPACITY);
@@ -117,8 +402,8 @@ mod tests {
     #[test]
     fn gsm0710_buffer_pop_frame1() {
         let mut buffer = AllocRingBuffer::<u8>::new(GSM0710_BUFFER_CAPACITY);
-        let frame1 = Frame::new(7, 239, 4, vec![0x41, 0x54, 0xD, 0xA]);
-        let frame2 = Frame::new(13, 239, 4, vec![0x44, 0x55, 0xD, 0xA]);
+        let frame1 = Frame::new(7, 239, vec![0x41, 0x54, 0xD, 0xA]);
+        let frame2 = Frame::new(13, 239, vec![0x44, 0x55, 0xD, 0xA]);
         let frame1_bytes = frame1.try_to_bytes().unwrap();
         let frame2_bytes = frame2.try_to_bytes().unwrap();
         buffer.push_vec(frame1_bytes.clone());
@@ -135,4 +420,290 @@ mod tests {
         assert_eq!(popped_frame2, Some(frame2));
         assert_eq!(popped_frame3, None);
     }
+
+    #[test]
+    fn gsm0710_buffer_pop_frame_two_octet_length() {
+        let mut buffer = AllocRingBuffer::<u8>::new(GSM0710_BUFFER_CAPACITY);
+        let frame1 = Frame::new(7, 239, vec![0x41; 200]);
+        let frame2 = Frame::new(13, 239, vec![0x44, 0x55, 0xD, 0xA]);
+        buffer.push_vec(frame1.try_to_bytes().unwrap());
+        buffer.push_vec(frame2.try_to_bytes().unwrap());
+        assert_eq!(buffer.pop_frame(), Some(frame1));
+        assert_eq!(buffer.pop_frame(), Some(frame2));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn gsm0710_buffer_keeps_frame_split_across_reads() {
+        let mut buffer = AllocRingBuffer::<u8>::new(GSM0710_BUFFER_CAPACITY);
+        let frame = Frame::new(7, 239, vec![0x41, 0x54, 0xD, 0xA]);
+        let bytes = frame.try_to_bytes().unwrap();
+        buffer.push_vec(bytes[..5].to_vec());
+        assert_eq!(buffer.try_pop_frame(), Err(ParseError::Incomplete));
+        assert_eq!(buffer.pop_frame1(), None);
+        assert_eq!(buffer.len(), 5);
+        buffer.push_vec(bytes[5..].to_vec());
+        assert_eq!(buffer.pop_frame1(), Some(frame));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn gsm0710_buffer_pop_frame_waits_for_tail_at_any_split() {
+        let frame = Frame::new(7, 239, vec![0x41, 0x54, 0xD, 0xA]);
+        let bytes = frame.try_to_bytes().unwrap();
+        for split in 1..bytes.len() {
+            let mut buffer = AllocRingBuffer::<u8>::new(GSM0710_BUFFER_CAPACITY);
+            buffer.push_vec(bytes[..split].to_vec());
+            assert_eq!(buffer.pop_frame(), None, "split at {}", split);
+            assert_eq!(buffer.to_vec(), bytes[..split], "split at {}", split);
+            buffer.push_vec(bytes[split..].to_vec());
+            assert_eq!(
+                buffer.pop_frame(),
+                Some(frame.clone()),
+                "split at {}",
+                split
+            );
+        }
+    }
+
+    #[test]
+    fn gsm0710_buffer_pop_frame1_skips_a_full_buffer_of_garbage() {
+        let frame = Frame::new(7, 239, vec![0x41, 0x54, 0xD, 0xA]);
+        let bytes = frame.try_to_bytes().unwrap();
+
+        let mut buffer = AllocRingBuffer::<u8>::new(GSM0710_BUFFER_CAPACITY);
+        buffer.push_vec(vec![0x55; GSM0710_BUFFER_CAPACITY - bytes.len()]);
+        buffer.push_vec(bytes.clone());
+        assert_eq!(buffer.len(), GSM0710_BUFFER_CAPACITY);
+        assert_eq!(buffer.pop_frame1(), Some(frame.clone()));
+        assert!(buffer.is_empty());
+
+        // Corrupt frames back to back, each one discarded in turn
+        let corrupt = [FLAG, 0x07, 0xEF, 0x01, 0x00];
+        let mut buffer = AllocRingBuffer::<u8>::new(GSM0710_BUFFER_CAPACITY);
+        let copies = (GSM0710_BUFFER_CAPACITY - bytes.len()) / corrupt.len();
+        buffer.push_vec(corrupt.repeat(copies));
+        buffer.push_vec(bytes);
+        assert_eq!(buffer.pop_frame1(), Some(frame));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn gsm0710_buffer_advanced_frames_share_flags() {
+        let frame = |dlci, content: &[u8]| {
+            Frame::new(
+                Address::new_address(true, true, dlci),
+                Control::new_control(FrameType::UIH, false),
+                content.to_vec(),
+            )
+        };
+        let frame1 = frame(1, &[0x41, ADVANCED_FLAG]);
+        let frame2 = frame(2, &[0x7D, 0x42]);
+        let bytes1 = frame1
+            .try_to_bytes_with(TransparencyMode::Advanced)
+            .unwrap();
+        let bytes2 = frame2
+            .try_to_bytes_with(TransparencyMode::Advanced)
+            .unwrap();
+
+        // The closing flag of the first frame opens the second one
+        let mut buffer = AllocRingBuffer::<u8>::new(GSM0710_BUFFER_CAPACITY);
+        buffer.push_vec(bytes1);
+        buffer.push_vec(bytes2[1..].to_vec());
+        let advanced = TransparencyMode::Advanced;
+        assert_eq!(buffer.pop_frame1_with(advanced), Some(frame1.clone()));
+        assert_eq!(buffer.pop_frame1_with(advanced), Some(frame2.clone()));
+        assert_eq!(buffer.pop_frame1_with(advanced), None);
+
+        // A corrupt frame leaves its closing flag for the next one
+        let mut corrupt = frame1.try_to_bytes_with(advanced).unwrap();
+        corrupt[2] ^= 0x10;
+        buffer.push_vec(corrupt);
+        buffer.push_vec(bytes2[1..].to_vec());
+        assert!(matches!(
+            buffer.try_pop_frame_with(advanced),
+            Err(ParseError::BadFcs { .. })
+        ));
+        assert_eq!(buffer.pop_frame1_with(advanced), Some(frame2));
+    }
+
+    #[test]
+    fn serialized_batches_round_trip() {
+        let frames: Vec<Frame> = (1..4u8)
+            .map(|dlci| {
+                Frame::new(
+                    Address::new_address(true, true, dlci),
+                    Control::new_control(FrameType::UIH, false),
+                    vec![FLAG, ADVANCED_FLAG, 0x7D, dlci],
+                )
+            })
+            .collect();
+        let codecs = [
+            FrameCodec::from(Framing::from(TransparencyMode::Basic)),
+            FrameCodec::from(Framing::from(TransparencyMode::Advanced)),
+            FrameCodec {
+                framing: TransparencyMode::Advanced.into(),
+                share_flags: true,
+            },
+        ];
+        for codec in codecs {
+            let mut buffer = AllocRingBuffer::<u8>::new(GSM0710_BUFFER_CAPACITY);
+            buffer.push_vec(serialize_batch(&frames, codec).unwrap());
+            let popped: Vec<Frame> =
+                std::iter::from_fn(|| buffer.pop_frame1_with(codec.framing)).collect();
+            assert_eq!(popped, frames, "{:?}", codec);
+        }
+    }
+
+    #[test]
+    fn oversized_length_is_rejected_instead_of_awaited() {
+        let frame = Frame::new(7, 239, vec![0x41, 0x54, 0xD, 0xA]);
+        let bytes = frame.try_to_bytes().unwrap();
+        // A corrupted two octet length announcing 32767 octets
+        let corrupt = [FLAG, 0x07, 0xEF, 0xFE, 0xFF, 0x41, 0x54, 0x00, FLAG];
+        let mut buffer = AllocRingBuffer::<u8>::new(GSM0710_BUFFER_CAPACITY);
+        buffer.push_vec(corrupt.to_vec());
+        buffer.push_vec(bytes.clone());
+        let n1 = Framing {
+            max_length: 31,
+            ..Default::default()
+        };
+        assert_eq!(
+            buffer.try_pop_frame_with(n1),
+            Err(ParseError::UnsupportedLength)
+        );
+        // Resynchronized on the next flag
+        assert_eq!(buffer.pop_frame1_with(n1), Some(frame.clone()));
+        assert!(buffer.is_empty());
+
+        // Lengths N1 allows but the buffer can't hold are rejected too
+        let mut drops = DropStats::default();
+        let mut buffer = ReceiveBuffer::new(64);
+        buffer.push(&corrupt, &mut drops);
+        buffer.push(&bytes, &mut drops);
+        assert_eq!(
+            buffer.next_frame(Framing::default(), &mut drops),
+            Some(frame)
+        );
+        assert_eq!(drops.total(DropReason::BadFrame), corrupt.len() as u64);
+    }
+
+    #[test]
+    fn receive_buffer_counts_every_dropped_octet() {
+        let frame = Frame::new(7, 239, vec![0x41, 0x54, 0xD, 0xA]);
+        let bytes = frame.try_to_bytes().unwrap();
+        let mut drops = DropStats::default();
+        let mut buffer = ReceiveBuffer::new(32);
+        // Garbage, a corrupt frame, then the frame
+        buffer.push(&[0x01, 0x02], &mut drops);
+        buffer.push(&[FLAG, 0x07, 0xEF, 0x01, 0x00, FLAG], &mut drops);
+        buffer.push(&bytes, &mut drops);
+        assert_eq!(
+            buffer.next_frame(Framing::default(), &mut drops),
+            Some(frame)
+        );
+        // The corrupt frame is dropped up to its closing flag
+        assert_eq!(drops.total(DropReason::BadFrame), 8);
+        assert!(buffer.is_empty());
+
+        // Overwritten octets, then octets left over
+        buffer.push(&[0x55; 40], &mut drops);
+        assert_eq!(drops.total(DropReason::BufferOverflow), 8);
+        assert_eq!(buffer.next_frame(Framing::default(), &mut drops), None);
+        buffer.clear(&mut drops);
+        assert_eq!(drops.total(DropReason::BadFrame), 8 + 32);
+    }
+
+    #[test]
+    fn frames_are_popped_without_copying() {
+        let framing = Framing {
+            flow_control: FlowControl::CreditBased,
+            ..Default::default()
+        };
+        let frame = Frame::new(7, 239, b"OK".to_vec()).with_credit(3);
+        let bytes = frame.try_to_bytes_with(framing).unwrap();
+        let mut drops = DropStats::default();
+        let mut buffer = ReceiveBuffer::new(32);
+        buffer.push(&[0x01], &mut drops);
+        buffer.push(&bytes, &mut drops);
+        buffer.push(&bytes[..3], &mut drops);
+        let storage = buffer.data.as_ptr();
+        let popped = buffer.pop_frame_bytes(framing, &mut drops).unwrap();
+        assert_eq!(popped.to_frame(), frame);
+        // The content points into the buffer, past the garbage, the header and the credit
+        assert!(std::ptr::eq(
+            popped.content.as_ptr(),
+            storage.wrapping_add(6)
+        ));
+        assert_eq!(drops.total(DropReason::BadFrame), 1);
+
+        // Split frames are completed by the next push, which reclaims the parsed octets
+        assert_eq!(buffer.pop_frame_bytes(framing, &mut drops), None);
+        buffer.push(&bytes[3..], &mut drops);
+        assert_eq!(buffer.data.len(), bytes.len());
+        let popped = buffer.pop_frame_bytes(framing, &mut drops).unwrap();
+        assert_eq!(popped.to_frame(), frame);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn borrowed_frames_dont_allocate() {
+        let frames: Vec<Frame> = (0..32u8).map(|i| Frame::new(7, 239, vec![i; 16])).collect();
+        let bytes = serialize_batch(&frames, FrameCodec::default()).unwrap();
+        let mut drops = DropStats::default();
+        let mut buffer = ReceiveBuffer::new(GSM0710_BUFFER_CAPACITY);
+        let framing = Framing::default();
+
+        buffer.push(&bytes, &mut drops);
+        let (popped, owned) =
+            allocations(|| std::iter::from_fn(|| buffer.next_frame(framing, &mut drops)).count());
+        assert_eq!(popped, frames.len());
+        // One content copy per frame
+        assert!(owned >= frames.len(), "{} allocations", owned);
+
+        buffer.push(&bytes, &mut drops);
+        let (popped, borrowed) = allocations(|| {
+            let mut popped = 0;
+            while let Some(frame) = buffer.pop_frame_bytes(framing, &mut drops) {
+                assert_eq!(frame.content.len(), 16);
+                popped += 1;
+            }
+            popped
+        });
+        assert_eq!(popped, frames.len());
+        assert_eq!(borrowed, 0);
+    }
+
+    #[test]
+    fn discarded_octets_end_at_a_flag() {
+        let discard = |octets: &[u8], transparency| {
+            discard_len(octets.iter().copied(), octets.len(), transparency)
+        };
+        assert_eq!(discard(&[0x01, FLAG, 0x02], TransparencyMode::Basic), 2);
+        assert_eq!(discard(&[0x01, 0x02], TransparencyMode::Basic), 2);
+        let advanced = [
+            0x01,
+            ADVANCED_FLAG,
+            ADVANCED_FLAG,
+            0x02,
+            ADVANCED_FLAG,
+            0x03,
+        ];
+        assert_eq!(discard(&advanced, TransparencyMode::Advanced), 4);
+        assert_eq!(discard(&advanced[..4], TransparencyMode::Advanced), 4);
+        assert_eq!(discard(&[0x01, 0x02], TransparencyMode::Advanced), 2);
+        assert_eq!(discard(&[], TransparencyMode::Advanced), 0);
+    }
+
+    #[test]
+    fn gsm0710_buffer_keeps_bytes_without_flag() {
+        let frame = Frame::new(7, 239, vec![0x41, 0x54, 0xD, 0xA]);
+        let mut buffer = AllocRingBuffer::<u8>::new(GSM0710_BUFFER_CAPACITY);
+        buffer.push_vec(vec![0x01, 0x02, 0x03]);
+        assert_eq!(buffer.pop_frame1(), None);
+        assert_eq!(buffer.to_vec(), vec![0x01, 0x02, 0x03]);
+        buffer.push_vec(frame.try_to_bytes().unwrap());
+        assert_eq!(buffer.pop_frame1(), Some(frame));
+        assert!(buffer.is_empty());
+    }
 }
@@ -0,0 +1,1226 @@
+use std::{
+    borrow::Cow,
+    collections::hash_map::DefaultHasher,
+    fmt::Debug,
+    hash::{Hash, Hasher},
+    time::{Duration, Instant},
+};
+
+use crc::Crc;
+use log::{debug, error, info, warn};
+
+use crate::types::{AddressImpl, ControlImpl, FrameRef, FrameType};
+
+/// Unsolicited result codes that don't start with `+`
+const URC_KEYWORDS: [&str; 4] = ["RING", "NO CARRIER", "NO DIALTONE", "BUSY"];
+/// Credits granted on each side of a channel in credit based flow control, unless
+/// PN negotiates others
+pub const INITIAL_CREDITS: u8 = 7;
+/// Number of payloads remembered by a [`DuplicateFilter`]
+pub const DUPLICATE_HISTORY: usize = 16;
+/// Delay before the first SABM retransmission, doubled for each further one
+pub const SABM_BACKOFF: Duration = Duration::from_millis(50);
+/// Octets of the CRC16 trailer of the payloads of a channel in integrity mode
+pub const INTEGRITY_TRAILER: usize = 2;
+/// CRC-16/X-25 of the integrity trailer
+static INTEGRITY_CRC: Crc<u16> = Crc::<u16>::new(&crc::CRC_16_IBM_SDLC);
+
+/// Role of a logical channel, selects the per-channel processing
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ChannelKind {
+    /// Channel dedicated to AT commands. Data is line buffered and URCs are parsed
+    At,
+    /// Binary data channel (PPP, GPS, ...). Data is forwarded untouched
+    Data,
+}
+
+impl ChannelKind {
+    pub fn for_dlci(dlci: u8, at_channel: Option<u8>) -> Self {
+        match at_channel {
+            Some(at) if at == dlci => ChannelKind::At,
+            _ => ChannelKind::Data,
+        }
+    }
+}
+
+/// Check whether a response line is an unsolicited result code
+pub fn is_urc(line: &str) -> bool {
+    line.starts_with('+') || URC_KEYWORDS.contains(&line)
+}
+
+/// Splits AT channel output into lines
+#[derive(Debug, Default)]
+pub struct LineBuffer {
+    line: Vec<u8>,
+}
+
+impl LineBuffer {
+    /// Push data into the buffer and return all completed, non-empty lines
+    pub fn push(&mut self, data: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+        for &byte in data {
+            match byte {
+                b'\r' | b'\n' => {
+                    if !self.line.is_empty() {
+                        lines.push(String::from_utf8_lossy(&self.line).into_owned());
+                        self.line.clear();
+                    }
+                }
+                _ => self.line.push(byte),
+            }
+        }
+        lines
+    }
+}
+
+/// Reversible transformation of a channel's data (compression, escaping, ...)
+///
+/// Outbound data is encoded before it is framed, inbound data is decoded after
+/// it is de-framed. Both ends of the link must use the same transform.
+pub trait Transform: Debug {
+    /// Transform data read from the pty before it is framed
+    fn encode(&mut self, data: Vec<u8>) -> Vec<u8>;
+    /// Reverse [`Transform::encode`] on data received from the modem
+    fn decode(&mut self, data: Vec<u8>) -> Vec<u8>;
+}
+
+/// Transform that leaves the data untouched
+#[derive(Debug, Default)]
+pub struct Identity;
+
+impl Transform for Identity {
+    fn encode(&mut self, data: Vec<u8>) -> Vec<u8> {
+        data
+    }
+
+    fn decode(&mut self, data: Vec<u8>) -> Vec<u8> {
+        data
+    }
+}
+
+/// Drops payloads identical to one delivered shortly before, for channels whose
+/// messages are idempotent and that the modem may retransmit
+#[derive(Debug)]
+pub struct DuplicateFilter {
+    window: Duration,
+    /// Hashes of the last payloads and when they were delivered
+    history: [Option<(u64, Instant)>; DUPLICATE_HISTORY],
+    next: usize,
+    /// Number of payloads dropped as duplicates
+    pub suppressed: u64,
+}
+
+impl DuplicateFilter {
+    pub fn new(window: Duration) -> Self {
+        DuplicateFilter {
+            window,
+            history: [None; DUPLICATE_HISTORY],
+            next: 0,
+            suppressed: 0,
+        }
+    }
+
+    /// Whether a payload was delivered within the window, otherwise remember it
+    pub fn is_duplicate(&mut self, data: &[u8], now: Instant) -> bool {
+        // Hashing a slice covers its length too
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        let hash = hasher.finish();
+        let window = self.window;
+        if self
+            .history
+            .iter()
+            .flatten()
+            .any(|&(seen, at)| seen == hash && now.saturating_duration_since(at) < window)
+        {
+            self.suppressed += 1;
+            return true;
+        }
+        self.history[self.next] = Some((hash, now));
+        self.next = (self.next + 1) % DUPLICATE_HISTORY;
+        false
+    }
+}
+
+/// Per-channel behavior selected by the channel's [`ChannelKind`]
+#[derive(Debug)]
+pub struct ChannelBehavior {
+    pub dlci: u8,
+    pub kind: ChannelKind,
+    lines: LineBuffer,
+    /// Transform of the channel's data, none leaves it untouched without a copy
+    transform: Option<Box<dyn Transform>>,
+    duplicates: Option<DuplicateFilter>,
+    /// Whether the payloads sent carry a CRC16 trailer
+    integrity_tx: bool,
+    /// Whether the payloads received carry a CRC16 trailer
+    integrity_rx: bool,
+}
+
+impl ChannelBehavior {
+    pub fn new(dlci: u8, at_channel: Option<u8>) -> Self {
+        ChannelBehavior {
+            dlci,
+            kind: ChannelKind::for_dlci(dlci, at_channel),
+            lines: LineBuffer::default(),
+            transform: None,
+            duplicates: None,
+            integrity_tx: false,
+            integrity_rx: false,
+        }
+    }
+
+    /// Drop received payloads repeated within `window`, off by default
+    pub fn set_duplicate_window(&mut self, window: Duration) {
+        self.duplicates = Some(DuplicateFilter::new(window));
+    }
+
+    /// Whether received data repeats a recent payload and must be dropped
+    pub fn is_duplicate(&mut self, data: &[u8], now: Instant) -> bool {
+        self.duplicates
+            .as_mut()
+            .is_some_and(|filter| filter.is_duplicate(data, now))
+    }
+
+    /// Number of payloads dropped as duplicates
+    pub fn suppressed(&self) -> u64 {
+        self.duplicates
+            .as_ref()
+            .map_or(0, |filter| filter.suppressed)
+    }
+
+    /// Replace the channel's transform, the data is left untouched by default
+    pub fn set_transform(&mut self, transform: Box<dyn Transform>) {
+        self.transform = Some(transform);
+    }
+
+    /// Apply the channel's transform to data read from the pty
+    pub fn encode_tx(&mut self, data: Vec<u8>) -> Vec<u8> {
+        match self.transform.as_mut() {
+            Some(transform) => transform.encode(data),
+            None => data,
+        }
+    }
+
+    /// Reverse the channel's transform on data received from the modem, only copying
+    /// it when there is a transform
+    pub fn decode_rx<'a>(&mut self, data: &'a [u8]) -> Cow<'a, [u8]> {
+        match self.transform.as_mut() {
+            Some(transform) => Cow::Owned(transform.decode(data.to_vec())),
+            None => Cow::Borrowed(data),
+        }
+    }
+
+    /// Switch the integrity mode on for each direction, or off for both.
+    ///
+    /// The CRC16 trailer covers the payload end to end, which the FCS of UIH frames
+    /// doesn't. Both ends of the link must agree on it.
+    pub fn set_integrity(&mut self, tx: bool, rx: bool) {
+        self.integrity_tx = tx;
+        self.integrity_rx = rx;
+    }
+
+    /// Append the CRC16 trailer to a payload sent in integrity mode
+    pub fn seal_tx(&self, mut data: Vec<u8>) -> Vec<u8> {
+        if self.integrity_tx {
+            let crc = INTEGRITY_CRC.checksum(&data);
+            data.extend(crc.to_be_bytes());
+        }
+        data
+    }
+
+    /// Check and strip the CRC16 trailer of a payload received in integrity mode,
+    /// `None` if it doesn't match
+    pub fn unseal_rx<'a>(&self, data: &'a [u8]) -> Option<&'a [u8]> {
+        if !self.integrity_rx {
+            return Some(data);
+        }
+        let len = data.len().checked_sub(INTEGRITY_TRAILER)?;
+        let (payload, trailer) = data.split_at(len);
+        match INTEGRITY_CRC.checksum(payload).to_be_bytes() == trailer {
+            true => Some(payload),
+            false => None,
+        }
+    }
+
+    /// Inspect data received from the modem on this channel.
+    ///
+    /// On the AT channel the data is logged line by line and the URCs found are returned.
+    /// Data channels are left alone and never yield URCs.
+    pub fn inspect_rx(&mut self, data: &[u8]) -> Vec<String> {
+        if self.kind != ChannelKind::At {
+            return Vec::new();
+        }
+        let mut urcs = Vec::new();
+        for line in self.lines.push(data) {
+            if is_urc(&line) {
+                info!("URC on AT channel {}: {}", self.dlci, line);
+                urcs.push(line);
+            } else {
+                debug!("AT channel {}: {}", self.dlci, line);
+            }
+        }
+        urcs
+    }
+}
+
+/// Validation of the C/R bit of frames received on a channel
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum CrPolicy {
+    /// Accept frames whatever their C/R bit
+    #[default]
+    Lenient,
+    /// Drop frames whose C/R bit doesn't match the command/response convention
+    Strict,
+}
+
+/// C/R bit convention of a channel, by default the mux being the initiator
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct CrConvention {
+    pub policy: CrPolicy,
+    /// Quirk of modems that invert the C/R bit on this channel, both ways
+    pub invert_cr: bool,
+    /// Whether the mux started the session, the modem being the responder
+    pub is_initiator: bool,
+}
+
+impl Default for CrConvention {
+    fn default() -> Self {
+        CrConvention {
+            policy: CrPolicy::default(),
+            invert_cr: false,
+            is_initiator: true,
+        }
+    }
+}
+
+impl CrConvention {
+    /// C/R bit of a frame sent to the modem
+    pub fn tx_cr(&self, frame_type: FrameType) -> bool {
+        frame_type.cr(self.is_initiator) != self.invert_cr
+    }
+
+    /// C/R bit expected on a frame sent by the modem
+    pub fn rx_cr(&self, frame_type: FrameType) -> bool {
+        frame_type.cr(!self.is_initiator) != self.invert_cr
+    }
+
+    /// Whether a frame received from the modem passes the C/R validation
+    pub fn accepts(&self, frame: &FrameRef) -> bool {
+        match (self.policy, frame.control.get_frame_type()) {
+            (CrPolicy::Strict, Ok(frame_type)) => frame.address.get_cr() == self.rx_cr(frame_type),
+            _ => true,
+        }
+    }
+}
+
+/// What to do when the modem disconnects a data channel
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum ReestablishPolicy {
+    /// Re-open the channel after a delay, keeping the pty untouched
+    Auto,
+    /// Leave the channel closed until it is opened again by the operator
+    #[default]
+    Manual,
+}
+
+/// Link state of a logical channel
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LinkState {
+    Closed,
+    /// Waiting to send the next SABM of an automatic re-establishment or after a DM
+    Waiting,
+    /// SABM sent, waiting for UA
+    Opening,
+    Open,
+    /// DISC sent, waiting for UA
+    Closing,
+}
+
+/// Lifecycle events emitted by a [`ChannelLink`]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LifecycleEvent {
+    /// The channel was opened for the first time
+    Opened,
+    /// The channel came back after the modem closed it
+    Reestablished,
+    /// The modem closed the channel
+    ClosedByRemote,
+    /// The modem restarted the control channel mid-session
+    ControlRestarted,
+    /// The modem answered DM on an open channel, it no longer knows it
+    Forgotten,
+    /// Automatic re-establishment gave up
+    Degraded,
+    /// The modem answered DM on the open control channel, the mux is down
+    ControlLost,
+    /// The channel was closed locally
+    Closed,
+}
+
+/// Actions requested by a [`ChannelLink`]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LinkAction {
+    /// Send a frame of the given type with the given P/F bit on the channel
+    Send(FrameType, bool),
+    Event(LifecycleEvent),
+}
+
+/// Link state machine of a logical channel, handling re-establishment after remote DISC
+#[derive(Debug)]
+pub struct ChannelLink {
+    pub dlci: u8,
+    pub state: LinkState,
+    pub policy: ReestablishPolicy,
+    /// Delay before each re-establishment attempt
+    pub delay: Duration,
+    /// Number of SABM attempts before the channel is declared degraded
+    pub max_retries: u8,
+    /// Acknowledgement timer T1 of SABM and DISC
+    pub t1: Duration,
+    /// Maximum number of transmissions N2 of SABM and DISC
+    pub n2: u8,
+    /// Number of times an opening rejected with DM is retried
+    pub dm_retries: u8,
+    /// Delay before retrying an opening rejected with DM
+    pub dm_retry_delay: Duration,
+    attempts: u8,
+    /// DMs answering the current opening
+    rejections: u8,
+    /// Transmissions of the pending SABM or DISC
+    transmissions: u8,
+    reestablishing: bool,
+    /// Whether the modem answered the last SABM with DM
+    rejected: bool,
+    /// Whether the channel opens again once the pending DISC is acknowledged
+    reopen: bool,
+    deadline: Option<Instant>,
+    /// UIH frames the modem lets us send in credit based flow control
+    pub tx_credits: u8,
+    /// UIH frames we let the modem send
+    rx_credits: u8,
+}
+
+impl ChannelLink {
+    pub fn new(
+        dlci: u8,
+        policy: ReestablishPolicy,
+        delay: Duration,
+        max_retries: u8,
+        t1: Duration,
+        n2: u8,
+    ) -> Self {
+        ChannelLink {
+            dlci,
+            state: LinkState::Closed,
+            policy,
+            delay,
+            max_retries,
+            t1,
+            n2,
+            dm_retries: 0,
+            dm_retry_delay: Duration::ZERO,
+            attempts: 0,
+            rejections: 0,
+            transmissions: 0,
+            reestablishing: false,
+            rejected: false,
+            reopen: false,
+            deadline: None,
+            tx_credits: INITIAL_CREDITS,
+            rx_credits: INITIAL_CREDITS,
+        }
+    }
+
+    /// Spend a credit on a UIH frame, false if the modem granted none left
+    pub fn take_credit(&mut self) -> bool {
+        match self.tx_credits {
+            0 => false,
+            _ => {
+                self.tx_credits -= 1;
+                true
+            }
+        }
+    }
+
+    /// Add credits granted by the modem, returns whether sending was blocked on them
+    pub fn add_credits(&mut self, credits: u8) -> bool {
+        let blocked = self.tx_credits == 0;
+        self.tx_credits = self.tx_credits.saturating_add(credits);
+        blocked && credits > 0
+    }
+
+    /// Count a UIH frame from the modem, returns the credits to grant back once
+    /// half of them are spent
+    pub fn on_credit_used(&mut self) -> Option<u8> {
+        self.rx_credits = self.rx_credits.saturating_sub(1);
+        match self.rx_credits <= INITIAL_CREDITS / 2 {
+            true => {
+                let grant = INITIAL_CREDITS - self.rx_credits;
+                self.rx_credits = INITIAL_CREDITS;
+                Some(grant)
+            }
+            false => None,
+        }
+    }
+
+    /// Deadline of the pending timer, if any
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Whether the modem answered the last SABM with DM
+    pub fn rejected(&self) -> bool {
+        self.rejected
+    }
+
+    /// Open the channel: send SABM, resent every T1 until the UA arrives, at most N2
+    /// times with exponential backoff between them. A DM is retried up to
+    /// `dm_retries` times after `dm_retry_delay`.
+    pub fn open(&mut self, now: Instant) -> Vec<LinkAction> {
+        self.attempts = 0;
+        self.rejections = 0;
+        self.reestablishing = false;
+        debug!("Sending SABM on DLCI {}", self.dlci);
+        self.on_sabm_sent(now);
+        vec![LinkAction::Send(FrameType::SABM, true)]
+    }
+
+    /// Record that a SABM was sent to open the channel
+    pub fn on_sabm_sent(&mut self, now: Instant) {
+        self.state = LinkState::Opening;
+        self.transmissions = 1;
+        self.rejected = false;
+        self.deadline = Some(now + self.sabm_timeout());
+    }
+
+    /// Time until the SABM just sent is resent or given up on: T1, plus the backoff
+    /// when another transmission follows
+    fn sabm_timeout(&self) -> Duration {
+        match self.transmissions < self.n2 {
+            true => self.t1 + SABM_BACKOFF * (1 << (self.transmissions - 1).min(16)),
+            false => self.t1,
+        }
+    }
+
+    pub fn on_ua(&mut self, now: Instant) -> Vec<LinkAction> {
+        if self.state == LinkState::Closing {
+            debug!("Channel {} disconnected", self.dlci);
+            self.on_disconnected(now);
+            return Vec::new();
+        }
+        if self.state != LinkState::Opening {
+            return Vec::new();
+        }
+        self.state = LinkState::Open;
+        self.deadline = None;
+        self.attempts = 0;
+        let event = match self.reestablishing {
+            true => LifecycleEvent::Reestablished,
+            false => LifecycleEvent::Opened,
+        };
+        self.reestablishing = false;
+        info!("Channel {} {:?}", self.dlci, event);
+        vec![LinkAction::Event(event)]
+    }
+
+    /// Close the channel locally, disconnecting it if it is open.
+    ///
+    /// The DISC is resent every T1 until the UA arrives, at most N2 times.
+    pub fn on_close(&mut self, now: Instant) -> Vec<LinkAction> {
+        let mut actions = Vec::new();
+        (self.state, self.deadline) = match self.state {
+            LinkState::Open => {
+                actions.push(LinkAction::Send(FrameType::DISC, true));
+                self.transmissions = 1;
+                (LinkState::Closing, Some(now + self.t1))
+            }
+            _ => (LinkState::Closed, None),
+        };
+        self.reestablishing = false;
+        self.reopen = false;
+        info!("Channel {} {:?}", self.dlci, LifecycleEvent::Closed);
+        actions.push(LinkAction::Event(LifecycleEvent::Closed));
+        actions
+    }
+
+    /// Reset the channel: disconnect it with DISC, then open it again once the modem
+    /// acknowledged the DISC or it timed out.
+    ///
+    /// The reopening is a re-establishment, retried up to `max_retries` times.
+    pub fn reset(&mut self, now: Instant) -> Vec<LinkAction> {
+        if self.state != LinkState::Open {
+            self.reopen = true;
+            self.on_disconnected(now);
+            return Vec::new();
+        }
+        self.state = LinkState::Closing;
+        self.transmissions = 1;
+        self.deadline = Some(now + self.t1);
+        self.reopen = true;
+        vec![LinkAction::Send(FrameType::DISC, true)]
+    }
+
+    /// Close the channel after its DISC was acknowledged or timed out, reopening it
+    /// right away if it is being reset
+    fn on_disconnected(&mut self, now: Instant) {
+        self.state = LinkState::Closed;
+        self.deadline = None;
+        if std::mem::take(&mut self.reopen) {
+            self.reestablishing = true;
+            self.attempts = 0;
+            self.state = LinkState::Waiting;
+            self.deadline = Some(now);
+        }
+    }
+
+    pub fn on_dm(&mut self, now: Instant) -> Vec<LinkAction> {
+        match self.state {
+            LinkState::Opening if !self.reestablishing && self.rejections < self.dm_retries => {
+                self.rejected = true;
+                self.rejections += 1;
+                warn!(
+                    "DLCI {} rejected with DM, retrying ({}/{})",
+                    self.dlci, self.rejections, self.dm_retries
+                );
+                self.state = LinkState::Waiting;
+                self.deadline = Some(now + self.dm_retry_delay);
+                Vec::new()
+            }
+            LinkState::Opening => {
+                debug!("Channel {} rejected with DM", self.dlci);
+                self.rejected = true;
+                self.retry(now)
+            }
+            // The modem lost the channel (e.g. its mux task restarted), open it again
+            LinkState::Open if self.dlci != 0 => {
+                warn!(
+                    "Channel {} unknown to the modem, re-establishing",
+                    self.dlci
+                );
+                self.reestablishing = true;
+                self.attempts = 0;
+                self.state = LinkState::Waiting;
+                self.deadline = Some(now);
+                vec![LinkAction::Event(LifecycleEvent::Forgotten)]
+            }
+            LinkState::Open => {
+                error!("Control channel rejected with DM, the mux is down");
+                self.state = LinkState::Closed;
+                self.deadline = None;
+                vec![LinkAction::Event(LifecycleEvent::ControlLost)]
+            }
+            // The channel we disconnected was already closed
+            LinkState::Closing => {
+                debug!("Channel {} already closed", self.dlci);
+                self.on_disconnected(now);
+                Vec::new()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Handle a SABM sent by the modem.
+    ///
+    /// A SABM on an already open control channel means the modem's mux restarted
+    /// without dropping the data channels, only the control channel state is reset.
+    pub fn on_sabm(&mut self, pf: bool) -> Vec<LinkAction> {
+        let mut actions = vec![LinkAction::Send(FrameType::UA, pf)];
+        let restarted = self.dlci == 0 && self.state == LinkState::Open;
+        let opened = self.dlci != 0 && self.state != LinkState::Open;
+        self.state = LinkState::Open;
+        self.deadline = None;
+        self.attempts = 0;
+        self.reestablishing = false;
+        self.reopen = false;
+        if restarted {
+            info!("Control channel restarted by the modem");
+            actions.push(LinkAction::Event(LifecycleEvent::ControlRestarted));
+        }
+        if opened {
+            info!("Channel {} opened by the modem", self.dlci);
+            actions.push(LinkAction::Event(LifecycleEvent::Opened));
+        }
+        actions
+    }
+
+    pub fn on_disc(&mut self, pf: bool, now: Instant) -> Vec<LinkAction> {
+        let mut actions = vec![LinkAction::Send(FrameType::UA, pf)];
+        if self.state == LinkState::Closed {
+            return actions;
+        }
+        actions.push(LinkAction::Event(LifecycleEvent::ClosedByRemote));
+        self.state = LinkState::Closed;
+        self.deadline = None;
+        self.reopen = false;
+        match self.policy {
+            ReestablishPolicy::Auto => {
+                info!(
+                    "Channel {} closed by modem, re-establishing in {:?}",
+                    self.dlci, self.delay
+                );
+                self.reestablishing = true;
+                self.attempts = 0;
+                self.state = LinkState::Waiting;
+                self.deadline = Some(now + self.delay);
+            }
+            ReestablishPolicy::Manual => {
+                info!("Channel {} closed by modem, leaving it closed", self.dlci);
+            }
+        }
+        actions
+    }
+
+    /// Fire the pending timer if its deadline has passed
+    pub fn poll_timers(&mut self, now: Instant) -> Vec<LinkAction> {
+        match self.deadline {
+            Some(deadline) if deadline <= now => {}
+            _ => return Vec::new(),
+        }
+        match self.state {
+            LinkState::Waiting => {
+                if self.reestablishing {
+                    self.attempts += 1;
+                    debug!(
+                        "Channel {} re-establishment attempt {}/{}",
+                        self.dlci, self.attempts, self.max_retries
+                    );
+                }
+                self.on_sabm_sent(now);
+                vec![LinkAction::Send(FrameType::SABM, true)]
+            }
+            LinkState::Opening if self.transmissions < self.n2 => {
+                self.transmissions += 1;
+                debug!(
+                    "Channel {} resending SABM ({}/{})",
+                    self.dlci, self.transmissions, self.n2
+                );
+                self.deadline = Some(now + self.sabm_timeout());
+                vec![LinkAction::Send(FrameType::SABM, true)]
+            }
+            LinkState::Opening => {
+                warn!(
+                    "Channel {} got no UA after {} SABM",
+                    self.dlci, self.transmissions
+                );
+                self.retry(now)
+            }
+            LinkState::Closing if self.transmissions < self.n2 => {
+                self.transmissions += 1;
+                debug!(
+                    "Channel {} resending DISC ({}/{})",
+                    self.dlci, self.transmissions, self.n2
+                );
+                self.deadline = Some(now + self.t1);
+                vec![LinkAction::Send(FrameType::DISC, true)]
+            }
+            LinkState::Closing => {
+                error!(
+                    "Channel {} got no UA after {} DISC, considering it closed",
+                    self.dlci, self.transmissions
+                );
+                self.on_disconnected(now);
+                Vec::new()
+            }
+            _ => {
+                self.deadline = None;
+                Vec::new()
+            }
+        }
+    }
+
+    fn retry(&mut self, now: Instant) -> Vec<LinkAction> {
+        if self.reestablishing && self.attempts < self.max_retries {
+            self.state = LinkState::Waiting;
+            self.deadline = Some(now + self.delay);
+            return Vec::new();
+        }
+        self.state = LinkState::Closed;
+        self.deadline = None;
+        if !self.reestablishing {
+            error!("Channel {} could not be established", self.dlci);
+            return Vec::new();
+        }
+        self.reestablishing = false;
+        error!(
+            "Channel {} could not be re-established after {} attempts",
+            self.dlci, self.attempts
+        );
+        vec![LinkAction::Event(LifecycleEvent::Degraded)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        buffer::{GSM0710Buffer, GSM0710_BUFFER_CAPACITY},
+        types::{Address, Control, Frame},
+    };
+    use ringbuffer::AllocRingBuffer;
+
+    #[test]
+    fn credits_are_spent_and_granted() {
+        let mut link = ChannelLink::new(
+            1,
+            ReestablishPolicy::Manual,
+            Duration::ZERO,
+            3,
+            Duration::from_secs(1),
+            3,
+        );
+        link.tx_credits = 1;
+        assert!(link.take_credit());
+        assert!(!link.take_credit());
+        assert!(!link.add_credits(0));
+        assert!(link.add_credits(3));
+        assert!(!link.add_credits(1));
+        assert_eq!(link.tx_credits, 4);
+
+        // Half of the modem's credits are spent before new ones are granted
+        for _ in 0..INITIAL_CREDITS / 2 {
+            assert_eq!(link.on_credit_used(), None);
+        }
+        assert_eq!(link.on_credit_used(), Some(INITIAL_CREDITS / 2 + 1));
+        assert_eq!(link.on_credit_used(), None);
+    }
+
+    #[test]
+    fn cr_convention_per_channel() {
+        let normal = CrConvention {
+            policy: CrPolicy::Strict,
+            invert_cr: false,
+            is_initiator: true,
+        };
+        let inverted = CrConvention {
+            invert_cr: true,
+            ..normal
+        };
+        assert!(normal.tx_cr(FrameType::SABM));
+        assert!(!normal.tx_cr(FrameType::UA));
+        assert!(!inverted.tx_cr(FrameType::UIH));
+        assert!(inverted.tx_cr(FrameType::DM));
+
+        let frame = |cr, frame_type| {
+            Frame::new(
+                Address::new_address(cr, true, 1),
+                Control::new_control(frame_type, true),
+                vec![],
+            )
+        };
+        // The modem answers with C/R=1 and sends commands with C/R=0, unless inverted
+        assert!(normal.accepts(&frame(true, FrameType::UA).as_frame_ref()));
+        assert!(!normal.accepts(&frame(false, FrameType::UA).as_frame_ref()));
+        assert!(normal.accepts(&frame(false, FrameType::UIH).as_frame_ref()));
+        assert!(!inverted.accepts(&frame(true, FrameType::UA).as_frame_ref()));
+        assert!(inverted.accepts(&frame(true, FrameType::UIH).as_frame_ref()));
+        assert!(CrConvention::default().accepts(&frame(false, FrameType::UA).as_frame_ref()));
+
+        // As the responder the table is mirrored
+        let responder = CrConvention {
+            is_initiator: false,
+            ..normal
+        };
+        assert!(!responder.tx_cr(FrameType::SABM));
+        assert!(responder.tx_cr(FrameType::UA));
+        assert!(responder.accepts(&frame(true, FrameType::SABM).as_frame_ref()));
+        assert!(!responder.accepts(&frame(true, FrameType::UA).as_frame_ref()));
+    }
+
+    #[derive(Debug)]
+    struct Xor(u8);
+
+    impl Transform for Xor {
+        fn encode(&mut self, data: Vec<u8>) -> Vec<u8> {
+            data.into_iter().map(|b| b ^ self.0).collect()
+        }
+
+        fn decode(&mut self, data: Vec<u8>) -> Vec<u8> {
+            self.encode(data)
+        }
+    }
+
+    #[test]
+    fn transform_round_trip() {
+        let mut tx = ChannelBehavior::new(1, None);
+        let mut rx = ChannelBehavior::new(1, None);
+        tx.set_transform(Box::new(Xor(0x5A)));
+        rx.set_transform(Box::new(Xor(0x5A)));
+
+        let payload = b"AT+CSQ\r\n".to_vec();
+        let content = tx.encode_tx(payload.clone());
+        assert_ne!(content, payload);
+        let frame = Frame::new(
+            Address::new_address(true, true, 1),
+            Control::new_control(FrameType::UIH, false),
+            content,
+        );
+
+        let mut buffer = AllocRingBuffer::<u8>::new(GSM0710_BUFFER_CAPACITY);
+        buffer.push_vec(frame.try_to_bytes().unwrap());
+        let received = buffer.pop_frame1().unwrap();
+        assert_eq!(rx.decode_rx(&received.content), payload);
+    }
+
+    #[test]
+    fn integrity_trailer_catches_corruption() {
+        let mut tx = ChannelBehavior::new(1, None);
+        let mut rx = ChannelBehavior::new(1, None);
+        assert_eq!(tx.seal_tx(b"AT".to_vec()), b"AT");
+        assert_eq!(rx.unseal_rx(b"AT"), Some(&b"AT"[..]));
+
+        tx.set_integrity(true, false);
+        rx.set_integrity(false, true);
+        // CRC-16/X-25 check value
+        assert_eq!(tx.seal_tx(b"123456789".to_vec())[9..], [0x90, 0x6E]);
+        let sealed = tx.seal_tx(b"AT+CSQ\r\n".to_vec());
+        assert_eq!(sealed.len(), 8 + INTEGRITY_TRAILER);
+        assert_eq!(rx.unseal_rx(&sealed), Some(&b"AT+CSQ\r\n"[..]));
+        let mut corrupt = sealed;
+        corrupt[3] ^= 0x01;
+        assert_eq!(rx.unseal_rx(&corrupt), None);
+        assert_eq!(rx.unseal_rx(&[0x90]), None);
+        // The directions are independent
+        assert_eq!(rx.seal_tx(b"AT".to_vec()), b"AT");
+    }
+
+    #[test]
+    fn identity_is_default() {
+        let mut behavior = ChannelBehavior::new(1, None);
+        assert_eq!(behavior.encode_tx(vec![1, 2, 3]), vec![1, 2, 3]);
+        // Without a transform the received data isn't copied
+        assert!(matches!(
+            behavior.decode_rx(&[1, 2, 3]),
+            Cow::Borrowed([1, 2, 3])
+        ));
+    }
+
+    #[test]
+    fn line_buffer_splits_lines() {
+        let mut lines = LineBuffer::default();
+        assert!(lines.push(b"\r\nOK").is_empty());
+        assert_eq!(lines.push(b"\r\n+CREG: 1\r\n"), vec!["OK", "+CREG: 1"]);
+    }
+
+    #[test]
+    fn only_at_channel_parses_urcs() {
+        let data = b"\r\nRING\r\n\r\n+CMTI: \"SM\",3\r\n\r\nOK\r\n";
+        let mut at = ChannelBehavior::new(2, Some(2));
+        let mut ppp = ChannelBehavior::new(1, Some(2));
+        assert_eq!(at.kind, ChannelKind::At);
+        assert_eq!(ppp.kind, ChannelKind::Data);
+        assert_eq!(at.inspect_rx(data), vec!["RING", "+CMTI: \"SM\",3"]);
+        assert!(ppp.inspect_rx(data).is_empty());
+    }
+
+    #[test]
+    fn no_at_channel_by_default() {
+        assert_eq!(ChannelKind::for_dlci(1, None), ChannelKind::Data);
+    }
+
+    #[test]
+    fn duplicates_are_suppressed_within_window() {
+        let now = Instant::now();
+        let window = Duration::from_millis(500);
+        let mut behavior = ChannelBehavior::new(3, None);
+        // Disabled by default
+        assert!(!behavior.is_duplicate(b"+CMTI: \"SM\",1", now));
+        assert!(!behavior.is_duplicate(b"+CMTI: \"SM\",1", now));
+
+        behavior.set_duplicate_window(window);
+        assert!(!behavior.is_duplicate(b"+CMTI: \"SM\",1", now));
+        assert!(behavior.is_duplicate(b"+CMTI: \"SM\",1", now + window / 2));
+        assert!(!behavior.is_duplicate(b"+CMTI: \"SM\",2", now + window / 2));
+        // Expired
+        assert!(!behavior.is_duplicate(b"+CMTI: \"SM\",1", now + window));
+        assert_eq!(behavior.suppressed(), 1);
+    }
+
+    fn open_link(policy: ReestablishPolicy, now: Instant) -> ChannelLink {
+        let t1 = Duration::from_millis(100);
+        let mut link = ChannelLink::new(1, policy, Duration::from_millis(100), 2, t1, 1);
+        link.on_sabm_sent(now);
+        assert_eq!(
+            link.on_ua(now),
+            vec![LinkAction::Event(LifecycleEvent::Opened)]
+        );
+        link
+    }
+
+    #[test]
+    fn manual_policy_stays_closed() {
+        let now = Instant::now();
+        let mut link = open_link(ReestablishPolicy::Manual, now);
+        assert_eq!(
+            link.on_disc(true, now),
+            vec![
+                LinkAction::Send(FrameType::UA, true),
+                LinkAction::Event(LifecycleEvent::ClosedByRemote)
+            ]
+        );
+        assert_eq!(link.state, LinkState::Closed);
+        assert!(link.poll_timers(now + Duration::from_secs(10)).is_empty());
+        assert_eq!(link.state, LinkState::Closed);
+    }
+
+    #[test]
+    fn local_close_waits_for_ua() {
+        let now = Instant::now();
+        let mut link = open_link(ReestablishPolicy::Auto, now);
+        assert_eq!(
+            link.on_close(now),
+            vec![
+                LinkAction::Send(FrameType::DISC, true),
+                LinkAction::Event(LifecycleEvent::Closed)
+            ]
+        );
+        assert_eq!(link.state, LinkState::Closing);
+        assert!(link.on_ua(now).is_empty());
+        assert_eq!(link.state, LinkState::Closed);
+        assert_eq!(
+            link.on_close(now),
+            vec![LinkAction::Event(LifecycleEvent::Closed)]
+        );
+    }
+
+    #[test]
+    fn unanswered_commands_are_resent_n2_times() {
+        let mut now = Instant::now();
+        let t1 = Duration::from_millis(100);
+        let mut link = ChannelLink::new(1, ReestablishPolicy::Manual, t1, 3, t1, 3);
+        let sent = |link: &mut ChannelLink, now| {
+            link.poll_timers(now)
+                .iter()
+                .filter(|action| matches!(action, LinkAction::Send(..)))
+                .count()
+        };
+
+        link.on_sabm_sent(now);
+        let mut sabms = 1;
+        for _ in 0..5 {
+            now += t1;
+            sabms += sent(&mut link, now);
+        }
+        assert_eq!(sabms, 3);
+        assert_eq!(link.state, LinkState::Closed);
+
+        link.on_sabm_sent(now);
+        link.on_ua(now);
+        let mut discs = link.on_close(now).len() - 1;
+        for _ in 0..5 {
+            now += t1;
+            discs += sent(&mut link, now);
+        }
+        assert_eq!(discs, 3);
+        assert_eq!(link.state, LinkState::Closed);
+        assert_eq!(link.deadline(), None);
+    }
+
+    #[test]
+    fn sabm_retransmissions_back_off() {
+        let now = Instant::now();
+        let t1 = Duration::from_millis(20);
+        let mut link = ChannelLink::new(1, ReestablishPolicy::Manual, t1, 3, t1, 3);
+        assert_eq!(
+            link.open(now),
+            vec![LinkAction::Send(FrameType::SABM, true)]
+        );
+        // T1, then 50ms and 100ms of backoff before the second and third SABM
+        assert!(link.poll_timers(now + t1).is_empty());
+        let second = now + t1 + SABM_BACKOFF;
+        assert_eq!(
+            link.poll_timers(second),
+            vec![LinkAction::Send(FrameType::SABM, true)]
+        );
+        assert!(link.poll_timers(second + t1 + SABM_BACKOFF).is_empty());
+        let third = second + t1 + SABM_BACKOFF * 2;
+        assert_eq!(
+            link.poll_timers(third),
+            vec![LinkAction::Send(FrameType::SABM, true)]
+        );
+        // No backoff after the last one
+        assert!(link.poll_timers(third + t1).is_empty());
+        assert_eq!(link.state, LinkState::Closed);
+        assert!(!link.rejected());
+    }
+
+    #[test]
+    fn rejected_opening_is_retried_after_a_delay() {
+        let now = Instant::now();
+        let t1 = Duration::from_millis(20);
+        let mut link = ChannelLink::new(1, ReestablishPolicy::Manual, t1, 3, t1, 1);
+        link.dm_retries = 1;
+        link.dm_retry_delay = Duration::from_millis(100);
+        link.open(now);
+        assert!(link.on_dm(now).is_empty());
+        assert_eq!(link.state, LinkState::Waiting);
+        assert!(link.poll_timers(now + t1).is_empty());
+        let now = now + link.dm_retry_delay;
+        assert_eq!(
+            link.poll_timers(now),
+            vec![LinkAction::Send(FrameType::SABM, true)]
+        );
+        // The second DM is final
+        assert!(link.on_dm(now).is_empty());
+        assert_eq!(link.state, LinkState::Closed);
+        assert!(link.rejected());
+    }
+
+    #[test]
+    fn reset_reopens_once_the_disc_is_acknowledged() {
+        let now = Instant::now();
+        let mut link = open_link(ReestablishPolicy::Manual, now);
+        assert_eq!(
+            link.reset(now),
+            vec![LinkAction::Send(FrameType::DISC, true)]
+        );
+        assert_eq!(link.state, LinkState::Closing);
+        assert!(link.on_ua(now).is_empty());
+        assert_eq!(
+            link.poll_timers(now),
+            vec![LinkAction::Send(FrameType::SABM, true)]
+        );
+        assert_eq!(
+            link.on_ua(now),
+            vec![LinkAction::Event(LifecycleEvent::Reestablished)]
+        );
+        // A later local close doesn't reopen it
+        link.on_close(now);
+        link.on_ua(now);
+        assert_eq!(link.state, LinkState::Closed);
+        assert_eq!(link.deadline(), None);
+    }
+
+    #[test]
+    fn auto_policy_reestablishes() {
+        let now = Instant::now();
+        let mut link = open_link(ReestablishPolicy::Auto, now);
+        link.on_disc(true, now);
+        assert_eq!(link.state, LinkState::Waiting);
+        // Nothing happens before the delay has passed
+        assert!(link.poll_timers(now + Duration::from_millis(50)).is_empty());
+        let now = now + Duration::from_millis(100);
+        assert_eq!(
+            link.poll_timers(now),
+            vec![LinkAction::Send(FrameType::SABM, true)]
+        );
+        assert_eq!(link.state, LinkState::Opening);
+        assert_eq!(
+            link.on_ua(now),
+            vec![LinkAction::Event(LifecycleEvent::Reestablished)]
+        );
+        assert_eq!(link.state, LinkState::Open);
+    }
+
+    #[test]
+    fn auto_policy_degrades_after_retries() {
+        let mut now = Instant::now();
+        let mut link = open_link(ReestablishPolicy::Auto, now);
+        link.on_disc(false, now);
+        // First attempt is rejected with DM
+        now += Duration::from_millis(100);
+        assert_eq!(
+            link.poll_timers(now),
+            vec![LinkAction::Send(FrameType::SABM, true)]
+        );
+        assert!(link.on_dm(now).is_empty());
+        assert_eq!(link.state, LinkState::Waiting);
+        // Second attempt times out
+        now += Duration::from_millis(100);
+        assert_eq!(
+            link.poll_timers(now),
+            vec![LinkAction::Send(FrameType::SABM, true)]
+        );
+        now += Duration::from_millis(100);
+        assert_eq!(
+            link.poll_timers(now),
+            vec![LinkAction::Event(LifecycleEvent::Degraded)]
+        );
+        assert_eq!(link.state, LinkState::Closed);
+        assert!(link.poll_timers(now + Duration::from_secs(10)).is_empty());
+    }
+
+    #[test]
+    fn control_channel_restart_keeps_data_channels() {
+        let now = Instant::now();
+        let mut control = open_link(ReestablishPolicy::Manual, now);
+        control.dlci = 0;
+        let mut data = open_link(ReestablishPolicy::Manual, now);
+        assert_eq!(
+            control.on_sabm(true),
+            vec![
+                LinkAction::Send(FrameType::UA, true),
+                LinkAction::Event(LifecycleEvent::ControlRestarted)
+            ]
+        );
+        assert_eq!(control.state, LinkState::Open);
+        assert_eq!(data.state, LinkState::Open);
+        assert!(data.poll_timers(now + Duration::from_secs(10)).is_empty());
+    }
+
+    #[test]
+    fn channel_reopened_by_the_modem() {
+        let now = Instant::now();
+        let mut data = open_link(ReestablishPolicy::Manual, now);
+        data.on_disc(true, now);
+        assert_eq!(
+            data.on_sabm(true),
+            vec![
+                LinkAction::Send(FrameType::UA, true),
+                LinkAction::Event(LifecycleEvent::Opened)
+            ]
+        );
+        assert_eq!(data.state, LinkState::Open);
+        // A repeated SABM is only acknowledged
+        assert_eq!(
+            data.on_sabm(false),
+            vec![LinkAction::Send(FrameType::UA, false)]
+        );
+    }
+
+    #[test]
+    fn forgotten_channel_is_reestablished() {
+        let now = Instant::now();
+        let mut data = open_link(ReestablishPolicy::Manual, now);
+        // After the restart the modem answers DM on the data channel
+        assert_eq!(
+            data.on_dm(now),
+            vec![LinkAction::Event(LifecycleEvent::Forgotten)]
+        );
+        assert_eq!(
+            data.poll_timers(now),
+            vec![LinkAction::Send(FrameType::SABM, true)]
+        );
+        assert_eq!(
+            data.on_ua(now),
+            vec![LinkAction::Event(LifecycleEvent::Reestablished)]
+        );
+        assert_eq!(data.state, LinkState::Open);
+    }
+
+    #[test]
+    fn dm_on_control_channel_is_fatal() {
+        let now = Instant::now();
+        let mut control = open_link(ReestablishPolicy::Manual, now);
+        control.dlci = 0;
+        assert_eq!(
+            control.on_dm(now),
+            vec![LinkAction::Event(LifecycleEvent::ControlLost)]
+        );
+        assert_eq!(control.state, LinkState::Closed);
+        assert!(control
+            .poll_timers(now + Duration::from_secs(10))
+            .is_empty());
+    }
+
+    #[test]
+    fn dm_answering_disc_closes_the_channel() {
+        let now = Instant::now();
+        let mut data = open_link(ReestablishPolicy::Auto, now);
+        data.on_close(now);
+        assert_eq!(data.state, LinkState::Closing);
+        assert!(data.on_dm(now).is_empty());
+        assert_eq!(data.state, LinkState::Closed);
+        assert!(data.poll_timers(now + Duration::from_secs(10)).is_empty());
+    }
+}
@@ -1,8 +1,10 @@
-use std::fmt::Display;
+use std::{fmt::Display, time::Duration};
 
 use clap::{ArgAction, Parser, ValueEnum};
 use serde::Serialize;
 
+use gsm0710::{serial::resolve_symlink_prefix, FcsAlgorithm, FlowControl, MuxMode, MuxerConfig};
+
 #[derive(ValueEnum, Clone, Default, Debug, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum ModemType {
@@ -11,6 +13,10 @@ pub enum ModemType {
     Generic,
     /// Init Sam201 modem
     Sam201,
+    /// Init Quectel EC21/EC25 modem
+    QuectelEc2x,
+    /// Init SIMCom SIM7600/SIM7000 modem
+    Sim7600,
 }
 
 impl Display for ModemType {
@@ -18,6 +24,27 @@ impl Display for ModemType {
         match self {
             ModemType::Generic => write!(f, "generic"),
             ModemType::Sam201 => write!(f, "sam201"),
+            ModemType::QuectelEc2x => write!(f, "quectel-ec2x"),
+            ModemType::Sim7600 => write!(f, "sim7600"),
+        }
+    }
+}
+
+impl ModemType {
+    /// Maximum frame size N1 the modem is set up with unless configured
+    pub fn default_frame_size(&self) -> u16 {
+        match self {
+            ModemType::QuectelEc2x | ModemType::Sim7600 => 1509,
+            ModemType::Generic | ModemType::Sam201 => 31,
+        }
+    }
+
+    /// Multiplexer mode requested with `AT+CMUX` unless configured, SAM201 modems
+    /// have always been set up in the advanced option
+    pub fn default_mode(&self) -> MuxMode {
+        match self {
+            ModemType::Sam201 => MuxMode::Advanced,
+            ModemType::Generic | ModemType::QuectelEc2x | ModemType::Sim7600 => MuxMode::Basic,
         }
     }
 }
@@ -34,6 +61,18 @@ pub struct Args {
     #[arg(short, long, default_value = "7")]
     pub channels: u8,
 
+    /// DLCI of the channel dedicated to AT commands
+    #[arg(long)]
+    pub at_channel: Option<u8>,
+
+    /// Maximum frame size N1 [default: 1509 for Quectel EC2x and SIM7600 modems, 31 otherwise]
+    #[arg(long)]
+    pub frame_size: Option<u16>,
+
+    /// Maximum frame size N1 sent in the AT+CMUX command, same as --frame-size
+    #[arg(long, conflicts_with = "frame_size")]
+    pub cmux_n1: Option<u16>,
+
     /// Baud rate to use
     #[arg(short, long, default_value = "115200")]
     pub baud: u32,
@@ -42,18 +81,152 @@ pub struct Args {
     #[arg(short, long, default_value = "generic")]
     pub modem: ModemType,
 
+    /// Multiplexer mode requested with AT+CMUX [default: advanced for SAM201 modems, basic otherwise]
+    #[arg(long)]
+    pub mode: Option<MuxMode>,
+
+    /// Algorithm computing the FCS of the frames
+    #[arg(long, default_value = "standard")]
+    pub fcs: FcsAlgorithm,
+
+    /// Detect the FCS algorithm, probing the control channel with each one in turn
+    #[arg(long, action = ArgAction::SetTrue)]
+    pub detect_fcs: bool,
+
+    /// Flow control of the data channels, credit-based grants UIH frames with credits
+    #[arg(long, default_value = "msc")]
+    pub flow_control: FlowControl,
+
     /// Create symlinks for pts. (e.g. /dev/mux)
     #[arg(short, long)]
     pub symlink_prefix: Option<String>,
 
+    /// Don't create temporary symlinks for ptys allocated from /dev/ptmx without --symlink-prefix
+    #[arg(long, action = ArgAction::SetTrue)]
+    pub no_symlink_on_ptmx: bool,
+
     /// Disable daemon mode
     #[arg(short, long, action = ArgAction::SetTrue)]
     pub no_daemon: bool,
 
-    /// Auto restart on modem not responding
-    #[arg(short, long, action = ArgAction::SetTrue)]
+    /// Auto restart on modem not responding, and re-establish every data channel the
+    /// modem closes
+    #[arg(short, long, action = ArgAction::SetTrue, conflicts_with = "auto_reestablish")]
     pub auto_restart: bool,
 
+    /// Acknowledgement timer T1, in units of 10ms
+    #[arg(long, default_value = "100")]
+    pub t1: u16,
+
+    /// Response timer T3 for the control channel establishment, in milliseconds
+    #[arg(long, default_value = "3000")]
+    pub t3_ms: u64,
+
+    /// Number of flags sent in each burst waking the modem up from power saving
+    #[arg(long, default_value = "8")]
+    pub wake_up_flags: usize,
+
+    /// Time given to a closed channel's reader to close its pty after the end of file,
+    /// in milliseconds
+    #[arg(long, default_value = "500")]
+    pub hangup_timeout_ms: u64,
+
+    /// Time given to a reader to see the hangup when its pty is closed, before the
+    /// symlink is removed, in milliseconds
+    #[arg(long, default_value = "200")]
+    pub hangup_grace_ms: u64,
+
+    /// Time to wait for each answer of the modem when shutting down, in milliseconds
+    #[arg(long, default_value = "1000")]
+    pub shutdown_timeout_ms: u64,
+
+    /// Maximum number of SABM transmissions N2
+    #[arg(long, default_value = "3")]
+    pub n2: u8,
+
+    /// Subset of AT+CMUX: 0 carries data in UIH frames, 1 in UI frames
+    #[arg(long, default_value = "0", value_parser = clap::value_parser!(u8).range(0..=1))]
+    pub subset: u8,
+
+    /// Don't negotiate the channels' parameters with PN before opening them
+    #[arg(long)]
+    pub no_pn: bool,
+
+    /// Channels to re-open automatically after the modem disconnects them (e.g. 1,2)
+    #[arg(long, value_delimiter = ',')]
+    pub auto_reestablish: Vec<u8>,
+
+    /// Delay before re-opening a disconnected channel, in milliseconds
+    #[arg(long, default_value = "1000")]
+    pub reestablish_delay_ms: u64,
+
+    /// Number of attempts to re-open a disconnected channel
+    #[arg(long, default_value = "3")]
+    pub reestablish_retries: u8,
+
+    /// Number of SABM retries after the modem rejects a channel with DM
+    #[arg(long, default_value = "0")]
+    pub dm_retries: u8,
+
+    /// Delay before retrying a SABM the modem rejected, in milliseconds
+    #[arg(long, default_value = "1000")]
+    pub dm_retry_delay_ms: u64,
+
+    /// Channels dropping frames whose C/R bit breaks the command/response convention (e.g. 0,1)
+    #[arg(long, value_delimiter = ',')]
+    pub strict_cr: Vec<u8>,
+
+    /// Channels on which the modem inverts the C/R bit convention (e.g. 1,2)
+    #[arg(long, value_delimiter = ',')]
+    pub invert_cr: Vec<u8>,
+
+    /// Channels dropping payloads the modem repeats within the duplicate window (e.g. 3)
+    #[arg(long, value_delimiter = ',')]
+    pub suppress_duplicates: Vec<u8>,
+
+    /// Channels carrying a CRC16 payload trailer when the peer is another gsm0710-rs (e.g. 1,2)
+    #[arg(long, value_delimiter = ',')]
+    pub integrity_channels: Vec<u8>,
+
+    /// Window in which a repeated payload counts as a duplicate, in milliseconds
+    #[arg(long, default_value = "1000")]
+    pub duplicate_window_ms: u64,
+
+    /// Channels replaying their last received bytes to each reader attaching to their pty (e.g. 1)
+    #[arg(long, value_delimiter = ',')]
+    pub replay_channels: Vec<u8>,
+
+    /// Number of bytes kept for replay on each of --replay-channels
+    #[arg(long, default_value = "4096")]
+    pub replay_buffer_bytes: usize,
+
+    /// Line written before the replayed bytes
+    #[arg(long)]
+    pub replay_marker: Option<String>,
+
+    /// Log the V.24 signals (RTC/RTR/IC/DV) of every channel after each modem status command
+    #[arg(long, action = ArgAction::SetTrue)]
+    pub print_msc: bool,
+
+    /// Send a TEST command on the control channel after this many seconds without traffic,
+    /// N2 of them in a row without their echo end the session
+    #[arg(long)]
+    pub keepalive_secs: Option<u64>,
+
+    /// Close down the session and re-initialize the modem every this many minutes,
+    /// keeping the ptys
+    #[arg(long)]
+    pub reestablish_every: Option<u64>,
+
+    /// Fail at startup if the modem runs a channel with other parameters than configured
+    #[arg(long, action = ArgAction::SetTrue)]
+    pub strict_params: bool,
+
+    /// Open every channel, print which ones the modem accepted and exit, with status 1
+    /// if any was refused
+    #[arg(long, action = ArgAction::SetTrue)]
+    pub probe_only: bool,
+
     /// Verbose mode. (e.g. -v, -vv, -vvv)
     #[arg(short, long, action = ArgAction::Count)]
     pub verbose: u8,
@@ -61,3 +234,143 @@ pub struct Args {
     /// Serial port to use
     pub port: String,
 }
+
+impl Args {
+    /// Build the multiplexer configuration from the command line
+    pub fn muxer_config(&self) -> MuxerConfig {
+        MuxerConfig {
+            baud: self.baud,
+            frame_size: self
+                .frame_size
+                .or(self.cmux_n1)
+                .unwrap_or(self.modem.default_frame_size()),
+            channels: self.channels,
+            mode: self.mode.unwrap_or(self.modem.default_mode()),
+            fcs: self.fcs,
+            detect_fcs: self.detect_fcs,
+            pty: self.pty.clone(),
+            symlink_prefix: resolve_symlink_prefix(
+                self.symlink_prefix.clone(),
+                &self.pty,
+                self.no_symlink_on_ptmx,
+                &std::env::temp_dir(),
+                &self.port,
+            ),
+            at_channel: self.at_channel,
+            t1: Duration::from_millis(self.t1 as u64 * 10),
+            t3: Duration::from_millis(self.t3_ms),
+            n2: self.n2,
+            auto_reestablish: match self.auto_restart {
+                true => (1..self.channels).collect(),
+                false => self.auto_reestablish.clone(),
+            },
+            reestablish_delay: Duration::from_millis(self.reestablish_delay_ms),
+            reestablish_retries: self.reestablish_retries,
+            dm_retries: self.dm_retries,
+            dm_retry_delay: Duration::from_millis(self.dm_retry_delay_ms),
+            strict_cr: self.strict_cr.clone(),
+            invert_cr: self.invert_cr.clone(),
+            suppress_duplicates: self.suppress_duplicates.clone(),
+            duplicate_window: Duration::from_millis(self.duplicate_window_ms),
+            replay_channels: self.replay_channels.clone(),
+            replay_buffer_bytes: self.replay_buffer_bytes,
+            replay_marker: self.replay_marker.clone(),
+            subset: self.subset,
+            wake_up_flags: self.wake_up_flags,
+            hangup_timeout: Duration::from_millis(self.hangup_timeout_ms),
+            hangup_grace: Duration::from_millis(self.hangup_grace_ms),
+            shutdown_timeout: Duration::from_millis(self.shutdown_timeout_ms),
+            negotiate: !self.no_pn,
+            print_msc: self.print_msc,
+            keepalive: self.keepalive_secs.map(Duration::from_secs),
+            strict_params: self.strict_params,
+            is_initiator: true,
+            flow_control: self.flow_control,
+            reestablish_every: self
+                .reestablish_every
+                .map(|mins| Duration::from_secs(mins * 60)),
+            integrity_channels: self.integrity_channels.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_restart_conflicts_with_auto_reestablish() {
+        let args = Args::try_parse_from(["gsm0710-rs", "-a", "-c", "3", "/dev/ttyUSB0"]).unwrap();
+        assert_eq!(args.muxer_config().auto_reestablish, vec![1, 2]);
+        let args = [
+            "gsm0710-rs",
+            "-a",
+            "--auto-reestablish",
+            "1",
+            "/dev/ttyUSB0",
+        ];
+        assert!(Args::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn cli_only_options_are_arguments() {
+        use clap::CommandFactory;
+        let command = Args::command();
+        for (option, _) in gsm0710::config::CLI_OPTIONS {
+            assert!(
+                command.get_arguments().any(|arg| arg.get_id() == *option),
+                "{}",
+                option
+            );
+        }
+    }
+
+    #[test]
+    fn cmux_n1_is_the_frame_size() {
+        let config = |args: &[&str]| {
+            let args = [&["gsm0710-rs"], args, &["/dev/ttyUSB0"]].concat();
+            Args::try_parse_from(args).unwrap().muxer_config()
+        };
+        assert_eq!(config(&[]).frame_size, 31);
+        assert_eq!(config(&["-m", "quectel-ec2x"]).frame_size, 1509);
+        assert_eq!(
+            config(&["-m", "sim7600", "--frame-size", "127"]).frame_size,
+            127
+        );
+        let quectel = config(&["-m", "quectel-ec2x", "--cmux-n1", "127"]);
+        assert_eq!(quectel.frame_size, 127);
+        assert!(quectel.quectel_cmux_command().contains(",,127,"));
+        // Out of range values are rejected like --frame-size
+        let frame_size_range = |config: MuxerConfig| {
+            let violations = config.validate();
+            violations.iter().any(|v| v.id == "frame-size-range")
+        };
+        assert!(!frame_size_range(quectel));
+        assert!(frame_size_range(config(&["--cmux-n1", "0"])));
+        let both = [
+            "gsm0710-rs",
+            "--frame-size",
+            "64",
+            "--cmux-n1",
+            "64",
+            "/dev/ttyUSB0",
+        ];
+        assert!(Args::try_parse_from(both).is_err());
+    }
+
+    #[test]
+    fn mode_defaults_per_modem() {
+        let config = |args: &[&str]| {
+            let args = [&["gsm0710-rs"], args, &["/dev/ttyUSB0"]].concat();
+            Args::try_parse_from(args).unwrap().muxer_config()
+        };
+        // SAM201 modems keep the advanced option they were always set up in
+        let sam201 = config(&["-m", "sam201"]);
+        assert_eq!(sam201.mode, MuxMode::Advanced);
+        assert_eq!(sam201.cmux_command(), "AT+CMUX=1\r\n");
+        assert_eq!(config(&[]).mode, MuxMode::Basic);
+        assert_eq!(config(&["-m", "quectel-ec2x"]).mode, MuxMode::Basic);
+        let basic = config(&["-m", "sam201", "--mode", "basic"]);
+        assert_eq!(basic.cmux_command(), "AT+CMUX=0\r\n");
+    }
+}
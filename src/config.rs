@@ -0,0 +1,404 @@
+//! Cross-validation of the options of a [`MuxerConfig`].
+//!
+//! Each rule of [`RULES`] names the options it involves, so that every option either
+//! takes part in a rule or is listed in [`NO_INTERACTIONS`]. Options of the command
+//! line without a field of their own are listed in [`CLI_OPTIONS`].
+use std::fmt::Display;
+
+use crate::{
+    mux::MuxerConfig,
+    types::{FcsAlgorithm, FlowControl},
+    MuxMode,
+};
+
+/// How bad a violated rule is
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Severity {
+    /// The configuration can't work, the mux refuses to start
+    Error,
+    /// The configuration works but likely not as intended
+    Warn,
+}
+
+/// Constraint between options of a [`MuxerConfig`]
+pub struct ConfigRule {
+    pub id: &'static str,
+    pub severity: Severity,
+    /// Fields of [`MuxerConfig`] the rule involves
+    pub options: &'static [&'static str],
+    pub explanation: &'static str,
+    /// Whether the configuration breaks the rule
+    pub violated: fn(&MuxerConfig) -> bool,
+}
+
+/// Options that don't constrain any other
+pub const NO_INTERACTIONS: &[&str] = &[
+    "baud",
+    "pty",
+    "symlink_prefix",
+    "t1",
+    "t3",
+    "reestablish_delay",
+    "reestablish_retries",
+    "hangup_timeout",
+    "hangup_grace",
+    "shutdown_timeout",
+    "print_msc",
+    "strict_params",
+    "is_initiator",
+];
+
+/// Options of the command line without a field of their own, with the fields they
+/// set. They are checked through the rules of those fields.
+pub const CLI_OPTIONS: &[(&str, &[&str])] = &[
+    ("modem", &["frame_size", "mode"]),
+    ("cmux_n1", &["frame_size"]),
+    ("auto_restart", &["auto_reestablish"]),
+];
+
+/// Whether a DLCI list names a channel the mux doesn't open
+fn out_of_range(config: &MuxerConfig, dlcis: &[u8]) -> bool {
+    dlcis.iter().any(|&dlci| dlci >= config.channels)
+}
+
+pub const RULES: &[ConfigRule] = &[
+    ConfigRule {
+        id: "channels-range",
+        severity: Severity::Error,
+        options: &["channels"],
+        explanation: "channels must be between 1 and 64, DLCI 0 is the control channel",
+        violated: |c| c.channels == 0 || c.channels > 64,
+    },
+    ConfigRule {
+        id: "channel-out-of-range",
+        severity: Severity::Error,
+        options: &[
+            "channels",
+            "at_channel",
+            "auto_reestablish",
+            "strict_cr",
+            "invert_cr",
+            "suppress_duplicates",
+            "replay_channels",
+            "integrity_channels",
+        ],
+        explanation: "per-channel options must name DLCIs below the number of channels",
+        violated: |c| {
+            c.at_channel.is_some_and(|dlci| dlci >= c.channels)
+                || out_of_range(c, &c.auto_reestablish)
+                || out_of_range(c, &c.strict_cr)
+                || out_of_range(c, &c.invert_cr)
+                || out_of_range(c, &c.suppress_duplicates)
+                || out_of_range(c, &c.replay_channels)
+                || out_of_range(c, &c.integrity_channels)
+        },
+    },
+    ConfigRule {
+        id: "reestablish-control-channel",
+        severity: Severity::Warn,
+        options: &["auto_reestablish"],
+        explanation: "the control channel is never re-established, losing it ends the session",
+        violated: |c| c.auto_reestablish.contains(&0),
+    },
+    ConfigRule {
+        id: "frame-size-range",
+        severity: Severity::Error,
+        options: &["frame_size"],
+        explanation: "the maximum frame size N1 must be between 1 and 32767",
+        violated: |c| c.frame_size == 0 || c.frame_size > 0x7FFF,
+    },
+    ConfigRule {
+        id: "error-recovery-unsupported",
+        severity: Severity::Error,
+        options: &["mode"],
+        explanation: "the error recovery mode needs I frames, the mux only sends UIH and UI frames",
+        violated: |c| c.mode == MuxMode::ErrorRecovery,
+    },
+    ConfigRule {
+        id: "subset-range",
+        severity: Severity::Error,
+        options: &["subset"],
+        explanation: "the subset is 0 for UIH or 1 for UI frames",
+        violated: |c| c.subset > 1,
+    },
+    ConfigRule {
+        id: "n2-zero",
+        severity: Severity::Error,
+        options: &["n2"],
+        explanation: "N2 counts the transmissions of SABM and DISC, at least one is needed",
+        violated: |c| c.n2 == 0,
+    },
+    ConfigRule {
+        id: "wake-up-without-flags",
+        severity: Severity::Error,
+        options: &["wake_up_flags"],
+        explanation: "a modem in power saving can't be woken up without flags",
+        violated: |c| c.wake_up_flags == 0,
+    },
+    ConfigRule {
+        id: "at-channel-duplicates",
+        severity: Severity::Warn,
+        options: &["at_channel", "suppress_duplicates"],
+        explanation: "suppressing duplicates on the AT channel drops repeated answers like OK",
+        violated: |c| {
+            c.at_channel
+                .is_some_and(|dlci| c.suppress_duplicates.contains(&dlci))
+        },
+    },
+    ConfigRule {
+        id: "duplicate-window-zero",
+        severity: Severity::Warn,
+        options: &["suppress_duplicates", "duplicate_window"],
+        explanation: "with an empty duplicate window no payload counts as a duplicate",
+        violated: |c| !c.suppress_duplicates.is_empty() && c.duplicate_window.is_zero(),
+    },
+    ConfigRule {
+        id: "replay-without-bytes",
+        severity: Severity::Error,
+        options: &["replay_channels", "replay_buffer_bytes"],
+        explanation: "replay channels need a replay buffer of at least 1 byte",
+        violated: |c| !c.replay_channels.is_empty() && c.replay_buffer_bytes == 0,
+    },
+    ConfigRule {
+        id: "replay-marker-unused",
+        severity: Severity::Warn,
+        options: &["replay_marker", "replay_channels"],
+        explanation: "the replay marker is only written on replay channels",
+        violated: |c| c.replay_marker.is_some() && c.replay_channels.is_empty(),
+    },
+    ConfigRule {
+        id: "fcs-overridden-by-detection",
+        severity: Severity::Warn,
+        options: &["fcs", "detect_fcs"],
+        explanation: "the FCS algorithm is replaced by the detected one",
+        violated: |c| c.detect_fcs && c.fcs != FcsAlgorithm::Standard,
+    },
+    ConfigRule {
+        id: "dm-retry-without-delay",
+        severity: Severity::Warn,
+        options: &["dm_retries", "dm_retry_delay"],
+        explanation: "a SABM retried right after a DM is likely rejected again",
+        violated: |c| c.dm_retries > 0 && c.dm_retry_delay.is_zero(),
+    },
+    ConfigRule {
+        id: "keepalive-zero",
+        severity: Severity::Error,
+        options: &["keepalive"],
+        explanation: "a keepalive of 0 seconds floods the control channel with TEST commands",
+        violated: |c| c.keepalive.is_some_and(|idle| idle.is_zero()),
+    },
+    ConfigRule {
+        id: "reestablish-every-zero",
+        severity: Severity::Error,
+        options: &["reestablish_every"],
+        explanation: "a session of 0 minutes is re-established as soon as it opens",
+        violated: |c| c.reestablish_every.is_some_and(|every| every.is_zero()),
+    },
+    ConfigRule {
+        id: "credits-with-ui-frames",
+        severity: Severity::Error,
+        options: &["flow_control", "subset"],
+        explanation: "credits are only carried in UIH frames, subset 1 sends data in UI frames",
+        violated: |c| c.flow_control == FlowControl::CreditBased && c.subset == 1,
+    },
+    ConfigRule {
+        id: "credits-without-pn",
+        severity: Severity::Warn,
+        options: &["flow_control", "negotiate"],
+        explanation: "without PN the modem is never asked for credit based flow control",
+        violated: |c| c.flow_control == FlowControl::CreditBased && !c.negotiate,
+    },
+];
+
+/// Rule broken by a configuration
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Violation {
+    pub id: &'static str,
+    pub severity: Severity,
+    pub options: &'static [&'static str],
+    pub explanation: &'static str,
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warn => "warning",
+        };
+        write!(
+            f,
+            "{} {}: {} ({})",
+            severity,
+            self.id,
+            self.explanation,
+            self.options.join(", ")
+        )
+    }
+}
+
+impl MuxerConfig {
+    /// Every rule of [`RULES`] the configuration breaks
+    pub fn validate(&self) -> Vec<Violation> {
+        RULES
+            .iter()
+            .filter(|rule| (rule.violated)(self))
+            .map(|rule| Violation {
+                id: rule.id,
+                severity: rule.severity,
+                options: rule.options,
+                explanation: rule.explanation,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Names of the given fields of [`MuxerConfig`], failing to compile unless they
+    /// are all of them
+    macro_rules! config_fields {
+        ($($field:ident),* $(,)?) => {{
+            let MuxerConfig { $($field: _),* } = MuxerConfig::default();
+            vec![$(stringify!($field)),*]
+        }};
+    }
+
+    #[test]
+    fn every_option_declares_its_interactions() {
+        let fields = config_fields!(
+            baud,
+            frame_size,
+            channels,
+            mode,
+            fcs,
+            detect_fcs,
+            pty,
+            symlink_prefix,
+            at_channel,
+            t1,
+            t3,
+            n2,
+            auto_reestablish,
+            reestablish_delay,
+            reestablish_retries,
+            dm_retries,
+            dm_retry_delay,
+            strict_cr,
+            invert_cr,
+            suppress_duplicates,
+            duplicate_window,
+            replay_channels,
+            replay_buffer_bytes,
+            replay_marker,
+            subset,
+            wake_up_flags,
+            hangup_timeout,
+            hangup_grace,
+            shutdown_timeout,
+            negotiate,
+            print_msc,
+            keepalive,
+            strict_params,
+            is_initiator,
+            flow_control,
+            reestablish_every,
+            integrity_channels,
+        );
+        let in_rule = |field: &str| RULES.iter().any(|rule| rule.options.contains(&field));
+        for &field in &fields {
+            let alone = NO_INTERACTIONS.contains(&field);
+            assert!(in_rule(field) || alone, "{} is in no rule", field);
+            assert!(!(in_rule(field) && alone), "{} has interactions", field);
+        }
+        // Rules name existing options only, with unique ids
+        for (i, rule) in RULES.iter().enumerate() {
+            for option in rule.options {
+                assert!(fields.contains(option), "{}", option);
+            }
+            assert!(RULES[..i].iter().all(|other| other.id != rule.id));
+        }
+        for &option in NO_INTERACTIONS {
+            assert!(fields.contains(&option), "{}", option);
+        }
+        // Command line options set fields whose rules check them
+        for (option, set) in CLI_OPTIONS {
+            assert!(!fields.contains(option), "{} is a field", option);
+            assert!(!set.is_empty(), "{} sets no field", option);
+            for field in set.iter() {
+                assert!(fields.contains(field), "{} sets {}", option, field);
+                assert!(in_rule(field), "{} sets {}, in no rule", option, field);
+            }
+        }
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        assert_eq!(MuxerConfig::default().validate(), vec![]);
+    }
+
+    #[test]
+    fn error_recovery_is_rejected() {
+        let config = MuxerConfig {
+            mode: MuxMode::ErrorRecovery,
+            ..Default::default()
+        };
+        let violations = config.validate();
+        let rejected = violations
+            .iter()
+            .find(|v| v.id == "error-recovery-unsupported")
+            .unwrap();
+        assert_eq!(rejected.severity, Severity::Error);
+        for mode in [MuxMode::Basic, MuxMode::Advanced] {
+            let config = MuxerConfig {
+                mode,
+                ..Default::default()
+            };
+            assert_eq!(config.validate(), vec![]);
+        }
+    }
+
+    #[test]
+    fn replay_needs_a_buffer() {
+        let config = MuxerConfig {
+            replay_channels: vec![1],
+            replay_buffer_bytes: 0,
+            ..Default::default()
+        };
+        let violations = config.validate();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].id, "replay-without-bytes");
+        assert_eq!(violations[0].severity, Severity::Error);
+    }
+
+    #[test]
+    fn all_violations_are_reported() {
+        let config = MuxerConfig {
+            channels: 3,
+            at_channel: Some(1),
+            suppress_duplicates: vec![1],
+            duplicate_window: Duration::ZERO,
+            replay_channels: vec![5],
+            ..Default::default()
+        };
+        let violations = config.validate();
+        let ids: Vec<_> = violations.iter().map(|v| v.id).collect();
+        assert_eq!(
+            ids,
+            vec![
+                "channel-out-of-range",
+                "at-channel-duplicates",
+                "duplicate-window-zero",
+            ]
+        );
+        assert_eq!(violations[0].severity, Severity::Error);
+        assert_eq!(
+            violations[1].to_string(),
+            "warning at-channel-duplicates: suppressing duplicates on the AT channel \
+             drops repeated answers like OK (at_channel, suppress_duplicates)"
+        );
+    }
+}
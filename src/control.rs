@@ -0,0 +1,364 @@
+use anyhow::Result;
+use log::debug;
+
+use crate::{
+    error::GsmError,
+    mcc::{MccMessage, MccType, MscMessage, NscMessage, PnMessage, RlsMessage, TestMessage},
+    types::{Address, Control, ControlImpl, Frame, FrameType, CR},
+};
+
+/// Message carried in UIH frames on the control channel (DLCI 0)
+///
+/// Each message starts with a type octet and a length octet, both with an EA bit.
+/// The C/R bit of the type octet tells commands from responses.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ControlMessage {
+    /// DLC parameter negotiation
+    Pn(PnMessage),
+    /// Power saving control, with the optional parameter octet
+    Psc(Option<u8>),
+    /// Multiplexer close down
+    Cld,
+    /// Test, the pattern is echoed by the receiver
+    Test(TestMessage),
+    /// Flow control on: the receiver may send data again
+    FCon,
+    /// Flow control off: the receiver must stop sending data
+    FCoff,
+    /// Modem status command: V.24 signals of a DLCI and an optional break signal
+    Msc {
+        status: MscMessage,
+        break_signal: Option<u8>,
+    },
+    /// Remote line status: an overrun, parity or framing error on a DLCI
+    Rls(RlsMessage),
+    /// Non supported command response, carrying the type octet of the rejected command
+    Nsc(NscMessage),
+}
+
+impl ControlMessage {
+    /// Whether the C/R bit of the message's type octet marks a command
+    pub fn is_command(content: &[u8]) -> bool {
+        content.first().is_some_and(|t| t & CR == CR)
+    }
+
+    /// Parse a control channel message from the content of a UIH frame
+    pub fn parse(content: &[u8]) -> Result<ControlMessage> {
+        Self::from_mcc(&MccMessage::parse(content)?)
+    }
+
+    /// Decode the value octets of a Multiplexer Control Channel message
+    pub fn from_mcc(msg: &MccMessage) -> Result<ControlMessage> {
+        let value = &msg.data;
+        let message = match msg.msg_type {
+            MccType::Pn => ControlMessage::Pn(PnMessage::parse(value)?),
+            MccType::Psc => ControlMessage::Psc(value.first().copied()),
+            MccType::Cld => ControlMessage::Cld,
+            MccType::Test => ControlMessage::Test(TestMessage::parse(value)?),
+            MccType::FCon => ControlMessage::FCon,
+            MccType::FCoff => ControlMessage::FCoff,
+            MccType::Msc => ControlMessage::Msc {
+                status: MscMessage::parse(value)?,
+                break_signal: value.get(2).copied(),
+            },
+            MccType::Nsc => ControlMessage::Nsc(NscMessage::parse(value)?),
+            MccType::Rls => ControlMessage::Rls(RlsMessage::parse(value)?),
+            other => {
+                return Err(GsmError::InvalidControlMessage(format!(
+                    "unsupported type {:02X}",
+                    other.octet()
+                ))
+                .into())
+            }
+        };
+        Ok(message)
+    }
+
+    /// Type of the message
+    pub fn msg_type(&self) -> MccType {
+        match self {
+            ControlMessage::Pn(_) => MccType::Pn,
+            ControlMessage::Psc(_) => MccType::Psc,
+            ControlMessage::Cld => MccType::Cld,
+            ControlMessage::Test(_) => MccType::Test,
+            ControlMessage::FCon => MccType::FCon,
+            ControlMessage::FCoff => MccType::FCoff,
+            ControlMessage::Msc { .. } => MccType::Msc,
+            ControlMessage::Nsc(_) => MccType::Nsc,
+            ControlMessage::Rls(_) => MccType::Rls,
+        }
+    }
+
+    /// Value octets of the message
+    pub fn value(&self) -> Vec<u8> {
+        match self {
+            ControlMessage::Pn(pn) => pn.to_mcc_payload(),
+            ControlMessage::Psc(param) => param.iter().copied().collect(),
+            ControlMessage::Test(test) => test.to_mcc_payload(),
+            ControlMessage::Msc {
+                status,
+                break_signal,
+            } => {
+                let mut value = status.to_mcc_payload();
+                value.extend(break_signal);
+                value
+            }
+            ControlMessage::Nsc(nsc) => nsc.to_mcc_payload(),
+            ControlMessage::Rls(rls) => rls.to_mcc_payload(),
+            ControlMessage::Cld | ControlMessage::FCon | ControlMessage::FCoff => vec![],
+        }
+    }
+
+    /// Wrap the message in a Multiplexer Control Channel message, `cr` set for commands
+    pub fn to_mcc(&self, cr: bool) -> MccMessage {
+        MccMessage::new(self.msg_type(), cr, self.value())
+    }
+
+    /// Serialize the message as the content of a UIH frame, `cr` set for commands
+    pub fn try_to_bytes(&self, cr: bool) -> Result<Vec<u8>> {
+        self.to_mcc(cr).try_to_bytes()
+    }
+}
+
+/// What the answers to the modem's control commands depend on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControlState {
+    /// Address of the frames sent on the control channel
+    pub address: Address,
+    /// Largest maximum frame size N1 accepted in PN
+    pub frame_size: u16,
+}
+
+impl ControlState {
+    /// Accept the parameters the modem proposed for a channel, limiting the
+    /// maximum frame size to ours
+    pub fn accept(&self, mut pn: PnMessage) -> PnMessage {
+        pn.max_frame_size = pn.max_frame_size.min(self.frame_size);
+        pn
+    }
+}
+
+/// Changes to the multiplexer's state asked for by a control command
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct StateChanges {
+    /// The modem closed down the multiplexer (CLD)
+    pub close_down: bool,
+    /// Whether the flow of data stops (FCoff) or resumes (FCon)
+    pub flow_blocked: Option<bool>,
+    /// The modem enters power saving once answered (PSC)
+    pub asleep: bool,
+    /// V.24 signals of a channel (MSC)
+    pub modem_status: Option<MscMessage>,
+    /// Line status of a channel (RLS)
+    pub line_status: Option<RlsMessage>,
+    /// Parameters accepted for a channel (PN)
+    pub negotiated: Option<PnMessage>,
+}
+
+/// Answer a control message from the modem, without any IO.
+///
+/// Commands get their response frame, or NSC if unsupported. Responses get no answer
+/// and change nothing. The changes are meant to be applied after the response is sent.
+pub fn handle_control(
+    msg: &MccMessage,
+    state: &ControlState,
+) -> Result<(Option<Frame>, StateChanges)> {
+    let mut changes = StateChanges::default();
+    if !msg.cr {
+        return Ok((None, changes));
+    }
+    let response = match ControlMessage::from_mcc(msg) {
+        Ok(ControlMessage::Cld) => {
+            changes.close_down = true;
+            Some(ControlMessage::Cld)
+        }
+        Ok(ControlMessage::FCon) => {
+            changes.flow_blocked = Some(false);
+            Some(ControlMessage::FCon)
+        }
+        Ok(ControlMessage::FCoff) => {
+            changes.flow_blocked = Some(true);
+            Some(ControlMessage::FCoff)
+        }
+        Ok(ControlMessage::Test(test)) => Some(ControlMessage::Test(test)),
+        Ok(ControlMessage::Pn(pn)) => {
+            let pn = state.accept(pn);
+            changes.negotiated = Some(pn);
+            Some(ControlMessage::Pn(pn))
+        }
+        Ok(ControlMessage::Msc {
+            status,
+            break_signal,
+        }) => {
+            changes.modem_status = Some(status);
+            Some(ControlMessage::Msc {
+                status,
+                break_signal,
+            })
+        }
+        Ok(ControlMessage::Psc(param)) => {
+            changes.asleep = true;
+            Some(ControlMessage::Psc(param))
+        }
+        Ok(ControlMessage::Rls(rls)) => {
+            changes.line_status = Some(rls);
+            Some(ControlMessage::Rls(rls))
+        }
+        Ok(ControlMessage::Nsc(_)) => None,
+        Err(e) => {
+            debug!(
+                "Answering unsupported control command {:?} with NSC: {}",
+                msg.msg_type, e
+            );
+            Some(ControlMessage::Nsc(NscMessage::for_command(msg.msg_type)))
+        }
+    };
+    let Some(response) = response else {
+        return Ok((None, changes));
+    };
+    let content = response.try_to_bytes(false)?;
+    let frame = Frame::new(
+        state.address,
+        Control::new_control(FrameType::UIH, true),
+        content,
+    );
+    Ok((Some(frame), changes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATE: ControlState = ControlState {
+        address: 0x03,
+        frame_size: 64,
+    };
+
+    fn answer(content: &[u8]) -> (Option<Vec<u8>>, StateChanges) {
+        let msg = MccMessage::parse(content).unwrap();
+        let (frame, changes) = handle_control(&msg, &STATE).unwrap();
+        let content = frame.map(|frame| {
+            assert_eq!(frame.address, STATE.address);
+            assert_eq!(frame.control.get_frame_type().unwrap(), FrameType::UIH);
+            frame.content
+        });
+        (content, changes)
+    }
+
+    #[test]
+    fn control_commands_get_their_response() {
+        // Test, FCon and FCoff echo the command with C/R cleared
+        let (response, changes) = answer(&[0x23, 0x05, 0x41, 0x54]);
+        assert_eq!(response.unwrap(), vec![0x21, 0x05, 0x41, 0x54]);
+        assert_eq!(changes, StateChanges::default());
+        let (response, changes) = answer(&[0xA3, 0x01]);
+        assert_eq!(response.unwrap(), vec![0xA1, 0x01]);
+        assert_eq!(changes.flow_blocked, Some(false));
+        let (response, changes) = answer(&[0x63, 0x01]);
+        assert_eq!(response.unwrap(), vec![0x61, 0x01]);
+        assert_eq!(changes.flow_blocked, Some(true));
+
+        // CLD is acknowledged before closing down the link
+        let (response, changes) = answer(&[0xC3, 0x01]);
+        assert_eq!(response.unwrap(), vec![0xC1, 0x01]);
+        assert!(changes.close_down);
+
+        let (response, changes) = answer(&[0x43, 0x01]);
+        assert_eq!(response.unwrap(), vec![0x41, 0x01]);
+        assert!(changes.asleep);
+
+        let (response, changes) = answer(&[0xE3, 0x05, 0x07, 0x8D]);
+        assert_eq!(response.unwrap(), vec![0xE1, 0x05, 0x07, 0x8D]);
+        assert_eq!(changes.modem_status.unwrap().dlci, 1);
+
+        let (response, changes) = answer(&[0x53, 0x05, 0x0B, 0x03]);
+        assert_eq!(response.unwrap(), vec![0x51, 0x05, 0x0B, 0x03]);
+        let rls = changes.line_status.unwrap();
+        assert_eq!(rls.dlci, 2);
+        assert!(rls.is_error());
+    }
+
+    #[test]
+    fn pn_command_is_clamped_to_our_parameters() {
+        let pn = [0x83, 0x11, 0x03, 0x00, 0x07, 0x0A, 0x2C, 0x01, 0x03, 0x02];
+        let (response, changes) = answer(&pn);
+        let accepted = changes.negotiated.unwrap();
+        assert_eq!(accepted.max_frame_size, 64);
+        assert_eq!(accepted.window_size, 2);
+        assert_eq!(
+            response.unwrap(),
+            ControlMessage::Pn(accepted).try_to_bytes(false).unwrap()
+        );
+    }
+
+    #[test]
+    fn unsupported_commands_get_nsc_and_responses_nothing() {
+        // SNC command (type 0x34), unsupported
+        let (response, changes) = answer(&[0xD3, 0x01]);
+        assert_eq!(response.unwrap(), vec![0x11, 0x03, 0xD3]);
+        assert_eq!(changes, StateChanges::default());
+
+        // Responses, even unsupported ones, are not answered
+        for content in [&[0x21, 0x03, 0x41][..], &[0xC1, 0x01], &[0xD1, 0x01]] {
+            let (response, changes) = answer(content);
+            assert!(response.is_none());
+            assert_eq!(changes, StateChanges::default());
+        }
+    }
+
+    #[test]
+    fn control_message_msc_round_trip() {
+        let msc = ControlMessage::Msc {
+            status: MscMessage::parse(&[0x0B, 0x8D]).unwrap(),
+            break_signal: None,
+        };
+        let data = msc.try_to_bytes(true).unwrap();
+        assert_eq!(data, vec![0xE3, 0x05, 0x0B, 0x8D]);
+        assert!(ControlMessage::is_command(&data));
+        assert_eq!(ControlMessage::parse(&data).unwrap(), msc);
+
+        let msc = ControlMessage::Msc {
+            status: MscMessage {
+                dlci: 1,
+                ..Default::default()
+            },
+            break_signal: Some(0x03),
+        };
+        let data = msc.try_to_bytes(false).unwrap();
+        assert!(!ControlMessage::is_command(&data));
+        assert_eq!(ControlMessage::parse(&data).unwrap(), msc);
+    }
+
+    #[test]
+    fn control_message_pn_round_trip() {
+        let pn = ControlMessage::Pn(PnMessage {
+            dlci: 3,
+            frame_type: 0,
+            credit_flow: 0,
+            priority: 7,
+            ack_timer: 10,
+            max_frame_size: 300,
+            max_retransmit: 3,
+            window_size: 2,
+        });
+        let data = pn.try_to_bytes(true).unwrap();
+        assert_eq!(
+            data,
+            vec![0x83, 0x11, 0x03, 0x00, 0x07, 0x0A, 0x2C, 0x01, 0x03, 0x02]
+        );
+        assert_eq!(ControlMessage::parse(&data).unwrap(), pn);
+    }
+
+    #[test]
+    fn control_message_parse_rejects_malformed() {
+        assert_eq!(
+            ControlMessage::parse(&[0xC3, 0x01]).unwrap(),
+            ControlMessage::Cld
+        );
+        // Length says 2 but only 1 value octet
+        assert!(ControlMessage::parse(&[0x23, 0x05, 0xAA]).is_err());
+        assert!(ControlMessage::parse(&[0x83, 0x03, 0x01]).is_err());
+        assert!(ControlMessage::parse(&[0xFF, 0x01]).is_err());
+        assert!(ControlMessage::parse(&[]).is_err());
+    }
+}
@@ -1,13 +1,70 @@
-use thiserror::Error;
+use alloc::string::String;
+use core::fmt::Display;
 
-#[derive(Error, Debug)]
+#[derive(Debug)]
 pub enum GsmError {
-    #[error("AT command failed: {0}")]
     AtCommandFailed(String),
-    #[error("AT command timed out: {0}")]
     AtCommandTimedOut(String),
-    #[error("Unsupported frame type: {0}")]
     UnsupportedFrameType(String),
-    #[error("Unsupported modem type: {0}")]
     UnsupportedModemType(String),
+    FrameTooLong(usize),
+    SabmTimeout(u8),
+    ChannelRejected(u8),
+    ChannelNotOpen(u8),
+    NoCredits(u8),
+    PrefixInUse(String, u32),
+    FcsError { expected: u8, got: u8 },
+    InvalidFlag(u8),
+    ParseFrameError(String),
+    WakeUpTimeout,
+    FcsUndetected,
+    InvalidControlMessage(String),
+    InvalidConfig(String),
+    ParameterDrift(String),
+    PingTimeout,
+    ClosedDown,
+    SessionExpired,
 }
+
+impl Display for GsmError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            GsmError::AtCommandFailed(cmd) => write!(f, "AT command failed: {}", cmd),
+            GsmError::AtCommandTimedOut(cmd) => write!(f, "AT command timed out: {}", cmd),
+            GsmError::UnsupportedFrameType(t) => write!(f, "Unsupported frame type: {}", t),
+            GsmError::UnsupportedModemType(t) => write!(f, "Unsupported modem type: {}", t),
+            GsmError::FrameTooLong(n) => write!(f, "Frame too long: {} bytes", n),
+            GsmError::SabmTimeout(dlci) => write!(f, "No UA received for SABM on DLCI {}", dlci),
+            GsmError::ChannelRejected(dlci) => {
+                write!(f, "Channel rejected by modem: DLCI {}", dlci)
+            }
+            GsmError::ChannelNotOpen(dlci) => write!(f, "Channel not open: DLCI {}", dlci),
+            GsmError::NoCredits(dlci) => write!(f, "No credits left on DLCI {}", dlci),
+            GsmError::PrefixInUse(prefix, pid) => write!(
+                f,
+                "Symlink prefix {} is in use by process {}, use a distinct --symlink-prefix",
+                prefix, pid
+            ),
+            GsmError::FcsError { expected, got } => {
+                write!(f, "Bad FCS: expected {:#04X}, got {:#04X}", expected, got)
+            }
+            GsmError::InvalidFlag(flag) => write!(f, "Invalid flag: {:#04X}", flag),
+            GsmError::ParseFrameError(e) => write!(f, "Failed to parse frame: {}", e),
+            GsmError::WakeUpTimeout => write!(f, "Modem did not answer the wake-up flags"),
+            GsmError::FcsUndetected => {
+                write!(f, "No frame of the modem validates under any FCS algorithm")
+            }
+            GsmError::PingTimeout => write!(f, "Modem did not echo the TEST command"),
+            GsmError::ClosedDown => write!(f, "Modem closed down the multiplexer"),
+            GsmError::SessionExpired => write!(f, "Session reached its maximum duration"),
+            GsmError::InvalidControlMessage(e) => write!(f, "Invalid control message: {}", e),
+            GsmError::InvalidConfig(e) => write!(f, "Invalid configuration:\n{}", e),
+            GsmError::ParameterDrift(e) => {
+                write!(f, "Parameters differ from the configuration:\n{}", e)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for GsmError {}
@@ -0,0 +1,54 @@
+//! A GSM 07.10 / 3GPP 27.010 multiplexer.
+//!
+//! The [`Muxer`] bridges the logical channels of a serial port to ptys. The frame
+//! codec in [`types`] and the frame buffer in [`buffer`] can be used on their own.
+//! The `tokio` feature adds an async multiplexer handing out channels, in `tokio_mux`.
+//!
+//! Without the default `std` feature only the frame codec in [`types`] is built,
+//! for `no_std` targets with an allocator.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod buffer;
+#[cfg(feature = "std")]
+pub mod channel;
+#[cfg(feature = "std")]
+pub mod config;
+#[cfg(feature = "std")]
+pub mod control;
+pub mod error;
+#[cfg(feature = "std")]
+pub mod mcc;
+#[cfg(feature = "std")]
+pub mod mux;
+#[cfg(feature = "std")]
+pub mod serial;
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
+#[cfg(feature = "std")]
+pub mod stats;
+#[cfg(all(test, feature = "std"))]
+mod test_alloc;
+#[cfg(feature = "tokio")]
+pub mod tokio_mux;
+pub mod types;
+
+#[cfg(feature = "std")]
+pub use buffer::GSM0710Buffer;
+#[cfg(feature = "std")]
+pub use control::ControlMessage;
+pub use error::GsmError;
+#[cfg(feature = "std")]
+pub use mux::{
+    Drift, DriftReason, DriftReport, Muxer, MuxerConfig, ProbeReport, ProbeResult, ResetDataPolicy,
+};
+#[cfg(feature = "std")]
+pub use serial::PtyWriteFrame;
+#[cfg(feature = "tokio")]
+pub use tokio_mux::{AsyncChannel, AsyncMuxer};
+pub use types::{
+    serialize_batch, Address, AddressImpl, Control, ControlImpl, FcsAlgorithm, FlowControl, Frame,
+    FrameBuilder, FrameCodec, FrameRef, FrameType, Framing, MuxMode, ParseError,
+};
@@ -1,40 +1,36 @@
-use std::{
-    collections::HashMap,
-    io::{Read, Write},
-    time::Duration,
-};
-
 use anyhow::Result;
-use buffer::{GSM0710Buffer, GSM0710_BUFFER_CAPACITY};
 use clap::Parser;
 use cli::{Args, ModemType};
-use error::GsmError;
-use log::{debug, error, info};
-use mio::{Events, Poll, Token};
+use gsm0710::{
+    config::Severity,
+    serial::{claim_symlink_prefix, init_quectel_ec2x, init_sam201, init_sim7600},
+    GsmError, Muxer, MuxerConfig,
+};
+use log::{error, info, warn};
 use mio_serial::{SerialPortBuilderExt, SerialStream};
-use ringbuffer::{AllocRingBuffer, RingBuffer};
-use serial::{at_command, openpty, PtyStream, PtyWriteFrame};
-use signal_hook::consts::signal::*;
-use signal_hook_mio::v0_8::Signals;
-use types::{AddressImpl, ControlImpl, Frame, FrameType, CR, C_CLD};
-mod buffer;
+use nix::unistd::{access, AccessFlags};
+use std::process::ExitCode;
 mod cli;
-mod error;
-mod serial;
-mod types;
 
-pub fn init_sam201(ss: &mut SerialStream) -> Result<()> {
-    const MUX_CMD: &str = "AT+CMUX=1\r\n";
-    const HOLA_CMD: &str = "AT\r\n";
+/// Delay before re-initializing the modem after the multiplexer failed
+const RESTART_DELAY: std::time::Duration = std::time::Duration::from_secs(3);
+/// Exit status once the modem closed down the multiplexer with CLD
+const CLOSED_DOWN_EXIT_CODE: u8 = 3;
 
-    info!("Initializing SAM-201 modem");
-    at_command(ss, HOLA_CMD, 100)?;
-    at_command(ss, MUX_CMD, 100)?;
-    info!("SAM-201 modem initialized");
+/// Put the modem in multiplexed mode
+fn init_modem(args: &Args, config: &MuxerConfig, ss: &mut SerialStream) -> Result<()> {
+    match args.modem {
+        ModemType::Sam201 => init_sam201(ss, &config.cmux_command())?,
+        ModemType::QuectelEc2x => init_quectel_ec2x(ss, &config.quectel_cmux_command())?,
+        ModemType::Sim7600 => init_sim7600(ss, &config.sim7600_cmux_command())?,
+        _ => return Err(GsmError::UnsupportedModemType(args.modem.to_string()).into()),
+    }
+    info!("Modem {} initialized in {} mode", args.modem, config.mode);
     Ok(())
 }
 
-fn main() -> Result<()> {
+/// Run the multiplexer. Returning, rather than exiting, drops the prefix claim and the ptys
+fn main() -> Result<ExitCode> {
     let args = Args::parse();
     let log_level = match args.verbose {
         0 => log::Level::Error,
@@ -44,150 +40,71 @@ fn main() -> Result<()> {
     };
     simple_logger::init_with_level(log_level).unwrap();
 
-    let mut buffer = AllocRingBuffer::<u8>::new(GSM0710_BUFFER_CAPACITY);
-    info!("Initialized buffer with capacity {}", buffer.capacity());
-
-    let mut ptys = HashMap::<u8, PtyStream>::new();
-    for idx in 0..args.channels {
-        let pty = openpty(args.clone().pty, idx, args.clone().symlink_prefix)?;
-        ptys.insert(idx, PtyStream { inner: pty });
+    let config = args.muxer_config();
+    let violations = config.validate();
+    if violations.iter().any(|v| v.severity == Severity::Error) {
+        let report: Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+        return Err(GsmError::InvalidConfig(report.join("\n")).into());
     }
-    info!("Opened {} PTYs", ptys.len());
-
-    let mut ss = mio_serial::new(args.clone().port, args.baud)
-        .open_native_async()
-        .unwrap();
-    info!("Opened serial port {}", args.clone().port);
-
-    match args.modem {
-        ModemType::Sam201 => init_sam201(&mut ss)?,
-        _ => return Err(GsmError::UnsupportedModemType(args.modem.to_string()).into()),
+    for violation in &violations {
+        warn!("{}", violation);
     }
-    info!("Modem {} initialized", args.modem);
-
-    let addr = 0u8.with_cr(true).with_ea(true).with_dlci(0);
-    let ctrl = 0u8.with_pf(true).with_frame_type(FrameType::SABM);
-    let mut frame = Frame::new(addr, ctrl, 0, vec![0]);
-    ptys.iter_mut().for_each(|(idx, pty)| {
-        debug!("Sending SABM frame to PTY {}", idx);
-        frame.address.set_dlci(*idx);
-        pty.write_frame(frame.clone()).unwrap();
-    });
-    info!("Sent SABM frames to all PTYs");
-
-    let mut poll = Poll::new()?;
-    let mut events = Events::with_capacity(ptys.len() + 1);
+    // Held until exit so that other instances can't reuse our symlinks
+    let _claim = match &config.symlink_prefix {
+        Some(prefix) => {
+            let registry = match access("/run", AccessFlags::W_OK) {
+                Ok(()) => std::path::PathBuf::from("/run"),
+                Err(_) => std::env::temp_dir(),
+            };
+            Some(claim_symlink_prefix(prefix, &registry)?)
+        }
+        None => None,
+    };
+    loop {
+        let mut ss = mio_serial::new(args.clone().port, args.baud)
+            .open_native_async()
+            .unwrap();
+        info!("Opened serial port {}", args.clone().port);
 
-    // Register the serial port and all PTYs with the poller
-    poll.registry()
-        .register(&mut ss, Token(0), mio::Interest::READABLE)?;
-    for (idx, pty) in ptys.iter_mut() {
-        poll.registry()
-            .register(pty, Token((idx + 1).into()), mio::Interest::READABLE)?;
-    }
-    let mut signals = Signals::new([SIGTERM, SIGINT])?;
-    const SIGNAL_TOKEN: Token = Token(100);
-    poll.registry()
-        .register(&mut signals, SIGNAL_TOKEN, mio::Interest::READABLE)?;
+        init_modem(&args, &config, &mut ss)?;
 
-    'outer: loop {
-        match poll.poll(&mut events, Some(Duration::from_secs(1))) {
-            Ok(_) => {}
-            Err(e) => {
-                error!("Error polling: {}", e);
-                break;
-            }
+        let mut muxer = Muxer::new(ss, config.clone())?;
+        if args.probe_only {
+            let report = muxer.probe()?;
+            print!("{}", report);
+            return match report.all_open() {
+                true => Ok(ExitCode::SUCCESS),
+                false => Ok(ExitCode::FAILURE),
+            };
         }
-        for event in events.iter() {
-            match event.token() {
-                Token(0) => {
-                    let mut buf = vec![0u8; 1024];
-                    let n = ss.read(&mut buf)?;
-                    debug!(
-                        "Received {} bytes: {:02X?} from {}",
-                        n,
-                        &buf[..n],
-                        args.clone().port
-                    );
-                    buffer.push_vec((buf[..n]).to_vec());
-                    while let Some(frame) = buffer.pop_frame1() {
-                        match frame.address.get_frame_type() {
-                            Err(e) => {
-                                error!("Error parsing frame type: {}", e);
-                                continue;
-                            }
-                            Ok(ft) => match ft {
-                                FrameType::UIH | FrameType::UI => {
-                                    let pty = ptys.get_mut(&frame.address.get_dlci()).unwrap();
-                                    pty.inner.write_all(&frame.content)?;
-                                }
-                                _ => {}
-                            },
-                        }
-                    }
-                }
-                SIGNAL_TOKEN => {
-                    info!("Received signal, exiting");
-                    break 'outer;
-                }
-                Token(idx) => {
-                    let idx_real = (idx - 1) as u8;
-                    let pty = ptys.get_mut(&idx_real).unwrap();
-                    let mut buf = vec![0u8; 1024];
-                    let n = match pty.inner.read(&mut buf) {
-                        Ok(n) => n,
-                        Err(e) => {
-                            error!("Error reading from PTY {}: {}", idx_real, e);
-                            break;
-                        }
-                    };
-                    debug!(
-                        "Received {} bytes from PTY {}: {:02X?}",
-                        n,
-                        idx_real,
-                        &buf[..n]
-                    );
-
-                    let frame = Frame::new(
-                        addr.with_dlci(idx_real),
-                        ctrl.with_frame_type(FrameType::UIH),
-                        n as u16,
-                        buf[..n].to_vec(),
-                    );
-                    let data = frame.try_to_bytes()?;
-                    match ss.write_all(&data) {
-                        Ok(_) => debug!("Sent {} bytes to serial port: {:02X?}", data.len(), &data),
-                        Err(e) => {
-                            error!("Error sending data to serial port: {}", e);
-                            break;
-                        }
+        let result = loop {
+            match muxer.run() {
+                // Scheduled re-establishment, the ptys stay for the next session
+                Err(e) if matches!(e.downcast_ref(), Some(GsmError::SessionExpired)) => {
+                    info!("{}, re-initializing the modem", e);
+                    if let Err(e) = muxer.reinit(|ss| init_modem(&args, &config, ss)) {
+                        break Err(e);
                     }
                 }
+                result => break result,
             }
+        };
+        match result {
+            // The modem left multiplexed mode, its supervisor brings it back
+            Err(e) if matches!(e.downcast_ref(), Some(GsmError::ClosedDown)) => {
+                error!("{}", e);
+                return Ok(ExitCode::from(CLOSED_DOWN_EXIT_CODE));
+            }
+            // Restarting would run into the same parameters
+            Err(e) if matches!(e.downcast_ref(), Some(GsmError::ParameterDrift(_))) => {
+                return Err(e)
+            }
+            Err(e) if args.auto_restart => {
+                error!("Multiplexer stopped: {}, restarting", e);
+                drop(muxer);
+                std::thread::sleep(RESTART_DELAY);
+            }
+            result => return result.map(|()| ExitCode::SUCCESS),
         }
     }
-
-    info!("Closing logical channels");
-    ptys.iter_mut().for_each(|(idx, pty)| {
-        debug!("Sending DISC frame to PTY {}", idx);
-        if *idx != 0 {
-            let frame = Frame::new(
-                addr.with_dlci(*idx),
-                ctrl.with_frame_type(FrameType::DISC),
-                0,
-                vec![0],
-            );
-            pty.write_frame(frame.clone()).unwrap();
-        }
-    });
-    info!("Closing control channel");
-    let frame = Frame::new(
-        addr.with_dlci(0),
-        ctrl.with_frame_type(FrameType::UIH),
-        2,
-        vec![C_CLD | CR, 1],
-    );
-    ptys.get_mut(&0).unwrap().write_frame(frame)?;
-
-    Ok(())
 }
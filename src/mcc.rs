@@ -0,0 +1,499 @@
+use std::fmt::Display;
+
+use anyhow::Result;
+
+use crate::{
+    error::GsmError,
+    types::{CR, EA, MAX_LENGTH},
+};
+
+/// Type of a Multiplexer Control Channel message
+///
+/// The type octet carries the EA bit, the C/R bit and 6 bits of type.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MccType {
+    /// DLC parameter negotiation
+    Pn,
+    /// Power saving control
+    Psc,
+    /// Multiplexer close down
+    Cld,
+    /// Test command
+    Test,
+    /// Flow control on command
+    FCon,
+    /// Flow control off command
+    FCoff,
+    /// Modem status command
+    Msc,
+    /// Non supported command response
+    Nsc,
+    /// Remote port negotiation command
+    Rpn,
+    /// Remote line status command
+    Rls,
+    /// Type octet not defined by the spec, without the C/R bit
+    Unknown(u8),
+}
+
+impl MccType {
+    /// Type octet with the EA bit set and the C/R bit cleared
+    pub fn octet(&self) -> u8 {
+        match self {
+            MccType::Pn => 0x81,
+            MccType::Psc => 0x41,
+            MccType::Cld => 0xC1,
+            MccType::Test => 0x21,
+            MccType::FCon => 0xA1,
+            MccType::FCoff => 0x61,
+            MccType::Msc => 0xE1,
+            MccType::Nsc => 0x11,
+            MccType::Rpn => 0x91,
+            MccType::Rls => 0x51,
+            MccType::Unknown(octet) => *octet,
+        }
+    }
+}
+
+impl From<u8> for MccType {
+    fn from(octet: u8) -> Self {
+        match octet & !CR {
+            0x81 => MccType::Pn,
+            0x41 => MccType::Psc,
+            0xC1 => MccType::Cld,
+            0x21 => MccType::Test,
+            0xA1 => MccType::FCon,
+            0x61 => MccType::FCoff,
+            0xE1 => MccType::Msc,
+            0x11 => MccType::Nsc,
+            0x91 => MccType::Rpn,
+            0x51 => MccType::Rls,
+            other => MccType::Unknown(other),
+        }
+    }
+}
+
+/// Message of the Multiplexer Control Channel, carried in UIH frames on DLCI 0
+///
+/// | **Name** | Type    | Length  | Value                 |
+/// |----------|---------|---------|-----------------------|
+/// | **Size** | 1 octet | 1 octet | `length` octets       |
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct MccMessage {
+    pub msg_type: MccType,
+    /// C/R bit of the type octet, set for commands
+    pub cr: bool,
+    /// Number of value octets
+    pub length: u16,
+    pub data: Vec<u8>,
+}
+
+impl MccMessage {
+    /// Create a new message, deriving the length from the value octets
+    pub fn new(msg_type: MccType, cr: bool, data: Vec<u8>) -> Self {
+        MccMessage {
+            msg_type,
+            cr,
+            length: data.len() as u16,
+            data,
+        }
+    }
+
+    /// Parse a message from the content of a UIH frame
+    pub fn parse(bytes: &[u8]) -> Result<MccMessage> {
+        let [msg_type, rest @ ..] = bytes else {
+            return Err(
+                GsmError::InvalidControlMessage(format!("too short: {:02X?}", bytes)).into(),
+            );
+        };
+        // The length octets carry 7 bits each, least significant first, until the EA bit
+        let mut length: u32 = 0;
+        let mut octets = rest.iter().enumerate();
+        let data = loop {
+            let Some((i, octet)) = octets.next() else {
+                return Err(
+                    GsmError::InvalidControlMessage(format!("too short: {:02X?}", bytes)).into(),
+                );
+            };
+            length |= ((octet >> 1) as u32) << (7 * i);
+            if 7 * i >= u16::BITS as usize || length > MAX_LENGTH as u32 {
+                return Err(GsmError::InvalidControlMessage("length too long".to_string()).into());
+            }
+            if octet & EA == EA {
+                break &rest[i + 1..];
+            }
+        };
+        let length = length as u16;
+        if data.len() != length as usize {
+            return Err(GsmError::InvalidControlMessage(format!(
+                "length {} but {} value octets",
+                length,
+                data.len()
+            ))
+            .into());
+        }
+        Ok(MccMessage {
+            msg_type: MccType::from(*msg_type),
+            cr: msg_type & CR == CR,
+            length,
+            data: data.to_vec(),
+        })
+    }
+
+    /// Serialize the message as the content of a UIH frame
+    pub fn try_to_bytes(&self) -> Result<Vec<u8>> {
+        if self.length > MAX_LENGTH || self.length as usize != self.data.len() {
+            return Err(GsmError::InvalidControlMessage(format!(
+                "length {} with {} value octets",
+                self.length,
+                self.data.len()
+            ))
+            .into());
+        }
+        let msg_type = match self.cr {
+            true => self.msg_type.octet() | CR,
+            false => self.msg_type.octet(),
+        };
+        let mut bytes = vec![msg_type];
+        let mut length = self.length;
+        loop {
+            let octet = ((length & 0x7F) as u8) << 1;
+            length >>= 7;
+            match length {
+                0 => break bytes.push(octet | EA),
+                _ => bytes.push(octet),
+            }
+        }
+        bytes.extend_from_slice(&self.data);
+        Ok(bytes)
+    }
+}
+
+/// Modem status command (MSC): the V.24 signals of a DLCI
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct MscMessage {
+    pub dlci: u8,
+    /// Flow control, the sender can't accept frames
+    pub fc: bool,
+    /// Ready to communicate (DSR/DTR)
+    pub rtc: bool,
+    /// Ready to receive (RTS/CTS)
+    pub rtr: bool,
+    /// Incoming call indicator (RI)
+    pub ic: bool,
+    /// Data valid (DCD)
+    pub dv: bool,
+}
+
+impl MscMessage {
+    const FC: u8 = 1 << 1;
+    const RTC: u8 = 1 << 2;
+    const RTR: u8 = 1 << 3;
+    const IC: u8 = 1 << 6;
+    const DV: u8 = 1 << 7;
+
+    /// Parse the value octets of an MSC: the DLCI address octet and the signal octet.
+    ///
+    /// The optional break signal octet is ignored.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let [address, signals, ..] = data else {
+            return Err(GsmError::InvalidControlMessage(
+                "MSC needs a DLCI and a signal octet".to_string(),
+            )
+            .into());
+        };
+        Ok(MscMessage {
+            dlci: address >> 2,
+            fc: signals & Self::FC != 0,
+            rtc: signals & Self::RTC != 0,
+            rtr: signals & Self::RTR != 0,
+            ic: signals & Self::IC != 0,
+            dv: signals & Self::DV != 0,
+        })
+    }
+
+    /// Signal octet, with the EA bit set
+    pub fn signals(&self) -> u8 {
+        [
+            (self.fc, Self::FC),
+            (self.rtc, Self::RTC),
+            (self.rtr, Self::RTR),
+            (self.ic, Self::IC),
+            (self.dv, Self::DV),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .fold(EA, |octet, (_, bit)| octet | bit)
+    }
+
+    /// Value octets of the MSC, without break signal
+    pub fn to_mcc_payload(&self) -> Vec<u8> {
+        vec![(self.dlci << 2) | CR | EA, self.signals()]
+    }
+}
+
+impl Display for MscMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "FC={} RTC={} RTR={} IC={} DV={}",
+            self.fc as u8, self.rtc as u8, self.rtr as u8, self.ic as u8, self.dv as u8
+        )
+    }
+}
+
+/// Non supported command response (NSC): the type octet of the rejected command
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct NscMessage {
+    /// Type octet of the command, with its C/R bit
+    pub command_type: u8,
+}
+
+impl NscMessage {
+    /// NSC answering a command of the given type
+    pub fn for_command(msg_type: MccType) -> Self {
+        NscMessage {
+            command_type: msg_type.octet() | CR,
+        }
+    }
+
+    /// Parse the value octet of an NSC
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let [command_type] = data else {
+            return Err(
+                GsmError::InvalidControlMessage("NSC needs 1 value octet".to_string()).into(),
+            );
+        };
+        Ok(NscMessage {
+            command_type: *command_type,
+        })
+    }
+
+    /// Value octets of the NSC
+    pub fn to_mcc_payload(&self) -> Vec<u8> {
+        vec![self.command_type]
+    }
+}
+
+/// Test command (TEST): a pattern the receiver echoes back
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct TestMessage {
+    pub payload: Vec<u8>,
+}
+
+impl TestMessage {
+    /// Parse the value octets of a TEST, any pattern is valid
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        Ok(TestMessage {
+            payload: data.to_vec(),
+        })
+    }
+
+    /// Value octets of the TEST
+    pub fn to_mcc_payload(&self) -> Vec<u8> {
+        self.payload.clone()
+    }
+}
+
+/// Remote line status command (RLS): an error on the line of a DLCI
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct RlsMessage {
+    pub dlci: u8,
+    /// Line status octet, bit 1 set on error and bits 2-4 telling which one
+    pub status: u8,
+}
+
+impl RlsMessage {
+    const ERROR: u8 = 1 << 0;
+    const OVERRUN: u8 = 1 << 1;
+    const PARITY: u8 = 1 << 2;
+    const FRAMING: u8 = 1 << 3;
+
+    /// Parse the value octets of an RLS: the DLCI address octet and the line status octet
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let [address, status] = data else {
+            return Err(GsmError::InvalidControlMessage(
+                "RLS needs a DLCI and a line status octet".to_string(),
+            )
+            .into());
+        };
+        Ok(RlsMessage {
+            dlci: address >> 2,
+            status: status & 0x0F,
+        })
+    }
+
+    /// Whether the line status reports an error
+    pub fn is_error(&self) -> bool {
+        self.status & Self::ERROR != 0
+    }
+
+    /// Value octets of the RLS
+    pub fn to_mcc_payload(&self) -> Vec<u8> {
+        vec![(self.dlci << 2) | CR | EA, self.status]
+    }
+}
+
+impl Display for RlsMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.is_error() {
+            return write!(f, "no error");
+        }
+        match self.status & !Self::ERROR {
+            Self::OVERRUN => write!(f, "overrun error"),
+            Self::PARITY => write!(f, "parity error"),
+            Self::FRAMING => write!(f, "framing error"),
+            other => write!(f, "error {:02X}", other),
+        }
+    }
+}
+
+/// DLC parameter negotiation (PN): the parameters of a DLCI
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct PnMessage {
+    pub dlci: u8,
+    /// Type of frames used to carry information, 0 for UIH
+    pub frame_type: u8,
+    /// Convergence layer, credit based flow control when 15
+    pub credit_flow: u8,
+    pub priority: u8,
+    /// Acknowledgement timer T1 in units of 10ms
+    pub ack_timer: u8,
+    /// Maximum frame size N1
+    pub max_frame_size: u16,
+    /// Maximum number of retransmissions N2
+    pub max_retransmit: u8,
+    /// Window size k of the error recovery mode
+    pub window_size: u8,
+}
+
+impl PnMessage {
+    /// Parse the 8 value octets of a PN
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let [dlci, types, priority, ack_timer, n1_lo, n1_hi, max_retransmit, window_size] = data
+        else {
+            return Err(
+                GsmError::InvalidControlMessage("PN needs 8 value octets".to_string()).into(),
+            );
+        };
+        Ok(PnMessage {
+            dlci: dlci & 0x3F,
+            frame_type: types & 0x0F,
+            credit_flow: types >> 4,
+            priority: priority & 0x3F,
+            ack_timer: *ack_timer,
+            max_frame_size: u16::from_le_bytes([*n1_lo, *n1_hi]),
+            max_retransmit: *max_retransmit,
+            window_size: window_size & 0x07,
+        })
+    }
+
+    /// Value octets of the PN
+    pub fn to_mcc_payload(&self) -> Vec<u8> {
+        let n1 = self.max_frame_size.to_le_bytes();
+        vec![
+            self.dlci & 0x3F,
+            (self.credit_flow << 4) | (self.frame_type & 0x0F),
+            self.priority & 0x3F,
+            self.ack_timer,
+            n1[0],
+            n1[1],
+            self.max_retransmit,
+            self.window_size & 0x07,
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mcc_message_round_trip() {
+        let msg = MccMessage::parse(&[0x23, 0x07, 0xAA, 0x55, 0x01]).unwrap();
+        assert_eq!(msg.msg_type, MccType::Test);
+        assert!(msg.cr);
+        assert_eq!(msg.length, 3);
+        assert_eq!(
+            msg.try_to_bytes().unwrap(),
+            vec![0x23, 0x07, 0xAA, 0x55, 0x01]
+        );
+
+        let msg = MccMessage::new(MccType::Rls, false, vec![0x07, 0x03]);
+        assert_eq!(msg.try_to_bytes().unwrap(), vec![0x51, 0x05, 0x07, 0x03]);
+    }
+
+    #[test]
+    fn mcc_message_multi_octet_length() {
+        // 128 = 0 + (1 << 7): the first octet has no EA bit
+        let mut bytes = vec![0x23, 0x00, 0x03];
+        bytes.extend((0..128).map(|i| i as u8));
+        let msg = MccMessage::parse(&bytes).unwrap();
+        assert_eq!(msg.length, 128);
+        assert_eq!(msg.data, bytes[3..]);
+        assert_eq!(msg.try_to_bytes().unwrap(), bytes);
+        // The EA chain must end before the value octets
+        assert!(MccMessage::parse(&[0x23, 0x00]).is_err());
+        assert!(MccMessage::parse(&[0x23, 0x00, 0x00, 0x00, 0x01]).is_err());
+    }
+
+    #[test]
+    fn mcc_message_unknown_type() {
+        let msg = MccMessage::parse(&[0xF3, 0x01]).unwrap();
+        assert_eq!(msg.msg_type, MccType::Unknown(0xF1));
+        assert_eq!(msg.try_to_bytes().unwrap(), vec![0xF3, 0x01]);
+    }
+
+    #[test]
+    fn msc_message_round_trip() {
+        let msc = MscMessage::parse(&[0x0B, 0x8D, 0x03]).unwrap();
+        assert_eq!(
+            msc,
+            MscMessage {
+                dlci: 2,
+                fc: false,
+                rtc: true,
+                rtr: true,
+                ic: false,
+                dv: true,
+            }
+        );
+        assert_eq!(msc.to_mcc_payload(), vec![0x0B, 0x8D]);
+        assert_eq!(msc.to_string(), "FC=0 RTC=1 RTR=1 IC=0 DV=1");
+        assert_eq!(MscMessage::default().signals(), EA);
+        assert!(MscMessage::parse(&[0x0B]).is_err());
+    }
+
+    #[test]
+    fn nsc_message_round_trip() {
+        let nsc = NscMessage::for_command(MccType::Unknown(0xF1));
+        assert_eq!(nsc.to_mcc_payload(), vec![0xF3]);
+        assert_eq!(NscMessage::parse(&[0xF3]).unwrap(), nsc);
+        assert!(NscMessage::parse(&[]).is_err());
+    }
+
+    #[test]
+    fn rls_message_round_trip() {
+        let rls = RlsMessage::parse(&[0x07, 0x03]).unwrap();
+        assert_eq!(rls, RlsMessage { dlci: 1, status: 3 });
+        assert!(rls.is_error());
+        assert_eq!(rls.to_string(), "overrun error");
+        assert_eq!(rls.to_mcc_payload(), vec![0x07, 0x03]);
+        assert_eq!(
+            RlsMessage::parse(&[0x0B, 0x09]).unwrap().to_string(),
+            "framing error"
+        );
+        assert!(!RlsMessage::parse(&[0x07, 0x00]).unwrap().is_error());
+        assert!(RlsMessage::parse(&[0x07]).is_err());
+    }
+
+    #[test]
+    fn mcc_message_rejects_malformed() {
+        assert!(MccMessage::parse(&[0x23]).is_err());
+        assert!(MccMessage::parse(&[0x23, 0x05, 0xAA]).is_err());
+        assert!(MccMessage::parse(&[0x23, 0x04, 0xAA]).is_err());
+        assert!(MccMessage::new(MccType::Test, true, vec![0; 0x8000])
+            .try_to_bytes()
+            .is_err());
+    }
+}
@@ -0,0 +1,4793 @@
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet, VecDeque},
+    io::{ErrorKind, Read, Write},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use log::{debug, error, info, warn};
+use mio::{event::Source, Events, Interest, Poll, Token};
+use mio_serial::SerialStream;
+use signal_hook::consts::signal::*;
+use signal_hook_mio::v0_8::Signals;
+
+use crate::{
+    buffer::{ReceiveBuffer, GSM0710_BUFFER_CAPACITY},
+    channel::{
+        ChannelBehavior, ChannelLink, CrConvention, CrPolicy, LifecycleEvent, LinkAction,
+        LinkState, ReestablishPolicy, INITIAL_CREDITS, INTEGRITY_TRAILER,
+    },
+    control::{handle_control, ControlMessage, ControlState, StateChanges},
+    error::GsmError,
+    mcc::{MccMessage, MccType, MscMessage, PnMessage, TestMessage},
+    serial::{openpty, symlink_path, PtyStream, PtyWriteStatus, QueuedWriter, QUEUE_FULL},
+    stats::{Direction, DropReason, DropStats, FrameStats},
+    types::{
+        Address, AddressImpl, ControlImpl, FcsAlgorithm, FlowControl, Frame, FrameBuilder,
+        FrameRef, FrameType, Framing, MuxMode, TransparencyMode,
+    },
+};
+
+/// Maximum number of bytes read from a pty held while its channel is not open
+const HELD_TX_CAPACITY: usize = 4096;
+
+/// Response timer T2 of the control channel, in units of 10ms
+const CMUX_T2: u8 = 30;
+
+/// Window size k proposed in PN, the 07.10 default
+const DEFAULT_WINDOW_SIZE: u8 = 2;
+
+/// Interval between reads of the serial port while waiting for the modem
+const SERIAL_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Pattern of the keepalive TEST command, echoed by the modem
+const KEEPALIVE_PATTERN: &[u8] = b"gsm0710";
+
+/// Start of the TEST command offering the integrity mode, followed by a bitmap of the
+/// offered DLCIs. A modem only echoes it, another instance of this mux answers in kind.
+const INTEGRITY_OFFER: &[u8] = b"gsm0710-rs crc16";
+
+/// Pattern of the TEST command telling apart FCS algorithms that agree on empty frames
+const FCS_PROBE_PATTERN: &[u8] = b"fcs";
+
+/// Silence on the serial port after which the answers to an FCS probe are complete
+const FCS_PROBE_QUIET: Duration = Duration::from_millis(50);
+
+const SERIAL_TOKEN: Token = Token(0);
+const SIGNAL_TOKEN: Token = Token(100);
+
+/// Poll token of a channel's pty
+fn pty_token(dlci: u8) -> Token {
+    Token(dlci as usize + 1)
+}
+
+/// Handler a received frame goes to
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Route {
+    /// Multiplexer control message, carried in UIH or UI frames on DLCI 0
+    Control,
+    /// Data for the pty of a channel, carried in UIH or UI frames
+    Data(u8),
+    /// Link establishment and release of a channel: SABM, UA, DM or DISC
+    Link(u8, FrameType),
+}
+
+impl Route {
+    /// Decide where a frame of a known type goes
+    fn of(frame: &FrameRef, frame_type: FrameType) -> Route {
+        match frame_type {
+            FrameType::UIH | FrameType::UI if frame.is_control_channel() => Route::Control,
+            FrameType::UIH | FrameType::UI => Route::Data(frame.address.get_dlci()),
+            _ => Route::Link(frame.address.get_dlci(), frame_type),
+        }
+    }
+}
+
+/// Configuration of a [`Muxer`]
+#[derive(Debug, Clone)]
+pub struct MuxerConfig {
+    /// Baud rate of the serial port
+    pub baud: u32,
+    /// Maximum frame size N1
+    pub frame_size: u16,
+    /// Number of channels to open, starting with the control channel
+    pub channels: u8,
+    /// Operating mode requested with `AT+CMUX`
+    pub mode: MuxMode,
+    /// Algorithm computing the FCS of the frames
+    pub fcs: FcsAlgorithm,
+    /// Detect the FCS algorithm, sending the control channel's SABM checked with each one in turn
+    pub detect_fcs: bool,
+    /// Pty device to open
+    pub pty: String,
+    /// Prefix of the symlinks created for the channels' ptys
+    pub symlink_prefix: Option<String>,
+    /// DLCI of the channel dedicated to AT commands
+    pub at_channel: Option<u8>,
+    /// Acknowledgement timer T1, for the data channels
+    pub t1: Duration,
+    /// Response timer T3, for the control channel establishment
+    pub t3: Duration,
+    /// Maximum number of SABM transmissions N2
+    pub n2: u8,
+    /// Channels re-opened automatically after the modem disconnects them
+    pub auto_reestablish: Vec<u8>,
+    /// Delay before re-opening a disconnected channel
+    pub reestablish_delay: Duration,
+    /// Number of attempts to re-open a disconnected channel
+    pub reestablish_retries: u8,
+    /// Number of SABM retries after the modem rejects the opening of a channel with DM
+    pub dm_retries: u8,
+    /// Delay before retrying a SABM the modem rejected
+    pub dm_retry_delay: Duration,
+    /// Channels dropping received frames with an unexpected C/R bit
+    pub strict_cr: Vec<u8>,
+    /// Channels on which the modem inverts the C/R bit convention
+    pub invert_cr: Vec<u8>,
+    /// Channels dropping payloads repeated within `duplicate_window`
+    pub suppress_duplicates: Vec<u8>,
+    /// Window in which a repeated payload counts as a duplicate
+    pub duplicate_window: Duration,
+    /// Channels replaying their last received bytes to each reader attaching to their pty
+    pub replay_channels: Vec<u8>,
+    /// Number of bytes kept for replay on each of `replay_channels`
+    pub replay_buffer_bytes: usize,
+    /// Line written before the replayed bytes
+    pub replay_marker: Option<String>,
+    /// Subset of `AT+CMUX`: 0 carries data in UIH frames, 1 in UI frames
+    pub subset: u8,
+    /// Number of flags sent in each burst waking the modem up after a PSC
+    pub wake_up_flags: usize,
+    /// Time given to the reader of a closed channel's pty to close it after the end
+    /// of file
+    pub hangup_timeout: Duration,
+    /// Time given to the reader of a closed channel's pty to notice the hangup when the
+    /// master is closed, before the symlink is removed
+    pub hangup_grace: Duration,
+    /// Time to wait for each answer of the modem during [`Muxer::shutdown`]
+    pub shutdown_timeout: Duration,
+    /// Negotiate the parameters of the data channels with PN before opening them
+    pub negotiate: bool,
+    /// Log the V.24 signals of every channel after each modem status command
+    pub print_msc: bool,
+    /// Idle time after which a TEST command checks the link, none disables the keepalive.
+    /// N2 TEST commands in a row without their echo fail with [`GsmError::PingTimeout`].
+    pub keepalive: Option<Duration>,
+    /// Fail at startup if the modem runs a channel with other parameters than configured
+    pub strict_params: bool,
+    /// Whether the mux starts the session, deciding the C/R bit of the frames it sends
+    pub is_initiator: bool,
+    /// Flow control of the data channels
+    pub flow_control: FlowControl,
+    /// Duration after which the session is closed down and the modem re-initialized,
+    /// keeping the ptys
+    pub reestablish_every: Option<Duration>,
+    /// Channels whose payloads carry a CRC16 trailer when the peer is another instance
+    /// of this mux, see [`Muxer::offer_integrity`]
+    pub integrity_channels: Vec<u8>,
+}
+
+impl Default for MuxerConfig {
+    fn default() -> Self {
+        MuxerConfig {
+            baud: 115200,
+            frame_size: 31,
+            channels: 7,
+            mode: MuxMode::Basic,
+            fcs: FcsAlgorithm::Standard,
+            detect_fcs: false,
+            pty: "/dev/ptmx".to_string(),
+            symlink_prefix: None,
+            at_channel: None,
+            t1: Duration::from_millis(1000),
+            t3: Duration::from_millis(3000),
+            n2: 3,
+            auto_reestablish: Vec::new(),
+            reestablish_delay: Duration::from_millis(1000),
+            reestablish_retries: 3,
+            dm_retries: 0,
+            dm_retry_delay: Duration::from_millis(1000),
+            strict_cr: Vec::new(),
+            invert_cr: Vec::new(),
+            suppress_duplicates: Vec::new(),
+            duplicate_window: Duration::from_millis(1000),
+            replay_channels: Vec::new(),
+            replay_buffer_bytes: 4096,
+            replay_marker: None,
+            subset: 0,
+            wake_up_flags: 8,
+            hangup_timeout: Duration::from_millis(500),
+            hangup_grace: Duration::from_millis(200),
+            shutdown_timeout: Duration::from_millis(1000),
+            negotiate: true,
+            print_msc: false,
+            keepalive: None,
+            strict_params: false,
+            is_initiator: true,
+            flow_control: FlowControl::Msc,
+            reestablish_every: None,
+            integrity_channels: Vec::new(),
+        }
+    }
+}
+
+impl MuxerConfig {
+    /// `AT+CMUX` command selecting the multiplexer's mode
+    pub fn cmux_command(&self) -> String {
+        match self.mode.cmux_subset(self.subset) {
+            0 => format!("AT+CMUX={}\r\n", self.mode.cmux_mode()),
+            subset => format!("AT+CMUX={},{}\r\n", self.mode.cmux_mode(), subset),
+        }
+    }
+
+    /// `AT+CMUX` command of Quectel EC2x modems: the mode with N1, T1, N2 and T2,
+    /// without port speed, T3 and k
+    pub fn quectel_cmux_command(&self) -> String {
+        format!(
+            "AT+CMUX={},{},,{},{},{},{},,\r\n",
+            self.mode.cmux_mode(),
+            self.mode.cmux_subset(self.subset),
+            self.frame_size,
+            self.t1.as_millis() / 10,
+            self.n2,
+            CMUX_T2
+        )
+    }
+
+    /// `AT+CMUX` command of SIMCom SIM7600/SIM7000 modems: the mode with N1
+    pub fn sim7600_cmux_command(&self) -> String {
+        format!(
+            "AT+CMUX={},{},,{}\r\n",
+            self.mode.cmux_mode(),
+            self.mode.cmux_subset(self.subset),
+            self.frame_size
+        )
+    }
+
+    /// Type of the frames carrying data, selected by the subset
+    pub fn data_frame_type(&self) -> FrameType {
+        match self.subset {
+            1 => FrameType::UI,
+            _ => FrameType::UIH,
+        }
+    }
+
+    /// C/R bit convention of a channel
+    pub fn cr_convention(&self, dlci: u8) -> CrConvention {
+        CrConvention {
+            policy: match self.strict_cr.contains(&dlci) {
+                true => CrPolicy::Strict,
+                false => CrPolicy::Lenient,
+            },
+            invert_cr: self.invert_cr.contains(&dlci),
+            is_initiator: self.is_initiator,
+        }
+    }
+}
+
+/// What happens to data written to a channel's pty while the channel is reset
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum ResetDataPolicy {
+    /// Drop it, the application resends what it needs
+    #[default]
+    Discard,
+    /// Keep it and send it once the channel is open again
+    Replay,
+}
+
+/// Outcome of opening a channel in [`Muxer::probe`]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ProbeResult {
+    /// The modem answered UA
+    Open,
+    /// The modem answered DM
+    Rejected,
+    /// No answer after N2 SABM
+    TimedOut,
+    /// The channel could not be set up locally
+    Failed,
+}
+
+/// Channels opened by [`Muxer::probe`], by DLCI
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct ProbeReport {
+    pub channels: Vec<(u8, ProbeResult)>,
+}
+
+impl ProbeReport {
+    /// Whether every probed channel opened
+    pub fn all_open(&self) -> bool {
+        self.channels
+            .iter()
+            .all(|(_, result)| *result == ProbeResult::Open)
+    }
+}
+
+impl std::fmt::Display for ProbeReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (dlci, result) in &self.channels {
+            writeln!(f, "DLCI {:2}: {:?}", dlci, result)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why a parameter differs from the configuration
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DriftReason {
+    /// The modem answered PN with another value
+    PnResponse,
+    /// Neither `AT+CMUX` nor a PN carried the value, the 07.10 default applies
+    Default,
+}
+
+/// Parameter of a channel the modem runs with differently from the configuration
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Drift {
+    pub dlci: u8,
+    pub parameter: &'static str,
+    pub requested: u16,
+    pub effective: u16,
+    pub reason: DriftReason,
+}
+
+impl std::fmt::Display for Drift {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self.reason {
+            DriftReason::PnResponse => "PN response",
+            DriftReason::Default => "07.10 default, not negotiated",
+        };
+        write!(
+            f,
+            "DLCI {} {}: requested {}, effective {} ({})",
+            self.dlci, self.parameter, self.requested, self.effective, reason
+        )
+    }
+}
+
+/// Requested vs effective parameters of the open channels, see [`Muxer::param_drift`]
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct DriftReport {
+    pub drifts: Vec<Drift>,
+}
+
+impl DriftReport {
+    pub fn is_empty(&self) -> bool {
+        self.drifts.is_empty()
+    }
+}
+
+impl std::fmt::Display for DriftReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for drift in &self.drifts {
+            writeln!(f, "{}", drift)?;
+        }
+        Ok(())
+    }
+}
+
+/// DLCIs of an integrity offer, `None` for other TEST patterns
+fn parse_integrity_offer(payload: &[u8]) -> Option<Vec<u8>> {
+    let bitmap = payload.strip_prefix(INTEGRITY_OFFER)?;
+    let dlcis = (0..bitmap.len().min(8) as u8 * 8)
+        .filter(|dlci| bitmap[*dlci as usize / 8] & (1 << (dlci % 8)) != 0)
+        .collect();
+    Some(dlcis)
+}
+
+/// GSM 07.10 multiplexer, bridging the logical channels of a serial port to ptys
+pub struct Muxer<S = SerialStream> {
+    pub config: MuxerConfig,
+    serial: QueuedWriter<S>,
+    buffer: ReceiveBuffer,
+    /// PTYs indexed by DLCI
+    pub ptys: HashMap<u8, PtyStream>,
+    behaviors: HashMap<u8, ChannelBehavior>,
+    links: HashMap<u8, ChannelLink>,
+    /// Data read from ptys whose channel is not open yet, indexed by DLCI
+    held_tx: HashMap<u8, VecDeque<Vec<u8>>>,
+    /// DLCIs whose pty is no longer polled, their channel could not be established
+    unpolled: HashSet<u8>,
+    /// Last modem status reported by the modem, indexed by DLCI
+    modem_status: HashMap<u8, MscMessage>,
+    /// Line errors the modem reported with RLS, per DLCI
+    line_errors: HashMap<u8, u64>,
+    /// Parameters negotiated with PN, indexed by DLCI
+    negotiated: HashMap<u8, PnMessage>,
+    pub stats: FrameStats,
+    /// Bytes discarded, by reason
+    pub drops: DropStats,
+    poll: Poll,
+    serial_writable: bool,
+    /// Whether the modem disconnected the control channel or closed down the
+    /// multiplexer, ending the session
+    remote_closed: bool,
+    /// Whether the modem closed down the multiplexer with CLD, leaving multiplexed mode
+    closed_down: bool,
+    /// Whether the modem sent FCoff, pausing the reads of every pty until FCon
+    flow_blocked: bool,
+    /// Whether the modem entered power saving with PSC, it must be woken up before sending
+    asleep: bool,
+    /// Frames sent while the modem is in power saving, written once it woke up
+    asleep_tx: VecDeque<Frame>,
+    /// Bursts of wake-up flags sent so far
+    wake_bursts: u8,
+    /// Time the next wake-up burst is due, while waking the modem up
+    wake_deadline: Option<Instant>,
+    /// Whether the modem answered our CLD command
+    close_down_acked: bool,
+    /// Algorithm computing the FCS, detected when the control channel opens if configured
+    fcs: FcsAlgorithm,
+    /// Time of the last frame received or keepalive TEST sent, the keepalive only fires
+    /// after idle periods. Our own frames prove nothing about the link.
+    last_traffic: Instant,
+    /// Pattern of the last TEST response of the modem
+    test_echo: Option<Vec<u8>>,
+    /// Whether the last keepalive TEST awaits its echo
+    keepalive_pending: bool,
+    /// Keepalive TEST commands in a row the modem did not echo
+    keepalive_misses: u8,
+    /// Last PN response of the modem, awaited when proposing parameters
+    pn_response: Option<PnMessage>,
+    /// Channels the peer offered the integrity mode on, once it did
+    integrity_peer: Option<Vec<u8>>,
+    /// Whether we offered the integrity mode
+    integrity_offered: bool,
+    /// Whether the peer echoed our integrity offer
+    integrity_echoed: bool,
+    /// Start of the session, it is re-established once `reestablish_every` elapsed
+    session_start: Instant,
+}
+
+impl<S: Read + Write> Muxer<S> {
+    pub fn new(serial: S, config: MuxerConfig) -> Result<Self> {
+        let buffer = ReceiveBuffer::new(GSM0710_BUFFER_CAPACITY);
+        info!("Initialized buffer with capacity {}", buffer.capacity());
+        let fcs = config.fcs;
+        let stats = FrameStats::new(config.is_initiator);
+        Ok(Muxer {
+            config,
+            serial: QueuedWriter::new(serial),
+            buffer,
+            ptys: HashMap::new(),
+            behaviors: HashMap::new(),
+            links: HashMap::new(),
+            held_tx: HashMap::new(),
+            unpolled: HashSet::new(),
+            modem_status: HashMap::new(),
+            line_errors: HashMap::new(),
+            negotiated: HashMap::new(),
+            stats,
+            drops: DropStats::default(),
+            poll: Poll::new()?,
+            serial_writable: false,
+            remote_closed: false,
+            closed_down: false,
+            flow_blocked: false,
+            asleep: false,
+            asleep_tx: VecDeque::new(),
+            wake_bursts: 0,
+            wake_deadline: None,
+            close_down_acked: false,
+            fcs,
+            last_traffic: Instant::now(),
+            test_echo: None,
+            keepalive_pending: false,
+            keepalive_misses: 0,
+            pn_response: None,
+            integrity_peer: None,
+            integrity_offered: false,
+            integrity_echoed: false,
+            session_start: Instant::now(),
+        })
+    }
+
+    /// Operating mode of the multiplexer
+    pub fn mode(&self) -> MuxMode {
+        self.config.mode
+    }
+
+    /// Framing of the frames on the serial port, following the mode
+    pub fn transparency(&self) -> TransparencyMode {
+        self.config.mode.into()
+    }
+
+    /// Framing and FCS algorithm of the frames on the serial port. Frames longer than
+    /// the largest N1 of the channels are rejected.
+    pub fn framing(&self) -> Framing {
+        let max_length = self
+            .negotiated
+            .values()
+            .map(|pn| pn.max_frame_size)
+            .fold(self.config.frame_size, u16::max);
+        Framing {
+            transparency: self.transparency(),
+            fcs: self.fcs,
+            flow_control: self.config.flow_control,
+            max_length,
+        }
+    }
+
+    /// Calculate the FCS of a frame for the multiplexer's mode
+    pub fn fcs(&self, frame: &Frame) -> Result<u8> {
+        Ok(self.fcs.compute(&frame.as_frame_ref(), self.config.mode)?)
+    }
+
+    /// Algorithm computing the FCS of the frames
+    pub fn fcs_algorithm(&self) -> FcsAlgorithm {
+        self.fcs
+    }
+
+    /// Address field of a frame sent on a channel, following its C/R convention
+    fn address(&self, dlci: u8, frame_type: FrameType) -> Address {
+        let cr = self.config.cr_convention(dlci).tx_cr(frame_type);
+        Address::new_address(cr, true, dlci)
+    }
+
+    /// Builder of a frame sent on a channel, following its C/R convention and N1
+    fn frame(&self, dlci: u8, frame_type: FrameType) -> FrameBuilder {
+        Frame::builder()
+            .dlci(dlci)
+            .frame_type(frame_type)
+            .cr(self.config.cr_convention(dlci).tx_cr(frame_type))
+            .max_frame_size(self.max_frame_size(dlci) as u16)
+    }
+
+    /// Write a frame to the serial port
+    ///
+    /// Fails if the content exceeds the maximum frame size N1 negotiated for the DLCI.
+    /// Frames sent to a sleeping modem are queued until it woke up.
+    fn send_frame(&mut self, frame: &Frame) -> Result<()> {
+        if let Some(pn) = self.negotiated.get(&frame.address.get_dlci()) {
+            if frame.content.len() > pn.max_frame_size as usize {
+                bail!(GsmError::FrameTooLong(frame.content.len()));
+            }
+        }
+        if !self.asleep {
+            return self.write_frame(frame);
+        }
+        frame.try_to_bytes_with(self.framing())?;
+        self.asleep_tx.push_back(frame.clone());
+        if self.wake_deadline.is_none() {
+            self.wake_up()?;
+        }
+        Ok(())
+    }
+
+    /// Encode a frame and write it to the serial port right away
+    fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        let data = frame.try_to_bytes_with(self.framing())?;
+        if let Err(e) = self.serial.write_all(&data) {
+            if e.kind() == QUEUE_FULL {
+                let dlci = frame.address.get_dlci();
+                self.drops
+                    .record(Some(dlci), DropReason::SerialOverflow, frame.content.len());
+            }
+            return Err(e.into());
+        }
+        self.stats.record(&frame.as_frame_ref(), Direction::Tx);
+        debug!("Sent {} bytes to serial port: {:02X?}", data.len(), &data);
+        Ok(())
+    }
+
+    /// Start waking the modem up after it entered power saving with a burst of flags.
+    ///
+    /// The burst is repeated every T1 by the timers, at most N2 times, until the modem
+    /// answers with a flag or a frame. Giving up fails the timers with
+    /// [`GsmError::WakeUpTimeout`] and drops the queued frames.
+    pub fn wake_up(&mut self) -> Result<()> {
+        self.wake_bursts = 0;
+        self.send_wake_burst()
+    }
+
+    fn send_wake_burst(&mut self) -> Result<()> {
+        self.wake_bursts += 1;
+        debug!(
+            "Waking the modem up ({}/{})",
+            self.wake_bursts,
+            self.config.n2.max(1)
+        );
+        let burst = vec![self.transparency().flag(); self.config.wake_up_flags];
+        self.serial.write_all(&burst)?;
+        self.wake_deadline = Some(Instant::now() + self.config.t1);
+        Ok(())
+    }
+
+    /// The modem answered while in power saving, write the frames queued meanwhile
+    fn woke_up(&mut self) -> Result<()> {
+        if !self.asleep {
+            return Ok(());
+        }
+        info!("Modem woke up");
+        self.asleep = false;
+        self.wake_deadline = None;
+        while let Some(frame) = self.asleep_tx.pop_front() {
+            self.write_frame(&frame)?;
+        }
+        Ok(())
+    }
+
+    /// Send the next wake-up burst once T1 elapsed, giving up after N2 bursts
+    fn poll_wake_up(&mut self, now: Instant) -> Result<()> {
+        if self
+            .wake_deadline
+            .filter(|&deadline| deadline <= now)
+            .is_none()
+        {
+            return Ok(());
+        }
+        if self.wake_bursts < self.config.n2.max(1) {
+            return self.send_wake_burst();
+        }
+        self.wake_deadline = None;
+        for frame in self.asleep_tx.drain(..) {
+            let dlci = frame.address.get_dlci();
+            self.drops
+                .record(Some(dlci), DropReason::Asleep, frame.content.len());
+        }
+        bail!(GsmError::WakeUpTimeout)
+    }
+
+    /// Whether the modem is in power saving
+    pub fn is_asleep(&self) -> bool {
+        self.asleep
+    }
+
+    /// Write the frames requested by a channel's link state machine to the serial port
+    fn apply_link_actions(&mut self, dlci: u8, actions: Vec<LinkAction>) -> Result<()> {
+        for action in actions {
+            match action {
+                LinkAction::Send(frame_type, pf) => {
+                    let frame = self.frame(dlci, frame_type).pf(pf).build()?;
+                    self.send_frame(&frame)?;
+                }
+                LinkAction::Event(LifecycleEvent::Opened | LifecycleEvent::Reestablished) => {
+                    self.resume_polling(dlci)?;
+                    if dlci != 0 {
+                        self.send_modem_status(dlci, true)?;
+                    }
+                    self.flush_held_tx(dlci)?;
+                }
+                LinkAction::Event(LifecycleEvent::Degraded) => self.stop_polling(dlci)?,
+                LinkAction::Event(LifecycleEvent::ControlLost) => {
+                    bail!(GsmError::ChannelRejected(0))
+                }
+                // The modem's control channel state is gone, our data channels are kept
+                LinkAction::Event(LifecycleEvent::ControlRestarted) => {
+                    self.modem_status.clear();
+                    self.flow_blocked = false;
+                    let mut dlcis: Vec<u8> = self.links.keys().copied().collect();
+                    dlcis.sort();
+                    for dlci in dlcis {
+                        if dlci != 0 && self.is_open(dlci) {
+                            self.send_modem_status(dlci, true)?;
+                            self.flush_held_tx(dlci)?;
+                        }
+                    }
+                }
+                // Re-establishment follows, data written meanwhile is held
+                LinkAction::Event(LifecycleEvent::Forgotten) => {
+                    self.negotiated.remove(&dlci);
+                    self.modem_status.remove(&dlci);
+                }
+                LinkAction::Event(LifecycleEvent::ClosedByRemote) => {
+                    self.negotiated.remove(&dlci);
+                    self.modem_status.remove(&dlci);
+                    // Data written to a channel that stays closed would never be sent
+                    let closed = self
+                        .links
+                        .get(&dlci)
+                        .is_some_and(|link| link.state == LinkState::Closed);
+                    if dlci != 0 && closed {
+                        self.stop_polling(dlci)?;
+                    }
+                }
+                LinkAction::Event(LifecycleEvent::Closed) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop reading the pty of a channel that stays closed, dropping the data held for it
+    fn stop_polling(&mut self, dlci: u8) -> Result<()> {
+        let Some(pty) = self.ptys.get_mut(&dlci) else {
+            return Ok(());
+        };
+        if !self.unpolled.insert(dlci) {
+            return Ok(());
+        }
+        warn!("DLCI {} is closed, ignoring its pty", dlci);
+        self.poll.registry().deregister(pty)?;
+        let held = self.held_tx.remove(&dlci).unwrap_or_default();
+        let dropped = held.iter().map(Vec::len).sum();
+        self.drops
+            .record(Some(dlci), DropReason::ChannelClosed, dropped);
+        Ok(())
+    }
+
+    /// Read the pty of a channel again once it is established
+    fn resume_polling(&mut self, dlci: u8) -> Result<()> {
+        if !self.unpolled.remove(&dlci) {
+            return Ok(());
+        }
+        if let Some(pty) = self.ptys.get_mut(&dlci) {
+            self.poll
+                .registry()
+                .register(pty, pty_token(dlci), Interest::READABLE)?;
+        }
+        Ok(())
+    }
+
+    /// Send an MSC for a data channel, asserting or dropping our DTR/RTS (RTC/RTR)
+    fn send_modem_status(&mut self, dlci: u8, ready: bool) -> Result<()> {
+        let status = MscMessage {
+            dlci,
+            rtc: ready,
+            rtr: ready,
+            ..Default::default()
+        };
+        debug!("Sending modem status on DLCI {}: {}", dlci, status);
+        let msc = ControlMessage::Msc {
+            status,
+            break_signal: None,
+        };
+        self.send_control(&msc, true)
+    }
+
+    /// Send the data held while a channel was not open or out of credits
+    fn flush_held_tx(&mut self, dlci: u8) -> Result<()> {
+        if self.flow_blocked {
+            return Ok(());
+        }
+        let Some(mut held) = self.held_tx.remove(&dlci) else {
+            return Ok(());
+        };
+        debug!("Sending {} held writes on DLCI {}", held.len(), dlci);
+        while self.has_credit(dlci) {
+            let Some(content) = held.pop_front() else {
+                return Ok(());
+            };
+            // N1 may have shrunk since, with PN
+            let n1 = self.data_frame_size(dlci);
+            if content.len() > n1 {
+                for fragment in content.chunks(n1).rev() {
+                    held.push_front(fragment.to_vec());
+                }
+                continue;
+            }
+            if let Err(e) = self.send_data(dlci, content) {
+                // The failed write is counted as dropped or still queued, later ones are kept
+                if !held.is_empty() {
+                    self.held_tx.insert(dlci, held);
+                }
+                return Err(e);
+            }
+        }
+        if !held.is_empty() {
+            self.held_tx.insert(dlci, held);
+        }
+        Ok(())
+    }
+
+    /// Maximum frame size N1 of a channel, negotiated with PN or configured
+    fn max_frame_size(&self, dlci: u8) -> usize {
+        let n1 = match self.negotiated.get(&dlci) {
+            Some(pn) => pn.max_frame_size,
+            None => self.config.frame_size,
+        };
+        n1.max(1) as usize
+    }
+
+    /// Payload octets of a data frame on a channel, leaving room for the trailer of
+    /// the integrity mode where it is configured
+    fn data_frame_size(&self, dlci: u8) -> usize {
+        let n1 = self.max_frame_size(dlci);
+        match self.config.integrity_channels.contains(&dlci) {
+            true => n1.saturating_sub(INTEGRITY_TRAILER).max(1),
+            false => n1,
+        }
+    }
+
+    /// Whether the flow control lets a UIH frame out on a channel
+    fn has_credit(&self, dlci: u8) -> bool {
+        self.config.flow_control != FlowControl::CreditBased
+            || self
+                .links
+                .get(&dlci)
+                .is_some_and(|link| link.tx_credits > 0)
+    }
+
+    /// Credits the modem granted for sending on a channel
+    pub fn tx_credits(&self, dlci: u8) -> Option<u8> {
+        self.links.get(&dlci).map(|link| link.tx_credits)
+    }
+
+    /// Add the credits the modem granted in a UIH frame, sending what waited for them
+    fn on_credits(&mut self, dlci: u8, credits: u8) -> Result<()> {
+        let Some(link) = self.links.get_mut(&dlci) else {
+            return Ok(());
+        };
+        debug!("DLCI {}: {} credits granted", dlci, credits);
+        if link.add_credits(credits) && self.is_open(dlci) {
+            debug!("DLCI {}: credits arrived, resuming", dlci);
+            self.flush_held_tx(dlci)?;
+            self.handle_pty_readable(dlci)?;
+        }
+        Ok(())
+    }
+
+    /// Count a data frame against the modem's credits, granting new ones once
+    /// half of them are spent
+    fn spend_rx_credit(&mut self, dlci: u8) -> Result<()> {
+        let Some(grant) = self.links.get_mut(&dlci).and_then(|l| l.on_credit_used()) else {
+            return Ok(());
+        };
+        let frame = self.frame(dlci, FrameType::UIH).build()?.with_credit(grant);
+        self.send_frame(&frame)
+    }
+
+    /// Send data on a channel in a UIH or UI frame, as selected by the subset, failing
+    /// if the channel is not open
+    ///
+    /// The FCS of UI frames covers the content, see [`Frame::try_fcs`].
+    fn send_data(&mut self, dlci: u8, content: Vec<u8>) -> Result<()> {
+        if !self.is_open(dlci) {
+            bail!(GsmError::ChannelNotOpen(dlci));
+        }
+        // With credits the P/F bit announces a credit octet
+        let credit_based = self.config.flow_control == FlowControl::CreditBased;
+        if !self.has_credit(dlci) {
+            bail!(GsmError::NoCredits(dlci));
+        }
+        let content = match self.behaviors.get(&dlci) {
+            Some(behavior) => behavior.seal_tx(content),
+            None => content,
+        };
+        let frame_type = self.config.data_frame_type();
+        let frame = self
+            .frame(dlci, frame_type)
+            .pf(!credit_based)
+            .payload(content)
+            .build()?;
+        self.send_frame(&frame)?;
+        // The credit is only spent on a frame that went out
+        if let Some(link) = self.links.get_mut(&dlci).filter(|_| credit_based) {
+            link.take_credit();
+        }
+        Ok(())
+    }
+
+    /// Whether a channel completed its SABM/UA handshake
+    pub fn is_open(&self, dlci: u8) -> bool {
+        self.links
+            .get(&dlci)
+            .is_some_and(|link| link.state == LinkState::Open)
+    }
+
+    /// Send a control channel message in a UIH frame on DLCI 0
+    fn send_control(&mut self, message: &ControlMessage, cr: bool) -> Result<()> {
+        let content = message.try_to_bytes(cr)?;
+        let frame = self
+            .frame(0, FrameType::UIH)
+            .pf(true)
+            .payload(content)
+            .build()?;
+        self.send_frame(&frame)
+    }
+
+    /// Handle a Multiplexer Control Channel message received from the modem
+    fn handle_control(&mut self, content: &[u8]) -> Result<()> {
+        let msg = match MccMessage::parse(content) {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!("Ignoring control message {:02X?}: {}", content, e);
+                return Ok(());
+            }
+        };
+        if msg.cr {
+            debug!("Received control command: {:?}", msg.msg_type);
+            if msg.msg_type == MccType::Test {
+                if let Some(dlcis) = parse_integrity_offer(&msg.data) {
+                    self.on_integrity_offer(dlcis)?;
+                }
+            }
+            let (response, changes) = handle_control(&msg, &self.control_state())?;
+            if let Some(frame) = response {
+                self.send_frame(&frame)?;
+            }
+            return self.apply_control_changes(changes);
+        }
+        match ControlMessage::from_mcc(&msg) {
+            Ok(message) => {
+                debug!("Received control response: {:?}", message);
+                self.handle_control_response(message)
+            }
+            Err(e) => {
+                warn!("Ignoring control response {:?}: {}", msg.msg_type, e);
+                Ok(())
+            }
+        }
+    }
+
+    /// What the answers to the modem's control commands depend on
+    fn control_state(&self) -> ControlState {
+        ControlState {
+            address: self.address(0, FrameType::UIH),
+            frame_size: self.config.frame_size,
+        }
+    }
+
+    /// Apply the changes a control command asked for, once it is answered
+    fn apply_control_changes(&mut self, changes: StateChanges) -> Result<()> {
+        if changes.close_down {
+            info!("Modem closed down the multiplexer, shutting down");
+            self.closed_down = true;
+            self.remote_closed = true;
+        }
+        if let Some(pn) = changes.negotiated {
+            self.record_negotiated(pn);
+        }
+        if let Some(status) = changes.modem_status {
+            self.update_modem_status(status);
+        }
+        if let Some(rls) = changes.line_status {
+            if rls.is_error() {
+                *self.line_errors.entry(rls.dlci).or_default() += 1;
+                warn!("DLCI {} line status: {}", rls.dlci, rls);
+            }
+        }
+        if changes.asleep {
+            info!("Modem enters power saving");
+            self.asleep = true;
+        }
+        match changes.flow_blocked {
+            Some(true) => {
+                info!("Modem stopped the flow of data");
+                self.flow_blocked = true;
+            }
+            Some(false) => {
+                info!("Modem resumed the flow of data");
+                self.flow_blocked = false;
+                // Reads were paused, pick up what the ptys received meanwhile
+                let dlcis: Vec<u8> = self.ptys.keys().copied().collect();
+                for dlci in dlcis {
+                    if self.is_open(dlci) {
+                        self.flush_held_tx(dlci)?;
+                    }
+                    self.handle_pty_readable(dlci)?;
+                }
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Number of line errors the modem reported for a channel with RLS
+    pub fn line_errors(&self, dlci: u8) -> u64 {
+        self.line_errors.get(&dlci).copied().unwrap_or(0)
+    }
+
+    /// Parameters negotiated for a channel with PN
+    pub fn negotiated(&self, dlci: u8) -> Option<&PnMessage> {
+        self.negotiated.get(&dlci)
+    }
+
+    /// Store the parameters agreed for a channel
+    fn record_negotiated(&mut self, pn: PnMessage) {
+        info!(
+            "DLCI {} negotiated: N1 {}, k {}",
+            pn.dlci, pn.max_frame_size, pn.window_size
+        );
+        self.negotiated.insert(pn.dlci, pn);
+    }
+
+    /// Parameters of a channel as configured
+    fn proposal(&self, dlci: u8) -> PnMessage {
+        // In credit based flow control k carries the credits granted to the modem,
+        // otherwise it keeps its 07.10 default, the window only bounds I frames
+        let (credit_flow, window_size) = match self.config.flow_control {
+            FlowControl::CreditBased => (15, INITIAL_CREDITS),
+            FlowControl::Msc => (0, DEFAULT_WINDOW_SIZE),
+        };
+        PnMessage {
+            dlci,
+            frame_type: self.config.subset,
+            credit_flow,
+            priority: 7,
+            ack_timer: (self.config.t1.as_millis() / 10).min(255) as u8,
+            max_frame_size: self.config.frame_size,
+            max_retransmit: self.config.n2,
+            window_size,
+        }
+    }
+
+    /// Parameters of the open data channels that differ from the configuration.
+    ///
+    /// Channels without PN agreement run with the 07.10 defaults of N1, T1 and N2.
+    pub fn param_drift(&self) -> DriftReport {
+        let mut dlcis: Vec<_> = self.links.keys().copied().filter(|&d| d != 0).collect();
+        dlcis.sort();
+        let mut report = DriftReport::default();
+        for dlci in dlcis.into_iter().filter(|&dlci| self.is_open(dlci)) {
+            let requested = self.proposal(dlci);
+            let (effective, reason) = match self.negotiated.get(&dlci) {
+                Some(pn) => (*pn, DriftReason::PnResponse),
+                None => {
+                    let defaults = PnMessage {
+                        max_frame_size: match self.config.mode {
+                            MuxMode::Basic => 31,
+                            _ => 64,
+                        },
+                        ack_timer: 10,
+                        max_retransmit: 3,
+                        ..requested
+                    };
+                    (defaults, DriftReason::Default)
+                }
+            };
+            let mut compare = |parameter, requested: u16, effective: u16| {
+                if requested != effective {
+                    report.drifts.push(Drift {
+                        dlci,
+                        parameter,
+                        requested,
+                        effective,
+                        reason,
+                    });
+                }
+            };
+            compare("N1", requested.max_frame_size, effective.max_frame_size);
+            compare("T1", requested.ack_timer.into(), effective.ack_timer.into());
+            compare(
+                "N2",
+                requested.max_retransmit.into(),
+                effective.max_retransmit.into(),
+            );
+            compare(
+                "subset",
+                requested.frame_type.into(),
+                effective.frame_type.into(),
+            );
+            compare(
+                "priority",
+                requested.priority.into(),
+                effective.priority.into(),
+            );
+        }
+        report
+    }
+
+    /// Send a PN command with our parameters for a channel and store the ones the
+    /// modem agreed to. Without answer within T1 the modem's defaults are assumed.
+    fn propose_parameters(&mut self, dlci: u8) -> Result<()> {
+        let proposal = self.proposal(dlci);
+        self.pn_response = None;
+        debug!("Sending PN for DLCI {}", dlci);
+        self.send_control(&ControlMessage::Pn(proposal), true)?;
+        let answered = |muxer: &Self| muxer.pn_response.is_some_and(|pn| pn.dlci == dlci);
+        match self.pump_until(self.config.t1, answered)? {
+            true => {
+                let response = self.pn_response.take().unwrap();
+                if self.config.flow_control == FlowControl::CreditBased {
+                    match response.credit_flow {
+                        0 => warn!("DLCI {}: modem declined credit based flow control", dlci),
+                        _ => {
+                            if let Some(link) = self.links.get_mut(&dlci) {
+                                link.tx_credits = response.window_size;
+                            }
+                        }
+                    }
+                }
+                let pn = self.control_state().accept(response);
+                self.record_negotiated(pn);
+            }
+            false => info!("DLCI {}: no PN response, using the defaults", dlci),
+        }
+        Ok(())
+    }
+
+    /// Last modem status the modem reported for a channel
+    pub fn modem_status(&self, dlci: u8) -> Option<&MscMessage> {
+        self.modem_status.get(&dlci)
+    }
+
+    /// Record the V.24 signals the modem reported for a channel
+    fn update_modem_status(&mut self, status: MscMessage) {
+        let dlci = status.dlci;
+        if !self.ptys.contains_key(&dlci) {
+            warn!("Modem status for unknown DLCI {}: {}", dlci, status);
+            return;
+        }
+        match self.modem_status.insert(dlci, status) {
+            Some(old) if old == status => {}
+            Some(old) => info!("DLCI {} V.24 signals: {} -> {}", dlci, old, status),
+            None => info!("DLCI {} V.24 signals: {}", dlci, status),
+        }
+        if self.config.print_msc {
+            info!("{}", self.dump_signals());
+        }
+    }
+
+    /// Render the V.24 signals of every channel, one line per DLCI
+    pub fn dump_signals(&self) -> String {
+        let mut dlcis: Vec<u8> = self.ptys.keys().copied().collect();
+        dlcis.sort();
+        let mut out = String::from("V.24 signals:");
+        for dlci in dlcis {
+            match self.modem_status.get(&dlci) {
+                Some(status) => out.push_str(&format!("\n  DLCI {:2}: {}", dlci, status)),
+                None => out.push_str(&format!("\n  DLCI {:2}: -", dlci)),
+            }
+        }
+        out
+    }
+
+    /// Handle a control response sent by the modem
+    fn handle_control_response(&mut self, message: ControlMessage) -> Result<()> {
+        match message {
+            ControlMessage::Nsc(nsc) => warn!(
+                "Modem does not support control command {:02X}",
+                nsc.command_type
+            ),
+            ControlMessage::Cld => self.close_down_acked = true,
+            ControlMessage::Test(test) => {
+                if self.integrity_offered && test.payload == self.integrity_offer() {
+                    debug!("Peer echoed the integrity offer");
+                    self.integrity_echoed = true;
+                    self.apply_integrity();
+                }
+                if self.keepalive_pending {
+                    match test.payload == KEEPALIVE_PATTERN {
+                        true => self.keepalive_answered(),
+                        // Counted as a miss when the next keepalive is due
+                        false => warn!(
+                            "TEST echo {:02X?} doesn't match the keepalive",
+                            test.payload
+                        ),
+                    }
+                }
+                self.test_echo = Some(test.payload);
+            }
+            ControlMessage::Pn(pn) => self.pn_response = Some(pn),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Offer the integrity mode on [`MuxerConfig::integrity_channels`] with a TEST command.
+    ///
+    /// A modem only echoes the offer and the payloads stay as they are. When the peer is
+    /// another instance of this mux, it answers with its own offer and each direction
+    /// carries a CRC16 trailer on the channels both configured, once the offer of its
+    /// sender was echoed. Data crossing the handshake may fail the check.
+    pub fn offer_integrity(&mut self) -> Result<()> {
+        if self.config.integrity_channels.is_empty() || self.integrity_offered {
+            return Ok(());
+        }
+        debug!(
+            "Offering integrity mode on DLCIs {:?}",
+            self.config.integrity_channels
+        );
+        self.integrity_offered = true;
+        let test = ControlMessage::Test(TestMessage {
+            payload: self.integrity_offer(),
+        });
+        self.send_control(&test, true)
+    }
+
+    /// Payload of our integrity offer: the marker, then a bitmap of the DLCIs
+    fn integrity_offer(&self) -> Vec<u8> {
+        let mut bitmap = [0u8; 8];
+        for &dlci in self.config.integrity_channels.iter().filter(|&&d| d < 64) {
+            bitmap[dlci as usize / 8] |= 1 << (dlci % 8);
+        }
+        [INTEGRITY_OFFER, &bitmap].concat()
+    }
+
+    /// Take note of the integrity offer of the peer, offering ours in return
+    fn on_integrity_offer(&mut self, dlcis: Vec<u8>) -> Result<()> {
+        info!("Peer offers integrity mode on DLCIs {:?}", dlcis);
+        self.integrity_peer = Some(dlcis);
+        self.apply_integrity();
+        self.offer_integrity()
+    }
+
+    /// Switch the integrity mode of the channels following the handshake: the payloads
+    /// received carry the trailer once the peer offered it, the payloads sent once
+    /// the peer echoed our offer too
+    fn apply_integrity(&mut self) {
+        for (dlci, behavior) in self.behaviors.iter_mut() {
+            let agreed = self.config.integrity_channels.contains(dlci)
+                && self
+                    .integrity_peer
+                    .as_ref()
+                    .is_some_and(|peer| peer.contains(dlci));
+            behavior.set_integrity(agreed && self.integrity_echoed, agreed);
+        }
+    }
+
+    /// The modem proved alive, the keepalive starts over
+    fn keepalive_answered(&mut self) {
+        self.keepalive_pending = false;
+        self.keepalive_misses = 0;
+    }
+
+    /// Handle a frame received from the modem, its content is only copied if it has to
+    /// outlive the receive buffer
+    fn handle_frame(&mut self, frame: FrameRef<'_>) -> Result<()> {
+        self.stats.record(&frame, Direction::Rx);
+        self.last_traffic = Instant::now();
+        self.woke_up()?;
+        let ft = match frame.control.get_frame_type() {
+            Ok(ft) => ft,
+            Err(e) => {
+                error!("Error parsing frame type: {}", e);
+                return Ok(());
+            }
+        };
+        let dlci = frame.address.get_dlci();
+        if !self.config.cr_convention(dlci).accepts(&frame) {
+            warn!(
+                "Dropping {:?} frame with unexpected C/R bit on DLCI {}",
+                ft, dlci
+            );
+            self.drops
+                .record(Some(dlci), DropReason::CrMismatch, frame.content.len());
+            return Ok(());
+        }
+        if let Some(credits) = frame.credit {
+            self.on_credits(dlci, credits)?;
+        }
+        let route = Route::of(&frame, ft);
+        // Frames off the control channel prove the modem alive, control messages only
+        // with the keepalive's echo
+        if route != Route::Control {
+            self.keepalive_answered();
+        }
+        match route {
+            Route::Control => self.handle_control(frame.content)?,
+            // Frames only granting credits don't cost one
+            Route::Data(_) if frame.credit.is_some() && frame.content.is_empty() => {}
+            Route::Data(dlci) => {
+                if self.config.flow_control == FlowControl::CreditBased {
+                    self.spend_rx_credit(dlci)?;
+                }
+                self.handle_data(dlci, frame.content)?
+            }
+            // Refuse channels we don't serve
+            Route::Link(dlci, FrameType::SABM)
+                if dlci >= self.config.channels || !self.ptys.contains_key(&dlci) =>
+            {
+                warn!("Refusing SABM on DLCI {}", dlci);
+                let dm = LinkAction::Send(FrameType::DM, frame.control.get_pf());
+                self.apply_link_actions(dlci, vec![dm])?;
+            }
+            Route::Link(dlci, ft) => {
+                let Some(link) = self.links.get_mut(&dlci) else {
+                    return Ok(());
+                };
+                let actions = match ft {
+                    FrameType::SABM => link.on_sabm(frame.control.get_pf()),
+                    FrameType::UA => link.on_ua(Instant::now()),
+                    FrameType::DM => link.on_dm(Instant::now()),
+                    _ => link.on_disc(frame.control.get_pf(), Instant::now()),
+                };
+                self.apply_link_actions(dlci, actions)?;
+                if ft == FrameType::DISC {
+                    self.on_remote_disc(dlci)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Deliver data received on a channel to its pty
+    fn handle_data(&mut self, dlci: u8, received: &[u8]) -> Result<()> {
+        let mut content = Cow::Borrowed(received);
+        if let Some(behavior) = self.behaviors.get_mut(&dlci) {
+            let Some(unsealed) = behavior.unseal_rx(received) else {
+                warn!(
+                    "Dropping {} bytes failing the integrity check on DLCI {}",
+                    received.len(),
+                    dlci
+                );
+                self.drops
+                    .record(Some(dlci), DropReason::Integrity, received.len());
+                return Ok(());
+            };
+            content = behavior.decode_rx(unsealed);
+            if behavior.is_duplicate(&content, Instant::now()) {
+                debug!(
+                    "Dropping duplicate of {} bytes on DLCI {} ({} so far)",
+                    content.len(),
+                    dlci,
+                    behavior.suppressed()
+                );
+                self.drops
+                    .record(Some(dlci), DropReason::Duplicate, content.len());
+                return Ok(());
+            }
+            behavior.inspect_rx(&content);
+        }
+        let Some(pty) = self.ptys.get_mut(&dlci) else {
+            warn!("Dropping data for unknown DLCI {}", dlci);
+            self.drops
+                .record(Some(dlci), DropReason::UnknownChannel, content.len());
+            return Ok(());
+        };
+        let dropped_before = pty.health.dropped;
+        let status = pty.write_data(&content)?;
+        let reason = match status {
+            PtyWriteStatus::HangUp => DropReason::PtyHangUp,
+            PtyWriteStatus::Dropped => DropReason::PtyWedged,
+            _ => DropReason::PtyOverflow,
+        };
+        let dropped = pty.health.dropped - dropped_before;
+        self.drops.record(Some(dlci), reason, dropped as usize);
+        match status {
+            PtyWriteStatus::Written => {}
+            PtyWriteStatus::Backpressure => {
+                debug!("PTY {} is not draining, queueing data", dlci);
+                self.poll.registry().reregister(
+                    pty,
+                    pty_token(dlci),
+                    Interest::READABLE | Interest::WRITABLE,
+                )?;
+            }
+            PtyWriteStatus::HangUp => {
+                warn!("PTY {} hung up, dropped data", dlci);
+            }
+            PtyWriteStatus::Dropped => {
+                debug!("PTY {} is wedged, dropped {} bytes", dlci, content.len());
+            }
+        }
+        Ok(())
+    }
+
+    /// Clean up after the modem disconnected a channel
+    fn on_remote_disc(&mut self, dlci: u8) -> Result<()> {
+        if dlci == 0 {
+            info!("Modem disconnected the control channel, shutting down");
+            self.remote_closed = true;
+            return Ok(());
+        }
+        // Deliver what the modem sent before the DISC
+        if let Some(pty) = self.ptys.get_mut(&dlci) {
+            let dropped_before = pty.health.dropped;
+            pty.flush_pending()?;
+            let dropped = pty.health.dropped - dropped_before;
+            self.drops
+                .record(Some(dlci), DropReason::PtyHangUp, dropped as usize);
+        }
+        Ok(())
+    }
+
+    /// Read from the serial port and handle the frames received
+    ///
+    /// Reads until the port would block, a single wakeup may carry more than one read.
+    fn handle_serial_readable(&mut self) -> Result<()> {
+        let mut buf = vec![0u8; 1024];
+        loop {
+            let n = match self.serial.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            debug!("Received {} bytes from serial port: {:02X?}", n, &buf[..n]);
+            self.push_serial(&buf[..n])?;
+            // Parse each chunk before the next one can overwrite the receive buffer
+            self.handle_received()?;
+        }
+    }
+
+    /// Queue octets read from the serial port for parsing, counting those the
+    /// full receive buffer overwrites
+    ///
+    /// Anything from a modem in power saving means it woke up.
+    fn push_serial(&mut self, data: &[u8]) -> Result<()> {
+        if !data.is_empty() {
+            self.woke_up()?;
+        }
+        self.buffer.push(data, &mut self.drops);
+        Ok(())
+    }
+
+    /// Handle the frames of the receive buffer, counting the octets discarded on the way.
+    ///
+    /// Basic option frames are handled in place, their content borrowing the buffer. The
+    /// buffer is set aside meanwhile, handling a frame never reads the serial port.
+    fn handle_received(&mut self) -> Result<()> {
+        let mut buffer = std::mem::replace(&mut self.buffer, ReceiveBuffer::new(0));
+        let result = loop {
+            let framing = self.framing();
+            let handled = match framing.transparency {
+                TransparencyMode::Basic => match buffer.pop_frame_bytes(framing, &mut self.drops) {
+                    Some(frame) => self.handle_frame(frame),
+                    None => break Ok(()),
+                },
+                // Unescaping the octets of advanced option frames copies them
+                TransparencyMode::Advanced => match buffer.next_frame(framing, &mut self.drops) {
+                    Some(frame) => self.handle_frame(frame.as_frame_ref()),
+                    None => break Ok(()),
+                },
+            };
+            if let Err(e) = handled {
+                break Err(e);
+            }
+        };
+        debug_assert!(
+            self.buffer.is_empty(),
+            "serial port read while handling a frame"
+        );
+        self.buffer = buffer;
+        result
+    }
+
+    /// Forward data written to a channel's pty to the modem
+    fn handle_pty_readable(&mut self, dlci: u8) -> Result<()> {
+        // Leave the data in the pty until the modem grants credits
+        if self.flow_blocked || (self.is_open(dlci) && !self.has_credit(dlci)) {
+            return Ok(());
+        }
+        let Some(pty) = self.ptys.get_mut(&dlci) else {
+            return Ok(());
+        };
+        let mut buf = vec![0u8; 1024];
+        let dropped_before = pty.health.dropped;
+        let read = pty.read_data(&mut buf);
+        let dropped = pty.health.dropped - dropped_before;
+        self.drops
+            .record(Some(dlci), DropReason::PtyHangUp, dropped as usize);
+        let n = match read {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) => {
+                error!("Error reading from PTY {}: {}", dlci, e);
+                return Ok(());
+            }
+        };
+        debug!("Received {} bytes from PTY {}: {:02X?}", n, dlci, &buf[..n]);
+
+        let mut content = buf[..n].to_vec();
+        if let Some(behavior) = self.behaviors.get_mut(&dlci) {
+            content = behavior.encode_tx(content);
+        }
+        if !self.is_open(dlci) {
+            let held = self.held_tx.entry(dlci).or_default();
+            if held.iter().map(Vec::len).sum::<usize>() + content.len() > HELD_TX_CAPACITY {
+                warn!(
+                    "DLCI {} is not open, dropping {} bytes",
+                    dlci,
+                    content.len()
+                );
+                self.drops
+                    .record(Some(dlci), DropReason::HeldOverflow, content.len());
+            } else {
+                debug!("DLCI {} is not open, holding {} bytes", dlci, content.len());
+                held.push_back(content);
+            }
+            return Ok(());
+        }
+        // Each fragment is a frame of its own, no larger than N1
+        let n1 = self.data_frame_size(dlci);
+        for fragment in content.chunks(n1) {
+            if !self.has_credit(dlci) {
+                debug!(
+                    "DLCI {} is out of credits, holding {} bytes",
+                    dlci,
+                    fragment.len()
+                );
+                self.held_tx
+                    .entry(dlci)
+                    .or_default()
+                    .push_back(fragment.to_vec());
+                continue;
+            }
+            if let Err(e) = self.send_data(dlci, fragment.to_vec()) {
+                error!("Error sending data to serial port: {}", e);
+                // Frames the serial queue had no room for are already counted
+                let overflow = e
+                    .downcast_ref::<std::io::Error>()
+                    .is_some_and(|e| e.kind() == QUEUE_FULL);
+                if !overflow {
+                    self.drops
+                        .record(Some(dlci), DropReason::SendFailed, fragment.len());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fire the channels' expired timers
+    fn poll_timers(&mut self) -> Result<()> {
+        let now = Instant::now();
+        self.poll_wake_up(now)?;
+        let actions: Vec<_> = self
+            .links
+            .iter_mut()
+            .map(|(dlci, link)| (*dlci, link.poll_timers(now)))
+            .collect();
+        for (dlci, actions) in actions {
+            self.apply_link_actions(dlci, actions)?;
+        }
+        if self
+            .keepalive_deadline()
+            .is_some_and(|deadline| deadline <= now)
+        {
+            if self.keepalive_pending {
+                self.keepalive_misses += 1;
+                warn!(
+                    "Keepalive TEST not echoed ({} of {})",
+                    self.keepalive_misses, self.config.n2
+                );
+                if self.keepalive_misses >= self.config.n2.max(1) {
+                    bail!(GsmError::PingTimeout);
+                }
+            }
+            debug!("Link idle, sending keepalive TEST");
+            let test = ControlMessage::Test(TestMessage {
+                payload: KEEPALIVE_PATTERN.to_vec(),
+            });
+            self.send_control(&test, true)?;
+            self.keepalive_pending = true;
+            // The echo has until the next period to arrive
+            self.last_traffic = now;
+        }
+        // Readers attaching without new data get their replay here
+        for (dlci, pty) in self.ptys.iter_mut() {
+            if pty.poll_replay()? == PtyWriteStatus::Backpressure {
+                self.poll.registry().reregister(
+                    pty,
+                    pty_token(*dlci),
+                    Interest::READABLE | Interest::WRITABLE,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Time the keepalive TEST is due, unless the modem sends something first.
+    ///
+    /// A modem in power saving is left asleep.
+    fn keepalive_deadline(&self) -> Option<Instant> {
+        let idle = self.config.keepalive?;
+        match self.is_open(0) && !self.asleep {
+            true => Some(self.last_traffic + idle),
+            false => None,
+        }
+    }
+
+    /// Time until the next channel timer, the keepalive, the session or the wake-up
+    /// expires, at most `max`
+    fn next_timeout(&self, max: Duration) -> Duration {
+        let now = Instant::now();
+        self.links
+            .values()
+            .filter_map(|link| link.deadline())
+            .chain(self.keepalive_deadline())
+            .chain(self.session_deadline())
+            .chain(self.wake_deadline)
+            .map(|deadline| deadline.saturating_duration_since(now))
+            .fold(max, Duration::min)
+    }
+
+    /// Open a channel: allocate its pty and establish the DLCI with SABM/UA.
+    ///
+    /// The UA is awaited for T3 on the control channel and T1 on data channels.
+    /// The pty stays allocated if the modem doesn't accept the channel.
+    pub fn open_channel(&mut self, dlci: u8) -> Result<&PtyStream> {
+        let timeout = match dlci {
+            0 => self.config.t3,
+            _ => self.config.t1,
+        };
+        self.open_channel_with_retry(dlci, self.config.n2, timeout)?;
+        Ok(&self.ptys[&dlci])
+    }
+
+    /// Open a channel, sending up to `retries` SABM with exponential backoff between
+    /// them and waiting `timeout` for the UA after each. They become the channel's
+    /// acknowledgement timer T1 and N2.
+    ///
+    /// Fails with [`GsmError::SabmTimeout`] without answer and [`GsmError::ChannelRejected`]
+    /// if the modem still answers DM after [`MuxerConfig::dm_retries`] retries. `retries`
+    /// must be at least 1, as the `n2-zero` rule of [`crate::config::RULES`] requires of N2.
+    pub fn open_channel_with_retry(
+        &mut self,
+        dlci: u8,
+        retries: u8,
+        timeout: Duration,
+    ) -> Result<()> {
+        if retries == 0 {
+            bail!(GsmError::InvalidConfig(format!(
+                "DLCI {} can't be opened without sending a SABM",
+                dlci
+            )));
+        }
+        if !self.ptys.contains_key(&dlci) {
+            self.open_pty(dlci)?;
+        }
+        let link = self.links.get_mut(&dlci).unwrap();
+        link.t1 = timeout;
+        link.n2 = retries;
+        if dlci != 0 && self.config.negotiate {
+            self.propose_parameters(dlci)?;
+        }
+        if dlci == 0 && self.config.detect_fcs {
+            self.detect_fcs(timeout)?;
+            if self.is_open(0) {
+                return Ok(());
+            }
+        }
+        self.establish_channel(dlci)
+    }
+
+    /// Create the pty of a channel with its behavior and closed link
+    fn open_pty(&mut self, dlci: u8) -> Result<()> {
+        let pty = openpty(
+            self.config.pty.clone(),
+            dlci,
+            self.config.symlink_prefix.clone(),
+        )?;
+        let mut pty = PtyStream::new(pty);
+        if self.config.replay_channels.contains(&dlci) {
+            pty = pty.with_replay(
+                self.config.replay_buffer_bytes,
+                self.config.replay_marker.clone(),
+            )?;
+        }
+        self.poll
+            .registry()
+            .register(&mut pty, pty_token(dlci), Interest::READABLE)?;
+        self.ptys.insert(dlci, pty);
+        let mut behavior = ChannelBehavior::new(dlci, self.config.at_channel);
+        if self.config.suppress_duplicates.contains(&dlci) {
+            behavior.set_duplicate_window(self.config.duplicate_window);
+        }
+        self.behaviors.insert(dlci, behavior);
+        self.apply_integrity();
+        self.links.insert(dlci, self.new_link(dlci));
+        Ok(())
+    }
+
+    /// Closed link of a channel, as configured
+    fn new_link(&self, dlci: u8) -> ChannelLink {
+        let policy = match self.config.auto_reestablish.contains(&dlci) && dlci != 0 {
+            true => ReestablishPolicy::Auto,
+            false => ReestablishPolicy::Manual,
+        };
+        let mut link = ChannelLink::new(
+            dlci,
+            policy,
+            self.config.reestablish_delay,
+            self.config.reestablish_retries,
+            self.config.t1,
+            self.config.n2,
+        );
+        link.dm_retries = self.config.dm_retries;
+        link.dm_retry_delay = self.config.dm_retry_delay;
+        link
+    }
+
+    /// Establish a channel with SABM/UA, handling the frames of the other channels
+    /// until its link state machine opens it or gives up
+    ///
+    /// Fails with [`GsmError::SabmTimeout`] without answer and [`GsmError::ChannelRejected`]
+    /// if the modem answers DM.
+    fn establish_channel(&mut self, dlci: u8) -> Result<()> {
+        let Some(link) = self.links.get_mut(&dlci) else {
+            bail!(GsmError::ChannelNotOpen(dlci));
+        };
+        let actions = link.open(Instant::now());
+        self.apply_link_actions(dlci, actions)?;
+        let opening = |muxer: &Self| {
+            matches!(
+                muxer.links[&dlci].state,
+                LinkState::Opening | LinkState::Waiting
+            )
+        };
+        // The link's timers bound the wait
+        while !self.pump_until(self.config.t1, |muxer| !opening(muxer))? {}
+        let link = &self.links[&dlci];
+        match link.state {
+            LinkState::Open => Ok(()),
+            _ if link.rejected() => bail!(GsmError::ChannelRejected(dlci)),
+            _ => bail!(GsmError::SabmTimeout(dlci)),
+        }
+    }
+
+    /// Pick the FCS algorithm of the modem and open the control channel with it.
+    ///
+    /// SABM is sent on DLCI 0 checked with each algorithm in turn, a modem using another
+    /// one drops it. After each probe the answers are read up to `timeout`, until the line
+    /// goes quiet once something arrived. Empty frames can't tell some algorithms apart,
+    /// a TEST command checked with each of the remaining ones settles it. The algorithm is
+    /// kept for the rest of the session, and the UA answering its probe opens the channel.
+    ///
+    /// Fails with [`GsmError::FcsUndetected`] if no answer validates under any algorithm.
+    fn detect_fcs(&mut self, timeout: Duration) -> Result<()> {
+        let transparency = self.transparency();
+        let mut candidates = vec![];
+        for fcs in FcsAlgorithm::ALL {
+            self.fcs = fcs;
+            let sabm = self.frame(0, FrameType::SABM).pf(true).build()?;
+            self.send_frame(&sabm)?;
+            self.read_until_quiet(timeout)?;
+            let stream: Vec<u8> = self.buffer.iter().copied().collect();
+            candidates = FcsAlgorithm::candidates(&stream, transparency);
+            if !candidates.is_empty() {
+                break;
+            }
+        }
+        if candidates.len() > 1 {
+            let test = ControlMessage::Test(TestMessage {
+                payload: FCS_PROBE_PATTERN.to_vec(),
+            });
+            for &fcs in &candidates {
+                self.fcs = fcs;
+                self.send_control(&test, true)?;
+                self.read_until_quiet(timeout)?;
+            }
+            let stream: Vec<u8> = self.buffer.iter().copied().collect();
+            candidates = FcsAlgorithm::candidates(&stream, transparency);
+        }
+        let Some(&fcs) = candidates.first() else {
+            bail!(GsmError::FcsUndetected);
+        };
+        match candidates.len() {
+            1 => info!("Detected the {:?} FCS algorithm", fcs),
+            _ => warn!("FCS algorithms {:?} all fit, using {:?}", candidates, fcs),
+        }
+        self.fcs = fcs;
+        // The probe checked with this algorithm is the SABM the modem answered
+        let now = Instant::now();
+        let link = self.links.get_mut(&0).unwrap();
+        link.open(now);
+        link.on_sabm_sent(now);
+        self.handle_received()?;
+        Ok(())
+    }
+
+    /// Read the serial port into the receive buffer up to `timeout`, returning early
+    /// once bytes arrived and the line stayed quiet for [`FCS_PROBE_QUIET`]
+    fn read_until_quiet(&mut self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut last_read: Option<Instant> = None;
+        let mut buf = vec![0u8; 1024];
+        loop {
+            let now = Instant::now();
+            if now >= deadline || last_read.is_some_and(|at| now >= at + FCS_PROBE_QUIET) {
+                return Ok(());
+            }
+            match self.serial.read(&mut buf) {
+                Ok(n) if n > 0 => {
+                    self.push_serial(&buf[..n])?;
+                    last_read = Some(now);
+                }
+                Ok(_) => std::thread::sleep(SERIAL_POLL_INTERVAL),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    std::thread::sleep(SERIAL_POLL_INTERVAL)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Close a channel: disconnect the DLCI and release everything its opening allocated.
+    ///
+    /// The UA answering the DISC is not awaited.
+    pub fn close_channel(&mut self, dlci: u8) -> Result<()> {
+        self.disconnect_channel(dlci)?;
+        self.release_channel(dlci)
+    }
+
+    /// Drop the V.24 signals of a data channel and send DISC if it is open
+    fn disconnect_channel(&mut self, dlci: u8) -> Result<()> {
+        if dlci != 0 && self.is_open(dlci) {
+            self.send_modem_status(dlci, false)?;
+        }
+        if let Some(link) = self.links.get_mut(&dlci) {
+            let actions = link.on_close(Instant::now());
+            self.apply_link_actions(dlci, actions)?;
+        }
+        Ok(())
+    }
+
+    /// Handle the frames of the modem until `done` holds or `timeout` expires.
+    ///
+    /// Returns whether `done` holds.
+    fn pump_until(&mut self, timeout: Duration, done: impl Fn(&Self) -> bool) -> Result<bool> {
+        let deadline = Instant::now() + timeout;
+        let mut buf = vec![0u8; 1024];
+        while !done(self) {
+            if Instant::now() >= deadline {
+                return Ok(false);
+            }
+            self.poll_timers()?;
+            self.serial.flush_pending()?;
+            match self.serial.read(&mut buf) {
+                Ok(n) if n > 0 => self.push_serial(&buf[..n])?,
+                // Ports without data may read nothing rather than block
+                Ok(_) => {
+                    std::thread::sleep(SERIAL_POLL_INTERVAL);
+                    continue;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    std::thread::sleep(SERIAL_POLL_INTERVAL);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            }
+            self.handle_received()?;
+        }
+        Ok(true)
+    }
+
+    /// Send a TEST command and wait for the modem to echo it, returning the round-trip time.
+    ///
+    /// Fails with [`GsmError::PingTimeout`] without echo within `timeout`.
+    pub fn ping(&mut self, payload: Vec<u8>, timeout: Duration) -> Result<Duration> {
+        self.test_echo = None;
+        let start = Instant::now();
+        let test = ControlMessage::Test(TestMessage {
+            payload: payload.clone(),
+        });
+        self.send_control(&test, true)?;
+        if !self.pump_until(timeout, |muxer| muxer.test_echo.as_ref() == Some(&payload))? {
+            bail!(GsmError::PingTimeout);
+        }
+        Ok(start.elapsed())
+    }
+
+    /// Ask the modem to stop sending data on every channel with FCoff, when the
+    /// host can't keep up
+    pub fn send_fc_off(&mut self) -> Result<()> {
+        info!("Stopping the flow of data from the modem");
+        self.send_control(&ControlMessage::FCoff, true)
+    }
+
+    /// Let the modem send data again with FCon
+    pub fn send_fc_on(&mut self) -> Result<()> {
+        info!("Resuming the flow of data from the modem");
+        self.send_control(&ControlMessage::FCon, true)
+    }
+
+    /// Whether a channel sent DISC and waits for the UA
+    fn is_closing(&self, dlci: u8) -> bool {
+        self.links
+            .get(&dlci)
+            .is_some_and(|link| link.state == LinkState::Closing)
+    }
+
+    /// Reset a channel: disconnect it with DISC, then open it again with SABM.
+    ///
+    /// Returns once the DISC is sent, the channel's link reopens it as the modem
+    /// answers while the event loop carries on with the other channels. The pty stays
+    /// allocated. `policy` selects what happens to the data written to the pty that
+    /// was not sent before the DISC, data written later is sent once the channel is
+    /// open again. If it doesn't open again its pty is no longer read.
+    pub fn reset_channel(&mut self, dlci: u8, policy: ResetDataPolicy) -> Result<()> {
+        info!("Resetting DLCI {}, {:?} in-flight data", dlci, policy);
+        if !self.links.contains_key(&dlci) {
+            bail!(GsmError::ChannelNotOpen(dlci));
+        }
+        if dlci != 0 && self.is_open(dlci) {
+            self.send_modem_status(dlci, false)?;
+        }
+        let actions = self.links.get_mut(&dlci).unwrap().reset(Instant::now());
+        self.apply_link_actions(dlci, actions)?;
+        match policy {
+            ResetDataPolicy::Discard => {
+                let held = self.held_tx.remove(&dlci).unwrap_or_default();
+                let mut dropped = held.iter().map(Vec::len).sum();
+                if let Some(pty) = self.ptys.get_mut(&dlci) {
+                    let mut buf = [0u8; 1024];
+                    while let Ok(n @ 1..) = pty.read_data(&mut buf) {
+                        dropped += n;
+                    }
+                }
+                debug!("Discarded {} bytes of DLCI {}", dropped, dlci);
+                self.drops.record(Some(dlci), DropReason::Reset, dropped);
+            }
+            // Held until the channel is open again
+            ResetDataPolicy::Replay => self.handle_pty_readable(dlci)?,
+        }
+        Ok(())
+    }
+
+    /// Release everything the opening of a channel allocated, without telling the modem
+    fn release_channel(&mut self, dlci: u8) -> Result<()> {
+        self.release_channels(&[dlci])
+    }
+
+    /// Release the channels `dlcis` together, without telling the modem
+    ///
+    /// The slaves are hung up, their readers get the end of file and up to
+    /// [`MuxerConfig::hangup_timeout`] to close them. Where hanging up is not permitted
+    /// or a reader still holds its slave, the master is closed instead and the reader
+    /// gets [`MuxerConfig::hangup_grace`] to see the hangup before the symlink is removed.
+    fn release_channels(&mut self, dlcis: &[u8]) -> Result<()> {
+        let mut attached = vec![];
+        let mut closed = 0;
+        for &dlci in dlcis {
+            let Some(mut pty) = self.ptys.remove(&dlci) else {
+                continue;
+            };
+            if !self.unpolled.remove(&dlci) {
+                self.poll.registry().deregister(&mut pty)?;
+            }
+            if let Err(e) = pty.flush_pending() {
+                debug!("Failed to flush PTY {}: {}", dlci, e);
+            }
+            self.drops
+                .record(Some(dlci), DropReason::PtyHangUp, pty.pending.len());
+            if pty.is_slave_open() {
+                match pty.hang_up_slave() {
+                    Ok(true) => attached.push(pty),
+                    Ok(false) => {
+                        debug!("Not permitted to hang up PTY {}, closing it", dlci);
+                        closed += 1;
+                    }
+                    Err(e) => {
+                        debug!("Failed to hang up PTY {}, closing it: {}", dlci, e);
+                        closed += 1;
+                    }
+                }
+            }
+        }
+
+        let deadline = Instant::now() + self.config.hangup_timeout;
+        loop {
+            attached.retain(|pty| pty.is_slave_open());
+            let now = Instant::now();
+            if attached.is_empty() || now >= deadline {
+                break;
+            }
+            PtyStream::wait_hang_up(&attached, deadline - now)?;
+        }
+        if !attached.is_empty() {
+            warn!("{} pty readers still open after the hangup", attached.len());
+            closed += attached.len();
+        }
+        // Closing the master hangs up the slave, its reader gets EOF
+        drop(attached);
+        if closed > 0 {
+            std::thread::sleep(self.config.hangup_grace);
+        }
+
+        for &dlci in dlcis {
+            self.unpolled.remove(&dlci);
+            self.behaviors.remove(&dlci);
+            self.links.remove(&dlci);
+            self.held_tx.remove(&dlci);
+            self.modem_status.remove(&dlci);
+            self.line_errors.remove(&dlci);
+            self.negotiated.remove(&dlci);
+            if let Some(prefix) = &self.config.symlink_prefix {
+                let symlink = symlink_path(prefix, dlci);
+                if let Err(e) = std::fs::remove_file(&symlink) {
+                    debug!("Failed to remove symlink {}: {}", symlink, e);
+                }
+            }
+            self.check_released(dlci);
+        }
+        Ok(())
+    }
+
+    /// Debug check that no registry references a closed channel
+    fn check_released(&self, dlci: u8) {
+        let registries = [
+            ("pty", self.ptys.contains_key(&dlci)),
+            ("behavior", self.behaviors.contains_key(&dlci)),
+            ("link", self.links.contains_key(&dlci)),
+            ("held data", self.held_tx.contains_key(&dlci)),
+            ("unpolled entry", self.unpolled.contains(&dlci)),
+            ("status", self.modem_status.contains_key(&dlci)),
+            ("line errors", self.line_errors.contains_key(&dlci)),
+            ("parameters", self.negotiated.contains_key(&dlci)),
+        ];
+        for (registry, left) in registries {
+            debug_assert!(!left, "{} of DLCI {} left", registry, dlci);
+        }
+    }
+
+    /// Open the control channel and every data channel, report which ones the modem
+    /// accepted, then shut the multiplexer down
+    pub fn probe(&mut self) -> Result<ProbeReport> {
+        let mut report = ProbeReport::default();
+        for dlci in 0..self.config.channels {
+            let result = match self.open_channel(dlci) {
+                Ok(_) => ProbeResult::Open,
+                Err(e) => match e.downcast_ref::<GsmError>() {
+                    Some(GsmError::ChannelRejected(_)) => ProbeResult::Rejected,
+                    Some(GsmError::SabmTimeout(_)) => ProbeResult::TimedOut,
+                    _ => ProbeResult::Failed,
+                },
+            };
+            info!("DLCI {}: {:?}", dlci, result);
+            report.channels.push((dlci, result));
+            if dlci == 0 && result != ProbeResult::Open {
+                // Nothing to probe without the control channel
+                self.release_channel(0)?;
+                return Ok(report);
+            }
+        }
+        self.shutdown()?;
+        Ok(report)
+    }
+
+    /// Disconnect the data channels and close down the multiplexer, waiting for the
+    /// modem's answers:
+    ///
+    /// 1. DISC on every data channel, collecting the UAs
+    /// 2. CLD on the control channel, waiting for the CLD response
+    /// 3. DISC on the control channel
+    ///
+    /// Each step waits at most [`MuxerConfig::shutdown_timeout`].
+    pub fn shutdown(&mut self) -> Result<()> {
+        if self.remote_closed {
+            return self.close();
+        }
+        self.end_session(true)
+    }
+
+    /// Disconnect the channels and close down the multiplexer, waiting for each
+    /// answer. The ptys stay allocated unless `release` is set.
+    fn end_session(&mut self, release: bool) -> Result<()> {
+        let timeout = self.config.shutdown_timeout;
+        let mut dlcis: Vec<u8> = self.ptys.keys().copied().filter(|&d| d != 0).collect();
+        dlcis.sort();
+        info!("Disconnecting logical channels");
+        for &dlci in &dlcis {
+            self.disconnect_channel(dlci)?;
+        }
+        if !self.pump_until(timeout, |muxer| {
+            !dlcis.iter().any(|&dlci| muxer.is_closing(dlci))
+        })? {
+            warn!("Not every channel acknowledged its DISC");
+        }
+        if release {
+            self.release_channels(&dlcis)?;
+        }
+
+        info!("Closing down the multiplexer");
+        self.close_down_acked = false;
+        self.send_control(&ControlMessage::Cld, true)?;
+        if !self.pump_until(timeout, |muxer| muxer.close_down_acked)? {
+            warn!("No CLD response from the modem");
+        }
+        self.disconnect_channel(0)?;
+        if !self.pump_until(timeout, |muxer| !muxer.is_closing(0))? {
+            warn!("No UA for the DISC of the control channel");
+        }
+        if release {
+            self.release_channel(0)?;
+        }
+        self.serial.flush_pending()?;
+        Ok(())
+    }
+
+    /// Whether the session has run for `reestablish_every`
+    pub fn session_due(&self, now: Instant) -> bool {
+        self.session_deadline()
+            .is_some_and(|deadline| now >= deadline)
+    }
+
+    /// When the session is due for re-establishment, if it is scheduled
+    fn session_deadline(&self) -> Option<Instant> {
+        let every = self.config.reestablish_every?;
+        Some(self.session_start + every)
+    }
+
+    /// Start a new session after [`GsmError::SessionExpired`]: `init` puts the modem
+    /// back in multiplexed mode, then [`Muxer::run`] reopens the channels on their ptys
+    ///
+    /// Everything the previous session left behind is reset, only the ptys, their
+    /// behaviors and the statistics remain.
+    pub fn reinit(&mut self, init: impl FnOnce(&mut S) -> Result<()>) -> Result<()> {
+        self.buffer.clear(&mut self.drops);
+        let dlcis: Vec<u8> = self.links.keys().copied().collect();
+        for dlci in dlcis {
+            let link = self.new_link(dlci);
+            self.links.insert(dlci, link);
+            // Polled again until the channel fails to reopen, its data is held meanwhile
+            self.resume_polling(dlci)?;
+        }
+        for (dlci, held) in self.held_tx.drain() {
+            let dropped = held.iter().map(Vec::len).sum();
+            self.drops.record(Some(dlci), DropReason::Reset, dropped);
+        }
+        for frame in self.asleep_tx.drain(..) {
+            let dlci = frame.address.get_dlci();
+            self.drops
+                .record(Some(dlci), DropReason::Reset, frame.content.len());
+        }
+        self.negotiated.clear();
+        self.modem_status.clear();
+        self.flow_blocked = false;
+        self.asleep = false;
+        self.wake_deadline = None;
+        self.close_down_acked = false;
+        self.fcs = self.config.fcs;
+        self.test_echo = None;
+        self.keepalive_answered();
+        self.pn_response = None;
+        self.integrity_peer = None;
+        self.integrity_offered = false;
+        self.integrity_echoed = false;
+        self.apply_integrity();
+        init(&mut self.serial.inner)?;
+        self.last_traffic = Instant::now();
+        self.session_start = Instant::now();
+        Ok(())
+    }
+
+    /// Disconnect the data channels and close down the multiplexer without waiting
+    /// for the modem's answers
+    pub fn close(&mut self) -> Result<()> {
+        info!("Closing logical channels");
+        let mut dlcis: Vec<u8> = self.ptys.keys().copied().filter(|&d| d != 0).collect();
+        dlcis.sort();
+        // Once the modem disconnected the control channel there is nobody to tell
+        let control_open = !self.remote_closed;
+        for dlci in dlcis {
+            match control_open {
+                true => self.close_channel(dlci)?,
+                false => self.release_channel(dlci)?,
+            }
+        }
+        match control_open {
+            true => {
+                info!("Closing control channel");
+                self.send_control(&ControlMessage::Cld, true)?;
+            }
+            false => self.release_channel(0)?,
+        }
+        self.serial.flush_pending()?;
+        Ok(())
+    }
+}
+
+impl<S: Read + Write + Source> Muxer<S> {
+    /// Open the configured channels and run the event loop until SIGTERM or SIGINT.
+    ///
+    /// SIGUSR1 dumps the frame statistics. Fails with [`GsmError::ClosedDown`] once
+    /// the ptys are closed if the modem closed down the multiplexer.
+    pub fn run(&mut self) -> Result<()> {
+        // The control channel must be up before any data channel can be opened
+        self.open_channel(0)?;
+        for dlci in 1..self.config.channels {
+            if let Err(e) = self.open_channel(dlci) {
+                error!("Failed to open DLCI {}: {}", dlci, e);
+                self.stop_polling(dlci)?;
+            }
+        }
+        info!("Opened {} channels", self.ptys.len());
+        self.offer_integrity()?;
+        self.session_start = Instant::now();
+        let drift = self.param_drift();
+        if !drift.is_empty() {
+            if self.config.strict_params {
+                bail!(GsmError::ParameterDrift(drift.to_string()));
+            }
+            warn!("Parameters differ from the configuration:\n{}", drift);
+        }
+        match self.config.at_channel {
+            Some(dlci) if !self.ptys.contains_key(&dlci) => {
+                warn!("AT channel {} is not one of the opened channels", dlci)
+            }
+            Some(dlci) => info!("Using channel {} as AT channel", dlci),
+            None => {}
+        }
+
+        let mut events = Events::with_capacity(self.ptys.len() + 2);
+        self.poll
+            .registry()
+            .register(&mut self.serial, SERIAL_TOKEN, Interest::READABLE)?;
+        let mut signals = Signals::new([SIGTERM, SIGINT, SIGUSR1])?;
+        self.poll
+            .registry()
+            .register(&mut signals, SIGNAL_TOKEN, Interest::READABLE)?;
+        // Frames that followed the UAs of the channels' establishment
+        self.handle_received()?;
+
+        'outer: while !self.remote_closed {
+            let timeout = self.next_timeout(Duration::from_secs(1));
+            if let Err(e) = self.poll.poll(&mut events, Some(timeout)) {
+                error!("Error polling: {}", e);
+                break;
+            }
+            for event in events.iter() {
+                match event.token() {
+                    SERIAL_TOKEN => {
+                        if event.is_writable() && self.serial.flush_pending()? {
+                            debug!("Flushed queued serial data");
+                        }
+                        if event.is_readable() {
+                            self.handle_serial_readable()?;
+                        }
+                    }
+                    SIGNAL_TOKEN => {
+                        for signal in signals.pending() {
+                            if signal == SIGUSR1 {
+                                info!("{}", self.stats.dump());
+                                info!("{}", self.drops.dump());
+                                info!("Parameter drift:\n{}", self.param_drift());
+                                continue;
+                            }
+                            info!("Received signal, exiting");
+                            break 'outer;
+                        }
+                    }
+                    Token(idx) => {
+                        let dlci = (idx - 1) as u8;
+                        if event.is_writable() {
+                            if let Some(pty) = self.ptys.get_mut(&dlci) {
+                                let dropped_before = pty.health.dropped;
+                                let status = pty.flush_pending()?;
+                                let dropped = pty.health.dropped - dropped_before;
+                                self.drops.record(
+                                    Some(dlci),
+                                    DropReason::PtyHangUp,
+                                    dropped as usize,
+                                );
+                                if status != PtyWriteStatus::Backpressure {
+                                    self.poll.registry().reregister(
+                                        pty,
+                                        event.token(),
+                                        Interest::READABLE,
+                                    )?;
+                                }
+                            }
+                        }
+                        if event.is_readable() {
+                            self.handle_pty_readable(dlci)?;
+                        }
+                    }
+                }
+            }
+            if self.remote_closed {
+                break;
+            }
+            self.poll_timers()?;
+            if self.session_due(Instant::now()) {
+                info!("Session is due for re-establishment, closing it down");
+                self.poll.registry().deregister(&mut self.serial)?;
+                self.serial_writable = false;
+                self.end_session(false)?;
+                bail!(GsmError::SessionExpired);
+            }
+            // Wait for the serial port to become writable while data is queued
+            if self.serial.has_pending() != self.serial_writable {
+                self.serial_writable = self.serial.has_pending();
+                let interest = match self.serial_writable {
+                    true => Interest::READABLE | Interest::WRITABLE,
+                    false => Interest::READABLE,
+                };
+                self.poll
+                    .registry()
+                    .reregister(&mut self.serial, SERIAL_TOKEN, interest)?;
+            }
+        }
+
+        self.shutdown()?;
+        if self.closed_down {
+            bail!(GsmError::ClosedDown);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, os::unix::fs::OpenOptionsExt, thread::JoinHandle};
+
+    use super::*;
+    use crate::buffer::GSM0710Buffer;
+    use crate::serial::SERIAL_PENDING_CAPACITY;
+    use crate::sim::VirtualModem;
+    use crate::stats::FrameKind;
+    use crate::test_alloc::allocations;
+    use crate::types::{serialize_batch, Control, FrameCodec, ADVANCED_FLAG, FLAG};
+    use ringbuffer::AllocRingBuffer;
+
+    fn control_frame(content: Vec<u8>) -> Frame {
+        Frame::new(
+            Address::new_address(true, true, 0),
+            Control::new_control(FrameType::UIH, false),
+            content,
+        )
+    }
+
+    /// Frames written to the serial port of a muxer
+    fn sent_frames(muxer: &Muxer<Cursor<Vec<u8>>>) -> Vec<Frame> {
+        let mut data = muxer.serial.inner.get_ref().iter().copied().peekable();
+        let mut frames = vec![];
+        while data.peek().is_some() {
+            frames.push(Frame::parse(&mut data).unwrap().0);
+        }
+        frames
+    }
+
+    /// Configuration opening the data channels with SABM only
+    fn no_pn() -> MuxerConfig {
+        MuxerConfig {
+            negotiate: false,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn unknown_control_command_gets_nsc() {
+        let mut muxer = Muxer::new(Cursor::new(vec![]), MuxerConfig::default()).unwrap();
+        // Unknown type 0xF1 sent as a command
+        muxer
+            .handle_frame(control_frame(vec![0xF3, 0x01]).as_frame_ref())
+            .unwrap();
+        // RPN is defined but not implemented
+        muxer
+            .handle_frame(control_frame(vec![0x93, 0x03, 0x07]).as_frame_ref())
+            .unwrap();
+        // Malformed and unknown responses are only logged
+        muxer
+            .handle_frame(control_frame(vec![0x23, 0x05]).as_frame_ref())
+            .unwrap();
+        muxer
+            .handle_frame(control_frame(vec![0xF1, 0x01]).as_frame_ref())
+            .unwrap();
+
+        let frames = sent_frames(&muxer);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].address.get_dlci(), 0);
+        assert_eq!(frames[0].content, vec![0x11, 0x03, 0xF3]);
+        assert_eq!(frames[1].content, vec![0x11, 0x03, 0x93]);
+        // UIH response on DLCI 0, FCS over the header
+        assert_eq!(
+            muxer.serial.inner.get_ref()[..9],
+            [0xF9, 0x03, 0xFF, 0x07, 0x11, 0x03, 0xF3, 0x04, 0xF9]
+        );
+    }
+
+    #[test]
+    fn scripted_modem_exchange() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 0)
+            .then_reply(FrameType::UA, 0)
+            .expect(FrameType::SABM, 2)
+            .then_reply(FrameType::UA, 2)
+            .expect(FrameType::UIH, 2)
+            .then_data(2, b"\r\nOK\r\n")
+            .then_data(2, b"\r\nRING\r\n")
+            .expect(FrameType::DISC, 2)
+            .then_reply(FrameType::UA, 2)
+            .expect(FrameType::UIH, 0)
+            .then_frame(control_frame(
+                ControlMessage::Cld.try_to_bytes(false).unwrap(),
+            ));
+        let config = MuxerConfig {
+            t1: Duration::from_millis(50),
+            negotiate: false,
+            ..Default::default()
+        };
+        let mut muxer = Muxer::new(modem, config).unwrap();
+        muxer.open_channel(0).unwrap();
+        muxer.open_channel(2).unwrap();
+
+        let mut slave = open_slave(&muxer, 2);
+        slave.write_all(b"AT\r").unwrap();
+        muxer.handle_pty_readable(2).unwrap();
+        assert!(muxer.serial.inner.has_data());
+        muxer.handle_serial_readable().unwrap();
+        let mut buf = [0u8; 64];
+        let n = slave.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"\r\nOK\r\n\r\nRING\r\n");
+
+        muxer.close().unwrap();
+        muxer.handle_serial_readable().unwrap();
+        let modem = &muxer.serial.inner;
+        assert!(modem.is_done());
+        assert_eq!(modem.received[3].content, b"AT\r");
+    }
+
+    #[test]
+    fn inverted_cr_on_data_channel() {
+        let frame = |cr, frame_type, dlci, data: &[u8]| {
+            Frame::new(
+                Address::new_address(cr, true, dlci),
+                Control::new_control(frame_type, true),
+                data.to_vec(),
+            )
+        };
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 0)
+            .then_frame(frame(true, FrameType::UA, 0, b""))
+            .expect(FrameType::SABM, 1)
+            .then_frame(frame(true, FrameType::UA, 1, b""))
+            .then_frame(frame(false, FrameType::UA, 1, b""))
+            .expect(FrameType::UIH, 1)
+            .then_frame(frame(false, FrameType::UIH, 1, b"bad"))
+            .then_frame(frame(true, FrameType::UIH, 1, b"good"));
+        let config = MuxerConfig {
+            strict_cr: vec![0, 1],
+            invert_cr: vec![1],
+            t1: Duration::from_millis(50),
+            negotiate: false,
+            ..Default::default()
+        };
+        let mut muxer = Muxer::new(modem, config).unwrap();
+        muxer.open_channel(0).unwrap();
+        muxer.open_channel(1).unwrap();
+
+        let mut slave = open_slave(&muxer, 1);
+        slave.write_all(b"AT").unwrap();
+        muxer.handle_pty_readable(1).unwrap();
+        muxer.handle_serial_readable().unwrap();
+        let mut buf = [0u8; 64];
+        let n = slave.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"good");
+
+        let modem = &muxer.serial.inner;
+        assert!(modem.is_done());
+        // SABM on DLCI 0 with C/R=1, SABM and UIH on DLCI 1 with C/R=0, MSC on DLCI 0
+        let addresses: Vec<u8> = modem.received.iter().map(|f| f.address).collect();
+        assert_eq!(addresses, vec![0x03, 0x05, 0x03, 0x05]);
+    }
+
+    #[test]
+    fn frames_crossing_the_serial_port_are_counted() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1)
+            .expect(FrameType::UIH, 1)
+            .then_data(1, b"OK")
+            // The modem disconnects the channel
+            .then_frame(Frame::new(
+                Address::new_address(false, true, 1),
+                Control::new_control(FrameType::DISC, true),
+                vec![],
+            ));
+        let mut muxer = Muxer::new(modem, no_pn()).unwrap();
+        muxer.open_channel(1).unwrap();
+        muxer.send_data(1, b"AT".to_vec()).unwrap();
+        muxer.handle_serial_readable().unwrap();
+        assert!(!muxer.is_open(1));
+
+        let count =
+            |frame_type, direction, kind, pf| muxer.stats.get(1, frame_type, direction, kind, pf);
+        assert_eq!(
+            count(FrameType::SABM, Direction::Tx, FrameKind::Command, true),
+            1
+        );
+        assert_eq!(
+            count(FrameType::UA, Direction::Rx, FrameKind::Response, true),
+            1
+        );
+        assert_eq!(
+            count(FrameType::UIH, Direction::Tx, FrameKind::Command, true),
+            1
+        );
+        assert_eq!(
+            count(FrameType::UIH, Direction::Rx, FrameKind::Response, false),
+            1
+        );
+        assert_eq!(
+            count(FrameType::DISC, Direction::Rx, FrameKind::Command, true),
+            1
+        );
+        assert_eq!(
+            count(FrameType::UA, Direction::Tx, FrameKind::Response, true),
+            1
+        );
+        assert_eq!(muxer.stats.unknown, 0);
+        let dump = muxer.stats.dump();
+        assert!(
+            dump.contains("DLCI  1 SABM tx: cmd 0|1 resp 0|0"),
+            "{}",
+            dump
+        );
+        assert!(
+            dump.contains("DLCI  1 UA   tx: cmd 0|0 resp 0|1"),
+            "{}",
+            dump
+        );
+        // The MSC following the establishment
+        assert!(
+            dump.contains("DLCI  0 UIH  tx: cmd 0|1 resp 0|0"),
+            "{}",
+            dump
+        );
+    }
+
+    #[test]
+    fn dropped_bytes_are_counted_by_reason() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1)
+            .expect(FrameType::UIH, 1)
+            .then_bytes(&[0x01, 0x02, 0x03])
+            // UIH on DLCI 1 with "AT" and a wrong FCS
+            .then_bytes(&[0xF9, 0x07, 0xEF, 0x05, 0x41, 0x54, 0x00, 0xF9])
+            .then_data(1, b"hi")
+            .then_data(1, b"hi")
+            .then_data(2, b"cr")
+            .then_data(5, b"zz");
+        let config = MuxerConfig {
+            strict_cr: vec![2],
+            suppress_duplicates: vec![1],
+            ..no_pn()
+        };
+        let mut muxer = Muxer::new(FaultyPort::new(modem), config).unwrap();
+        muxer.open_channel(1).unwrap();
+        muxer.send_data(1, b"AT".to_vec()).unwrap();
+        muxer.handle_serial_readable().unwrap();
+
+        // Data of a pty that can't reach the modem
+        muxer.serial.inner.unplugged = true;
+        let mut slave = open_slave(&muxer, 1);
+        slave.write_all(b"ATZ").unwrap();
+        muxer.handle_pty_readable(1).unwrap();
+
+        let drops = &muxer.drops;
+        // Garbage, then the bad frame up to its closing flag
+        assert_eq!(drops.total(DropReason::BadFrame), 11);
+        assert_eq!(drops.get(1, DropReason::Duplicate), 2);
+        assert_eq!(drops.get(2, DropReason::CrMismatch), 2);
+        assert_eq!(drops.get(5, DropReason::UnknownChannel), 2);
+        assert_eq!(drops.get(1, DropReason::SendFailed), 3);
+        assert_eq!(drops.total(DropReason::Reset), 0);
+    }
+
+    #[test]
+    fn test_command_is_echoed() {
+        let mut muxer = Muxer::new(Cursor::new(vec![]), MuxerConfig::default()).unwrap();
+        muxer
+            .handle_frame(control_frame(vec![0x23, 0x07, 0x01, 0x02, 0x03]).as_frame_ref())
+            .unwrap();
+        // Length mismatch and test responses get no answer
+        muxer
+            .handle_frame(control_frame(vec![0x23, 0x09, 0x01]).as_frame_ref())
+            .unwrap();
+        muxer
+            .handle_frame(control_frame(vec![0x21, 0x03, 0x01]).as_frame_ref())
+            .unwrap();
+
+        let frames = sent_frames(&muxer);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].content, vec![0x21, 0x07, 0x01, 0x02, 0x03]);
+    }
+
+    #[test]
+    fn test_response_bytes() {
+        let mut muxer = Muxer::new(Cursor::new(vec![]), MuxerConfig::default()).unwrap();
+        let command = [
+            0xF9, 0x01, 0xEF, 0x0B, 0x23, 0x07, 0x01, 0x02, 0x03, 0x79, 0xF9,
+        ];
+        let (frame, _) = Frame::parse(&mut command.iter().copied()).unwrap();
+        muxer.handle_frame(frame.as_frame_ref()).unwrap();
+
+        // Same value octets and length, C/R cleared in the type octet, UIH FCS over the header
+        assert_eq!(
+            muxer.serial.inner.get_ref(),
+            &vec![0xF9, 0x03, 0xFF, 0x0B, 0x21, 0x07, 0x01, 0x02, 0x03, 0x0D, 0xF9]
+        );
+    }
+
+    #[test]
+    fn control_channel_ui_frames_are_dispatched() {
+        let mut muxer = Muxer::new(Cursor::new(vec![]), MuxerConfig::default()).unwrap();
+        let mut frame = control_frame(vec![0x23, 0x03, 0x7F]);
+        frame.control.set_frame_type(FrameType::UI);
+        muxer.handle_frame(frame.as_frame_ref()).unwrap();
+
+        let frames = sent_frames(&muxer);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].content, vec![0x21, 0x03, 0x7F]);
+    }
+
+    #[test]
+    fn advanced_mode_frames_are_escaped_on_the_wire() {
+        // UIH on DLCI 37 with C/R=0 and P/F=1 has FCS 0x7E, sent escaped
+        let modem = VirtualModem::with_transparency(TransparencyMode::Advanced)
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1)
+            .then_frame(Frame::new(
+                Address::new_address(false, true, 37),
+                Control::new_control(FrameType::UIH, true),
+                vec![ADVANCED_FLAG],
+            ));
+        let config = MuxerConfig {
+            mode: MuxMode::Advanced,
+            ..no_pn()
+        };
+        let mut muxer = Muxer::new(modem, config).unwrap();
+        muxer.open_channel(1).unwrap();
+        assert!(muxer.is_open(1));
+        // Handled with the UA
+        assert_eq!(muxer.drops.total(DropReason::UnknownChannel), 1);
+
+        let frame = Frame::new(
+            Address::new_address(false, true, 37),
+            Control::new_control(FrameType::UIH, true),
+            vec![ADVANCED_FLAG],
+        );
+        assert_eq!(
+            frame.try_to_bytes_with(muxer.transparency()).unwrap(),
+            vec![
+                ADVANCED_FLAG,
+                0x95,
+                0xFF,
+                0x7D,
+                0x5E,
+                0x7D,
+                0x5E,
+                ADVANCED_FLAG
+            ]
+        );
+    }
+
+    #[test]
+    fn fcs_algorithm_is_detected_on_the_first_frames() {
+        let framing = Framing {
+            transparency: TransparencyMode::Basic,
+            fcs: FcsAlgorithm::NotInverted,
+            ..Default::default()
+        };
+        // The modem drops the SABM checked with the standard FCS and answers the next probe
+        let modem = VirtualModem::with_framing(framing)
+            .expect(FrameType::SABM, 0)
+            .then_reply(FrameType::UA, 0)
+            .then_data(0, &[0xE3, 0x05, 0x03, 0x0D])
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1)
+            .then_data(1, b"OK");
+        let config = MuxerConfig {
+            detect_fcs: true,
+            ..no_pn()
+        };
+        let mut muxer = Muxer::new(modem, config).unwrap();
+        muxer
+            .open_channel_with_retry(0, 3, Duration::from_millis(100))
+            .unwrap();
+        assert_eq!(muxer.fcs_algorithm(), FcsAlgorithm::NotInverted);
+        assert!(muxer.is_open(0));
+        muxer.open_channel(1).unwrap();
+        assert!(muxer.is_open(1));
+        let data = muxer
+            .stats
+            .get(1, FrameType::UIH, Direction::Rx, FrameKind::Response, false);
+        assert_eq!(data, 1);
+        assert_eq!(muxer.drops.total(DropReason::BadFrame), 0);
+        // The probe the modem answered opened the control channel, no SABM followed
+        let sabms = muxer
+            .stats
+            .get(0, FrameType::SABM, Direction::Tx, FrameKind::Command, true);
+        assert_eq!(sabms, 2);
+        let received: Vec<u8> = muxer
+            .serial
+            .inner
+            .received
+            .iter()
+            .filter(|frame| frame.control.get_frame_type().ok() == Some(FrameType::SABM))
+            .map(|frame| frame.address.get_dlci())
+            .collect();
+        assert_eq!(received, vec![0, 1]);
+    }
+
+    #[test]
+    fn full_frame_fcs_is_told_apart_with_a_test_command() {
+        let framing = Framing {
+            transparency: TransparencyMode::Basic,
+            fcs: FcsAlgorithm::FullFrame,
+            ..Default::default()
+        };
+        let echo = ControlMessage::Test(TestMessage {
+            payload: FCS_PROBE_PATTERN.to_vec(),
+        });
+        // An empty UA validates under the standard FCS too, only the TEST checked
+        // with the full frame FCS gets an echo
+        let modem = VirtualModem::with_framing(framing)
+            .expect(FrameType::SABM, 0)
+            .then_reply(FrameType::UA, 0)
+            .expect(FrameType::UIH, 0)
+            .then_frame(control_frame(echo.try_to_bytes(false).unwrap()));
+        let config = MuxerConfig {
+            detect_fcs: true,
+            ..no_pn()
+        };
+        let mut muxer = Muxer::new(modem, config).unwrap();
+        muxer
+            .open_channel_with_retry(0, 3, Duration::from_millis(100))
+            .unwrap();
+        assert_eq!(muxer.fcs_algorithm(), FcsAlgorithm::FullFrame);
+        assert!(muxer.is_open(0));
+        assert!(muxer.serial.inner.is_done());
+        assert_eq!(muxer.drops.total(DropReason::BadFrame), 0);
+    }
+
+    #[test]
+    fn silent_modem_fails_fcs_detection() {
+        let config = MuxerConfig {
+            detect_fcs: true,
+            ..no_pn()
+        };
+        let mut muxer = Muxer::new(VirtualModem::new(), config).unwrap();
+        let err = muxer
+            .open_channel_with_retry(0, 1, Duration::from_millis(20))
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<GsmError>(),
+            Some(GsmError::FcsUndetected)
+        ));
+        // One probe per algorithm
+        let sabms = muxer
+            .stats
+            .get(0, FrameType::SABM, Direction::Tx, FrameKind::Command, true);
+        assert_eq!(sabms as usize, FcsAlgorithm::ALL.len());
+    }
+
+    #[test]
+    fn late_reader_gets_replay_then_live_data() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1)
+            .then_data(1, b"+CREG: 1\r\n")
+            .then_data(1, b"RING\r\n");
+        let config = MuxerConfig {
+            replay_channels: vec![1],
+            replay_buffer_bytes: 12,
+            replay_marker: Some("-- replay --".to_string()),
+            ..no_pn()
+        };
+        let mut muxer = Muxer::new(modem, config).unwrap();
+        muxer.open_channel(1).unwrap();
+        muxer.handle_received().unwrap();
+        assert!(muxer.ptys[&1].pending.is_empty());
+
+        let mut slave = open_slave(&muxer, 1);
+        muxer.poll_timers().unwrap();
+        muxer.handle_data(1, b"OK\r\n").unwrap();
+
+        let expected = b"-- replay --\r\nG: 1\r\nRING\r\nOK\r\n";
+        let mut received = Vec::new();
+        let mut buf = [0u8; 64];
+        while received.len() < expected.len() {
+            let n = slave.read(&mut buf).unwrap();
+            received.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn received_data_reaches_the_pty_without_allocating() {
+        let mut muxer = Muxer::new(Cursor::new(vec![]), no_pn()).unwrap();
+        muxer.open_pty(1).unwrap();
+        let mut slave = open_slave(&muxer, 1);
+        let frames: Vec<Frame> = (0..32u8)
+            .map(|i| {
+                Frame::new(
+                    Address::new_address(false, true, 1),
+                    Control::new_control(FrameType::UIH, false),
+                    vec![b'a' + i % 26; 16],
+                )
+            })
+            .collect();
+        let bytes = serialize_batch(&frames, FrameCodec::default()).unwrap();
+        // The first frames size the pty's pending queue
+        muxer.push_serial(&bytes).unwrap();
+        muxer.handle_received().unwrap();
+
+        muxer.push_serial(&bytes).unwrap();
+        let (handled, allocations) = allocations(|| muxer.handle_received());
+        handled.unwrap();
+        assert_eq!(allocations, 0);
+        let mut received = vec![0u8; 2 * 32 * 16];
+        slave.read_exact(&mut received).unwrap();
+        assert_eq!(&received[..16], &[b'a'; 16]);
+    }
+
+    #[test]
+    fn frames_route_to_control_data_or_link() {
+        let frame = |dlci, frame_type| {
+            Frame::new(
+                Address::new_address(true, true, dlci),
+                Control::new_control(frame_type, false),
+                vec![],
+            )
+        };
+        let route =
+            |dlci, frame_type| Route::of(&frame(dlci, frame_type).as_frame_ref(), frame_type);
+        assert!(frame(0, FrameType::UIH).is_control_channel());
+        assert!(!frame(1, FrameType::UIH).is_control_channel());
+        assert_eq!(route(0, FrameType::UIH), Route::Control);
+        assert_eq!(route(0, FrameType::UI), Route::Control);
+        assert_eq!(route(3, FrameType::UIH), Route::Data(3));
+        assert_eq!(route(3, FrameType::UI), Route::Data(3));
+        assert_eq!(route(0, FrameType::SABM), Route::Link(0, FrameType::SABM));
+        assert_eq!(route(2, FrameType::DISC), Route::Link(2, FrameType::DISC));
+    }
+
+    #[test]
+    fn psc_is_answered_and_modem_woken_before_sending() {
+        let modem = VirtualModem::new().then_bytes(&[FLAG]);
+        let config = MuxerConfig {
+            t1: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let mut muxer = Muxer::new(modem, config).unwrap();
+        muxer
+            .handle_frame(control_frame(vec![0x43, 0x01]).as_frame_ref())
+            .unwrap();
+        assert!(muxer.is_asleep());
+        let test = ControlMessage::Test(TestMessage {
+            payload: vec![0x01],
+        });
+        muxer.send_control(&test, true).unwrap();
+        // Queued behind the wake-up flags instead of waiting for the modem
+        assert!(muxer.is_asleep());
+        assert_eq!(muxer.asleep_tx.len(), 1);
+        assert!(muxer.wake_deadline.is_some());
+
+        assert!(muxer
+            .pump_until(Duration::from_secs(1), |muxer| !muxer.is_asleep())
+            .unwrap());
+        assert!(muxer.asleep_tx.is_empty() && muxer.wake_deadline.is_none());
+        let contents: Vec<_> = muxer
+            .serial
+            .inner
+            .received
+            .iter()
+            .map(|f| f.content.clone())
+            .collect();
+        assert_eq!(contents, vec![vec![0x41, 0x01], vec![0x23, 0x03, 0x01]]);
+    }
+
+    #[test]
+    fn wake_up_gives_up_after_n2_bursts() {
+        let config = MuxerConfig {
+            t1: Duration::from_millis(20),
+            n2: 2,
+            ..Default::default()
+        };
+        let mut muxer = Muxer::new(VirtualModem::new(), config).unwrap();
+        muxer.asleep = true;
+        let test = ControlMessage::Test(TestMessage {
+            payload: vec![0x01],
+        });
+        muxer.send_control(&test, true).unwrap();
+        assert_eq!(muxer.wake_bursts, 1);
+        let err = muxer
+            .pump_until(Duration::from_secs(1), |_| false)
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<GsmError>(),
+            Some(GsmError::WakeUpTimeout)
+        ));
+        assert_eq!(muxer.wake_bursts, 2);
+        assert!(muxer.is_asleep());
+        assert!(muxer.asleep_tx.is_empty() && muxer.wake_deadline.is_none());
+        assert_eq!(muxer.drops.get(0, DropReason::Asleep), 3);
+        assert!(muxer.serial.inner.received.is_empty());
+    }
+
+    #[test]
+    fn rls_is_counted_and_acknowledged() {
+        let mut muxer = Muxer::new(Cursor::new(vec![]), MuxerConfig::default()).unwrap();
+        // Overrun then parity error on DLCI 1, then a status without error
+        for status in [0x03, 0x05, 0x00] {
+            muxer
+                .handle_frame(control_frame(vec![0x53, 0x05, 0x07, status]).as_frame_ref())
+                .unwrap();
+        }
+        assert_eq!(muxer.line_errors(1), 2);
+        assert_eq!(muxer.line_errors(2), 0);
+
+        let frames = sent_frames(&muxer);
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].content, vec![0x51, 0x05, 0x07, 0x03]);
+        assert_eq!(frames[2].content, vec![0x51, 0x05, 0x07, 0x00]);
+
+        // A reopened channel starts without the errors of its previous life
+        muxer.release_channel(1).unwrap();
+        assert_eq!(muxer.line_errors(1), 0);
+    }
+
+    #[test]
+    fn msc_updates_signals_and_is_acknowledged() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1);
+        let mut muxer = Muxer::new(modem, no_pn()).unwrap();
+        muxer.open_channel(1).unwrap();
+
+        // DLCI 1: RTC, RTR and DV set, then DV dropped
+        muxer
+            .handle_frame(control_frame(vec![0xE3, 0x05, 0x07, 0x8D]).as_frame_ref())
+            .unwrap();
+        assert!(muxer.modem_status(1).unwrap().dv);
+        muxer
+            .handle_frame(control_frame(vec![0xE3, 0x05, 0x07, 0x0D]).as_frame_ref())
+            .unwrap();
+        assert!(!muxer.modem_status(1).unwrap().dv);
+        assert!(muxer.modem_status(1).unwrap().rtc);
+        assert!(muxer.modem_status(2).is_none());
+        assert!(muxer
+            .dump_signals()
+            .contains("DLCI  1: FC=0 RTC=1 RTR=1 IC=0 DV=0"));
+
+        let modem = &muxer.serial.inner;
+        assert_eq!(modem.received.len(), 4);
+        assert_eq!(modem.received[3].content, vec![0xE1, 0x05, 0x07, 0x0D]);
+    }
+
+    /// Frame sent by the modem on a channel
+    fn modem_frame(dlci: u8, frame_type: FrameType) -> Frame {
+        Frame::new(
+            Address::new_address(false, true, dlci),
+            Control::new_control(frame_type, true),
+            vec![],
+        )
+    }
+
+    /// MSC command of the modem for DLCI 1 with RTC, RTR and DV set
+    const MODEM_MSC: [u8; 4] = [0xE3, 0x05, 0x07, 0x8D];
+
+    #[test]
+    fn control_channel_restart_resends_modem_status() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 0)
+            .then_reply(FrameType::UA, 0)
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1);
+        let mut muxer = Muxer::new(modem, no_pn()).unwrap();
+        muxer.open_channel(0).unwrap();
+        muxer.open_channel(1).unwrap();
+        muxer
+            .handle_frame(control_frame(MODEM_MSC.to_vec()).as_frame_ref())
+            .unwrap();
+        // FCoff
+        muxer
+            .handle_frame(control_frame(vec![0x63, 0x01]).as_frame_ref())
+            .unwrap();
+        assert!(muxer.flow_blocked);
+        let sent = muxer.serial.inner.received.len();
+
+        muxer
+            .handle_frame(modem_frame(0, FrameType::SABM).as_frame_ref())
+            .unwrap();
+        assert!(muxer.modem_status(1).is_none());
+        assert!(!muxer.flow_blocked);
+        assert!(muxer.is_open(1));
+        // UA on DLCI 0, then our MSC for DLCI 1 again
+        let frames = &muxer.serial.inner.received[sent..];
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].control.get_frame_type().unwrap(), FrameType::UA);
+        assert_eq!(frames[1].content, vec![0xE3, 0x05, 0x07, 0x0D]);
+    }
+
+    #[test]
+    fn forgotten_channel_is_reopened_with_fresh_state() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1)
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1);
+        let mut muxer = Muxer::new(modem, no_pn()).unwrap();
+        muxer.open_channel(1).unwrap();
+        muxer
+            .handle_frame(control_frame(MODEM_MSC.to_vec()).as_frame_ref())
+            .unwrap();
+        assert!(muxer.modem_status(1).is_some());
+
+        muxer
+            .handle_frame(modem_frame(1, FrameType::DM).as_frame_ref())
+            .unwrap();
+        assert!(!muxer.is_open(1));
+        assert!(muxer.modem_status(1).is_none());
+        assert!(!muxer.unpolled.contains(&1));
+        assert!(muxer
+            .pump_until(Duration::from_secs(1), |muxer| muxer.is_open(1))
+            .unwrap());
+        assert!(muxer.serial.inner.is_done());
+        // Our MSC follows the second UA
+        let last = muxer.serial.inner.received.last().unwrap();
+        assert_eq!(last.content, vec![0xE3, 0x05, 0x07, 0x0D]);
+    }
+
+    #[test]
+    fn channel_closed_by_the_modem_forgets_its_state() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1)
+            .expect(FrameType::SABM, 2)
+            .then_reply(FrameType::UA, 2);
+        let config = MuxerConfig {
+            channels: 3,
+            auto_reestablish: vec![2],
+            ..no_pn()
+        };
+        let mut muxer = Muxer::new(modem, config).unwrap();
+        muxer.open_channel(1).unwrap();
+        muxer.open_channel(2).unwrap();
+        muxer
+            .handle_frame(control_frame(MODEM_MSC.to_vec()).as_frame_ref())
+            .unwrap();
+
+        muxer
+            .handle_frame(modem_frame(1, FrameType::DISC).as_frame_ref())
+            .unwrap();
+        muxer
+            .handle_frame(modem_frame(2, FrameType::DISC).as_frame_ref())
+            .unwrap();
+        assert!(muxer.modem_status(1).is_none());
+        // DLCI 1 stays closed, DLCI 2 is re-established
+        assert!(muxer.unpolled.contains(&1));
+        assert!(!muxer.unpolled.contains(&2));
+        assert!(muxer.ptys.contains_key(&1));
+    }
+
+    #[test]
+    fn control_channel_establishment_uses_t3() {
+        let config = MuxerConfig {
+            t1: Duration::from_secs(5),
+            t3: Duration::from_millis(50),
+            n2: 2,
+            ..Default::default()
+        };
+        let mut muxer = Muxer::new(VirtualModem::new(), config).unwrap();
+        let start = Instant::now();
+        assert!(muxer.open_channel(0).is_err());
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(muxer.serial.inner.received.len(), 2);
+    }
+
+    /// Open the slave of a channel's pty as its reader would
+    fn open_slave<S>(muxer: &Muxer<S>, dlci: u8) -> std::fs::File {
+        let name = nix::pty::ptsname_r(&muxer.ptys[&dlci].inner).unwrap();
+        std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(nix::libc::O_NOCTTY)
+            .open(name)
+            .unwrap()
+    }
+
+    fn open_fds() -> usize {
+        std::fs::read_dir("/proc/self/fd").unwrap().count()
+    }
+
+    /// Resident set size of this process in KiB
+    fn rss_kib() -> usize {
+        let status = std::fs::read_to_string("/proc/self/status").unwrap();
+        let line = status.lines().find(|l| l.starts_with("VmRSS:")).unwrap();
+        line.split_whitespace().nth(1).unwrap().parse().unwrap()
+    }
+
+    /// Run the current test alone in a child process, returning `true` in the child.
+    ///
+    /// Other tests open fds and allocate concurrently, so exact fd and memory
+    /// counts need a process of their own.
+    fn isolated(test: &str) -> bool {
+        const CHILD: &str = "GSM0710_ISOLATED_TEST";
+        if std::env::var_os(CHILD).is_some() {
+            return true;
+        }
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args([test, "--exact", "--test-threads=1", "--nocapture"])
+            .env(CHILD, "1")
+            .status()
+            .unwrap();
+        assert!(status.success(), "{test} failed in its child process");
+        false
+    }
+
+    #[test]
+    fn close_channel_releases_everything() {
+        if !isolated("mux::tests::close_channel_releases_everything") {
+            return;
+        }
+        const CYCLES: usize = 500;
+        let mut modem = VirtualModem::new();
+        for _ in 0..CYCLES {
+            modem = modem
+                .expect(FrameType::SABM, 1)
+                .then_reply(FrameType::UA, 1)
+                .expect(FrameType::DISC, 1)
+                .then_reply(FrameType::UA, 1);
+        }
+        let mut muxer = Muxer::new(modem, no_pn()).unwrap();
+        // Warm up both backends before taking the baseline
+        for replay in [false, true] {
+            muxer.config.replay_channels = if replay { vec![1] } else { vec![] };
+            muxer.open_channel(1).unwrap();
+            muxer.close_channel(1).unwrap();
+            muxer.handle_serial_readable().unwrap();
+        }
+        let fds = open_fds();
+        let rss = rss_kib();
+
+        for cycle in 2..CYCLES {
+            // Alternate plain pty and replay-buffer channels
+            muxer.config.replay_channels = if cycle % 2 == 1 { vec![1] } else { vec![] };
+            muxer.open_channel(1).unwrap();
+            assert_eq!(muxer.ptys[&1].replay.is_some(), cycle % 2 == 1);
+            assert!(muxer
+                .poll
+                .registry()
+                .reregister(
+                    muxer.ptys.get_mut(&1).unwrap(),
+                    pty_token(1),
+                    Interest::READABLE
+                )
+                .is_ok());
+            muxer.close_channel(1).unwrap();
+            muxer.handle_serial_readable().unwrap();
+
+            assert!(muxer.ptys.is_empty());
+            assert!(muxer.behaviors.is_empty());
+            assert!(muxer.links.is_empty());
+            assert!(muxer.held_tx.is_empty());
+            assert!(muxer.unpolled.is_empty());
+            assert!(muxer.modem_status.is_empty());
+            assert!(muxer.line_errors.is_empty());
+            assert!(muxer.negotiated.is_empty());
+            assert_eq!(open_fds(), fds);
+        }
+        assert!(muxer.serial.inner.is_done());
+        // A leaked pty or buffer would grow by at least a page per cycle
+        assert!(
+            rss_kib() < rss + 256,
+            "{} KiB grew from {rss} KiB",
+            rss_kib()
+        );
+    }
+
+    #[test]
+    fn pn_clamps_frame_size() {
+        let mut muxer = Muxer::new(Cursor::new(vec![]), MuxerConfig::default()).unwrap();
+        let pn = PnMessage {
+            dlci: 1,
+            frame_type: 0,
+            credit_flow: 0,
+            priority: 7,
+            ack_timer: 10,
+            max_frame_size: 300,
+            max_retransmit: 3,
+            window_size: 2,
+        };
+        let command = ControlMessage::Pn(pn).try_to_bytes(true).unwrap();
+        muxer
+            .handle_frame(control_frame(command).as_frame_ref())
+            .unwrap();
+
+        let expected = PnMessage {
+            max_frame_size: 31,
+            ..pn
+        };
+        assert_eq!(muxer.negotiated(1), Some(&expected));
+        let frames = sent_frames(&muxer);
+        assert_eq!(
+            ControlMessage::parse(&frames[0].content).unwrap(),
+            ControlMessage::Pn(expected)
+        );
+        assert!(!ControlMessage::is_command(&frames[0].content));
+
+        let frame = |len: usize| {
+            Frame::new(
+                Address::new_address(true, true, 1),
+                Control::new_control(FrameType::UIH, true),
+                vec![0; len],
+            )
+        };
+        assert!(muxer.send_frame(&frame(31)).is_ok());
+        assert!(muxer.send_frame(&frame(32)).is_err());
+    }
+
+    #[test]
+    fn garbage_during_the_handshakes_is_counted() {
+        let pn = PnMessage {
+            dlci: 1,
+            frame_type: 0,
+            credit_flow: 0,
+            priority: 7,
+            ack_timer: 5,
+            max_frame_size: 31,
+            max_retransmit: 3,
+            window_size: 2,
+        };
+        let response = control_frame(ControlMessage::Pn(pn).try_to_bytes(false).unwrap());
+        let modem = VirtualModem::new()
+            .expect(FrameType::UIH, 0)
+            .then_bytes(&[0x01, 0x02, 0x03])
+            .then_frame(response)
+            .expect(FrameType::SABM, 1)
+            .then_bytes(b"\r\nOK\r\n")
+            .then_reply(FrameType::UA, 1);
+        let config = MuxerConfig {
+            t1: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let mut muxer = Muxer::new(modem, config).unwrap();
+        muxer.open_channel(1).unwrap();
+        assert!(muxer.negotiated(1).is_some());
+        assert!(muxer.is_open(1));
+        assert_eq!(muxer.drops.total(DropReason::BadFrame), 9);
+    }
+
+    #[test]
+    fn pn_is_sent_before_sabm() {
+        let response = |n1| {
+            let pn = PnMessage {
+                dlci: 1,
+                frame_type: 0,
+                credit_flow: 0,
+                priority: 7,
+                ack_timer: 5,
+                max_frame_size: n1,
+                max_retransmit: 3,
+                window_size: 2,
+            };
+            control_frame(ControlMessage::Pn(pn).try_to_bytes(false).unwrap())
+        };
+        let modem = VirtualModem::new()
+            .expect(FrameType::UIH, 0)
+            .then_frame(response(24))
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1)
+            .expect(FrameType::SABM, 2)
+            .then_reply(FrameType::UA, 2);
+        let config = MuxerConfig {
+            t1: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let mut muxer = Muxer::new(modem, config).unwrap();
+        muxer.open_channel(1).unwrap();
+        assert_eq!(muxer.negotiated(1).unwrap().max_frame_size, 24);
+        let proposal = ControlMessage::parse(&muxer.serial.inner.received[0].content).unwrap();
+        let ControlMessage::Pn(proposal) = proposal else {
+            panic!("expected PN, got {:?}", proposal);
+        };
+        assert_eq!((proposal.max_frame_size, proposal.ack_timer), (31, 5));
+
+        // The modem ignores the PN for DLCI 2, SABM is sent after T1
+        muxer.open_channel(2).unwrap();
+        assert!(muxer.is_open(2));
+        assert!(muxer.negotiated(2).is_none());
+        assert!(muxer.serial.inner.is_done());
+    }
+
+    #[test]
+    fn data_is_held_until_ua() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1);
+        let config = MuxerConfig {
+            auto_reestablish: vec![1],
+            reestablish_delay: Duration::ZERO,
+            negotiate: false,
+            ..Default::default()
+        };
+        let mut muxer = Muxer::new(modem, config).unwrap();
+        muxer.open_channel(1).unwrap();
+        let mut slave = open_slave(&muxer, 1);
+
+        // The modem drops the channel, it is re-opened with SABM
+        let mut disc = control_frame(vec![]);
+        disc.address.set_dlci(1);
+        disc.control.set_frame_type(FrameType::DISC);
+        muxer.handle_frame(disc.as_frame_ref()).unwrap();
+        muxer.poll_timers().unwrap();
+        assert_eq!(muxer.links[&1].state, LinkState::Opening);
+
+        slave.write_all(b"AT\r").unwrap();
+        muxer.handle_pty_readable(1).unwrap();
+        let received = muxer.serial.inner.received.len();
+        assert_eq!(muxer.serial.inner.received[received - 1].control, 0x3F);
+
+        let mut ua = control_frame(vec![]);
+        ua.address.set_dlci(1);
+        ua.control.set_frame_type(FrameType::UA);
+        muxer.handle_frame(ua.as_frame_ref()).unwrap();
+        assert!(muxer.is_open(1));
+        let modem = &muxer.serial.inner;
+        assert_eq!(modem.received.len(), received + 2);
+        assert_eq!(modem.received[received + 1].content, b"AT\r");
+    }
+
+    #[test]
+    fn data_on_closed_channel_is_rejected() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::DM, 1);
+        let mut muxer = Muxer::new(modem, no_pn()).unwrap();
+        assert!(muxer.open_channel(1).is_err());
+        assert!(matches!(
+            muxer
+                .send_data(1, b"AT".to_vec())
+                .unwrap_err()
+                .downcast_ref::<GsmError>(),
+            Some(GsmError::ChannelNotOpen(1))
+        ));
+        assert_eq!(muxer.serial.inner.received.len(), 1);
+    }
+
+    #[test]
+    fn fcoff_pauses_data_until_fcon() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1);
+        let mut muxer = Muxer::new(modem, no_pn()).unwrap();
+        muxer.open_channel(1).unwrap();
+        let mut slave = open_slave(&muxer, 1);
+
+        muxer
+            .handle_frame(control_frame(vec![0x63, 0x01]).as_frame_ref())
+            .unwrap();
+        slave.write_all(b"AT\r").unwrap();
+        muxer.handle_pty_readable(1).unwrap();
+        let received = &muxer.serial.inner.received;
+        // SABM, MSC and the FCoff response, no UIH on DLCI 1
+        assert_eq!(received.len(), 3);
+        assert_eq!(received[2].content, vec![0x61, 0x01]);
+
+        muxer
+            .handle_frame(control_frame(vec![0xA3, 0x01]).as_frame_ref())
+            .unwrap();
+        let received = &muxer.serial.inner.received;
+        assert_eq!(received.len(), 5);
+        assert_eq!(received[3].content, vec![0xA1, 0x01]);
+        assert_eq!(received[4].address.get_dlci(), 1);
+        assert_eq!(received[4].content, b"AT\r");
+    }
+
+    #[test]
+    fn pty_reads_are_split_by_the_frame_size() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1);
+        let config = MuxerConfig {
+            frame_size: 32,
+            ..no_pn()
+        };
+        let mut muxer = Muxer::new(modem, config).unwrap();
+        muxer.open_channel(1).unwrap();
+        let mut slave = open_slave(&muxer, 1);
+        let data: Vec<u8> = (0..100).map(|i| b'A' + i % 26).collect();
+        slave.write_all(&data).unwrap();
+        muxer.handle_pty_readable(1).unwrap();
+
+        let fragments: Vec<_> = muxer
+            .serial
+            .inner
+            .received
+            .iter()
+            .filter(|frame| frame.address.get_dlci() == 1)
+            .filter(|frame| frame.control.get_frame_type().unwrap() == FrameType::UIH)
+            .map(|frame| frame.content.clone())
+            .collect();
+        let sizes: Vec<_> = fragments.iter().map(Vec::len).collect();
+        assert_eq!(sizes, vec![32, 32, 32, 4]);
+        assert_eq!(fragments.concat(), data);
+
+        // Data held before a PN shrank N1 is split when flushed
+        muxer
+            .held_tx
+            .entry(1)
+            .or_default()
+            .push_back(vec![0x41; 40]);
+        muxer.record_negotiated(PnMessage {
+            max_frame_size: 16,
+            ..muxer.proposal(1)
+        });
+        muxer.flush_held_tx(1).unwrap();
+        let sizes: Vec<_> = muxer.serial.inner.received[muxer.serial.inner.received.len() - 3..]
+            .iter()
+            .map(|frame| frame.content.len())
+            .collect();
+        assert_eq!(sizes, vec![16, 16, 8]);
+    }
+
+    #[test]
+    fn host_flow_control_is_sent_on_the_control_channel() {
+        let modem = VirtualModem::new()
+            .expect_matching(|frame| frame.content == [0x63, 0x01])
+            .then_frame(control_frame(vec![0x61, 0x01]))
+            .expect_matching(|frame| frame.content == [0xA3, 0x01])
+            .then_frame(control_frame(vec![0xA1, 0x01]));
+        let mut muxer = Muxer::new(modem, no_pn()).unwrap();
+        muxer.send_fc_off().unwrap();
+        muxer.send_fc_on().unwrap();
+        muxer.handle_serial_readable().unwrap();
+        let modem = &muxer.serial.inner;
+        assert!(modem.is_done());
+        assert!(modem
+            .received
+            .iter()
+            .all(|frame| frame.is_control_channel()));
+        // The modem's answers don't stop our own data
+        assert!(!muxer.flow_blocked);
+        assert!(!muxer.serial.inner.has_data());
+    }
+
+    #[test]
+    fn failed_sends_keep_their_credit() {
+        let framing = Framing {
+            flow_control: FlowControl::CreditBased,
+            ..Default::default()
+        };
+        let modem = VirtualModem::with_framing(framing)
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1);
+        let config = MuxerConfig {
+            flow_control: FlowControl::CreditBased,
+            ..no_pn()
+        };
+        let mut muxer = Muxer::new(FaultyPort::new(modem), config).unwrap();
+        muxer.open_channel(1).unwrap();
+
+        muxer.serial.inner.unplugged = true;
+        assert!(muxer.send_data(1, b"AT".to_vec()).is_err());
+        assert_eq!(muxer.tx_credits(1), Some(INITIAL_CREDITS));
+    }
+
+    #[test]
+    fn held_writes_survive_a_failed_flush() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1);
+        let mut muxer = Muxer::new(FaultyPort::new(modem), no_pn()).unwrap();
+        muxer.open_channel(1).unwrap();
+        muxer.held_tx.insert(
+            1,
+            VecDeque::from([b"AT".to_vec(), b"ATI".to_vec(), b"ATZ".to_vec()]),
+        );
+
+        muxer.serial.inner.unplugged = true;
+        assert!(muxer.flush_held_tx(1).is_err());
+        assert_eq!(
+            muxer.held_tx[&1],
+            VecDeque::from([b"ATI".to_vec(), b"ATZ".to_vec()])
+        );
+    }
+
+    #[test]
+    fn frames_overflowing_the_serial_queue_are_dropped() {
+        let framing = Framing {
+            flow_control: FlowControl::CreditBased,
+            ..Default::default()
+        };
+        let modem = VirtualModem::with_framing(framing)
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1);
+        let config = MuxerConfig {
+            flow_control: FlowControl::CreditBased,
+            ..no_pn()
+        };
+        let mut muxer = Muxer::new(FaultyPort::new(modem), config).unwrap();
+        muxer.open_channel(1).unwrap();
+
+        // The port stops taking data until the queue is full
+        muxer.serial.inner.stalled = true;
+        muxer
+            .serial
+            .write_all(&vec![FLAG; SERIAL_PENDING_CAPACITY])
+            .unwrap();
+        let mut slave = open_slave(&muxer, 1);
+        slave.write_all(b"ATZ").unwrap();
+        muxer.handle_pty_readable(1).unwrap();
+        assert!(muxer.send_data(1, b"AT".to_vec()).is_err());
+
+        assert_eq!(muxer.tx_credits(1), Some(INITIAL_CREDITS));
+        assert_eq!(muxer.drops.get(1, DropReason::SerialOverflow), 5);
+        assert_eq!(muxer.drops.get(1, DropReason::SendFailed), 0);
+    }
+
+    #[test]
+    fn credits_gate_the_data_frames() {
+        let framing = Framing {
+            flow_control: FlowControl::CreditBased,
+            ..Default::default()
+        };
+        let modem = VirtualModem::with_framing(framing)
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1);
+        let config = MuxerConfig {
+            flow_control: FlowControl::CreditBased,
+            ..no_pn()
+        };
+        let mut muxer = Muxer::new(modem, config).unwrap();
+        muxer.open_channel(1).unwrap();
+        let mut slave = open_slave(&muxer, 1);
+        let data_frames = |muxer: &Muxer<VirtualModem>| -> Vec<Frame> {
+            let received = &muxer.serial.inner.received;
+            received
+                .iter()
+                .filter(|frame| frame.address.get_dlci() == 1 && !frame.content.is_empty())
+                .cloned()
+                .collect()
+        };
+
+        // Each UIH frame spends one of the initial credits, P/F stays cleared
+        for _ in 0..INITIAL_CREDITS {
+            slave.write_all(b"AT\r").unwrap();
+            muxer.handle_pty_readable(1).unwrap();
+        }
+        assert_eq!(muxer.tx_credits(1), Some(0));
+        assert_eq!(data_frames(&muxer).len(), INITIAL_CREDITS as usize);
+        assert!(data_frames(&muxer).iter().all(|f| !f.control.get_pf()));
+        slave.write_all(b"ATI\r").unwrap();
+        muxer.handle_pty_readable(1).unwrap();
+        assert_eq!(data_frames(&muxer).len(), INITIAL_CREDITS as usize);
+        assert!(matches!(
+            muxer
+                .send_data(1, b"AT".to_vec())
+                .unwrap_err()
+                .downcast_ref::<GsmError>(),
+            Some(GsmError::NoCredits(1))
+        ));
+
+        // Fresh credits in an empty UIH frame release the pending data
+        let mut grant = control_frame(vec![]);
+        grant.address.set_dlci(1);
+        muxer
+            .handle_frame(grant.with_credit(2).as_frame_ref())
+            .unwrap();
+        let sent = data_frames(&muxer);
+        assert_eq!(sent.len(), INITIAL_CREDITS as usize + 1);
+        assert_eq!(sent.last().unwrap().content, b"ATI\r");
+        assert_eq!(muxer.tx_credits(1), Some(1));
+
+        // Credits go back to the modem once half of ours are spent
+        let mut data = control_frame(b"OK\r\n".to_vec());
+        data.address.set_dlci(1);
+        data.control.set_pf(false);
+        for _ in 0..=INITIAL_CREDITS / 2 {
+            muxer.handle_frame(data.clone().as_frame_ref()).unwrap();
+        }
+        let last = muxer.serial.inner.received.last().unwrap().clone();
+        assert_eq!(last.address.get_dlci(), 1);
+        assert_eq!(last.credit, Some(INITIAL_CREDITS / 2 + 1));
+        assert!(last.control.get_pf() && last.content.is_empty());
+    }
+
+    #[test]
+    fn shutdown_waits_for_each_answer() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 0)
+            .then_reply(FrameType::UA, 0)
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1)
+            .expect(FrameType::DISC, 1)
+            .then_reply(FrameType::UA, 1)
+            .expect_matching(|frame| frame.content == [0xC3, 0x01])
+            .then_frame(control_frame(vec![0xC1, 0x01]))
+            .expect(FrameType::DISC, 0)
+            .then_reply(FrameType::UA, 0);
+        let mut muxer = Muxer::new(modem, no_pn()).unwrap();
+        muxer.open_channel(0).unwrap();
+        muxer.open_channel(1).unwrap();
+        muxer.shutdown().unwrap();
+
+        assert!(muxer.close_down_acked);
+        assert!(muxer.ptys.is_empty());
+        assert!(muxer.links.is_empty());
+        let modem = &muxer.serial.inner;
+        assert!(modem.is_done());
+        assert!(!modem.has_data());
+    }
+
+    #[test]
+    fn scheduled_reestablishment_keeps_the_ptys() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 0)
+            .then_reply(FrameType::UA, 0)
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1)
+            .expect(FrameType::DISC, 1)
+            .then_reply(FrameType::UA, 1)
+            .expect_matching(|frame| frame.content == [0xC3, 0x01])
+            .then_frame(control_frame(vec![0xC1, 0x01]))
+            .expect(FrameType::DISC, 0)
+            .then_reply(FrameType::UA, 0)
+            .expect(FrameType::SABM, 0)
+            .then_reply(FrameType::UA, 0)
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1);
+        let config = MuxerConfig {
+            reestablish_every: Some(Duration::from_secs(600)),
+            ..no_pn()
+        };
+        let mut muxer = Muxer::new(modem, config).unwrap();
+        muxer.open_channel(0).unwrap();
+        muxer.open_channel(1).unwrap();
+        let start = Instant::now();
+        muxer.session_start = start;
+        assert!(!muxer.session_due(start + Duration::from_secs(599)));
+        assert!(muxer.session_due(start + Duration::from_secs(600)));
+        let name = nix::pty::ptsname_r(&muxer.ptys[&1].inner).unwrap();
+
+        muxer.end_session(false).unwrap();
+        assert!(muxer.close_down_acked);
+        assert!(!muxer.is_open(0) && !muxer.is_open(1));
+        let mut inits = 0;
+        muxer
+            .reinit(|_| {
+                inits += 1;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(inits, 1);
+        assert!(!muxer.session_due(Instant::now()));
+
+        // The next session reopens the channels on the same ptys
+        muxer.open_channel(0).unwrap();
+        muxer.open_channel(1).unwrap();
+        assert!(muxer.is_open(1));
+        assert_eq!(nix::pty::ptsname_r(&muxer.ptys[&1].inner).unwrap(), name);
+        assert!(muxer.serial.inner.is_done());
+
+        // Without a schedule the session is never due
+        let muxer = Muxer::new(VirtualModem::new(), no_pn()).unwrap();
+        assert!(!muxer.session_due(Instant::now() + Duration::from_secs(86400)));
+    }
+
+    #[test]
+    fn shutdown_skips_channels_that_never_opened() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 0)
+            .then_reply(FrameType::UA, 0)
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::DM, 1)
+            .expect_matching(|frame| frame.content == [0xC3, 0x01])
+            .then_frame(control_frame(vec![0xC1, 0x01]))
+            .expect(FrameType::DISC, 0)
+            .then_reply(FrameType::UA, 0);
+        let mut muxer = Muxer::new(modem, no_pn()).unwrap();
+        muxer.open_channel(0).unwrap();
+        assert!(muxer.open_channel(1).is_err());
+        assert!(!muxer.is_open(1));
+        muxer.shutdown().unwrap();
+
+        let modem = &muxer.serial.inner;
+        assert!(modem.is_done());
+        assert!(!modem.received.iter().any(|frame| {
+            frame.address.get_dlci() == 1
+                && frame.control.get_frame_type().ok() == Some(FrameType::DISC)
+        }));
+    }
+
+    /// Open DLCI 1 with up to 3 SABM 20ms apart, returning the result and the SABMs sent
+    fn establish(modem: VirtualModem) -> (Result<()>, usize) {
+        let mut muxer = Muxer::new(modem, no_pn()).unwrap();
+        let result = muxer.open_channel_with_retry(1, 3, Duration::from_millis(20));
+        let sabms = muxer
+            .serial
+            .inner
+            .received
+            .iter()
+            .filter(|f| f.control.get_frame_type().ok() == Some(FrameType::SABM))
+            .count();
+        (result, sabms)
+    }
+
+    #[test]
+    fn establish_channel_succeeds() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1);
+        let (result, sabms) = establish(modem);
+        assert!(result.is_ok());
+        assert_eq!(sabms, 1);
+    }
+
+    #[test]
+    fn establish_channel_retries_then_succeeds() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 1)
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1);
+        let (result, sabms) = establish(modem);
+        assert!(result.is_ok());
+        assert_eq!(sabms, 2);
+    }
+
+    #[test]
+    fn establish_channel_exhausts_retries() {
+        let (result, sabms) = establish(VirtualModem::new());
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<GsmError>(),
+            Some(GsmError::SabmTimeout(1))
+        ));
+        assert_eq!(sabms, 3);
+    }
+
+    #[test]
+    fn establish_channel_backs_off_exponentially() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 1)
+            .expect(FrameType::SABM, 1)
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1);
+        let start = Instant::now();
+        let (result, sabms) = establish(modem);
+        assert!(result.is_ok());
+        assert_eq!(sabms, 3);
+        // Two timeouts of 20ms, then waits of 50ms and 100ms
+        assert!(start.elapsed() >= Duration::from_millis(190));
+    }
+
+    #[test]
+    fn establish_channel_rejected() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::DM, 1);
+        let (result, sabms) = establish(modem);
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<GsmError>(),
+            Some(GsmError::ChannelRejected(1))
+        ));
+        assert_eq!(sabms, 1);
+    }
+
+    #[test]
+    fn establish_channel_needs_a_transmission() {
+        let mut muxer = Muxer::new(VirtualModem::new(), no_pn()).unwrap();
+        let err = muxer
+            .open_channel_with_retry(1, 0, Duration::from_millis(20))
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<GsmError>(),
+            Some(GsmError::InvalidConfig(_))
+        ));
+        assert!(muxer.serial.inner.received.is_empty());
+        let config = MuxerConfig { n2: 0, ..no_pn() };
+        assert!(config.validate().iter().any(|v| v.id == "n2-zero"));
+    }
+
+    #[test]
+    fn rejected_channel_is_retried() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::DM, 1)
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1);
+        let config = MuxerConfig {
+            dm_retries: 1,
+            dm_retry_delay: Duration::ZERO,
+            ..no_pn()
+        };
+        let mut muxer = Muxer::new(modem, config).unwrap();
+        muxer.open_channel(1).unwrap();
+        assert!(muxer.is_open(1));
+        assert!(muxer.serial.inner.is_done());
+    }
+
+    /// Serve a scripted modem on a socket, for tests through [`Muxer::run`]. The modem
+    /// is handed back once the mux closes its end.
+    fn serve(mut modem: VirtualModem) -> (mio::net::UnixStream, JoinHandle<VirtualModem>) {
+        let (ours, mut theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+        ours.set_nonblocking(true).unwrap();
+        theirs
+            .set_read_timeout(Some(Duration::from_millis(5)))
+            .unwrap();
+        let modem = std::thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            loop {
+                while let Ok(n @ 1..) = modem.read(&mut buf) {
+                    theirs.write_all(&buf[..n]).unwrap();
+                }
+                match theirs.read(&mut buf) {
+                    Ok(0) => return modem,
+                    Ok(n) => modem.write_all(&buf[..n]).unwrap(),
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                    Err(_) => return modem,
+                }
+            }
+        });
+        (mio::net::UnixStream::from_std(ours), modem)
+    }
+
+    #[test]
+    fn run_reestablishes_the_session_when_due() {
+        let msc = |frame: &Frame| frame.content == [0xE3, 0x05, 0x07, 0x0D];
+        let (serial, modem) = serve(
+            VirtualModem::new()
+                .expect(FrameType::SABM, 0)
+                .then_reply(FrameType::UA, 0)
+                .expect(FrameType::SABM, 1)
+                .then_reply(FrameType::UA, 1)
+                // The DISC of DLCI 1 goes unanswered
+                .expect_matching(|frame| frame.content == [0xC3, 0x01])
+                .then_frame(control_frame(vec![0xC1, 0x01]))
+                .expect(FrameType::DISC, 0)
+                .then_reply(FrameType::UA, 0)
+                .expect(FrameType::SABM, 0)
+                .then_reply(FrameType::UA, 0)
+                .expect(FrameType::SABM, 1)
+                .then_reply(FrameType::UA, 1)
+                .expect_matching(msc)
+                .then_data(1, b"OK"),
+        );
+        let config = MuxerConfig {
+            channels: 2,
+            reestablish_every: Some(Duration::from_millis(200)),
+            shutdown_timeout: Duration::from_millis(20),
+            ..no_pn()
+        };
+        let mut muxer = Muxer::new(serial, config).unwrap();
+        let expired = |result: Result<()>| {
+            matches!(
+                result.unwrap_err().downcast_ref::<GsmError>(),
+                Some(GsmError::SessionExpired)
+            )
+        };
+        assert!(expired(muxer.run()));
+        assert!(muxer.is_closing(1));
+        assert!(muxer.close_down_acked);
+
+        let mut inits = 0;
+        muxer
+            .reinit(|_| {
+                inits += 1;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(inits, 1);
+        // Nothing of the previous session is left pending
+        for link in muxer.links.values() {
+            assert_eq!(link.state, LinkState::Closed);
+            assert_eq!(link.deadline(), None);
+        }
+        assert!(!muxer.close_down_acked);
+        assert!(muxer.unpolled.is_empty() && muxer.held_tx.is_empty());
+
+        // The serial port and the signals are registered again
+        assert!(expired(muxer.run()));
+        let data = muxer
+            .stats
+            .get(1, FrameType::UIH, Direction::Rx, FrameKind::Response, false);
+        assert_eq!(data, 1);
+        muxer.release_channels(&[0, 1]).unwrap();
+        drop(muxer);
+        let modem = modem.join().unwrap();
+        assert!(modem.is_done());
+        let discs = modem
+            .received
+            .iter()
+            .filter(|f| {
+                f.address.get_dlci() == 1
+                    && f.control.get_frame_type().ok() == Some(FrameType::DISC)
+            })
+            .count();
+        assert_eq!(discs, 2);
+    }
+
+    #[test]
+    fn unestablished_channel_stops_polling_its_pty() {
+        let dir = std::env::temp_dir().join(format!("gsm0710-unpolled-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let prefix = format!("{}/mux", dir.display());
+        let (serial, modem) = serve(
+            VirtualModem::new()
+                .expect(FrameType::SABM, 0)
+                .then_reply(FrameType::UA, 0)
+                .expect(FrameType::SABM, 1)
+                .then_reply(FrameType::DM, 1)
+                .expect(FrameType::SABM, 1)
+                .then_reply(FrameType::DM, 1),
+        );
+        // The application writes to the channel as soon as its pty shows up
+        let symlink = symlink_path(&prefix, 1);
+        let writer = std::thread::spawn(move || {
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_secs(5) {
+                if let Ok(mut slave) = std::fs::OpenOptions::new()
+                    .write(true)
+                    .custom_flags(nix::libc::O_NOCTTY)
+                    .open(&symlink)
+                {
+                    slave.write_all(b"AT\r").unwrap();
+                    return;
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            panic!("pty never showed up");
+        });
+        let config = MuxerConfig {
+            channels: 2,
+            symlink_prefix: Some(prefix),
+            dm_retries: 1,
+            dm_retry_delay: Duration::from_millis(10),
+            reestablish_every: Some(Duration::from_millis(300)),
+            shutdown_timeout: Duration::from_millis(20),
+            ..no_pn()
+        };
+        let mut muxer = Muxer::new(serial, config).unwrap();
+        let err = muxer.run().unwrap_err();
+        writer.join().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<GsmError>(),
+            Some(GsmError::SessionExpired)
+        ));
+        assert!(muxer.ptys.contains_key(&1));
+        assert!(muxer.unpolled.contains(&1));
+        assert!(muxer.held_tx.is_empty());
+        muxer.release_channels(&[0, 1]).unwrap();
+        drop(muxer);
+
+        // The rejected SABM was retried once, the data written to the pty never went out
+        let modem = modem.join().unwrap();
+        let on_dlci_1 = |frame_type| {
+            modem
+                .received
+                .iter()
+                .filter(|f| {
+                    f.address.get_dlci() == 1 && f.control.get_frame_type().ok() == Some(frame_type)
+                })
+                .count()
+        };
+        assert_eq!(on_dlci_1(FrameType::SABM), 2);
+        assert_eq!(on_dlci_1(FrameType::UIH), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dm_on_control_channel_is_fatal() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 0)
+            .then_reply(FrameType::UA, 0);
+        let mut muxer = Muxer::new(modem, no_pn()).unwrap();
+        muxer.open_channel(0).unwrap();
+        let mut dm = control_frame(vec![]);
+        dm.control.set_frame_type(FrameType::DM);
+        let err = muxer.handle_frame(dm.as_frame_ref()).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<GsmError>(),
+            Some(GsmError::ChannelRejected(0))
+        ));
+        assert!(!muxer.is_open(0));
+    }
+
+    #[test]
+    fn serial_is_drained_in_one_event() {
+        let mut modem = VirtualModem::new();
+        for _ in 0..30 {
+            modem = modem.then_data(5, &[0x55; 100]);
+        }
+        let config = MuxerConfig {
+            frame_size: 100,
+            ..no_pn()
+        };
+        let mut muxer = Muxer::new(modem, config).unwrap();
+        muxer.handle_serial_readable().unwrap();
+        assert!(!muxer.serial.inner.has_data());
+        assert_eq!(muxer.drops.get(5, DropReason::UnknownChannel), 3000);
+    }
+
+    #[test]
+    fn keepalive_only_fires_on_idle_links() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 0)
+            .then_reply(FrameType::UA, 0);
+        let config = MuxerConfig {
+            keepalive: Some(Duration::from_millis(100)),
+            ..no_pn()
+        };
+        let mut muxer = Muxer::new(modem, config).unwrap();
+        muxer.open_channel(0).unwrap();
+        let tests = |muxer: &Muxer<VirtualModem>| {
+            let received = &muxer.serial.inner.received;
+            received
+                .iter()
+                .filter(|f| f.content.first() == Some(&0x23))
+                .count()
+        };
+
+        // Traffic within the window postpones the TEST
+        muxer.last_traffic = Instant::now() - Duration::from_millis(90);
+        muxer
+            .handle_frame(control_frame(vec![0x21, 0x01]).as_frame_ref())
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        muxer.poll_timers().unwrap();
+        assert_eq!(tests(&muxer), 0);
+        assert!(muxer.next_timeout(Duration::from_secs(1)) <= Duration::from_millis(80));
+
+        muxer.last_traffic = Instant::now() - Duration::from_millis(100);
+        muxer.poll_timers().unwrap();
+        assert_eq!(tests(&muxer), 1);
+        let test = muxer.serial.inner.received.last().unwrap();
+        assert_eq!(test.content[2..], *KEEPALIVE_PATTERN);
+        // Sending the TEST restarts the window
+        muxer.poll_timers().unwrap();
+        assert_eq!(tests(&muxer), 1);
+    }
+
+    #[test]
+    fn outgoing_data_doesnt_keep_a_silent_link_alive() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 0)
+            .then_reply(FrameType::UA, 0)
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1);
+        let config = MuxerConfig {
+            keepalive: Some(Duration::from_millis(30)),
+            n2: 2,
+            ..no_pn()
+        };
+        let mut muxer = Muxer::new(modem, config).unwrap();
+        muxer.open_channel(0).unwrap();
+        muxer.open_channel(1).unwrap();
+        let tests = |muxer: &Muxer<VirtualModem>| {
+            let received = &muxer.serial.inner.received;
+            received
+                .iter()
+                .filter(|f| f.content.first() == Some(&0x23))
+                .count()
+        };
+
+        // The modem never answers while we keep sending data
+        let start = Instant::now();
+        let err = loop {
+            assert!(start.elapsed() < Duration::from_secs(1), "no PingTimeout");
+            muxer.send_data(1, b"AT\r".to_vec()).unwrap();
+            if let Err(e) = muxer.poll_timers() {
+                break e;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        };
+        assert_eq!(tests(&muxer), 2);
+        assert!(matches!(err.downcast_ref(), Some(GsmError::PingTimeout)));
+    }
+
+    #[test]
+    fn keepalive_needs_the_matching_echo() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 0)
+            .then_reply(FrameType::UA, 0);
+        let config = MuxerConfig {
+            keepalive: Some(Duration::from_millis(100)),
+            n2: 2,
+            ..no_pn()
+        };
+        let mut muxer = Muxer::new(modem, config).unwrap();
+        muxer.open_channel(0).unwrap();
+        let keepalive_due = |muxer: &mut Muxer<VirtualModem>| {
+            muxer.last_traffic = Instant::now() - Duration::from_millis(100);
+            muxer.poll_timers()
+        };
+        let echo = |payload: &[u8]| {
+            let test = ControlMessage::Test(TestMessage {
+                payload: payload.to_vec(),
+            });
+            control_frame(test.try_to_bytes(false).unwrap())
+        };
+
+        // An echo of another pattern is no answer
+        keepalive_due(&mut muxer).unwrap();
+        muxer.handle_frame(echo(b"gsm0711").as_frame_ref()).unwrap();
+        keepalive_due(&mut muxer).unwrap();
+        assert_eq!(muxer.keepalive_misses, 1);
+        muxer
+            .handle_frame(echo(KEEPALIVE_PATTERN).as_frame_ref())
+            .unwrap();
+        assert_eq!(muxer.keepalive_misses, 0);
+
+        // N2 TEST commands in a row go unanswered
+        keepalive_due(&mut muxer).unwrap();
+        keepalive_due(&mut muxer).unwrap();
+        let err = keepalive_due(&mut muxer).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(GsmError::PingTimeout)));
+    }
+
+    #[test]
+    fn param_drift_follows_the_negotiation() {
+        let response = |dlci, n1, priority| {
+            let pn = PnMessage {
+                dlci,
+                frame_type: 0,
+                credit_flow: 0,
+                priority,
+                ack_timer: 5,
+                max_frame_size: n1,
+                max_retransmit: 3,
+                window_size: 2,
+            };
+            control_frame(ControlMessage::Pn(pn).try_to_bytes(false).unwrap())
+        };
+        // DLCI 1 gets its N1 clamped, DLCI 2 agrees, DLCI 3 ignores the PN
+        let modem = VirtualModem::new()
+            .expect(FrameType::UIH, 0)
+            .then_frame(response(1, 31, 7))
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1)
+            .expect(FrameType::UIH, 0)
+            .then_frame(response(2, 128, 7))
+            .expect(FrameType::SABM, 2)
+            .then_reply(FrameType::UA, 2)
+            .expect(FrameType::SABM, 3)
+            .then_reply(FrameType::UA, 3);
+        let config = MuxerConfig {
+            frame_size: 128,
+            t1: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let mut muxer = Muxer::new(modem, config).unwrap();
+        for dlci in 1..=3 {
+            muxer.open_channel(dlci).unwrap();
+        }
+        let report = muxer.param_drift();
+        assert_eq!(
+            report.drifts,
+            vec![
+                Drift {
+                    dlci: 1,
+                    parameter: "N1",
+                    requested: 128,
+                    effective: 31,
+                    reason: DriftReason::PnResponse,
+                },
+                Drift {
+                    dlci: 3,
+                    parameter: "N1",
+                    requested: 128,
+                    effective: 31,
+                    reason: DriftReason::Default,
+                },
+                Drift {
+                    dlci: 3,
+                    parameter: "T1",
+                    requested: 5,
+                    effective: 10,
+                    reason: DriftReason::Default,
+                },
+            ]
+        );
+        assert_eq!(
+            report.to_string(),
+            "DLCI 1 N1: requested 128, effective 31 (PN response)\n\
+             DLCI 3 N1: requested 128, effective 31 (07.10 default, not negotiated)\n\
+             DLCI 3 T1: requested 5, effective 10 (07.10 default, not negotiated)\n"
+        );
+    }
+
+    /// Muxer on a channel pty of its own with the channel open, as `run` leaves it
+    fn integrity_muxer(is_initiator: bool, integrity_channels: Vec<u8>) -> Muxer<Cursor<Vec<u8>>> {
+        let config = MuxerConfig {
+            is_initiator,
+            integrity_channels,
+            ..no_pn()
+        };
+        let mut muxer = Muxer::new(Cursor::new(vec![]), config).unwrap();
+        muxer.open_pty(1).unwrap();
+        for dlci in [0, 1] {
+            let mut link = muxer.new_link(dlci);
+            link.state = LinkState::Open;
+            muxer.links.insert(dlci, link);
+        }
+        muxer
+    }
+
+    /// Hand the frames one muxer sent to the other, returning how many there were
+    fn shuttle(from: &mut Muxer<Cursor<Vec<u8>>>, to: &mut Muxer<Cursor<Vec<u8>>>) -> usize {
+        let frames = sent_frames(from);
+        from.serial.inner = Cursor::new(vec![]);
+        let count = frames.len();
+        for frame in frames {
+            to.handle_frame(frame.as_frame_ref()).unwrap();
+        }
+        count
+    }
+
+    #[test]
+    fn integrity_mode_is_agreed_between_two_muxers() {
+        let mut a = integrity_muxer(true, vec![1]);
+        let mut b = integrity_muxer(false, vec![1]);
+        a.offer_integrity().unwrap();
+        b.offer_integrity().unwrap();
+        while shuttle(&mut a, &mut b) + shuttle(&mut b, &mut a) > 0 {}
+        assert!(a.integrity_echoed && b.integrity_echoed);
+
+        let mut slave = open_slave(&b, 1);
+        a.send_data(1, b"AT+CSQ\r".to_vec()).unwrap();
+        let sent = sent_frames(&a);
+        assert_eq!(sent[0].content.len(), 7 + INTEGRITY_TRAILER);
+        shuttle(&mut a, &mut b);
+        let mut received = [0; 16];
+        let n = slave.read(&mut received).unwrap();
+        assert_eq!(&received[..n], b"AT+CSQ\r");
+
+        // The FCS of a UIH frame doesn't cover the content, the trailer does
+        b.send_data(1, b"OK\r\n".to_vec()).unwrap();
+        let mut frame = sent_frames(&b).remove(0);
+        b.serial.inner = Cursor::new(vec![]);
+        frame.content[1] ^= 0x20;
+        a.handle_frame(frame.as_frame_ref()).unwrap();
+        assert_eq!(
+            a.drops.get(1, DropReason::Integrity),
+            4 + INTEGRITY_TRAILER as u64
+        );
+
+        // Room is left for the trailer
+        assert_eq!(a.data_frame_size(1), 31 - INTEGRITY_TRAILER);
+    }
+
+    #[test]
+    fn integrity_mode_stays_off_with_a_modem() {
+        let mut muxer = integrity_muxer(true, vec![1]);
+        muxer.offer_integrity().unwrap();
+        let offer = sent_frames(&muxer).remove(0);
+        muxer.serial.inner = Cursor::new(vec![]);
+        // A modem only echoes the offer
+        let mut echo = offer.content.clone();
+        echo[0] &= !0x02;
+        muxer
+            .handle_frame(control_frame(echo).as_frame_ref())
+            .unwrap();
+        assert!(muxer.integrity_echoed);
+        muxer.send_data(1, b"AT\r".to_vec()).unwrap();
+        assert_eq!(sent_frames(&muxer)[0].content, b"AT\r");
+
+        let mut plain = integrity_muxer(true, vec![]);
+        plain.offer_integrity().unwrap();
+        assert!(sent_frames(&plain).is_empty());
+        assert_eq!(plain.data_frame_size(1), 31);
+    }
+
+    #[test]
+    fn ping_measures_the_test_echo() {
+        let echo = ControlMessage::Test(TestMessage {
+            payload: b"ping".to_vec(),
+        });
+        let modem = VirtualModem::new()
+            .expect_matching(|frame| frame.content == [0x23, 0x09, b'p', b'i', b'n', b'g'])
+            .then_frame(control_frame(echo.try_to_bytes(false).unwrap()));
+        let mut muxer = Muxer::new(modem, no_pn()).unwrap();
+        let rtt = muxer
+            .ping(b"ping".to_vec(), Duration::from_millis(200))
+            .unwrap();
+        assert!(rtt < Duration::from_millis(200));
+        assert!(muxer.serial.inner.is_done());
+
+        // A modem that doesn't echo times out
+        let err = muxer
+            .ping(b"lost".to_vec(), Duration::from_millis(30))
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<GsmError>(),
+            Some(GsmError::PingTimeout)
+        ));
+    }
+
+    /// Serial port whose writes fail once unplugged, or block while stalled
+    struct FaultyPort<S> {
+        inner: S,
+        unplugged: bool,
+        stalled: bool,
+    }
+
+    impl<S> FaultyPort<S> {
+        fn new(inner: S) -> Self {
+            FaultyPort {
+                inner,
+                unplugged: false,
+                stalled: false,
+            }
+        }
+    }
+
+    impl<S: Read> Read for FaultyPort<S> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl<S: Write> Write for FaultyPort<S> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            match (self.unplugged, self.stalled) {
+                (true, _) => Err(ErrorKind::BrokenPipe.into()),
+                (false, true) => Err(ErrorKind::WouldBlock.into()),
+                (false, false) => self.inner.write(buf),
+            }
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    /// Serial port reading nothing without blocking, counting the reads
+    #[derive(Default)]
+    struct EmptyPort {
+        reads: usize,
+    }
+
+    impl Read for EmptyPort {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            self.reads += 1;
+            Ok(0)
+        }
+    }
+
+    impl Write for EmptyPort {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn empty_reads_are_polled_like_blocking_ones() {
+        let mut muxer = Muxer::new(EmptyPort::default(), no_pn()).unwrap();
+        let err = muxer
+            .ping(b"ping".to_vec(), Duration::from_millis(50))
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<GsmError>(),
+            Some(GsmError::PingTimeout)
+        ));
+        // One read per poll interval, not a busy loop
+        assert!(
+            muxer.serial.inner.reads <= 6,
+            "{} reads",
+            muxer.serial.inner.reads
+        );
+    }
+
+    #[test]
+    fn cld_from_the_modem_ends_the_run_loop() {
+        let (ours, theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+        ours.set_nonblocking(true).unwrap();
+        let modem = std::thread::spawn(move || {
+            let mut theirs = theirs;
+            theirs
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut buf = [0u8; 64];
+            // SABM on DLCI 0
+            assert!(theirs.read(&mut buf).unwrap() > 0);
+            let ua = Frame::new(
+                Address::new_address(true, true, 0),
+                Control::new_control(FrameType::UA, true),
+                vec![],
+            );
+            theirs.write_all(&ua.try_to_bytes().unwrap()).unwrap();
+            let cld = control_frame(ControlMessage::Cld.try_to_bytes(true).unwrap());
+            theirs.write_all(&cld.try_to_bytes().unwrap()).unwrap();
+            let mut received = Vec::new();
+            theirs.read_to_end(&mut received).unwrap();
+            received
+        });
+        let config = MuxerConfig {
+            channels: 1,
+            ..no_pn()
+        };
+        let mut muxer = Muxer::new(mio::net::UnixStream::from_std(ours), config).unwrap();
+        let err = muxer.run().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<GsmError>(),
+            Some(GsmError::ClosedDown)
+        ));
+        assert!(muxer.ptys.is_empty());
+        drop(muxer);
+
+        // The CLD was acknowledged, nothing followed it
+        let received = modem.join().unwrap();
+        let mut buffer = AllocRingBuffer::new(GSM0710_BUFFER_CAPACITY);
+        buffer.push_vec(received);
+        let response = buffer.pop_frame1_with(Framing::default()).unwrap();
+        assert_eq!(response.content, vec![0xC1, 0x01]);
+        assert!(buffer.pop_frame1_with(Framing::default()).is_none());
+    }
+
+    #[test]
+    fn sabm_on_unsupported_dlci_gets_dm() {
+        let mut muxer = Muxer::new(Cursor::new(vec![]), MuxerConfig::default()).unwrap();
+        let sabm = Frame::new(
+            Address::new_address(false, true, 40),
+            Control::new_control(FrameType::SABM, true),
+            vec![],
+        );
+        muxer.handle_frame(sabm.as_frame_ref()).unwrap();
+
+        let frames = sent_frames(&muxer);
+        assert_eq!(frames.len(), 1);
+        // DM response on DLCI 40: C/R=0, P/F copied from the SABM
+        assert_eq!((frames[0].address, frames[0].control), (0xA1, 0x1F));
+    }
+
+    #[test]
+    fn responder_sends_frames_with_mirrored_cr() {
+        let config = MuxerConfig {
+            is_initiator: false,
+            ..Default::default()
+        };
+        let mut muxer = Muxer::new(Cursor::new(vec![]), config).unwrap();
+        let sabm = Frame::command(
+            40,
+            Control::new_control(FrameType::SABM, true),
+            vec![],
+            true,
+        );
+        muxer.handle_frame(sabm.as_frame_ref()).unwrap();
+        muxer.send_control(&ControlMessage::FCon, true).unwrap();
+
+        let frames = sent_frames(&muxer);
+        // DM response on DLCI 40 with C/R=1, then a UIH command on DLCI 0 with C/R=0
+        assert_eq!((frames[0].address, frames[0].control), (0xA3, 0x1F));
+        assert_eq!(frames[1].address, 0x01);
+    }
+
+    #[test]
+    fn probe_reports_each_channel() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 0)
+            .then_reply(FrameType::UA, 0)
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1)
+            .expect(FrameType::SABM, 2)
+            .then_reply(FrameType::DM, 2)
+            .expect(FrameType::SABM, 3)
+            .expect(FrameType::SABM, 4)
+            .then_reply(FrameType::UA, 4)
+            .expect(FrameType::DISC, 1)
+            .then_reply(FrameType::UA, 1)
+            .expect(FrameType::DISC, 4)
+            .then_reply(FrameType::UA, 4)
+            .expect_matching(|frame| frame.content == [0xC3, 0x01])
+            .then_frame(control_frame(vec![0xC1, 0x01]))
+            .expect(FrameType::DISC, 0)
+            .then_reply(FrameType::UA, 0);
+        let config = MuxerConfig {
+            channels: 5,
+            t1: Duration::from_millis(20),
+            n2: 1,
+            ..no_pn()
+        };
+        let mut muxer = Muxer::new(modem, config).unwrap();
+        let report = muxer.probe().unwrap();
+
+        assert_eq!(
+            report.channels,
+            vec![
+                (0, ProbeResult::Open),
+                (1, ProbeResult::Open),
+                (2, ProbeResult::Rejected),
+                (3, ProbeResult::TimedOut),
+                (4, ProbeResult::Open),
+            ]
+        );
+        assert!(!report.all_open());
+        assert!(report.to_string().contains("DLCI  2: Rejected"));
+        assert!(muxer.serial.inner.is_done());
+        assert!(muxer.ptys.is_empty());
+    }
+
+    fn reset_with(policy: ResetDataPolicy) -> Vec<Frame> {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1)
+            .expect(FrameType::DISC, 1)
+            .then_reply(FrameType::UA, 1)
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1);
+        let mut muxer = Muxer::new(modem, no_pn()).unwrap();
+        muxer.open_channel(1).unwrap();
+        let mut slave = open_slave(&muxer, 1);
+
+        // Written by the application but not read yet when the reset starts
+        slave.write_all(b"AT\r").unwrap();
+        muxer.reset_channel(1, policy).unwrap();
+        assert!(!muxer.is_open(1));
+        assert!(muxer
+            .pump_until(Duration::from_secs(1), |muxer| muxer.is_open(1))
+            .unwrap());
+        assert!(muxer.serial.inner.is_done());
+        muxer.serial.inner.received.clone()
+    }
+
+    #[test]
+    fn reset_discards_or_replays_data() {
+        let data = |frames: &[Frame]| {
+            frames
+                .iter()
+                .filter(|f| {
+                    f.address.get_dlci() == 1
+                        && f.control.get_frame_type().ok() == Some(FrameType::UIH)
+                })
+                .map(|f| f.content.clone())
+                .collect::<Vec<_>>()
+        };
+        assert!(data(&reset_with(ResetDataPolicy::Discard)).is_empty());
+
+        let frames = reset_with(ResetDataPolicy::Replay);
+        assert_eq!(data(&frames), vec![b"AT\r".to_vec()]);
+        // Sent after the second UA
+        assert_eq!(frames.last().unwrap().content, b"AT\r");
+    }
+
+    #[test]
+    fn other_channels_are_served_during_a_reset() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1)
+            .expect(FrameType::SABM, 2)
+            .then_reply(FrameType::UA, 2)
+            .expect(FrameType::DISC, 1)
+            .then_reply(FrameType::UA, 1)
+            .then_data(2, b"RING")
+            .expect(FrameType::SABM, 1)
+            .then_data(2, b"\r\n")
+            .then_reply(FrameType::UA, 1);
+        let mut muxer = Muxer::new(modem, no_pn()).unwrap();
+        muxer.open_channel(1).unwrap();
+        muxer.open_channel(2).unwrap();
+        let mut slave = open_slave(&muxer, 2);
+
+        muxer.reset_channel(1, ResetDataPolicy::Discard).unwrap();
+        assert!(muxer
+            .pump_until(Duration::from_secs(1), |muxer| muxer.is_open(1))
+            .unwrap());
+        assert!(muxer.serial.inner.is_done());
+        let mut received = Vec::new();
+        let mut buf = [0u8; 64];
+        while received.len() < 6 {
+            let n = slave.read(&mut buf).unwrap();
+            received.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(received, b"RING\r\n");
+    }
+
+    #[test]
+    fn pty_reader_sees_eof_before_symlink_goes() {
+        let dir = std::env::temp_dir().join(format!("gsm0710-hangup-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let prefix = format!("{}/mux", dir.display());
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1);
+        let config = MuxerConfig {
+            symlink_prefix: Some(prefix.clone()),
+            hangup_timeout: Duration::from_secs(5),
+            hangup_grace: Duration::ZERO,
+            ..no_pn()
+        };
+        let mut muxer = Muxer::new(modem, config).unwrap();
+        muxer.open_channel(1).unwrap();
+        let symlink = symlink_path(&prefix, 1);
+
+        // The shell holds the slave until it checked the symlink after cat's EOF, cat
+        // is already blocked in its read when the channel closes
+        let mut reader = std::process::Command::new("sh")
+            .args([
+                "-c",
+                r#"exec 3<"$0"; cat <&3 >/dev/null; test -L "$0"; s=$?; exec 3<&-; exit $s"#,
+                &symlink,
+            ])
+            .spawn()
+            .unwrap();
+        let children = format!("/proc/{0}/task/{0}/children", reader.id());
+        let cat_reading = || {
+            let pids = std::fs::read_to_string(&children).unwrap_or_default();
+            pids.split_whitespace().any(|pid| {
+                let syscall = std::fs::read_to_string(format!("/proc/{}/syscall", pid));
+                syscall.is_ok_and(|s| s.split(' ').next() == Some(&nix::libc::SYS_read.to_string()))
+            })
+        };
+        while !cat_reading() {
+            assert!(reader.try_wait().unwrap().is_none(), "reader exited early");
+            std::thread::yield_now();
+        }
+
+        muxer.close_channel(1).unwrap();
+        assert!(
+            reader.wait().unwrap().success(),
+            "symlink removed before EOF"
+        );
+        assert!(std::fs::symlink_metadata(&symlink).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn shutdown_waits_once_for_every_reader() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1)
+            .expect(FrameType::SABM, 2)
+            .then_reply(FrameType::UA, 2);
+        let config = MuxerConfig {
+            hangup_timeout: Duration::from_millis(300),
+            hangup_grace: Duration::ZERO,
+            shutdown_timeout: Duration::ZERO,
+            ..no_pn()
+        };
+        let mut muxer = Muxer::new(modem, config).unwrap();
+        muxer.open_channel(1).unwrap();
+        muxer.open_channel(2).unwrap();
+        // Readers that never close their slaves
+        let _slaves: Vec<_> = [1, 2]
+            .iter()
+            .map(|&dlci| open_slave(&muxer, dlci))
+            .collect();
+
+        let start = Instant::now();
+        muxer.shutdown().unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(300));
+        assert!(elapsed < Duration::from_millis(600), "waited {elapsed:?}");
+        assert!(muxer.ptys.is_empty());
+    }
+
+    #[test]
+    fn modem_status_is_sent_on_open_and_close() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1);
+        let mut muxer = Muxer::new(modem, no_pn()).unwrap();
+        muxer.open_channel(1).unwrap();
+        muxer.close_channel(1).unwrap();
+
+        let modem = &muxer.serial.inner;
+        let frames: Vec<Vec<u8>> = modem
+            .received
+            .iter()
+            .map(|f| f.try_to_bytes().unwrap())
+            .collect();
+        assert_eq!(frames.len(), 4);
+        // MSC command on DLCI 1 with RTC and RTR set
+        assert_eq!(
+            frames[1],
+            vec![0xF9, 0x03, 0xFF, 0x09, 0xE3, 0x05, 0x07, 0x0D, 0xEE, 0xF9]
+        );
+        // Lines dropped before the DISC
+        assert_eq!(
+            frames[2],
+            vec![0xF9, 0x03, 0xFF, 0x09, 0xE3, 0x05, 0x07, 0x01, 0xEE, 0xF9]
+        );
+        assert_eq!(modem.received[3].control, 0x53);
+    }
+
+    #[test]
+    fn disc_is_answered_with_ua() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 0)
+            .then_reply(FrameType::UA, 0)
+            .expect(FrameType::SABM, 2)
+            .then_reply(FrameType::UA, 2);
+        let mut muxer = Muxer::new(modem, no_pn()).unwrap();
+        muxer.open_channel(0).unwrap();
+        muxer.open_channel(2).unwrap();
+        let disc = |dlci| {
+            Frame::new(
+                Address::new_address(false, true, dlci),
+                Control::new_control(FrameType::DISC, true),
+                vec![],
+            )
+        };
+
+        muxer.handle_frame(disc(2).as_frame_ref()).unwrap();
+        assert!(!muxer.is_open(2));
+        assert!(muxer.unpolled.contains(&2));
+        assert!(!muxer.remote_closed);
+        let ua = muxer.serial.inner.received.last().unwrap().clone();
+        // Response to the modem: C/R=0, P/F copied from the DISC
+        assert_eq!((ua.address, ua.control), (0x09, 0x73));
+
+        muxer.handle_frame(disc(0).as_frame_ref()).unwrap();
+        assert!(muxer.remote_closed);
+        let ua = muxer.serial.inner.received.last().unwrap().clone();
+        assert_eq!((ua.address, ua.control), (0x01, 0x73));
+
+        // Nothing is sent to a modem that closed the control channel
+        let sent = muxer.serial.inner.received.len();
+        muxer.close().unwrap();
+        assert_eq!(muxer.serial.inner.received.len(), sent);
+        assert!(!muxer.ptys.contains_key(&2));
+    }
+
+    #[test]
+    fn channel_reopened_by_the_modem_forwards_its_pty() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 0)
+            .then_reply(FrameType::UA, 0)
+            .expect(FrameType::SABM, 2)
+            .then_reply(FrameType::UA, 2);
+        let mut muxer = Muxer::new(modem, no_pn()).unwrap();
+        muxer.open_channel(0).unwrap();
+        muxer.open_channel(2).unwrap();
+        let command = |frame_type, dlci| {
+            Frame::new(
+                Address::new_address(false, true, dlci),
+                Control::new_control(frame_type, true),
+                vec![],
+            )
+        };
+
+        muxer
+            .handle_frame(command(FrameType::DISC, 2).as_frame_ref())
+            .unwrap();
+        assert!(muxer.unpolled.contains(&2));
+        muxer
+            .handle_frame(command(FrameType::SABM, 2).as_frame_ref())
+            .unwrap();
+        assert!(muxer.is_open(2));
+        assert!(!muxer.unpolled.contains(&2));
+
+        let mut slave = open_slave(&muxer, 2);
+        slave.write_all(b"AT\r").unwrap();
+        muxer.handle_pty_readable(2).unwrap();
+        let data = muxer.serial.inner.received.last().unwrap();
+        assert_eq!(data.address.get_dlci(), 2);
+        assert_eq!(data.content, b"AT\r");
+    }
+
+    #[test]
+    fn cmux_command_follows_mode() {
+        let mut config = MuxerConfig::default();
+        assert_eq!(config.cmux_command(), "AT+CMUX=0\r\n");
+        config.mode = MuxMode::Advanced;
+        assert_eq!(config.cmux_command(), "AT+CMUX=1\r\n");
+        config.subset = 1;
+        assert_eq!(config.cmux_command(), "AT+CMUX=1,1\r\n");
+    }
+
+    #[test]
+    fn vendor_cmux_commands_follow_the_config() {
+        let mut config = MuxerConfig {
+            frame_size: 1509,
+            t1: Duration::from_millis(100),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.quectel_cmux_command(),
+            "AT+CMUX=0,0,,1509,10,3,30,,\r\n"
+        );
+        assert_eq!(config.sim7600_cmux_command(), "AT+CMUX=0,0,,1509\r\n");
+        config.mode = MuxMode::Advanced;
+        config.frame_size = 127;
+        config.t1 = Duration::from_millis(300);
+        config.n2 = 5;
+        assert_eq!(
+            config.quectel_cmux_command(),
+            "AT+CMUX=1,0,,127,30,5,30,,\r\n"
+        );
+        assert_eq!(config.sim7600_cmux_command(), "AT+CMUX=1,0,,127\r\n");
+        config.mode = MuxMode::ErrorRecovery;
+        assert_eq!(config.sim7600_cmux_command(), "AT+CMUX=1,2,,127\r\n");
+    }
+
+    #[test]
+    fn error_recovery_is_the_advanced_mode_with_subset_2() {
+        let config = MuxerConfig {
+            mode: MuxMode::ErrorRecovery,
+            ..Default::default()
+        };
+        assert_eq!(config.cmux_command(), "AT+CMUX=1,2\r\n");
+    }
+
+    #[test]
+    fn ui_data_frames_cover_the_content() {
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1);
+        let config = MuxerConfig {
+            subset: 1,
+            ..no_pn()
+        };
+        let mut muxer = Muxer::new(modem, config).unwrap();
+        muxer.open_channel(1).unwrap();
+        muxer.send_data(1, b"AT".to_vec()).unwrap();
+
+        let sent = muxer.serial.inner.received.last().unwrap();
+        assert_eq!(sent.control.get_frame_type().unwrap(), FrameType::UI);
+        // FCS over 07 13 05 41 54, 0x6C would cover the header only
+        assert_eq!(
+            sent.try_to_bytes().unwrap(),
+            vec![0xF9, 0x07, 0x13, 0x05, 0x41, 0x54, 0xA7, 0xF9]
+        );
+    }
+}
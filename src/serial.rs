@@ -1,26 +1,327 @@
 use std::{
-    io::{Read, Write},
-    os::fd::AsRawFd,
+    collections::VecDeque,
+    io::{ErrorKind, Read, Write},
+    os::{
+        fd::AsRawFd,
+        unix::fs::{FileExt, MetadataExt, OpenOptionsExt},
+    },
+    time::Duration,
 };
 
 use crate::{error::GsmError, types::Frame};
 use anyhow::{bail, Result};
-use log::debug;
+use log::{debug, info, warn};
 use mio::{event::Source, unix::SourceFd, Events, Interest, Poll, Token};
 use mio_serial::SerialStream;
 use nix::{
-    fcntl::OFlag,
+    errno::Errno,
+    fcntl::{Flock, FlockArg, OFlag},
     pty::PtyMaster,
     sys::{
         stat::Mode,
         termios::{tcgetattr, tcsetattr, InputFlags, LocalFlags, OutputFlags, SetArg},
     },
 };
+use ringbuffer::{AllocRingBuffer, RingBuffer};
+
+/// Maximum number of bytes queued for a pty whose slave is not reading
+pub const PTY_PENDING_CAPACITY: usize = 4096;
+/// Number of consecutive writes overflowing the pending queue before a pty is wedged
+pub const PTY_WEDGE_THRESHOLD: u8 = 3;
+
+/// Classification of an IO error returned by a pty master
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PtyIoError {
+    /// EAGAIN: the slave's buffer is full, the consumer is slow
+    Backpressure,
+    /// EIO: the slave side is closed
+    HangUp,
+    /// Any other error
+    Other,
+}
+
+impl PtyIoError {
+    pub fn classify(err: &std::io::Error) -> Self {
+        match err.raw_os_error() {
+            Some(code) if code == nix::libc::EIO => PtyIoError::HangUp,
+            _ if err.kind() == ErrorKind::WouldBlock => PtyIoError::Backpressure,
+            _ => PtyIoError::Other,
+        }
+    }
+}
+
+/// Result of handing channel data to a pty
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PtyWriteStatus {
+    /// All bytes reached the pty master
+    Written,
+    /// The slave is not draining, the remaining bytes are queued
+    Backpressure,
+    /// The slave hung up, queued bytes were discarded
+    HangUp,
+    /// The slave stopped reading and the pty is wedged, the bytes were dropped
+    Dropped,
+}
+
+/// Per-channel pty health counters
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ChannelHealth {
+    /// Number of writes that failed with EAGAIN
+    pub eagain: u64,
+    /// Number of reads or writes that failed with EIO
+    pub eio: u64,
+    /// Number of bytes discarded on hangup or queue overflow
+    pub dropped: u64,
+}
+
+/// Last bytes delivered to a pty, written again to each reader attaching to its slave
+#[derive(Debug)]
+pub struct ReplayBuffer {
+    ring: AllocRingBuffer<u8>,
+    /// Line written before the replayed bytes
+    marker: Option<String>,
+    /// Whether a reader held the slave open at the last check
+    attached: bool,
+}
+
+impl ReplayBuffer {
+    /// Marker line followed by the buffered bytes
+    fn contents(&self) -> Vec<u8> {
+        let mut data = match &self.marker {
+            Some(marker) => format!("{}\r\n", marker).into_bytes(),
+            None => Vec::new(),
+        };
+        data.extend(self.ring.iter());
+        data
+    }
+}
 
 /// PtyStream
 #[derive(Debug)]
 pub struct PtyStream {
     pub inner: PtyMaster,
+    /// Bytes waiting for the slave to drain its buffer
+    pub pending: VecDeque<u8>,
+    pub health: ChannelHealth,
+    /// Whether the slave stopped reading, inbound data is dropped until it drains
+    pub wedged: bool,
+    /// Consecutive writes that overflowed the pending queue
+    overflows: u8,
+    pub replay: Option<ReplayBuffer>,
+}
+
+impl PtyStream {
+    pub fn new(inner: PtyMaster) -> Self {
+        PtyStream {
+            inner,
+            pending: VecDeque::new(),
+            health: ChannelHealth::default(),
+            wedged: false,
+            overflows: 0,
+            replay: None,
+        }
+    }
+
+    /// Keep the last `capacity` bytes written, to replay them to each reader attaching
+    /// to the slave after a line holding `marker`.
+    ///
+    /// While no reader is attached the bytes are only kept in the replay buffer.
+    /// Fails with [`GsmError::InvalidConfig`] if `capacity` is 0.
+    pub fn with_replay(mut self, capacity: usize, marker: Option<String>) -> Result<Self> {
+        if capacity == 0 {
+            bail!(GsmError::InvalidConfig(
+                "the replay buffer needs a capacity".into()
+            ));
+        }
+        self.replay = Some(ReplayBuffer {
+            ring: AllocRingBuffer::new(capacity),
+            marker,
+            attached: false,
+        });
+        Ok(self)
+    }
+
+    /// Write the replay buffer if a reader attached to the slave since the last check.
+    ///
+    /// The replay goes through the pending queue ahead of any later data, so the two
+    /// never interleave.
+    pub fn poll_replay(&mut self) -> Result<PtyWriteStatus> {
+        let attached = self.is_slave_open();
+        let Some(replay) = self.replay.as_mut() else {
+            return Ok(PtyWriteStatus::Written);
+        };
+        let attaching = attached && !replay.attached;
+        replay.attached = attached;
+        if !attaching {
+            return Ok(PtyWriteStatus::Written);
+        }
+        info!("Pty reader attached, replaying {} bytes", replay.ring.len());
+        let data = replay.contents();
+        self.forward(&data)
+    }
+
+    /// Write channel data to the pty master.
+    ///
+    /// On EAGAIN the unwritten bytes are queued (up to [`PTY_PENDING_CAPACITY`])
+    /// and [`PtyWriteStatus::Backpressure`] is returned, the caller should wait
+    /// for the pty to become writable and call [`PtyStream::flush_pending`].
+    /// On EIO the queue is discarded and [`PtyWriteStatus::HangUp`] is returned.
+    ///
+    /// After [`PTY_WEDGE_THRESHOLD`] consecutive writes overflowing the queue the pty
+    /// is wedged: data is dropped without queueing and [`PtyWriteStatus::Dropped`]
+    /// is returned until the slave drains the queue.
+    ///
+    /// With a replay buffer the data is kept in it too, and held there without a reader.
+    pub fn write_data(&mut self, data: &[u8]) -> Result<PtyWriteStatus> {
+        if let Some(replay) = self.replay.as_mut() {
+            let was_attached = replay.attached;
+            replay.ring.extend(data.iter().copied());
+            let status = self.poll_replay()?;
+            // A reader that just attached got the data with the replay
+            if !was_attached || !self.replay.as_ref().is_some_and(|r| r.attached) {
+                return Ok(status);
+            }
+        }
+        self.forward(data)
+    }
+
+    /// Queue data for the pty master and flush what it accepts
+    fn forward(&mut self, data: &[u8]) -> Result<PtyWriteStatus> {
+        if self.wedged && self.flush_pending()? != PtyWriteStatus::Written {
+            self.health.dropped += data.len() as u64;
+            return Ok(PtyWriteStatus::Dropped);
+        }
+        let room = PTY_PENDING_CAPACITY.saturating_sub(self.pending.len());
+        if data.len() > room {
+            warn!(
+                "Pty pending queue full, dropping {} bytes",
+                data.len() - room
+            );
+            self.health.dropped += (data.len() - room) as u64;
+            self.overflows += 1;
+        } else {
+            self.overflows = 0;
+        }
+        self.pending.extend(&data[..data.len().min(room)]);
+        let status = self.flush_pending()?;
+        if status == PtyWriteStatus::Backpressure && self.overflows >= PTY_WEDGE_THRESHOLD {
+            warn!("Pty slave stopped reading, dropping its data until it drains");
+            self.wedged = true;
+            return Ok(PtyWriteStatus::Dropped);
+        }
+        Ok(status)
+    }
+
+    /// Write as much of the pending queue as the pty master accepts
+    pub fn flush_pending(&mut self) -> Result<PtyWriteStatus> {
+        while !self.pending.is_empty() {
+            let (head, _) = self.pending.as_slices();
+            match self.inner.write(head) {
+                Ok(n) => {
+                    self.pending.drain(..n);
+                }
+                Err(e) => match PtyIoError::classify(&e) {
+                    PtyIoError::Backpressure => {
+                        self.health.eagain += 1;
+                        return Ok(PtyWriteStatus::Backpressure);
+                    }
+                    PtyIoError::HangUp => {
+                        self.hang_up();
+                        return Ok(PtyWriteStatus::HangUp);
+                    }
+                    PtyIoError::Other => return Err(e.into()),
+                },
+            }
+        }
+        if self.wedged {
+            info!("Pty slave is reading again");
+            self.wedged = false;
+            self.overflows = 0;
+        }
+        Ok(PtyWriteStatus::Written)
+    }
+
+    /// Read data written by the slave.
+    ///
+    /// Returns `Ok(0)` if nothing is available or the slave hung up.
+    pub fn read_data(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self.inner.read(buf) {
+            Ok(n) => Ok(n),
+            Err(e) => match PtyIoError::classify(&e) {
+                PtyIoError::Backpressure => Ok(0),
+                PtyIoError::HangUp => {
+                    self.hang_up();
+                    Ok(0)
+                }
+                PtyIoError::Other => Err(e.into()),
+            },
+        }
+    }
+
+    /// Whether a process holds the slave open
+    pub fn is_slave_open(&self) -> bool {
+        let mut fd = nix::libc::pollfd {
+            fd: self.inner.as_raw_fd(),
+            events: 0,
+            revents: 0,
+        };
+        // The master reports POLLHUP while no slave is open
+        let ready = unsafe { nix::libc::poll(&mut fd, 1, 0) };
+        ready == 0 || fd.revents & nix::libc::POLLHUP == 0
+    }
+
+    /// Hang up the slave while the master stays open: its readers see the end of file,
+    /// even one already blocked in a read, and the slave is closed once they close it.
+    ///
+    /// Hanging up a tty takes CAP_SYS_ADMIN. Without it `false` is returned, closing the
+    /// master is then the only way to reach the readers.
+    pub fn hang_up_slave(&self) -> Result<bool> {
+        let name = nix::pty::ptsname_r(&self.inner)?;
+        let slave = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(nix::libc::O_NOCTTY | nix::libc::O_NONBLOCK)
+            .open(name)?;
+        match unsafe { nix::libc::ioctl(slave.as_raw_fd(), nix::libc::TIOCVHANGUP) } {
+            0 => Ok(true),
+            _ => match Errno::last() {
+                Errno::EPERM => Ok(false),
+                e => Err(e.into()),
+            },
+        }
+    }
+
+    /// Wait until the slave of one of `ptys` is closed, at most `timeout`
+    pub fn wait_hang_up(ptys: &[PtyStream], timeout: Duration) -> Result<()> {
+        // The master reports POLLHUP even without requested events
+        let mut fds: Vec<nix::libc::pollfd> = ptys
+            .iter()
+            .map(|pty| nix::libc::pollfd {
+                fd: pty.inner.as_raw_fd(),
+                events: 0,
+                revents: 0,
+            })
+            .collect();
+        let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+        let ready = unsafe { nix::libc::poll(fds.as_mut_ptr(), fds.len() as _, timeout) };
+        if ready < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() != ErrorKind::Interrupted {
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Discard the pending queue of a pty whose slave is closed
+    fn hang_up(&mut self) {
+        self.health.eio += 1;
+        if !self.pending.is_empty() {
+            debug!("Pty hung up, dropping {} bytes", self.pending.len());
+            self.health.dropped += self.pending.len() as u64;
+            self.pending.clear();
+        }
+    }
 }
 
 impl Source for PtyStream {
@@ -47,6 +348,109 @@ impl Source for PtyStream {
     }
 }
 
+/// Maximum number of bytes queued while the serial port's OS buffer is full
+pub const SERIAL_PENDING_CAPACITY: usize = 64 * 1024;
+/// Kind of the error of writes overflowing the queue of a [`QueuedWriter`]
+pub const QUEUE_FULL: ErrorKind = ErrorKind::QuotaExceeded;
+
+/// Writer that queues the bytes a non-blocking port doesn't accept.
+///
+/// Writes never return `WouldBlock`: what the port doesn't take is queued and the
+/// caller should wait for the port to become writable and call
+/// [`QueuedWriter::flush_pending`]. Writes that would overflow
+/// [`SERIAL_PENDING_CAPACITY`] fail as a whole with [`QUEUE_FULL`], so no partial
+/// frame is sent.
+#[derive(Debug)]
+pub struct QueuedWriter<W> {
+    pub inner: W,
+    pending: VecDeque<u8>,
+}
+
+impl<W: Write> QueuedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        QueuedWriter {
+            inner,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Check whether bytes are waiting for the port to become writable
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Write as much of the queue as the port accepts.
+    ///
+    /// Returns `true` once the queue is empty.
+    pub fn flush_pending(&mut self) -> std::io::Result<bool> {
+        while !self.pending.is_empty() {
+            let (head, _) = self.pending.as_slices();
+            match self.inner.write(head) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.pending.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl<W: Write> Write for QueuedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.pending.len() + buf.len() > SERIAL_PENDING_CAPACITY {
+            warn!("Serial write queue full, dropping {} bytes", buf.len());
+            return Err(QUEUE_FULL.into());
+        }
+        self.pending.extend(buf);
+        if self.pending.len() > buf.len() {
+            debug!("Queued {} bytes behind pending serial data", buf.len());
+            return Ok(buf.len());
+        }
+        if !self.flush_pending()? {
+            debug!("Serial port busy, queued {} bytes", self.pending.len());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Read> Read for QueuedWriter<W> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<W: Source> Source for QueuedWriter<W> {
+    fn register(
+        &mut self,
+        registry: &mio::Registry,
+        token: Token,
+        interests: Interest,
+    ) -> std::io::Result<()> {
+        self.inner.register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &mio::Registry,
+        token: Token,
+        interests: Interest,
+    ) -> std::io::Result<()> {
+        self.inner.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> std::io::Result<()> {
+        self.inner.deregister(registry)
+    }
+}
+
 pub trait PtyWriteFrame {
     fn write_frame(&mut self, frame: Frame) -> Result<()>;
 }
@@ -62,12 +466,25 @@ impl PtyWriteFrame for PtyStream {
 pub const SERIAL_TOKEN: Token = Token(0);
 /// Send an AT command to the modem and wait for a response.
 pub fn at_command(ss: &mut SerialStream, command: &str, timeout_ms: u32) -> Result<()> {
+    at_command_ex(ss, command, &["OK"], &["ERROR"], timeout_ms).map(|_| ())
+}
+
+/// Send an AT command to the modem and wait for a line containing one of `ok_patterns`,
+/// which is returned, or one of `err_patterns`.
+pub fn at_command_ex(
+    ss: &mut SerialStream,
+    command: &str,
+    ok_patterns: &[&str],
+    err_patterns: &[&str],
+    timeout_ms: u32,
+) -> Result<String> {
     let mut poll = Poll::new()?;
     let mut events = Events::with_capacity(1);
     poll.registry()
         .register(ss, SERIAL_TOKEN, Interest::READABLE)?;
 
     let mut buf = vec![0u8; 1024];
+    let mut response = String::new();
     let timeout = Some(std::time::Duration::from_millis(timeout_ms as u64));
 
     debug!(
@@ -82,12 +499,15 @@ pub fn at_command(ss: &mut SerialStream, command: &str, timeout_ms: u32) -> Resu
         for event in events.iter() {
             if event.token() == SERIAL_TOKEN {
                 let n = ss.read(&mut buf)?;
-                let response = std::str::from_utf8(&buf[..n])?;
-                debug!("Received {} bytes: {:02X?} -> {}", n, &buf[..n], response);
-                if response.contains("OK") {
-                    return Ok(());
-                } else if response.contains("ERROR") {
-                    return Err(GsmError::AtCommandFailed(command.to_string()).into());
+                let chunk = std::str::from_utf8(&buf[..n])?;
+                debug!("Received {} bytes: {:02X?} -> {}", n, &buf[..n], chunk);
+                response.push_str(chunk);
+                match match_response(&response, ok_patterns, err_patterns) {
+                    Some(Ok(line)) => return Ok(line.to_string()),
+                    Some(Err(_)) => {
+                        return Err(GsmError::AtCommandFailed(command.to_string()).into())
+                    }
+                    None => {}
                 }
             }
         }
@@ -95,15 +515,182 @@ pub fn at_command(ss: &mut SerialStream, command: &str, timeout_ms: u32) -> Resu
     bail!(GsmError::AtCommandTimedOut(command.to_string()))
 }
 
+/// First line of a response containing one of `ok_patterns` or `err_patterns`
+fn match_response<'a>(
+    response: &'a str,
+    ok_patterns: &[&str],
+    err_patterns: &[&str],
+) -> Option<Result<&'a str, &'a str>> {
+    response.lines().map(str::trim).find_map(|line| {
+        if ok_patterns.iter().any(|p| line.contains(p)) {
+            Some(Ok(line))
+        } else if err_patterns.iter().any(|p| line.contains(p)) {
+            Some(Err(line))
+        } else {
+            None
+        }
+    })
+}
+
+pub fn init_sam201(ss: &mut SerialStream, mux_cmd: &str) -> Result<()> {
+    const HOLA_CMD: &str = "AT\r\n";
+
+    info!("Initializing SAM-201 modem");
+    at_command(ss, HOLA_CMD, 100)?;
+    at_command(ss, mux_cmd, 100)?;
+    info!("SAM-201 modem initialized");
+    Ok(())
+}
+
+/// Time Quectel EC2x modems need after answering `AT+CMUX` before the first SABM
+const QUECTEL_CMUX_SETTLE: Duration = Duration::from_millis(300);
+
+/// Initialize a Quectel EC21/EC25 modem with its `AT+CMUX` command, see
+/// [`MuxerConfig::quectel_cmux_command`](crate::MuxerConfig::quectel_cmux_command)
+pub fn init_quectel_ec2x(ss: &mut SerialStream, mux_cmd: &str) -> Result<()> {
+    info!("Initializing Quectel EC2x modem");
+    at_command(ss, "AT\r\n", 100)?;
+    at_command(ss, mux_cmd, 100)?;
+    std::thread::sleep(QUECTEL_CMUX_SETTLE);
+    info!("Quectel EC2x modem initialized");
+    Ok(())
+}
+
+/// Initialize a SIMCom SIM7600/SIM7000 modem with its `AT+CMUX` command, see
+/// [`MuxerConfig::sim7600_cmux_command`](crate::MuxerConfig::sim7600_cmux_command).
+///
+/// Some firmware versions answer `AT+CMUX` with `+CMUX: 0` ahead of the final `OK`,
+/// which is still awaited: multiplexed mode starts after it, an `OK` arriving late
+/// would land among the first frames. These modems need DLCI 0 open before any data channel, as
+/// [`Muxer::run`](crate::Muxer::run) opens them.
+pub fn init_sim7600(ss: &mut SerialStream, mux_cmd: &str) -> Result<()> {
+    info!("Initializing SIM7600 modem");
+    at_command(ss, "AT\r\n", 100)?;
+    // A `+CMUX: 0` line matches no pattern, only the final result ends the command
+    at_command(ss, mux_cmd, 100)?;
+    info!("SIM7600 modem initialized");
+    Ok(())
+}
+
+/// Path of the symlink pointing at a channel's pty
+pub fn symlink_path(prefix: &str, channel_index: u8) -> String {
+    format!("{}{}", prefix, channel_index)
+}
+
+/// Pick the symlink prefix for the channels' ptys.
+///
+/// Without an explicit prefix, ptys allocated from `/dev/ptmx` get unpredictable
+/// names, so a prefix under `tmp_dir` named after the serial port is used unless
+/// `no_auto` is set. It stays the same across restarts of the mux.
+pub fn resolve_symlink_prefix(
+    prefix: Option<String>,
+    ptmx: &str,
+    no_auto: bool,
+    tmp_dir: &std::path::Path,
+    port: &str,
+) -> Option<String> {
+    let port = std::path::Path::new(port)
+        .file_name()
+        .map_or_else(|| port.into(), |name| name.to_string_lossy());
+    match prefix {
+        Some(prefix) => Some(prefix),
+        None if !no_auto && ptmx.contains("/dev/ptmx") => {
+            Some(format!("{}/gsm0710-{}-", tmp_dir.display(), port))
+        }
+        None => None,
+    }
+}
+
+/// Claim of a symlink prefix by a running instance, released on drop
+#[derive(Debug)]
+pub struct PrefixClaim {
+    path: std::path::PathBuf,
+    /// Lock on the claim file, the kernel releases it when the process dies
+    _lock: Flock<std::fs::File>,
+}
+
+impl Drop for PrefixClaim {
+    fn drop(&mut self) {
+        // Removed while still locked, the lock is released right after
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            debug!("Failed to remove claim {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+/// Name of the claim file of a prefix. Every octet but ASCII alphanumerics is
+/// escaped as `%XX`, so distinct prefixes never share a claim file.
+fn claim_file_name(prefix: &str) -> String {
+    let mut name = String::from("gsm0710-");
+    for byte in prefix.bytes() {
+        match byte.is_ascii_alphanumeric() {
+            true => name.push(byte as char),
+            false => name.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    name + ".claim"
+}
+
+/// Claim a symlink prefix for this process in the registry under `registry_dir`.
+///
+/// The claim is an exclusive `flock` on a claim file holding the owner's pid. Fails
+/// with [`GsmError::PrefixInUse`] if another instance holds the lock. Claim files
+/// left behind by dead processes are no longer locked and are taken over.
+pub fn claim_symlink_prefix(prefix: &str, registry_dir: &std::path::Path) -> Result<PrefixClaim> {
+    let path = registry_dir.join(claim_file_name(prefix));
+    loop {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let mut lock = match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+            Ok(lock) => lock,
+            Err((mut file, Errno::EWOULDBLOCK)) => {
+                // The owner may not have written its pid yet
+                let mut claim = String::new();
+                file.read_to_string(&mut claim)?;
+                let pid = claim.lines().next().and_then(|pid| pid.parse().ok());
+                bail!(GsmError::PrefixInUse(prefix.to_string(), pid.unwrap_or(0)))
+            }
+            Err((_, errno)) => return Err(errno.into()),
+        };
+        // The previous owner removes the file before unlocking it, lock the one in place
+        let locked = lock.metadata()?;
+        let replaced = std::fs::metadata(&path)
+            .map_or(true, |m| (m.dev(), m.ino()) != (locked.dev(), locked.ino()));
+        if replaced {
+            continue;
+        }
+        let mut claim = String::new();
+        lock.read_to_string(&mut claim)?;
+        if let Some(pid) = claim.lines().next() {
+            debug!("Taking over claim of {} from process {}", prefix, pid);
+        }
+        lock.set_len(0)?;
+        lock.write_all_at(
+            format!("{}\n{}\n", std::process::id(), prefix).as_bytes(),
+            0,
+        )?;
+        return Ok(PrefixClaim { path, _lock: lock });
+    }
+}
+
 pub fn openpty(
     ptmx: String,
     channel_index: u8,
     symlink_prefix: Option<String>,
 ) -> Result<PtyMaster> {
     let fd = nix::pty::posix_openpt(OFlag::O_RDWR | OFlag::O_NONBLOCK)?;
+    // grant access to the slave pty
+    if ptmx.contains("/dev/ptmx") {
+        nix::pty::grantpt(&fd)?;
+        nix::pty::unlockpt(&fd)?;
+    }
+    let sym_path = unsafe { nix::pty::ptsname(&fd)? };
     if let Some(prefix) = symlink_prefix {
-        let symlink = format!("{}{}", prefix, channel_index);
-        let sym_path = unsafe { nix::pty::ptsname(&fd)? };
+        let symlink = symlink_path(&prefix, channel_index);
 
         // Remove the symlink if it already exists
         if let Err(err) = nix::unistd::unlink(symlink.as_str()) {
@@ -113,10 +700,7 @@ pub fn openpty(
         debug!("Creating symlink: {} -> {}", sym_path, symlink);
         nix::unistd::symlinkat(sym_path.as_str(), None, symlink.as_str())?;
 
-        // grant access to the slave pty
         if ptmx.contains("/dev/ptmx") {
-            nix::pty::grantpt(&fd)?;
-            nix::pty::unlockpt(&fd)?;
             nix::sys::stat::fchmodat(
                 None,
                 symlink.as_str(),
@@ -124,6 +708,9 @@ pub fn openpty(
                 nix::sys::stat::FchmodatFlags::FollowSymlink,
             )?;
         }
+        info!("Channel {}: {} -> {}", channel_index, symlink, sym_path);
+    } else {
+        info!("Channel {}: {}", channel_index, sym_path);
     }
 
     // Set the slave pty terminal settings
@@ -137,5 +724,422 @@ pub fn openpty(
         | OutputFlags::ONOCR
         | OutputFlags::OCRNL);
     tcsetattr(&fd, SetArg::TCSANOW, &termios)?;
+
+    // A slave never opened doesn't hang up the master, open and close it once
+    drop(
+        std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(nix::libc::O_NOCTTY)
+            .open(&sym_path)?,
+    );
     Ok(fd)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs::File, os::unix::fs::OpenOptionsExt};
+
+    /// Port whose OS buffer is full for the first `busy` writes, then accepts `chunk` bytes per write
+    struct BusyPort {
+        busy: usize,
+        chunk: usize,
+        written: Vec<u8>,
+    }
+
+    impl Write for BusyPort {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.busy > 0 {
+                self.busy -= 1;
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(self.chunk);
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn queued_writer_retries_on_would_block() {
+        let port = BusyPort {
+            busy: 1,
+            chunk: 3,
+            written: Vec::new(),
+        };
+        let mut writer = QueuedWriter::new(port);
+        writer.write_all(&[1, 2, 3, 4, 5]).unwrap();
+        assert!(writer.has_pending());
+        assert!(writer.inner.written.is_empty());
+        // Data written while the queue is not empty is appended in order
+        writer.write_all(&[6, 7]).unwrap();
+        assert!(writer.flush_pending().unwrap());
+        assert!(!writer.has_pending());
+        assert_eq!(writer.inner.written, vec![1, 2, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn queued_writer_rejects_whole_writes_when_full() {
+        let port = BusyPort {
+            busy: usize::MAX,
+            chunk: 0,
+            written: Vec::new(),
+        };
+        let mut writer = QueuedWriter::new(port);
+        writer
+            .write_all(&vec![0; SERIAL_PENDING_CAPACITY - 1])
+            .unwrap();
+        let err = writer.write_all(&[1, 2]).unwrap_err();
+        assert_eq!(err.kind(), QUEUE_FULL);
+        assert!(!writer.flush_pending().unwrap());
+    }
+
+    fn open_pair() -> (PtyStream, File) {
+        let master = nix::pty::posix_openpt(OFlag::O_RDWR | OFlag::O_NONBLOCK).unwrap();
+        nix::pty::grantpt(&master).unwrap();
+        nix::pty::unlockpt(&master).unwrap();
+        let mut termios = tcgetattr(&master).unwrap();
+        nix::sys::termios::cfmakeraw(&mut termios);
+        tcsetattr(&master, SetArg::TCSANOW, &termios).unwrap();
+        let name = nix::pty::ptsname_r(&master).unwrap();
+        let slave = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(nix::libc::O_NONBLOCK | nix::libc::O_NOCTTY)
+            .open(name)
+            .unwrap();
+        (PtyStream::new(master), slave)
+    }
+
+    fn fill(pty: &mut PtyStream) {
+        for _ in 0..1024 {
+            if pty.write_data(&[0x55; 256]).unwrap() == PtyWriteStatus::Backpressure {
+                return;
+            }
+        }
+        panic!("slave buffer never filled");
+    }
+
+    #[test]
+    fn replay_buffer_needs_a_capacity() {
+        let (pty, _slave) = open_pair();
+        let err = pty.with_replay(0, None).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<GsmError>(),
+            Some(GsmError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn pty_write_eagain_queues_data() {
+        let (mut pty, mut slave) = open_pair();
+        fill(&mut pty);
+        assert!(pty.health.eagain >= 1);
+        assert_eq!(pty.health.eio, 0);
+        assert_eq!(pty.health.dropped, 0);
+        assert!(!pty.pending.is_empty());
+
+        // Drain the slave until the queue is flushed
+        let mut buf = [0u8; 4096];
+        let mut status = PtyWriteStatus::Backpressure;
+        for _ in 0..1024 {
+            while slave.read(&mut buf).is_ok_and(|n| n > 0) {}
+            status = pty.flush_pending().unwrap();
+            if status == PtyWriteStatus::Written {
+                break;
+            }
+        }
+        assert_eq!(status, PtyWriteStatus::Written);
+        assert!(pty.pending.is_empty());
+        assert_eq!(pty.health.dropped, 0);
+    }
+
+    #[test]
+    fn pty_wedge_drops_data_until_drained() {
+        let (mut pty, mut slave) = open_pair();
+        fill(&mut pty);
+        let mut status = PtyWriteStatus::Backpressure;
+        for _ in 0..PTY_WEDGE_THRESHOLD + 2 {
+            status = pty.write_data(&[0xAA; PTY_PENDING_CAPACITY]).unwrap();
+        }
+        assert_eq!(status, PtyWriteStatus::Dropped);
+        assert!(pty.wedged);
+        let dropped = pty.health.dropped;
+        assert_eq!(
+            pty.write_data(&[0xAA; 10]).unwrap(),
+            PtyWriteStatus::Dropped
+        );
+        assert_eq!(pty.health.dropped, dropped + 10);
+        assert_eq!(pty.pending.len(), PTY_PENDING_CAPACITY);
+
+        // The slave reads again, the queue drains and data flows
+        let mut buf = [0u8; 4096];
+        for _ in 0..1024 {
+            while slave.read(&mut buf).is_ok_and(|n| n > 0) {}
+            if pty.flush_pending().unwrap() == PtyWriteStatus::Written {
+                break;
+            }
+        }
+        assert!(!pty.wedged);
+        assert_eq!(pty.write_data(b"OK").unwrap(), PtyWriteStatus::Written);
+    }
+
+    #[test]
+    fn pty_hangup_discards_pending() {
+        let (mut pty, slave) = open_pair();
+        fill(&mut pty);
+        let queued = pty.pending.len() as u64;
+        drop(slave);
+
+        let mut buf = [0u8; 64];
+        assert_eq!(pty.read_data(&mut buf).unwrap(), 0);
+        assert_eq!(pty.health.eio, 1);
+        assert_eq!(pty.health.dropped, queued);
+        assert!(pty.pending.is_empty());
+    }
+
+    /// Whether a thread of this process is blocked in read(2)
+    fn blocked_in_read(tid: nix::unistd::Pid) -> bool {
+        let syscall = std::fs::read_to_string(format!("/proc/self/task/{}/syscall", tid));
+        syscall.is_ok_and(|s| s.split(' ').next() == Some(&nix::libc::SYS_read.to_string()))
+    }
+
+    #[test]
+    fn hang_up_wakes_a_blocked_reader() {
+        let (pty, slave) = open_pair();
+        let name = nix::pty::ptsname_r(&pty.inner).unwrap();
+        drop(slave);
+        let mut reader = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(nix::libc::O_NOCTTY)
+            .open(name)
+            .unwrap();
+        let (tid_tx, tid_rx) = std::sync::mpsc::channel();
+        let (read_tx, read_rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tid_tx.send(nix::unistd::gettid()).unwrap();
+            let mut buf = [0u8; 16];
+            read_tx.send(reader.read(&mut buf).unwrap()).unwrap();
+        });
+        let tid = tid_rx.recv().unwrap();
+        while !blocked_in_read(tid) {
+            std::thread::yield_now();
+        }
+
+        // The master stays open unless hanging up is not permitted
+        let _master = match pty.hang_up_slave().unwrap() {
+            true => Some(pty),
+            false => None,
+        };
+        assert_eq!(read_rx.recv_timeout(Duration::from_secs(5)), Ok(0));
+    }
+
+    #[test]
+    fn pty_write_eio_discards_pending() {
+        let (mut pty, _slave) = open_pair();
+        fill(&mut pty);
+        let queued = pty.pending.len() as u64;
+        let eagain = pty.health.eagain;
+        // Closing the slave doesn't fail writes to the master on Linux, a slave whose
+        // master is closed does: it stands in for the master of a hung up pty
+        let (hung_up_master, hung_up) = open_pair();
+        drop(hung_up_master);
+        nix::unistd::dup2(hung_up.as_raw_fd(), pty.inner.as_raw_fd()).unwrap();
+
+        assert_eq!(pty.write_data(b"AT").unwrap(), PtyWriteStatus::HangUp);
+        assert_eq!(pty.health.eio, 1);
+        assert_eq!(pty.health.eagain, eagain);
+        assert_eq!(pty.health.dropped, queued + 2);
+        assert!(pty.pending.is_empty());
+    }
+
+    #[test]
+    fn at_response_matches_patterns_per_line() {
+        let ok = ["OK", "+CMUX: 0"];
+        let err = ["ERROR"];
+        assert_eq!(match_response("AT+CMUX=0\r\n", &ok, &err), None);
+        assert_eq!(
+            match_response("AT+CMUX=0\r\n+CMUX: 0\r\n", &ok, &err),
+            Some(Ok("+CMUX: 0"))
+        );
+        assert_eq!(
+            match_response("\r\n+CME ERROR: 4\r\n", &ok, &err),
+            Some(Err("+CME ERROR: 4"))
+        );
+        assert_eq!(match_response("\r\nOK\r\n", &["OK"], &err), Some(Ok("OK")));
+    }
+
+    #[test]
+    fn sim7600_init_waits_for_the_final_ok() {
+        use crate::types::FLAG;
+        use mio_serial::SerialPortBuilderExt;
+
+        let (mut modem, slave) = open_pair();
+        let name = nix::pty::ptsname_r(&modem.inner).unwrap();
+        drop(slave);
+        let mut ss = mio_serial::new(name, 115200).open_native_async().unwrap();
+        // The `OK` of AT+CMUX comes in a write of its own after `+CMUX: 0`, then the
+        // modem answers a byte of the multiplexer
+        let script: [(&[u8], &[&[u8]]); 3] = [
+            (b"AT\r\n", &[b"\r\nOK\r\n"]),
+            (b"AT+CMUX=0\r\n", &[b"\r\n+CMUX: 0\r\n", b"\r\nOK\r\n"]),
+            (&[FLAG], &[&[FLAG]]),
+        ];
+        let fake = std::thread::spawn(move || {
+            for (request, answers) in script {
+                let mut received = vec![];
+                while received != request {
+                    let mut buf = [0u8; 64];
+                    match modem.inner.read(&mut buf) {
+                        Ok(n) => received.extend_from_slice(&buf[..n]),
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => std::thread::yield_now(),
+                        Err(e) => panic!("{}", e),
+                    }
+                }
+                for answer in answers {
+                    modem.inner.write_all(answer).unwrap();
+                    std::thread::sleep(Duration::from_millis(20));
+                }
+            }
+            modem
+        });
+
+        init_sim7600(&mut ss, "AT+CMUX=0\r\n").unwrap();
+        ss.write_all(&[FLAG]).unwrap();
+        let _modem = fake.join().unwrap();
+        let mut poll = Poll::new().unwrap();
+        poll.registry()
+            .register(&mut ss, SERIAL_TOKEN, Interest::READABLE)
+            .unwrap();
+        poll.poll(&mut Events::with_capacity(1), Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buf = [0u8; 64];
+        assert_eq!(ss.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], FLAG);
+    }
+
+    #[test]
+    fn symlink_prefix_resolution() {
+        let tmp = std::path::Path::new("/tmp");
+        let port = "/dev/ttyUSB0";
+        let auto = "/tmp/gsm0710-ttyUSB0-".to_string();
+        assert_eq!(
+            resolve_symlink_prefix(Some("/dev/mux".into()), "/dev/ptmx", false, tmp, port),
+            Some("/dev/mux".to_string())
+        );
+        assert_eq!(
+            resolve_symlink_prefix(None, "/dev/ptmx", false, tmp, port),
+            Some(auto.clone())
+        );
+        assert_eq!(
+            resolve_symlink_prefix(None, "/dev/ptmx", true, tmp, port),
+            None
+        );
+        assert_eq!(
+            resolve_symlink_prefix(None, "/dev/pts", false, tmp, port),
+            None
+        );
+        assert_eq!(symlink_path(&auto, 3), format!("{}3", auto));
+        assert_eq!(symlink_path("/dev/mux", 0), "/dev/mux0");
+    }
+
+    #[test]
+    fn symlink_name_is_stable_across_runs() {
+        let tmp = std::env::temp_dir().join(format!("gsm0710-stable-{}", std::process::id()));
+        std::fs::create_dir_all(&tmp).unwrap();
+        let setup = || {
+            let prefix =
+                resolve_symlink_prefix(None, "/dev/ptmx", false, &tmp, "/dev/ttyACM1").unwrap();
+            let pty = openpty("/dev/ptmx".to_string(), 2, Some(prefix.clone())).unwrap();
+            let target = std::fs::read_link(symlink_path(&prefix, 2)).unwrap();
+            (symlink_path(&prefix, 2), target, pty)
+        };
+        let (first, first_target, first_pty) = setup();
+        drop(first_pty);
+        let (second, second_target, _pty) = setup();
+        assert_eq!(first, second);
+        assert_eq!(first, format!("{}/gsm0710-ttyACM1-2", tmp.display()));
+        assert!(first_target.starts_with("/dev/pts"));
+        assert!(second_target.starts_with("/dev/pts"));
+        std::fs::remove_dir_all(&tmp).unwrap();
+    }
+
+    #[test]
+    fn symlink_prefix_claimed_by_live_instance() {
+        let dir = std::env::temp_dir().join(format!("gsm0710-claims-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let claim = claim_symlink_prefix("/dev/mux", &dir).unwrap();
+        let path = dir.join("gsm0710-%2Fdev%2Fmux.claim");
+        assert!(path.exists());
+        drop(claim);
+        assert!(!path.exists());
+
+        // Another instance holds the lock, a second one in this process stands for it
+        let _claim = claim_symlink_prefix("/dev/mux", &dir).unwrap();
+        let err = claim_symlink_prefix("/dev/mux", &dir).unwrap_err();
+        let pid = std::process::id();
+        assert!(matches!(
+            err.downcast_ref::<GsmError>(),
+            Some(GsmError::PrefixInUse(prefix, owner)) if prefix == "/dev/mux" && *owner == pid
+        ));
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Symlink prefix /dev/mux is in use by process {}, use a distinct --symlink-prefix",
+                pid
+            )
+        );
+        // Prefixes that sanitizing used to map to the same file are distinct
+        assert!(claim_symlink_prefix("_dev_mux", &dir).is_ok());
+        assert!(claim_symlink_prefix("/dev/mux2", &dir).is_ok());
+        drop(_claim);
+
+        // A dead instance's claim is no longer locked and is taken over
+        std::fs::write(&path, format!("{}\n/dev/mux\n", u32::MAX)).unwrap();
+        let claim = claim_symlink_prefix("/dev/mux", &dir).unwrap();
+        let owner = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(owner, format!("{}\n/dev/mux\n", pid));
+        drop(claim);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn symlink_prefix_is_claimed_once_under_contention() {
+        let dir = std::env::temp_dir().join(format!("gsm0710-race-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let barrier = std::sync::Arc::new(std::sync::Barrier::new(8));
+        let claimers: Vec<_> = (0..8)
+            .map(|_| {
+                let (dir, barrier) = (dir.clone(), barrier.clone());
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    let claim = claim_symlink_prefix("/dev/mux", &dir);
+                    // Hold the claim until every thread tried
+                    barrier.wait();
+                    claim.is_ok()
+                })
+            })
+            .collect();
+        let claimed = claimers
+            .into_iter()
+            .map(|claimer| claimer.join().unwrap())
+            .filter(|&claimed| claimed)
+            .count();
+        assert_eq!(claimed, 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pty_io_error_classification() {
+        let eagain = std::io::Error::from_raw_os_error(nix::libc::EAGAIN);
+        let eio = std::io::Error::from_raw_os_error(nix::libc::EIO);
+        let other = std::io::Error::from_raw_os_error(nix::libc::EBADF);
+        assert_eq!(PtyIoError::classify(&eagain), PtyIoError::Backpressure);
+        assert_eq!(PtyIoError::classify(&eio), PtyIoError::HangUp);
+        assert_eq!(PtyIoError::classify(&other), PtyIoError::Other);
+    }
+}
@@ -0,0 +1,615 @@
+//! Scriptable modem end of a serial link, for end-to-end tests of the [`crate::Muxer`].
+//!
+//! Enabled by the `sim` feature. A [`VirtualModem`] stands in for the serial port:
+//!
+//! ```
+//! use gsm0710::{sim::VirtualModem, FrameType, Muxer, MuxerConfig};
+//!
+//! let modem = VirtualModem::new()
+//!     .expect(FrameType::SABM, 1)
+//!     .then_reply(FrameType::UA, 1);
+//! let config = MuxerConfig {
+//!     negotiate: false,
+//!     ..Default::default()
+//! };
+//! let mut muxer = Muxer::new(modem, config).unwrap();
+//! muxer.open_channel(1).unwrap();
+//! assert!(muxer.is_open(1));
+//! ```
+//!
+//! Or it answers on the responder end of a [`SimLink`], from a thread of its own, while
+//! the mux gets the initiator end like any byte stream:
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use gsm0710::{
+//!     sim::{SimLink, VirtualModem},
+//!     FrameType, Muxer, MuxerConfig,
+//! };
+//!
+//! let (initiator, responder) = SimLink::pair();
+//! let modem = VirtualModem::new()
+//!     .expect(FrameType::SABM, 1)
+//!     .then_delay(Duration::from_millis(20))
+//!     .then_reply(FrameType::UA, 1)
+//!     .serve(responder);
+//!
+//! let config = MuxerConfig {
+//!     negotiate: false,
+//!     ..Default::default()
+//! };
+//! let mut muxer = Muxer::new(initiator, config).unwrap();
+//! muxer.open_channel(1).unwrap();
+//! assert!(muxer.is_open(1));
+//!
+//! // Dropping the mux hangs the link up, which ends the modem's thread
+//! drop(muxer);
+//! assert!(modem.join().unwrap().is_done());
+//! ```
+use std::{
+    collections::VecDeque,
+    io::{ErrorKind, Read, Write},
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    buffer::{GSM0710Buffer, GSM0710_BUFFER_CAPACITY},
+    types::{
+        Address, AddressImpl, Control, ControlImpl, Frame, FrameType, Framing, TransparencyMode,
+    },
+};
+use ringbuffer::AllocRingBuffer;
+
+/// Interval between polls of a [`SimLink`] by a served [`VirtualModem`]
+const SERVE_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Predicate selecting the frame a step of a script waits for
+type FrameMatcher = Box<dyn Fn(&Frame) -> bool + Send>;
+
+/// What a step of a script does once its frame arrived
+#[derive(Debug)]
+enum Emit {
+    Bytes(Vec<u8>),
+    Delay(Duration),
+}
+
+/// Step of a modem script: wait for a frame, then emit bytes and wait in turn
+struct Step {
+    matches: FrameMatcher,
+    emit: Vec<Emit>,
+}
+
+impl std::fmt::Debug for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Step").field("emit", &self.emit).finish()
+    }
+}
+
+/// Modem side of a serial port, driven by a script of expected frames and responses.
+///
+/// Frames written by the mux are checked against the next step of the script. When
+/// a frame matches, the bytes of that step become readable, after the delays of the
+/// step. Frames not matching the next step are recorded but don't advance the script.
+#[derive(Debug)]
+pub struct VirtualModem {
+    steps: VecDeque<Step>,
+    /// Bytes emitted by the script and when they become readable
+    scheduled: VecDeque<(Instant, Vec<u8>)>,
+    /// End of the last emission scheduled, delays included
+    horizon: Option<Instant>,
+    buffer: AllocRingBuffer<u8>,
+    framing: Framing,
+    /// Every frame written by the mux
+    pub received: Vec<Frame>,
+}
+
+impl Default for VirtualModem {
+    fn default() -> Self {
+        VirtualModem {
+            steps: VecDeque::new(),
+            scheduled: VecDeque::new(),
+            horizon: None,
+            buffer: AllocRingBuffer::new(GSM0710_BUFFER_CAPACITY),
+            framing: Framing::default(),
+            received: Vec::new(),
+        }
+    }
+}
+impl VirtualModem {
+    /// Create a modem with an empty script
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a modem with an empty script, framing its frames as given
+    pub fn with_transparency(transparency: TransparencyMode) -> Self {
+        Self::with_framing(transparency.into())
+    }
+
+    /// Create a modem with an empty script, framing its frames and computing their FCS as given
+    pub fn with_framing(framing: Framing) -> Self {
+        VirtualModem {
+            framing,
+            ..Self::default()
+        }
+    }
+
+    /// Wait for a frame of the given type on a DLCI
+    pub fn expect(self, frame_type: FrameType, dlci: u8) -> Self {
+        self.expect_matching(move |frame| {
+            frame.control.get_frame_type().ok() == Some(frame_type)
+                && frame.address.get_dlci() == dlci
+        })
+    }
+
+    /// Wait for a frame accepted by a predicate
+    pub fn expect_matching(mut self, matches: impl Fn(&Frame) -> bool + Send + 'static) -> Self {
+        self.steps.push_back(Step {
+            matches: Box::new(matches),
+            emit: vec![],
+        });
+        self
+    }
+
+    /// Emit raw bytes once the last expected frame arrived, or right away
+    /// if nothing is expected yet
+    pub fn then_bytes(self, bytes: &[u8]) -> Self {
+        self.then(Emit::Bytes(bytes.to_vec()))
+    }
+
+    /// Wait before the next emission of the last expected frame, or before the next
+    /// one from the start if nothing is expected yet
+    pub fn then_delay(self, delay: Duration) -> Self {
+        self.then(Emit::Delay(delay))
+    }
+
+    fn then(mut self, emit: Emit) -> Self {
+        match self.steps.back_mut() {
+            Some(step) => step.emit.push(emit),
+            None => self.schedule(vec![emit]),
+        }
+        self
+    }
+
+    /// Queue the emissions of a step after those already queued
+    fn schedule(&mut self, emit: Vec<Emit>) {
+        let now = Instant::now();
+        let mut at = self.horizon.map_or(now, |horizon| horizon.max(now));
+        for emit in emit {
+            match emit {
+                Emit::Bytes(bytes) => self.scheduled.push_back((at, bytes)),
+                Emit::Delay(delay) => at += delay,
+            }
+        }
+        self.horizon = Some(at);
+    }
+
+    /// Emit a frame once the last expected frame arrived
+    pub fn then_frame(self, frame: Frame) -> Self {
+        let bytes = frame.try_to_bytes_with(self.framing).unwrap();
+        self.then_bytes(&bytes)
+    }
+
+    /// Answer the last expected frame with an empty frame on a DLCI
+    pub fn then_reply(self, frame_type: FrameType, dlci: u8) -> Self {
+        self.then_frame(Frame::new(
+            Address::new_address(true, true, dlci),
+            Control::new_control(frame_type, true),
+            vec![],
+        ))
+    }
+
+    /// Emit data in a UIH frame on a DLCI once the last expected frame arrived
+    pub fn then_data(self, dlci: u8, data: &[u8]) -> Self {
+        self.then_frame(Frame::new(
+            Address::new_address(true, true, dlci),
+            Control::new_control(FrameType::UIH, false),
+            data.to_vec(),
+        ))
+    }
+
+    /// Whether every step of the script ran
+    pub fn is_done(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Whether bytes are ready to be read by the mux, their delays elapsed
+    pub fn has_data(&self) -> bool {
+        self.scheduled
+            .front()
+            .is_some_and(|&(at, _)| at <= Instant::now())
+    }
+
+    /// Run the script on the responder end of a [`SimLink`] from a thread, until the
+    /// other end is dropped. The thread returns the modem, to check what it received.
+    pub fn serve(mut self, mut link: SimLink) -> JoinHandle<VirtualModem> {
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            loop {
+                match link.read(&mut buf) {
+                    Ok(0) => return self,
+                    Ok(n) => self.write_all(&buf[..n]).unwrap(),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        std::thread::sleep(SERVE_POLL_INTERVAL)
+                    }
+                    Err(e) => panic!("Reading the link failed: {}", e),
+                }
+                while let Ok(n) = self.read(&mut buf) {
+                    if link.write_all(&buf[..n]).is_err() {
+                        return self;
+                    }
+                }
+            }
+        })
+    }
+
+    fn on_frame(&mut self, frame: Frame) {
+        let matches = self
+            .steps
+            .front()
+            .is_some_and(|step| (step.matches)(&frame));
+        if matches {
+            let step = self.steps.pop_front().unwrap();
+            self.schedule(step.emit);
+        }
+        self.received.push(frame);
+    }
+}
+
+impl Read for VirtualModem {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if !self.has_data() {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        let mut n = 0;
+        while n < buf.len() && self.has_data() {
+            let (_, bytes) = self.scheduled.front_mut().unwrap();
+            let len = bytes.len().min(buf.len() - n);
+            buf[n..n + len].copy_from_slice(&bytes[..len]);
+            bytes.drain(..len);
+            if bytes.is_empty() {
+                self.scheduled.pop_front();
+            }
+            n += len;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for VirtualModem {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.push_vec(buf.to_vec());
+        while let Some(frame) = self.buffer.pop_frame1_with(self.framing) {
+            self.on_frame(frame);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Fault injected on an octet crossing a [`SimLink`]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Fault {
+    /// The octet is lost
+    Drop,
+    /// The octet arrives XORed with the mask
+    Corrupt(u8),
+}
+
+/// Faults of one direction of a [`SimLink`], each on the nth octet written in that
+/// direction, counting from 0
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LinkModel {
+    faults: Vec<(usize, Fault)>,
+}
+
+impl LinkModel {
+    /// A link delivering every octet untouched
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lose the nth octet
+    pub fn drop_octet(self, n: usize) -> Self {
+        self.with_fault(n, Fault::Drop)
+    }
+
+    /// Flip the bits of `mask` in the nth octet
+    pub fn corrupt_octet(self, n: usize, mask: u8) -> Self {
+        self.with_fault(n, Fault::Corrupt(mask))
+    }
+
+    fn with_fault(mut self, n: usize, fault: Fault) -> Self {
+        self.faults.push((n, fault));
+        self
+    }
+
+    /// Fault of the nth octet, if any
+    fn fault(&self, n: usize) -> Option<Fault> {
+        self.faults
+            .iter()
+            .find(|&&(at, _)| at == n)
+            .map(|&(_, fault)| fault)
+    }
+}
+
+/// One direction of a [`SimLink`]
+#[derive(Debug, Default)]
+struct Pipe {
+    octets: VecDeque<u8>,
+    model: LinkModel,
+    /// Octets written so far, faults included
+    written: usize,
+}
+
+/// End of an in-memory serial link, see [`SimLink::pair`].
+///
+/// Reads never block: they fail with [`ErrorKind::WouldBlock`] until the other end
+/// writes, and return 0 once it is dropped and everything it wrote was read. Writes
+/// fail with [`ErrorKind::BrokenPipe`] once the other end is dropped.
+#[derive(Debug)]
+pub struct SimLink {
+    rx: Arc<Mutex<Pipe>>,
+    tx: Arc<Mutex<Pipe>>,
+}
+
+impl SimLink {
+    /// Two connected ends, the initiator's for the mux and the responder's for the
+    /// modem, e.g. a [`VirtualModem::serve`]
+    pub fn pair() -> (SimLink, SimLink) {
+        Self::pair_with(LinkModel::new(), LinkModel::new())
+    }
+
+    /// Two connected ends whose directions inject faults: `to_responder` on what the
+    /// initiator writes, `to_initiator` on what the responder writes
+    pub fn pair_with(to_responder: LinkModel, to_initiator: LinkModel) -> (SimLink, SimLink) {
+        let pipe = |model| {
+            Arc::new(Mutex::new(Pipe {
+                model,
+                ..Default::default()
+            }))
+        };
+        let (upstream, downstream) = (pipe(to_responder), pipe(to_initiator));
+        let initiator = SimLink {
+            rx: downstream.clone(),
+            tx: upstream.clone(),
+        };
+        let responder = SimLink {
+            rx: upstream,
+            tx: downstream,
+        };
+        (initiator, responder)
+    }
+}
+
+impl Read for SimLink {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut pipe = self.rx.lock().unwrap();
+        if pipe.octets.is_empty() {
+            // The other end holds the pipe while it is alive
+            return match Arc::strong_count(&self.rx) {
+                1 => Ok(0),
+                _ => Err(ErrorKind::WouldBlock.into()),
+            };
+        }
+        let n = buf.len().min(pipe.octets.len());
+        for (dst, src) in buf.iter_mut().zip(pipe.octets.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for SimLink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if Arc::strong_count(&self.tx) == 1 {
+            return Err(ErrorKind::BrokenPipe.into());
+        }
+        let mut pipe = self.tx.lock().unwrap();
+        for &octet in buf {
+            let n = pipe.written;
+            pipe.written += 1;
+            match pipe.model.fault(n) {
+                Some(Fault::Drop) => {}
+                Some(Fault::Corrupt(mask)) => pipe.octets.push_back(octet ^ mask),
+                None => pipe.octets.push_back(octet),
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn virtual_modem_runs_script_in_order() {
+        let mut modem = VirtualModem::new()
+            .then_bytes(b"\r\nOK\r\n")
+            .expect(FrameType::SABM, 0)
+            .then_reply(FrameType::UA, 0)
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1)
+            .then_data(1, b"RING");
+        let mut buf = [0u8; 64];
+        assert_eq!(modem.read(&mut buf).unwrap(), 6);
+
+        let sabm = |dlci| {
+            Frame::new(
+                Address::new_address(true, true, dlci),
+                Control::new_control(FrameType::SABM, true),
+                vec![],
+            )
+            .try_to_bytes()
+            .unwrap()
+        };
+        // Out of order frames don't advance the script
+        modem.write_all(&sabm(1)).unwrap();
+        assert!(!modem.has_data());
+        modem.write_all(&sabm(0)).unwrap();
+        assert!(modem.has_data());
+        modem.write_all(&sabm(1)).unwrap();
+        assert!(modem.is_done());
+        assert_eq!(modem.received.len(), 3);
+
+        let n = modem.read(&mut buf).unwrap();
+        let mut frames = buf[..n].iter().copied();
+        let mut next = || Frame::parse(&mut frames).unwrap().0;
+        assert_eq!(next().address.get_dlci(), 0);
+        assert_eq!(next().address.get_dlci(), 1);
+        assert_eq!(next().content, b"RING");
+    }
+
+    #[test]
+    fn virtual_modem_matches_predicate() {
+        let mut modem = VirtualModem::new()
+            .expect_matching(|frame| frame.content == b"AT\r")
+            .then_data(1, b"OK");
+        let data = |content: &[u8]| {
+            Frame::new(
+                Address::new_address(true, true, 1),
+                Control::new_control(FrameType::UIH, false),
+                content.to_vec(),
+            )
+            .try_to_bytes()
+            .unwrap()
+        };
+        modem.write_all(&data(b"ATI\r")).unwrap();
+        assert!(!modem.is_done());
+        modem.write_all(&data(b"AT\r")).unwrap();
+        assert!(modem.is_done());
+        assert!(modem.has_data());
+    }
+
+    #[test]
+    fn delays_hold_the_emissions_back() {
+        let delay = Duration::from_millis(50);
+        let mut modem = VirtualModem::new()
+            .then_delay(delay)
+            .then_bytes(b"\r\nOK\r\n")
+            .expect(FrameType::SABM, 0)
+            .then_reply(FrameType::UA, 0)
+            .then_delay(delay)
+            .then_data(0, b"late");
+        let start = Instant::now();
+        assert!(!modem.has_data());
+        let mut buf = [0u8; 64];
+        assert_eq!(
+            modem.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+        std::thread::sleep(delay);
+        assert_eq!(modem.read(&mut buf).unwrap(), 6);
+
+        let sabm = Frame::new(
+            Address::new_address(true, true, 0),
+            Control::new_control(FrameType::SABM, true),
+            vec![],
+        );
+        modem.write_all(&sabm.try_to_bytes().unwrap()).unwrap();
+        // The UA right away, the data after the delay
+        let n = modem.read(&mut buf).unwrap();
+        assert_eq!(
+            Frame::parse(&mut buf[..n].iter().copied())
+                .unwrap()
+                .0
+                .content,
+            b""
+        );
+        assert!(!modem.has_data());
+        std::thread::sleep(delay);
+        let n = modem.read(&mut buf).unwrap();
+        assert_eq!(
+            Frame::parse(&mut buf[..n].iter().copied())
+                .unwrap()
+                .0
+                .content,
+            b"late"
+        );
+        assert!(start.elapsed() >= 2 * delay);
+    }
+
+    #[test]
+    fn sim_link_connects_its_ends() {
+        let (mut initiator, mut responder) = SimLink::pair();
+        let mut buf = [0u8; 8];
+        assert_eq!(
+            responder.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+        initiator.write_all(b"AT").unwrap();
+        assert_eq!(responder.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"AT");
+        responder.write_all(b"OK").unwrap();
+        assert_eq!(initiator.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"OK");
+
+        // What was written before the hangup is still read, then the end of file
+        responder.write_all(b"!").unwrap();
+        drop(responder);
+        assert_eq!(initiator.read(&mut buf).unwrap(), 1);
+        assert_eq!(initiator.read(&mut buf).unwrap(), 0);
+        assert_eq!(
+            initiator.write(b"AT").unwrap_err().kind(),
+            ErrorKind::BrokenPipe
+        );
+    }
+
+    #[test]
+    fn link_model_drops_and_corrupts_octets() {
+        let model = LinkModel::new().drop_octet(1).corrupt_octet(3, 0xFF);
+        let (mut initiator, mut responder) = SimLink::pair_with(model, LinkModel::new());
+        initiator.write_all(&[1, 2, 3]).unwrap();
+        initiator.write_all(&[4, 5]).unwrap();
+        let mut buf = [0u8; 8];
+        let n = responder.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], [1, 3, 0xFB, 5]);
+        // The other direction is untouched
+        responder.write_all(&[1, 2, 3, 4]).unwrap();
+        let n = initiator.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn served_modem_answers_through_a_faulty_link() {
+        use crate::{Muxer, MuxerConfig};
+
+        // The FCS of the first UA is corrupted, the mux retransmits its SABM
+        let (initiator, responder) =
+            SimLink::pair_with(LinkModel::new(), LinkModel::new().corrupt_octet(3, 0x01));
+        let modem = VirtualModem::new()
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1)
+            .expect(FrameType::SABM, 1)
+            .then_reply(FrameType::UA, 1)
+            .serve(responder);
+        let config = MuxerConfig {
+            negotiate: false,
+            t1: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let mut muxer = Muxer::new(initiator, config).unwrap();
+        muxer.open_channel(1).unwrap();
+        assert!(muxer.is_open(1));
+
+        drop(muxer);
+        let modem = modem.join().unwrap();
+        assert!(modem.is_done());
+        let sabms = modem
+            .received
+            .iter()
+            .filter(|frame| frame.control.get_frame_type().ok() == Some(FrameType::SABM));
+        assert_eq!(sabms.count(), 2);
+    }
+}
@@ -0,0 +1,374 @@
+use std::fmt::Write;
+
+use crate::types::{AddressImpl, ControlImpl, FrameRef, FrameType};
+
+const DLCIS: usize = 64;
+const FRAME_TYPES: [FrameType; 6] = [
+    FrameType::SABM,
+    FrameType::UA,
+    FrameType::DM,
+    FrameType::DISC,
+    FrameType::UIH,
+    FrameType::UI,
+];
+
+/// Direction of a frame relative to the mux
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Direction {
+    /// Received from the modem
+    Rx = 0,
+    /// Sent to the modem
+    Tx = 1,
+}
+
+/// Whether a frame is a command or a response, derived from the C/R bit and our role
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FrameKind {
+    Command = 0,
+    Response = 1,
+}
+
+impl FrameKind {
+    /// Derive the kind of a frame from its C/R bit, see the table in [`crate::types::Address`]
+    pub fn from_cr(cr: bool, direction: Direction, is_initiator: bool) -> Self {
+        // Commands sent by the initiator and responses sent by the responder carry C/R=1
+        let sent_by_initiator = is_initiator == (direction == Direction::Tx);
+        match cr == sent_by_initiator {
+            true => FrameKind::Command,
+            false => FrameKind::Response,
+        }
+    }
+}
+
+fn frame_type_index(frame_type: FrameType) -> usize {
+    FRAME_TYPES.iter().position(|ft| *ft == frame_type).unwrap()
+}
+
+/// Counts indexed by frame type, direction, command/response and P/F
+type FrameMatrix = [[[[u64; 2]; 2]; 2]; FRAME_TYPES.len()];
+
+/// Per-DLCI frame statistics for protocol debugging
+#[derive(Debug)]
+pub struct FrameStats {
+    pub is_initiator: bool,
+    counts: Box<[FrameMatrix; DLCIS]>,
+    /// Frames with an unknown control field
+    pub unknown: u64,
+}
+
+impl FrameStats {
+    pub fn new(is_initiator: bool) -> Self {
+        FrameStats {
+            is_initiator,
+            counts: Box::new([[[[[0; 2]; 2]; 2]; FRAME_TYPES.len()]; DLCIS]),
+            unknown: 0,
+        }
+    }
+
+    /// Count a frame
+    pub fn record(&mut self, frame: &FrameRef, direction: Direction) {
+        let Ok(frame_type) = frame.control.get_frame_type() else {
+            self.unknown += 1;
+            return;
+        };
+        let kind = FrameKind::from_cr(frame.address.get_cr(), direction, self.is_initiator);
+        let dlci = frame.address.get_dlci() as usize;
+        self.counts[dlci][frame_type_index(frame_type)][direction as usize][kind as usize]
+            [frame.control.get_pf() as usize] += 1;
+    }
+
+    /// Get the count of a single cell
+    pub fn get(
+        &self,
+        dlci: u8,
+        frame_type: FrameType,
+        direction: Direction,
+        kind: FrameKind,
+        pf: bool,
+    ) -> u64 {
+        self.counts[dlci as usize][frame_type_index(frame_type)][direction as usize][kind as usize]
+            [pf as usize]
+    }
+
+    /// Render all non-zero cells, one line per DLCI, frame type and direction
+    pub fn dump(&self) -> String {
+        let mut out = String::from("Frame statistics (cmd/resp, P/F=0|P/F=1):");
+        for (dlci, matrix) in self.counts.iter().enumerate() {
+            for (frame_type, by_direction) in FRAME_TYPES.iter().zip(matrix.iter()) {
+                for (direction, by_kind) in ["rx", "tx"].iter().zip(by_direction.iter()) {
+                    if by_kind.iter().flatten().all(|&count| count == 0) {
+                        continue;
+                    }
+                    let _ = write!(
+                        out,
+                        "\n  DLCI {:2} {:4} {}: cmd {}|{} resp {}|{}",
+                        dlci,
+                        format!("{:?}", frame_type),
+                        direction,
+                        by_kind[0][0],
+                        by_kind[0][1],
+                        by_kind[1][0],
+                        by_kind[1][1]
+                    );
+                }
+            }
+        }
+        if self.unknown > 0 {
+            let _ = write!(out, "\n  unknown: {}", self.unknown);
+        }
+        out
+    }
+}
+
+/// Why payload bytes were discarded
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DropReason {
+    /// Octets of the serial stream that did not parse as a frame, or failed the FCS
+    BadFrame,
+    /// Octets overwritten in the receive buffer before being parsed
+    BufferOverflow,
+    /// Frame with an unexpected C/R bit on a strict channel
+    CrMismatch,
+    /// Payload repeated by the modem within the duplicate window
+    Duplicate,
+    /// Data for a DLCI without pty
+    UnknownChannel,
+    /// Data beyond the capacity of a pty's pending queue
+    PtyOverflow,
+    /// Data for a pty whose slave stopped reading
+    PtyWedged,
+    /// Data for a pty whose slave hung up or was closed
+    PtyHangUp,
+    /// Data written to the pty of a closed channel beyond what is held for it
+    HeldOverflow,
+    /// Data written to the pty before a channel reset discarding it
+    Reset,
+    /// Data held for a channel the modem closed
+    ChannelClosed,
+    /// Frames queued for a modem in power saving that did not wake up
+    Asleep,
+    /// Payload failing the end-to-end integrity check
+    Integrity,
+    /// Data read from a pty that failed to be sent to the modem
+    SendFailed,
+    /// Frames beyond the capacity of the serial port's write queue
+    SerialOverflow,
+}
+
+const DROP_REASONS: [DropReason; 15] = [
+    DropReason::BadFrame,
+    DropReason::BufferOverflow,
+    DropReason::CrMismatch,
+    DropReason::Duplicate,
+    DropReason::UnknownChannel,
+    DropReason::PtyOverflow,
+    DropReason::PtyWedged,
+    DropReason::PtyHangUp,
+    DropReason::HeldOverflow,
+    DropReason::Reset,
+    DropReason::ChannelClosed,
+    DropReason::Asleep,
+    DropReason::Integrity,
+    DropReason::SendFailed,
+    DropReason::SerialOverflow,
+];
+
+impl DropReason {
+    /// Name of the reason in the statistics
+    pub fn label(&self) -> &'static str {
+        match self {
+            DropReason::BadFrame => "bad_frame",
+            DropReason::BufferOverflow => "buffer_overflow",
+            DropReason::CrMismatch => "cr_mismatch",
+            DropReason::Duplicate => "duplicate",
+            DropReason::UnknownChannel => "unknown_channel",
+            DropReason::PtyOverflow => "pty_overflow",
+            DropReason::PtyWedged => "pty_wedged",
+            DropReason::PtyHangUp => "pty_hangup",
+            DropReason::HeldOverflow => "held_overflow",
+            DropReason::Reset => "reset",
+            DropReason::ChannelClosed => "channel_closed",
+            DropReason::Asleep => "asleep",
+            DropReason::Integrity => "integrity",
+            DropReason::SendFailed => "send_failed",
+            DropReason::SerialOverflow => "serial_overflow",
+        }
+    }
+
+    fn index(&self) -> usize {
+        DROP_REASONS.iter().position(|r| r == self).unwrap()
+    }
+}
+
+/// Dropped bytes by reason, per DLCI and over the whole serial stream
+#[derive(Debug)]
+pub struct DropStats {
+    per_dlci: Box<[[u64; DROP_REASONS.len()]; DLCIS]>,
+    total: [u64; DROP_REASONS.len()],
+}
+
+impl Default for DropStats {
+    fn default() -> Self {
+        DropStats {
+            per_dlci: Box::new([[0; DROP_REASONS.len()]; DLCIS]),
+            total: [0; DROP_REASONS.len()],
+        }
+    }
+}
+
+impl DropStats {
+    /// Count dropped bytes, `dlci` is `None` when they can't be attributed to a channel
+    pub fn record(&mut self, dlci: Option<u8>, reason: DropReason, bytes: usize) {
+        if bytes == 0 {
+            return;
+        }
+        if let Some(dlci) = dlci {
+            self.per_dlci[dlci as usize][reason.index()] += bytes as u64;
+        }
+        self.total[reason.index()] += bytes as u64;
+    }
+
+    /// Bytes of a DLCI dropped for a reason
+    pub fn get(&self, dlci: u8, reason: DropReason) -> u64 {
+        self.per_dlci[dlci as usize][reason.index()]
+    }
+
+    /// Bytes dropped for a reason over all channels
+    pub fn total(&self, reason: DropReason) -> u64 {
+        self.total[reason.index()]
+    }
+
+    /// Render the non-zero counters, the totals first then one line per DLCI
+    pub fn dump(&self) -> String {
+        let render = |counts: &[u64; DROP_REASONS.len()]| {
+            DROP_REASONS
+                .iter()
+                .zip(counts)
+                .filter(|(_, &count)| count > 0)
+                .map(|(reason, count)| format!(" {}={}", reason.label(), count))
+                .collect::<String>()
+        };
+        let mut out = format!("Dropped bytes:{}", render(&self.total));
+        for (dlci, counts) in self.per_dlci.iter().enumerate() {
+            if counts.iter().any(|&count| count > 0) {
+                let _ = write!(out, "\n  DLCI {:2}:{}", dlci, render(counts));
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Address, Control, Frame};
+
+    fn frame(dlci: u8, cr: bool, frame_type: FrameType, pf: bool) -> Frame {
+        Frame::new(
+            Address::new_address(cr, true, dlci),
+            Control::new_control(frame_type, pf),
+            vec![],
+        )
+    }
+
+    #[test]
+    fn frame_kind_follows_role() {
+        assert_eq!(
+            FrameKind::from_cr(true, Direction::Tx, true),
+            FrameKind::Command
+        );
+        assert_eq!(
+            FrameKind::from_cr(true, Direction::Rx, true),
+            FrameKind::Response
+        );
+        assert_eq!(
+            FrameKind::from_cr(false, Direction::Rx, true),
+            FrameKind::Command
+        );
+        assert_eq!(
+            FrameKind::from_cr(true, Direction::Rx, false),
+            FrameKind::Command
+        );
+    }
+
+    #[test]
+    fn frame_stats_matrix() {
+        let mut stats = FrameStats::new(true);
+        // We open DLCI 1, the modem answers with and without the final bit
+        stats.record(
+            &frame(1, true, FrameType::SABM, true).as_frame_ref(),
+            Direction::Tx,
+        );
+        stats.record(
+            &frame(1, true, FrameType::UA, true).as_frame_ref(),
+            Direction::Rx,
+        );
+        stats.record(
+            &frame(1, true, FrameType::SABM, true).as_frame_ref(),
+            Direction::Tx,
+        );
+        stats.record(
+            &frame(1, true, FrameType::UA, false).as_frame_ref(),
+            Direction::Rx,
+        );
+        // The modem disconnects DLCI 2 and we answer
+        stats.record(
+            &frame(2, false, FrameType::DISC, true).as_frame_ref(),
+            Direction::Rx,
+        );
+        stats.record(
+            &frame(2, false, FrameType::UA, true).as_frame_ref(),
+            Direction::Tx,
+        );
+        stats.record(
+            &Frame::new(0x0B, 0x00, vec![]).as_frame_ref(),
+            Direction::Rx,
+        );
+
+        assert_eq!(
+            stats.get(1, FrameType::SABM, Direction::Tx, FrameKind::Command, true),
+            2
+        );
+        assert_eq!(
+            stats.get(1, FrameType::UA, Direction::Rx, FrameKind::Response, true),
+            1
+        );
+        assert_eq!(
+            stats.get(1, FrameType::UA, Direction::Rx, FrameKind::Response, false),
+            1
+        );
+        assert_eq!(
+            stats.get(2, FrameType::DISC, Direction::Rx, FrameKind::Command, true),
+            1
+        );
+        assert_eq!(
+            stats.get(2, FrameType::UA, Direction::Tx, FrameKind::Response, true),
+            1
+        );
+        assert_eq!(
+            stats.get(2, FrameType::UA, Direction::Rx, FrameKind::Response, true),
+            0
+        );
+        assert_eq!(stats.unknown, 1);
+        assert!(stats.dump().contains("DLCI  1 UA   rx: cmd 0|0 resp 1|1"));
+    }
+
+    #[test]
+    fn drop_stats_by_reason() {
+        let mut drops = DropStats::default();
+        drops.record(None, DropReason::BadFrame, 7);
+        drops.record(Some(1), DropReason::Duplicate, 4);
+        drops.record(Some(1), DropReason::Duplicate, 4);
+        drops.record(Some(2), DropReason::PtyHangUp, 3);
+        drops.record(Some(2), DropReason::Reset, 0);
+
+        assert_eq!(drops.total(DropReason::BadFrame), 7);
+        assert_eq!(drops.get(1, DropReason::Duplicate), 8);
+        assert_eq!(drops.get(2, DropReason::Duplicate), 0);
+        assert_eq!(drops.total(DropReason::PtyHangUp), 3);
+        assert_eq!(
+            drops.dump(),
+            "Dropped bytes: bad_frame=7 duplicate=8 pty_hangup=3\n  DLCI  1: duplicate=8\n  DLCI  2: pty_hangup=3"
+        );
+    }
+}
@@ -0,0 +1,629 @@
+//! Multiplexer on tokio, behind the `tokio` feature.
+//!
+//! The [`AsyncMuxer`] drives the serial port from a future and hands out an
+//! [`AsyncChannel`] per DLCI instead of a pty. It shares the frame codec, the control
+//! channel messages and the errors with the [`Muxer`](crate::Muxer), but only covers
+//! the basics of a session: opening channels, moving their data, answering the
+//! modem's control commands and closing down. The modem must already be in
+//! multiplexer mode, `AT+CMUX` is left to the caller.
+
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyhow::{bail, Result};
+use log::{debug, info, warn};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time::timeout,
+};
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+use crate::{
+    buffer::{ReceiveBuffer, GSM0710_BUFFER_CAPACITY},
+    control::{handle_control, ControlMessage, ControlState},
+    error::GsmError,
+    mcc::MccMessage,
+    stats::{DropReason, DropStats},
+    types::{
+        Address, AddressImpl, ControlImpl, FlowControl, Frame, FrameBuilder, FrameType, Framing,
+    },
+    MuxerConfig,
+};
+
+/// Data written to a channel, tagged with its DLCI
+type Outgoing = (u8, Vec<u8>);
+
+/// What a frame received while waiting for a UA means for the wait
+enum Answer {
+    Accepted,
+    Rejected,
+}
+
+/// Multiplexer of a serial port driven by tokio
+///
+/// Open the control channel with [`AsyncMuxer::start`] and the data channels with
+/// [`AsyncMuxer::open_channel`], then spawn [`AsyncMuxer::run`] to move their data.
+pub struct AsyncMuxer<S = SerialStream> {
+    serial: S,
+    config: MuxerConfig,
+    buffer: ReceiveBuffer,
+    drops: DropStats,
+    /// Senders of the data received on each open data channel
+    channels: HashMap<u8, UnboundedSender<Vec<u8>>>,
+    /// Handed to the writer half of each channel
+    outgoing_tx: UnboundedSender<Outgoing>,
+    outgoing: UnboundedReceiver<Outgoing>,
+}
+
+impl AsyncMuxer<SerialStream> {
+    /// Open the serial port at the configured baud rate
+    pub fn open(path: &str, config: MuxerConfig) -> Result<Self> {
+        let serial = tokio_serial::new(path, config.baud).open_native_async()?;
+        Self::new(serial, config)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncMuxer<S> {
+    /// Multiplex an already opened serial port. Credit based flow control is not
+    /// supported.
+    pub fn new(serial: S, config: MuxerConfig) -> Result<Self> {
+        if config.flow_control == FlowControl::CreditBased {
+            bail!(GsmError::InvalidConfig(
+                "credit based flow control needs the blocking Muxer".into()
+            ));
+        }
+        let (outgoing_tx, outgoing) = unbounded_channel();
+        Ok(AsyncMuxer {
+            serial,
+            config,
+            buffer: ReceiveBuffer::new(GSM0710_BUFFER_CAPACITY),
+            drops: DropStats::default(),
+            channels: HashMap::new(),
+            outgoing_tx,
+            outgoing,
+        })
+    }
+
+    /// Octets and frames dropped so far, by reason
+    pub fn drops(&self) -> &DropStats {
+        &self.drops
+    }
+
+    /// Framing and FCS algorithm of the frames on the serial port
+    fn framing(&self) -> Framing {
+        Framing {
+            fcs: self.config.fcs,
+            flow_control: self.config.flow_control,
+            max_length: self.config.frame_size,
+            ..self.config.mode.into()
+        }
+    }
+
+    /// Builder of a frame sent on a channel, following its C/R convention and N1
+    fn frame(&self, dlci: u8, frame_type: FrameType) -> FrameBuilder {
+        Frame::builder()
+            .dlci(dlci)
+            .frame_type(frame_type)
+            .cr(self.config.cr_convention(dlci).tx_cr(frame_type))
+            .max_frame_size(self.config.frame_size)
+    }
+
+    async fn send_frame(&mut self, frame: &Frame) -> Result<()> {
+        let data = frame.try_to_bytes_with(self.framing())?;
+        self.serial.write_all(&data).await?;
+        self.serial.flush().await?;
+        Ok(())
+    }
+
+    /// Read the serial port once, false at its end
+    async fn read_serial(&mut self) -> Result<bool> {
+        let mut data = [0u8; 1024];
+        let n = self.serial.read(&mut data).await?;
+        self.buffer.push(&data[..n], &mut self.drops);
+        Ok(n > 0)
+    }
+
+    /// Open the control channel (DLCI 0)
+    ///
+    /// Fails with [`GsmError::SabmTimeout`] without answer and
+    /// [`GsmError::ChannelRejected`] when the modem refuses it.
+    pub async fn start(&mut self) -> Result<()> {
+        self.establish(0).await
+    }
+
+    /// Open a data channel, once the control channel is
+    ///
+    /// Fails like [`AsyncMuxer::start`].
+    pub async fn open_channel(&mut self, dlci: u8) -> Result<AsyncChannel> {
+        if dlci == 0 || self.channels.contains_key(&dlci) {
+            bail!(GsmError::InvalidConfig(format!(
+                "DLCI {} cannot be opened",
+                dlci
+            )));
+        }
+        self.establish(dlci).await?;
+        let (data_tx, data_rx) = unbounded_channel();
+        self.channels.insert(dlci, data_tx);
+        Ok(AsyncChannel {
+            read: ChannelReader {
+                data: data_rx,
+                pending: Vec::new(),
+            },
+            write: ChannelWriter {
+                dlci,
+                outgoing: self.outgoing_tx.clone(),
+            },
+        })
+    }
+
+    /// Send SABM up to N2 times, waiting T1 for the UA, or T3 on the control channel
+    async fn establish(&mut self, dlci: u8) -> Result<()> {
+        let wait = match dlci {
+            0 => self.config.t3,
+            _ => self.config.t1,
+        };
+        for _ in 0..self.config.n2.max(1) {
+            let sabm = self.frame(dlci, FrameType::SABM).pf(true).build()?;
+            self.send_frame(&sabm).await?;
+            match timeout(wait, self.wait_answer(dlci)).await {
+                Ok(Ok(Answer::Accepted)) => {
+                    info!("DLCI {} opened", dlci);
+                    return Ok(());
+                }
+                Ok(Ok(Answer::Rejected)) => bail!(GsmError::ChannelRejected(dlci)),
+                Ok(Err(e)) => return Err(e),
+                Err(_) => debug!("No answer to SABM on DLCI {}", dlci),
+            }
+        }
+        bail!(GsmError::SabmTimeout(dlci))
+    }
+
+    /// Handle the modem's frames until it answers a SABM on the DLCI
+    async fn wait_answer(&mut self, dlci: u8) -> Result<Answer> {
+        loop {
+            while let Some(frame) = self.buffer.next_frame(self.framing(), &mut self.drops) {
+                let answered = frame.address.get_dlci() == dlci;
+                match frame.control.get_frame_type() {
+                    Ok(FrameType::UA) if answered => return Ok(Answer::Accepted),
+                    Ok(FrameType::DM) if answered => return Ok(Answer::Rejected),
+                    _ => {
+                        if self.handle_frame(frame).await? {
+                            bail!(GsmError::ClosedDown);
+                        }
+                    }
+                }
+            }
+            if !self.read_serial().await? {
+                bail!(io::Error::from(ErrorKind::UnexpectedEof));
+            }
+        }
+    }
+
+    /// Move the data of the channels until the modem closes down the multiplexer
+    /// or the serial port ends
+    pub async fn run(&mut self) -> Result<()> {
+        loop {
+            while let Some(frame) = self.buffer.next_frame(self.framing(), &mut self.drops) {
+                if self.handle_frame(frame).await? {
+                    return Ok(());
+                }
+            }
+            let mut data = [0u8; 1024];
+            tokio::select! {
+                read = self.serial.read(&mut data) => match read? {
+                    0 => {
+                        info!("Serial port closed");
+                        return Ok(());
+                    }
+                    n => self.buffer.push(&data[..n], &mut self.drops),
+                },
+                // Never ends, the muxer keeps a sender for new channels
+                Some((dlci, data)) = self.outgoing.recv() => self.send_data(dlci, &data).await?,
+            }
+        }
+    }
+
+    /// Close down the multiplexer, the modem's answer ends [`AsyncMuxer::run`]
+    pub async fn close(&mut self) -> Result<()> {
+        let cld = ControlMessage::Cld.try_to_bytes(true)?;
+        let frame = self.frame(0, FrameType::UIH).payload(cld).build()?;
+        self.send_frame(&frame).await
+    }
+
+    /// Send data written to a channel in frames of at most N1 octets
+    async fn send_data(&mut self, dlci: u8, data: &[u8]) -> Result<()> {
+        if !self.channels.contains_key(&dlci) {
+            self.drops
+                .record(Some(dlci), DropReason::SendFailed, data.len());
+            return Ok(());
+        }
+        let frame_type = self.config.data_frame_type();
+        for chunk in data.chunks(self.config.frame_size.max(1) as usize) {
+            let frame = self
+                .frame(dlci, frame_type)
+                .payload(chunk.to_vec())
+                .build()?;
+            self.send_frame(&frame).await?;
+        }
+        Ok(())
+    }
+
+    /// Handle a frame of the modem, true once it closed down the multiplexer
+    async fn handle_frame(&mut self, frame: Frame) -> Result<bool> {
+        let dlci = frame.address.get_dlci();
+        let ft = match frame.control.get_frame_type() {
+            Ok(ft) => ft,
+            Err(e) => {
+                warn!("Error parsing frame type: {}", e);
+                return Ok(false);
+            }
+        };
+        if !self
+            .config
+            .cr_convention(dlci)
+            .accepts(&frame.as_frame_ref())
+        {
+            warn!(
+                "Dropping {:?} frame with unexpected C/R bit on DLCI {}",
+                ft, dlci
+            );
+            self.drops
+                .record(Some(dlci), DropReason::CrMismatch, frame.content.len());
+            return Ok(false);
+        }
+        match ft {
+            FrameType::UIH | FrameType::UI if dlci == 0 => {
+                return self.handle_control(&frame.content).await
+            }
+            FrameType::UIH | FrameType::UI => self.handle_data(dlci, frame.content),
+            FrameType::SABM => {
+                let answer = match dlci == 0 || self.channels.contains_key(&dlci) {
+                    true => FrameType::UA,
+                    false => {
+                        warn!("Refusing SABM on DLCI {}", dlci);
+                        FrameType::DM
+                    }
+                };
+                let pf = frame.control.get_pf();
+                let frame = self.frame(dlci, answer).pf(pf).build()?;
+                self.send_frame(&frame).await?;
+            }
+            FrameType::DISC => {
+                let pf = frame.control.get_pf();
+                let ua = self.frame(dlci, FrameType::UA).pf(pf).build()?;
+                self.send_frame(&ua).await?;
+                if dlci == 0 {
+                    info!("Modem closed the control channel");
+                    return Ok(true);
+                }
+                // Ends the channel's reader
+                self.channels.remove(&dlci);
+                info!("Modem closed DLCI {}", dlci);
+            }
+            FrameType::UA | FrameType::DM => debug!("Ignoring {:?} on DLCI {}", ft, dlci),
+        }
+        Ok(false)
+    }
+
+    fn handle_data(&mut self, dlci: u8, data: Vec<u8>) {
+        let len = data.len();
+        let Some(channel) = self.channels.get(&dlci) else {
+            self.drops
+                .record(Some(dlci), DropReason::UnknownChannel, len);
+            return;
+        };
+        if channel.send(data).is_err() {
+            debug!("Reader of DLCI {} dropped", dlci);
+            self.channels.remove(&dlci);
+            self.drops.record(Some(dlci), DropReason::PtyHangUp, len);
+        }
+    }
+
+    /// Answer a control command, true once the modem closed down the multiplexer
+    async fn handle_control(&mut self, content: &[u8]) -> Result<bool> {
+        let msg = match MccMessage::parse(content) {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!("Ignoring control message {:02X?}: {}", content, e);
+                return Ok(false);
+            }
+        };
+        if !msg.cr {
+            debug!("Received control response: {:?}", msg.msg_type);
+            // The answer to our CLD
+            return Ok(ControlMessage::from_mcc(&msg).is_ok_and(|m| m == ControlMessage::Cld));
+        }
+        debug!("Received control command: {:?}", msg.msg_type);
+        let state = ControlState {
+            address: Address::new_address(self.frame_cr(), true, 0),
+            frame_size: self.config.frame_size,
+        };
+        let (response, changes) = handle_control(&msg, &state)?;
+        if let Some(frame) = response {
+            self.send_frame(&frame).await?;
+        }
+        if changes.close_down {
+            info!("Modem closed down the multiplexer");
+        }
+        Ok(changes.close_down)
+    }
+
+    /// C/R bit of the UIH frames sent on the control channel
+    fn frame_cr(&self) -> bool {
+        self.config.cr_convention(0).tx_cr(FrameType::UIH)
+    }
+}
+
+/// Channel of an [`AsyncMuxer`], split in its two halves
+///
+/// Dropping the reader drops the data received later on the channel, the reader
+/// ends once the modem closed the channel.
+pub struct AsyncChannel {
+    pub read: ChannelReader,
+    pub write: ChannelWriter,
+}
+
+/// Data received on a channel
+pub struct ChannelReader {
+    data: UnboundedReceiver<Vec<u8>>,
+    /// Rest of a payload that did not fit in the last read
+    pending: Vec<u8>,
+}
+
+impl AsyncRead for ChannelReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.pending.is_empty() {
+            match self.data.poll_recv(cx) {
+                Poll::Ready(Some(data)) => self.pending = data,
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let n = self.pending.len().min(buf.remaining());
+        buf.put_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Data sent on a channel, queued until the muxer runs
+pub struct ChannelWriter {
+    dlci: u8,
+    outgoing: UnboundedSender<Outgoing>,
+}
+
+impl AsyncWrite for ChannelWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let sent = self.outgoing.send((self.dlci, buf.to_vec()));
+        Poll::Ready(match sent {
+            Ok(()) => Ok(buf.len()),
+            Err(_) => Err(ErrorKind::BrokenPipe.into()),
+        })
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+    use crate::mcc::{MccType, TestMessage};
+
+    /// Far end of the serial port, answering as the modem
+    struct Modem {
+        port: DuplexStream,
+        buffer: ReceiveBuffer,
+        drops: DropStats,
+    }
+
+    impl Modem {
+        async fn send(&mut self, dlci: u8, frame_type: FrameType, content: &[u8]) {
+            let frame = Frame::builder()
+                .dlci(dlci)
+                .frame_type(frame_type)
+                .initiator(false)
+                .command(frame_type.is_command())
+                .pf(frame_type != FrameType::UIH)
+                .payload(content.to_vec())
+                .build()
+                .unwrap();
+            let data = frame.try_to_bytes().unwrap();
+            self.port.write_all(&data).await.unwrap();
+        }
+
+        async fn expect(&mut self) -> Frame {
+            loop {
+                if let Some(frame) = self.buffer.next_frame(Framing::default(), &mut self.drops) {
+                    return frame;
+                }
+                let mut data = [0u8; 256];
+                let n = self.port.read(&mut data).await.unwrap();
+                assert!(n > 0, "mux closed the serial port");
+                self.buffer.push(&data[..n], &mut self.drops);
+            }
+        }
+
+        /// Answer the SABM of a channel with UA
+        async fn accept(&mut self, dlci: u8) {
+            let sabm = self.expect().await;
+            assert_eq!(sabm.control.get_frame_type().unwrap(), FrameType::SABM);
+            assert_eq!(sabm.address.get_dlci(), dlci);
+            self.send(dlci, FrameType::UA, &[]).await;
+        }
+    }
+
+    fn pair(config: MuxerConfig) -> (AsyncMuxer<DuplexStream>, Modem) {
+        let (ours, theirs) = duplex(4096);
+        let modem = Modem {
+            port: theirs,
+            buffer: ReceiveBuffer::new(GSM0710_BUFFER_CAPACITY),
+            drops: DropStats::default(),
+        };
+        (AsyncMuxer::new(ours, config).unwrap(), modem)
+    }
+
+    #[tokio::test]
+    async fn channels_carry_data_both_ways() {
+        let (mut mux, mut modem) = pair(MuxerConfig::default());
+        let modem = tokio::spawn(async move {
+            modem.accept(0).await;
+            modem.accept(1).await;
+            let data = modem.expect().await;
+            assert_eq!(data.address.get_dlci(), 1);
+            assert_eq!(data.content, b"AT\r");
+            modem.send(1, FrameType::UIH, b"OK\r\n").await;
+            modem
+        });
+        mux.start().await.unwrap();
+        let mut channel = mux.open_channel(1).await.unwrap();
+        channel.write.write_all(b"AT\r").await.unwrap();
+        let run = tokio::spawn(async move { mux.run().await });
+        let mut answer = [0u8; 4];
+        channel.read.read_exact(&mut answer).await.unwrap();
+        assert_eq!(&answer, b"OK\r\n");
+        modem.await.unwrap();
+        run.abort();
+    }
+
+    #[tokio::test]
+    async fn writes_are_split_at_the_frame_size() {
+        let config = MuxerConfig {
+            frame_size: 4,
+            ..Default::default()
+        };
+        let (mut mux, mut modem) = pair(config);
+        let modem = tokio::spawn(async move {
+            modem.accept(0).await;
+            modem.accept(2).await;
+            let mut contents = Vec::new();
+            for _ in 0..3 {
+                contents.push(modem.expect().await.content);
+            }
+            contents
+        });
+        mux.start().await.unwrap();
+        let mut channel = mux.open_channel(2).await.unwrap();
+        channel.write.write_all(b"0123456789").await.unwrap();
+        let run = tokio::spawn(async move { mux.run().await });
+        assert_eq!(
+            modem.await.unwrap(),
+            vec![b"0123".to_vec(), b"4567".to_vec(), b"89".to_vec()]
+        );
+        run.abort();
+    }
+
+    #[tokio::test]
+    async fn rejected_channels_fail_to_open() {
+        let (mut mux, mut modem) = pair(MuxerConfig::default());
+        tokio::spawn(async move {
+            modem.accept(0).await;
+            modem.expect().await;
+            modem.send(3, FrameType::DM, &[]).await;
+            modem
+        });
+        mux.start().await.unwrap();
+        let e = mux.open_channel(3).await.err().unwrap();
+        assert!(matches!(
+            e.downcast_ref::<GsmError>(),
+            Some(GsmError::ChannelRejected(3))
+        ));
+    }
+
+    #[tokio::test]
+    async fn unanswered_sabm_times_out() {
+        let config = MuxerConfig {
+            t3: Duration::from_millis(10),
+            n2: 2,
+            ..Default::default()
+        };
+        let (mut mux, mut modem) = pair(config);
+        let e = mux.start().await.err().unwrap();
+        assert!(matches!(
+            e.downcast_ref::<GsmError>(),
+            Some(GsmError::SabmTimeout(0))
+        ));
+        for _ in 0..2 {
+            let sabm = modem.expect().await;
+            assert_eq!(sabm.control.get_frame_type().unwrap(), FrameType::SABM);
+        }
+    }
+
+    #[tokio::test]
+    async fn closed_channels_end_their_reader() {
+        let (mut mux, mut modem) = pair(MuxerConfig::default());
+        let modem = tokio::spawn(async move {
+            modem.accept(0).await;
+            modem.accept(1).await;
+            modem.send(1, FrameType::DISC, &[]).await;
+            let ua = modem.expect().await;
+            assert_eq!(ua.control.get_frame_type().unwrap(), FrameType::UA);
+            assert_eq!(ua.address.get_dlci(), 1);
+            modem
+        });
+        mux.start().await.unwrap();
+        let mut channel = mux.open_channel(1).await.unwrap();
+        let run = tokio::spawn(async move { mux.run().await });
+        let mut data = Vec::new();
+        channel.read.read_to_end(&mut data).await.unwrap();
+        assert!(data.is_empty());
+        modem.await.unwrap();
+        run.abort();
+    }
+
+    #[tokio::test]
+    async fn run_answers_test_and_ends_at_close_down() {
+        let (mut mux, mut modem) = pair(MuxerConfig::default());
+        let modem = tokio::spawn(async move {
+            modem.accept(0).await;
+            let test = ControlMessage::Test(TestMessage {
+                payload: b"ping".to_vec(),
+            });
+            modem
+                .send(0, FrameType::UIH, &test.try_to_bytes(true).unwrap())
+                .await;
+            let echo = MccMessage::parse(&modem.expect().await.content).unwrap();
+            assert_eq!((echo.msg_type, echo.cr), (MccType::Test, false));
+            assert_eq!(echo.data, b"ping");
+            let cld = ControlMessage::Cld.try_to_bytes(true).unwrap();
+            modem.send(0, FrameType::UIH, &cld).await;
+            modem.expect().await
+        });
+        mux.start().await.unwrap();
+        mux.run().await.unwrap();
+        let answer = MccMessage::parse(&modem.await.unwrap().content).unwrap();
+        assert_eq!((answer.msg_type, answer.cr), (MccType::Cld, false));
+    }
+
+    #[tokio::test]
+    async fn credit_based_flow_control_is_refused() {
+        let config = MuxerConfig {
+            flow_control: FlowControl::CreditBased,
+            ..Default::default()
+        };
+        let (ours, _theirs) = duplex(64);
+        assert!(AsyncMuxer::new(ours, config).is_err());
+    }
+}
@@ -1,8 +1,47 @@
-use anyhow::Result;
+use alloc::{format, vec, vec::Vec};
+use core::fmt::Display;
+
+#[cfg(feature = "std")]
+use clap::ValueEnum;
 use crc::Crc;
 
 use crate::error::GsmError;
 
+/// Reason a frame could not be parsed
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ParseError {
+    /// The stream ended before the end of the frame, more octets are needed
+    Incomplete,
+    BadFcs {
+        expected: u8,
+        got: u8,
+    },
+    /// The closing flag is missing or came too early, with the octet found in its place
+    BadFlag(u8),
+    /// Information field longer than [`Framing::max_length`]
+    UnsupportedLength,
+    UnknownControl(u8),
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ParseError::Incomplete => write!(f, "incomplete frame"),
+            ParseError::BadFcs { expected, got } => {
+                write!(f, "bad FCS: expected {:#04X}, got {:#04X}", expected, got)
+            }
+            ParseError::BadFlag(flag) => write!(f, "bad closing flag {:#04X}", flag),
+            ParseError::UnsupportedLength => write!(f, "unsupported length"),
+            ParseError::UnknownControl(control) => {
+                write!(f, "unknown control field {:#04X}", control)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseError {}
+
 /// [Control] Field of [`Frame`]
 ///
 /// The Control field is a 8-bit field, structured as follows:
@@ -59,14 +98,300 @@ pub type Control = u8;
 pub type Address = u8;
 
 pub const FLAG: u8 = 0xF9;
-pub const C_CLD: u8 = 193;
+/// Flag of advanced option frames
+pub const ADVANCED_FLAG: u8 = 0x7E;
+/// Control escape octet of advanced option frames
+pub const CONTROL_ESCAPE: u8 = 0x7D;
+/// Octets escaped in advanced option frames: the flag, the escape and XON/XOFF
+pub const ESCAPED_OCTETS: [u8; 6] = [ADVANCED_FLAG, CONTROL_ESCAPE, 0x11, 0x13, 0x91, 0x93];
+/// Largest length that fits in a one octet Length Indicator
+pub const MAX_SHORT_LENGTH: u16 = 0x7F;
+/// Largest length a two octet Length Indicator can carry
+pub const MAX_LENGTH: u16 = 0x7FFF;
+/// Largest DLCI, the address field carries 6 bits of it
+pub const MAX_DLCI: u8 = 63;
 
 pub const PF: u8 = 1 << 4;
 pub const CR: u8 = 1 << 1;
 pub const EA: u8 = 1 << 0;
 
+/// Multiplexer operating mode, the `<mode>` parameter of `AT+CMUX`
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[cfg_attr(feature = "std", derive(ValueEnum))]
+pub enum MuxMode {
+    /// Basic option: 0xF9 flags and a Length Indicator
+    #[default]
+    Basic,
+    /// Advanced option: 0x7E flags and transparency, no Length Indicator
+    Advanced,
+    /// Advanced option with error recovery. Not supported yet, the mux has no I frame
+    /// acknowledgement or retransmission, [`MuxerConfig::validate`] rejects it
+    ///
+    /// [`MuxerConfig::validate`]: crate::MuxerConfig::validate
+    ErrorRecovery,
+}
+
+impl MuxMode {
+    /// Value of the `<mode>` parameter of `AT+CMUX`, error recovery being a subset
+    /// of the advanced option
+    pub fn cmux_mode(&self) -> u8 {
+        match self {
+            MuxMode::Basic => 0,
+            MuxMode::Advanced | MuxMode::ErrorRecovery => 1,
+        }
+    }
+
+    /// Value of the `<subset>` parameter of `AT+CMUX`, 2 selects the error recovery
+    /// in I frames whatever the configured subset
+    pub fn cmux_subset(&self, subset: u8) -> u8 {
+        match self {
+            MuxMode::ErrorRecovery => 2,
+            _ => subset,
+        }
+    }
+
+    /// Whether frames carry a Length Indicator covered by the FCS
+    pub fn has_length_indicator(&self) -> bool {
+        *self == MuxMode::Basic
+    }
+}
+
+impl Display for MuxMode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MuxMode::Basic => write!(f, "basic"),
+            MuxMode::Advanced => write!(f, "advanced"),
+            MuxMode::ErrorRecovery => write!(f, "error-recovery"),
+        }
+    }
+}
+
+/// Framing of frames on the wire
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum TransparencyMode {
+    /// Basic option: 0xF9 flags and a Length Indicator, no transparency
+    #[default]
+    Basic,
+    /// Advanced option: 0x7E flags and control octet transparency, the
+    /// octets in [`ESCAPED_OCTETS`] are sent as 0x7D followed by the octet XOR 0x20
+    Advanced,
+}
+
+impl TransparencyMode {
+    /// Flag delimiting the frames
+    pub fn flag(&self) -> u8 {
+        match self {
+            TransparencyMode::Basic => FLAG,
+            TransparencyMode::Advanced => ADVANCED_FLAG,
+        }
+    }
+}
+
+impl From<MuxMode> for TransparencyMode {
+    fn from(mode: MuxMode) -> Self {
+        match mode {
+            MuxMode::Basic => TransparencyMode::Basic,
+            MuxMode::Advanced | MuxMode::ErrorRecovery => TransparencyMode::Advanced,
+        }
+    }
+}
+
+/// CRC-8-ROHC of the FCS, its table is built at compile time
+static FCS_CRC: Crc<u8> = Crc::<u8>::new(&crc::CRC_8_ROHC);
+
+/// Algorithm computing the Frame Check Sequence, some modems deviate from the standard one
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[cfg_attr(feature = "std", derive(ValueEnum))]
+pub enum FcsAlgorithm {
+    /// CRC-8-ROHC over the header, and the content of UI frames, inverted
+    #[default]
+    Standard,
+    /// [`FcsAlgorithm::Standard`] without the final inversion
+    NotInverted,
+    /// [`FcsAlgorithm::Standard`] covering the content of UIH frames as well
+    FullFrame,
+}
+
+impl FcsAlgorithm {
+    /// Every algorithm, in order of preference when several fit
+    pub const ALL: [FcsAlgorithm; 3] = [
+        FcsAlgorithm::Standard,
+        FcsAlgorithm::NotInverted,
+        FcsAlgorithm::FullFrame,
+    ];
+
+    /// Calculate the FCS of a frame in the given mode
+    pub fn compute(&self, frame: &FrameRef, mode: MuxMode) -> Result<u8, GsmError> {
+        let mut digest = FCS_CRC.digest();
+        digest.update(&[frame.address, frame.control]);
+        if mode.has_length_indicator() {
+            let (octets, n) = length_indicator(frame.length);
+            digest.update(&octets[..n]);
+        }
+        if frame.control.get_frame_type()? == FrameType::UI || *self == FcsAlgorithm::FullFrame {
+            digest.update(frame.content);
+        }
+        match self {
+            FcsAlgorithm::NotInverted => Ok(digest.finalize()),
+            // CRC-8-ROHC checksum is reversed here
+            _ => Ok(!digest.finalize()),
+        }
+    }
+
+    /// Find the algorithm under which the most frames of a byte stream validate.
+    ///
+    /// Returns `None` if no frame validates under any of them.
+    pub fn detect(stream: &[u8], transparency: TransparencyMode) -> Option<FcsAlgorithm> {
+        Self::candidates(stream, transparency).first().copied()
+    }
+
+    /// Every algorithm under which the most frames of a byte stream validate, in order
+    /// of preference.
+    ///
+    /// Several remain when the frames can't tell them apart, as empty frames under
+    /// [`FcsAlgorithm::Standard`] and [`FcsAlgorithm::FullFrame`]. None remains if no
+    /// frame validates under any of them.
+    pub fn candidates(stream: &[u8], transparency: TransparencyMode) -> Vec<FcsAlgorithm> {
+        let counts = FcsAlgorithm::ALL.map(|fcs| {
+            let framing = Framing {
+                transparency,
+                fcs,
+                ..Default::default()
+            };
+            let mut valid = 0;
+            let mut pos = 0;
+            while pos < stream.len() {
+                match Frame::parse_with(&mut stream[pos..].iter().copied(), framing) {
+                    Ok((_, len)) => {
+                        valid += 1;
+                        pos += len;
+                    }
+                    Err(ParseError::Incomplete) => break,
+                    // Resync on the next flag
+                    Err(_) => {
+                        pos += 1;
+                        pos += stream[pos..]
+                            .iter()
+                            .position(|&b| b == transparency.flag())
+                            .unwrap_or(stream.len() - pos);
+                    }
+                }
+            }
+            (fcs, valid)
+        });
+        let most = counts.iter().map(|&(_, valid)| valid).max().unwrap_or(0);
+        counts
+            .into_iter()
+            .filter(|&(_, valid)| valid > 0 && valid == most)
+            .map(|(fcs, _)| fcs)
+            .collect()
+    }
+}
+
+/// Flow control of the data channels
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[cfg_attr(feature = "std", derive(ValueEnum))]
+pub enum FlowControl {
+    /// FCon/FCoff and the FC bit of MSC on the control channel
+    #[default]
+    Msc,
+    /// Credits in UIH frames with the P/F bit set, one UIH frame costs one credit (5.5.3.2)
+    CreditBased,
+}
+
+/// How frames are laid out on the wire: their transparency, FCS algorithm and flow control
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Framing {
+    pub transparency: TransparencyMode,
+    pub fcs: FcsAlgorithm,
+    pub flow_control: FlowControl,
+    /// Longest information field accepted when parsing, [`MAX_LENGTH`] by default
+    pub max_length: u16,
+}
+
+impl Default for Framing {
+    fn default() -> Self {
+        Framing {
+            transparency: TransparencyMode::default(),
+            fcs: FcsAlgorithm::default(),
+            flow_control: FlowControl::default(),
+            max_length: MAX_LENGTH,
+        }
+    }
+}
+
+impl Framing {
+    /// Mode deciding which fields the FCS covers
+    fn fcs_mode(&self) -> MuxMode {
+        match self.transparency {
+            TransparencyMode::Basic => MuxMode::Basic,
+            TransparencyMode::Advanced => MuxMode::Advanced,
+        }
+    }
+
+    /// Whether a frame carries a credit octet, only UIH frames of data channels with
+    /// P/F set do
+    fn has_credit(&self, address: Address, control: Control) -> bool {
+        self.flow_control == FlowControl::CreditBased
+            && address.get_dlci() != 0
+            && control.get_pf()
+            && matches!(control.get_frame_type(), Ok(FrameType::UIH))
+    }
+}
+
+impl From<TransparencyMode> for Framing {
+    fn from(transparency: TransparencyMode) -> Self {
+        Framing {
+            transparency,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<MuxMode> for Framing {
+    fn from(mode: MuxMode) -> Self {
+        TransparencyMode::from(mode).into()
+    }
+}
+
+/// How frames are serialized: their [`Framing`], and whether consecutive frames
+/// share the flag between them
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct FrameCodec {
+    pub framing: Framing,
+    /// The closing flag of a frame also opens the next one, as 07.10 allows.
+    ///
+    /// Only supported with advanced option framing: the basic option parser
+    /// consumes the closing flag of each frame.
+    pub share_flags: bool,
+}
+
+impl From<Framing> for FrameCodec {
+    fn from(framing: Framing) -> Self {
+        FrameCodec {
+            framing,
+            share_flags: false,
+        }
+    }
+}
+
+/// Serialize frames one after the other into a single buffer
+pub fn serialize_batch(frames: &[Frame], codec: FrameCodec) -> Result<Vec<u8>, GsmError> {
+    if codec.share_flags && codec.framing.transparency == TransparencyMode::Basic {
+        return Err(GsmError::InvalidConfig(
+            "shared flags need advanced option framing".into(),
+        ));
+    }
+    let size = frames.iter().map(|frame| frame.content.len() + 6).sum();
+    let mut data = Vec::with_capacity(size);
+    for (i, frame) in frames.iter().enumerate() {
+        frame.encode_into(codec.framing, i == 0 || !codec.share_flags, &mut data)?;
+    }
+    Ok(data)
+}
+
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum FrameType {
     SABM,
     UA,
@@ -76,9 +401,21 @@ pub enum FrameType {
     UI,
 }
 
+impl FrameType {
+    /// Whether frames of this type are commands, UA and DM are responses
+    pub fn is_command(&self) -> bool {
+        !matches!(self, FrameType::UA | FrameType::DM)
+    }
+
+    /// C/R bit of a frame of this type sent by the initiator or the responder, see [`Address`]
+    pub fn cr(&self, is_initiator: bool) -> bool {
+        self.is_command() == is_initiator
+    }
+}
+
 #[allow(dead_code)]
 pub trait ControlImpl {
-    fn get_frame_type(&self) -> Result<FrameType>;
+    fn get_frame_type(&self) -> Result<FrameType, GsmError>;
     fn set_frame_type(&mut self, frame: FrameType);
     fn with_frame_type(&self, frame: FrameType) -> Self;
     fn get_pf(&self) -> bool;
@@ -88,7 +425,7 @@ pub trait ControlImpl {
 }
 
 impl ControlImpl for Control {
-    fn get_frame_type(&self) -> Result<FrameType> {
+    fn get_frame_type(&self) -> Result<FrameType, GsmError> {
         let address = self & !PF;
         match address {
             0x2F => Ok(FrameType::SABM),
@@ -97,7 +434,7 @@ impl ControlImpl for Control {
             0x43 => Ok(FrameType::DISC),
             0xEF => Ok(FrameType::UIH),
             0x03 => Ok(FrameType::UI),
-            _ => Err(GsmError::UnsupportedFrameType(format!("{:02X?}", address)).into()),
+            _ => Err(GsmError::UnsupportedFrameType(format!("{:02X?}", address))),
         }
     }
     fn set_frame_type(&mut self, frame: FrameType) {
@@ -230,104 +567,616 @@ pub struct Frame {
     /// Length of the frame content. Calc by `(len & 254) >> 1`
     pub length: u16,
     pub content: Vec<u8>,
+    /// Credits granted to the receiver in credit based flow control, sent after the
+    /// Length Indicator and not counted in it
+    pub credit: Option<u8>,
 }
 
 impl Frame {
-    /// Create a new frame
-    pub fn new(address: Address, control: Control, length: u16, content: Vec<u8>) -> Self {
+    /// Create a new frame, its length is the length of `content`
+    pub fn new(address: Address, control: Control, content: Vec<u8>) -> Self {
         Frame {
             address,
             control,
-            length,
+            length: content.len() as u16,
             content,
+            credit: None,
+        }
+    }
+
+    /// Override the length of the frame, even if the content disagrees
+    ///
+    /// Only meant to build malformed frames, for testing.
+    pub fn with_length(mut self, length: u16) -> Self {
+        self.length = length;
+        self
+    }
+
+    /// Create a new frame, failing if `length` is not the length of `content`
+    pub fn try_new(
+        address: Address,
+        control: Control,
+        length: u16,
+        content: Vec<u8>,
+    ) -> Result<Self, GsmError> {
+        let frame = Self::new(address, control, content).with_length(length);
+        frame.check_length()?;
+        Ok(frame)
+    }
+
+    /// Fail if the length disagrees with the content, see [`Frame::with_length`]
+    fn check_length(&self) -> Result<(), GsmError> {
+        match self.length as usize == self.content.len() {
+            true => Ok(()),
+            false => Err(GsmError::ParseFrameError(format!(
+                "length {} of a frame with {} octets of content",
+                self.length,
+                self.content.len()
+            ))),
         }
     }
 
+    /// Grant credits with the frame, setting its P/F bit
+    pub fn with_credit(mut self, credit: u8) -> Self {
+        self.control.set_pf(true);
+        self.credit = Some(credit);
+        self
+    }
+
+    /// Encode the Length Indicator.
+    ///
+    /// Lengths up to 127 fit in one octet with the EA bit set. Longer lengths use
+    /// two octets: the first carries the low 7 bits with EA cleared, the second the high 8 bits.
     pub fn length_bytes(&self) -> Vec<u8> {
-        let length = self.length * 2 + 1;
-        if length > u8::MAX as u16 {
-            length.to_be_bytes().to_vec()
-        } else {
-            vec![length as u8]
+        let (octets, n) = length_indicator(self.length);
+        octets[..n].to_vec()
+    }
+
+    /// Whether the frame is on the control channel, DLCI 0
+    pub fn is_control_channel(&self) -> bool {
+        self.address.get_dlci() == 0
+    }
+
+    /// View of the frame borrowing its content
+    pub fn as_frame_ref(&self) -> FrameRef<'_> {
+        FrameRef {
+            address: self.address,
+            control: self.control,
+            length: self.length,
+            content: &self.content,
+            credit: self.credit,
         }
     }
 
-    /// Calculate the Frame Check Sequence (FCS) of the frame
-    pub fn try_fcs(&self) -> Result<u8> {
-        let crc = Crc::<u8>::new(&crc::CRC_8_ROHC);
-        let mut data = vec![self.address, self.control];
-        data.extend_from_slice(&self.length_bytes());
-        match self.control.get_frame_type() {
-            Ok(FrameType::UI) => data.extend_from_slice(&self.content),
-            Ok(_) => {}
-            Err(e) => return Err(e),
+    /// Calculate the Frame Check Sequence (FCS) of a basic option frame
+    pub fn try_fcs(&self) -> Result<u8, GsmError> {
+        self.try_fcs_with_mode(MuxMode::Basic)
+    }
+
+    /// Calculate the Frame Check Sequence (FCS) of the frame in the given mode
+    ///
+    /// The Length Indicator is only covered in basic mode, advanced mode frames don't carry one.
+    pub fn try_fcs_with_mode(&self, mode: MuxMode) -> Result<u8, GsmError> {
+        self.as_frame_ref().try_fcs_with_mode(mode)
+    }
+
+    /// Parse a frame from a byte stream.
+    ///
+    /// Returns the frame and the number of octets it spans, from the first octet of
+    /// the stream. [`ParseError::Incomplete`] means the stream ended mid-frame.
+    pub fn parse<T: Iterator<Item = u8>>(iter: &mut T) -> Result<(Self, usize), ParseError> {
+        Self::parse_with(iter, Framing::default())
+    }
+
+    /// Parse a basic option frame with [`FrameRef::parse_with`], collecting its
+    /// octets from the stream first
+    fn parse_basic<T: Iterator<Item = u8>>(
+        iter: &mut T,
+        framing: Framing,
+    ) -> Result<(Self, usize), ParseError> {
+        // Skip the garbage before the first flag
+        let skipped = iter
+            .by_ref()
+            .position(|byte| byte == FLAG)
+            .ok_or(ParseError::Incomplete)?;
+        let mut octets = vec![FLAG];
+        let header = loop {
+            match BasicHeader::parse(&octets, framing) {
+                Err(ParseError::Incomplete) => {
+                    octets.push(iter.next().ok_or(ParseError::Incomplete)?)
+                }
+                header => break header?,
+            }
+        };
+        // Need more data if a slice iterator can't hold the content, FCS and flag
+        let missing = header.frame_end() - octets.len();
+        if iter
+            .size_hint()
+            .1
+            .is_some_and(|remaining| remaining < missing)
+        {
+            return Err(ParseError::Incomplete);
+        }
+        for _ in 0..missing {
+            octets.push(iter.next().ok_or(ParseError::Incomplete)?);
+        }
+        let (frame, len) = FrameRef::parse_with(&octets, framing)?;
+        Ok((frame.to_frame(), skipped + len))
+    }
+
+    /// [`Frame::parse`], without the failure reason
+    pub fn parse_opt<T: Iterator<Item = u8>>(iter: &mut T) -> Option<(Self, usize)> {
+        Self::parse(iter).ok()
+    }
+
+    /// Compare a received FCS with the one computed over the frame
+    fn check_fcs(&self, got: u8, mode: MuxMode, algorithm: FcsAlgorithm) -> Result<(), ParseError> {
+        let expected = algorithm
+            .compute(&self.as_frame_ref(), mode)
+            .map_err(|_| ParseError::UnknownControl(self.control))?;
+        match expected == got {
+            true => Ok(()),
+            false => Err(ParseError::BadFcs { expected, got }),
+        }
+    }
+
+    /// Parse a frame from a byte stream with the given framing
+    ///
+    /// For advanced option frames the closing flag is not counted in the returned
+    /// length, as it may also open the next frame.
+    pub fn parse_with<T: Iterator<Item = u8>>(
+        iter: &mut T,
+        framing: impl Into<Framing>,
+    ) -> Result<(Self, usize), ParseError> {
+        let framing = framing.into();
+        let parsed = match framing.transparency {
+            TransparencyMode::Basic => Self::parse_basic(iter, framing),
+            TransparencyMode::Advanced => Self::parse_advanced(iter, framing),
+        };
+        if let Ok((frame, _)) = &parsed {
+            debug_assert!(frame.check_length().is_ok(), "parsed {:?}", frame);
         }
-        // CRC-8-ROHC checksum is reversed here
-        Ok(!crc.checksum(&data))
+        parsed
     }
 
-    /// Parse a frame from a byte stream
-    pub fn parse<T: Iterator<Item = u8>>(iter: &mut T) -> Option<(Self, usize)> {
-        // 1 byte for address, 1 byte for control, 1 byte for length, 1 byte for FCS, 1 byte for flag
-        let mut len = 5;
+    fn parse_advanced<T: Iterator<Item = u8>>(
+        iter: &mut T,
+        framing: Framing,
+    ) -> Result<(Self, usize), ParseError> {
+        let mut len = 0;
         // Find the first flag
         for byte in iter.by_ref() {
             len += 1;
-            if byte == FLAG {
+            if byte == ADVANCED_FLAG {
                 break;
             }
         }
-        // Parse the address field
-        let address = iter.next()?;
-        // Parse the control field
-        let control = iter.next()?;
-        // Parse the length field
-        let mut length = iter.next()?;
-        if length & 1 == 0 {
-            unimplemented!("Length field is 2 octets long");
-        }
-        length = (length & 254) >> 1;
-        // Parse the information field
-        let mut content = Vec::new();
-        for _ in 0..length {
-            content.push(iter.next()?);
-        }
-        // Parse the FCS field
-        let fcs = iter.next()?;
-        // Parse the last flag
-        let flag = iter.next()?;
-        if flag != FLAG {
-            return None;
+        // Collect the unstuffed octets up to the closing flag
+        let mut data = Vec::new();
+        loop {
+            let byte = iter.next().ok_or(ParseError::Incomplete)?;
+            if byte == ADVANCED_FLAG {
+                if data.is_empty() {
+                    // Back-to-back flags, this one opens the frame
+                    len += 1;
+                    continue;
+                }
+                break;
+            }
+            len += 1;
+            if byte == CONTROL_ESCAPE {
+                len += 1;
+                data.push(iter.next().ok_or(ParseError::Incomplete)? ^ 0x20);
+            } else {
+                data.push(byte);
+            }
+        }
+        // Address, control and FCS fields are mandatory, the closing flag came too early
+        if data.len() < 3 {
+            return Err(ParseError::BadFlag(ADVANCED_FLAG));
+        }
+        let fcs = data.pop().ok_or(ParseError::BadFlag(ADVANCED_FLAG))?;
+        let credit = match framing.has_credit(data[0], data[1]) {
+            true if data.len() < 3 => return Err(ParseError::BadFlag(ADVANCED_FLAG)),
+            true => Some(data[2]),
+            false => None,
+        };
+        let content = data.split_off(2 + credit.is_some() as usize);
+        if content.len() > framing.max_length as usize {
+            return Err(ParseError::UnsupportedLength);
         }
-        len += length as usize;
         let frame = Frame {
-            address,
-            control,
-            length: length as u16,
+            address: data[0],
+            control: data[1],
+            length: content.len() as u16,
             content,
+            credit,
         };
+        frame.check_fcs(fcs, MuxMode::Advanced, framing.fcs)?;
+        Ok((frame, len))
+    }
 
-        // validate the frame
-        let fcs_calc = frame.try_fcs().ok()?;
-        if fcs != fcs_calc {
-            return None;
+    /// Serialize the frame with the given framing
+    pub fn try_to_bytes_with(&self, framing: impl Into<Framing>) -> Result<Vec<u8>, GsmError> {
+        let mut data = Vec::with_capacity(self.content.len() + 6);
+        self.encode_into(framing.into(), true, &mut data)?;
+        Ok(data)
+    }
+
+    /// Append the serialized frame to `data`, starting with the closing flag of the
+    /// previous frame unless `opening_flag` is set
+    fn encode_into(
+        &self,
+        framing: Framing,
+        opening_flag: bool,
+        data: &mut Vec<u8>,
+    ) -> Result<(), GsmError> {
+        if framing.transparency == TransparencyMode::Basic && self.length > MAX_LENGTH {
+            return Err(GsmError::FrameTooLong(self.length as usize));
         }
+        self.check_length()?;
+        let fcs = framing
+            .fcs
+            .compute(&self.as_frame_ref(), framing.fcs_mode())?;
+        let flag = framing.transparency.flag();
+        if opening_flag {
+            data.push(flag);
+        }
+        match framing.transparency {
+            TransparencyMode::Basic => {
+                data.extend_from_slice(&[self.address, self.control]);
+                data.extend_from_slice(&self.length_bytes());
+                data.extend(self.credit);
+                data.extend_from_slice(&self.content);
+                data.push(fcs);
+            }
+            TransparencyMode::Advanced => {
+                let fields = [self.address, self.control];
+                let octets = fields.iter().chain(&self.credit).chain(&self.content);
+                for &byte in octets.chain([fcs].iter()) {
+                    if ESCAPED_OCTETS.contains(&byte) {
+                        data.extend_from_slice(&[CONTROL_ESCAPE, byte ^ 0x20]);
+                    } else {
+                        data.push(byte);
+                    }
+                }
+            }
+        }
+        data.push(flag);
+        Ok(())
+    }
+
+    /// Serialize the frame with basic option framing
+    pub fn try_to_bytes(&self) -> Result<Vec<u8>, GsmError> {
+        self.try_to_bytes_with(TransparencyMode::Basic)
+    }
 
-        Some((frame, len))
+    /// Command frame on a DLCI, sent by the initiator if `is_initiator` else the responder
+    pub fn command(dlci: u8, control: Control, content: Vec<u8>, is_initiator: bool) -> Self {
+        Self::with_cr(dlci, control, content, is_initiator)
     }
 
-    pub fn try_to_bytes(&self) -> Result<Vec<u8>> {
-        let mut data = vec![FLAG, self.address, self.control];
-        if self.length > u8::MAX as u16 {
-            let len = self.length.to_be_bytes();
-            data.extend_from_slice(&len);
+    /// Response frame on a DLCI, sent by the initiator if `is_initiator` else the responder
+    pub fn response(dlci: u8, control: Control, content: Vec<u8>, is_initiator: bool) -> Self {
+        Self::with_cr(dlci, control, content, !is_initiator)
+    }
+
+    fn with_cr(dlci: u8, control: Control, content: Vec<u8>, cr: bool) -> Self {
+        Frame::new(Address::new_address(cr, true, dlci), control, content)
+    }
+
+    /// Builder of a frame whose length is derived from its content
+    pub fn builder() -> FrameBuilder {
+        FrameBuilder::default()
+    }
+}
+
+/// C/R bit of a frame being built
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum CrBit {
+    Raw(bool),
+    /// Command under the C/R table, see [`Frame::command`]
+    Command,
+    /// Response under the C/R table, see [`Frame::response`]
+    Response,
+}
+
+/// Builder of a [`Frame`], checking its fields.
+///
+/// The DLCI and frame type are required. By default the frame is an empty command
+/// of the initiator with P/F cleared, whose payload may be as long as a Length
+/// Indicator allows.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct FrameBuilder {
+    dlci: Option<u8>,
+    frame_type: Option<FrameType>,
+    cr: CrBit,
+    is_initiator: bool,
+    pf: bool,
+    payload: Vec<u8>,
+    max_frame_size: u16,
+}
+
+impl Default for FrameBuilder {
+    fn default() -> Self {
+        FrameBuilder {
+            dlci: None,
+            frame_type: None,
+            cr: CrBit::Command,
+            is_initiator: true,
+            pf: false,
+            payload: Vec::new(),
+            max_frame_size: MAX_LENGTH,
+        }
+    }
+}
+
+impl FrameBuilder {
+    /// DLCI of the frame, up to 63
+    pub fn dlci(mut self, dlci: u8) -> Self {
+        self.dlci = Some(dlci);
+        self
+    }
+
+    pub fn frame_type(mut self, frame_type: FrameType) -> Self {
+        self.frame_type = Some(frame_type);
+        self
+    }
+
+    /// Raw value of the C/R bit of the address
+    pub fn cr(mut self, cr: bool) -> Self {
+        self.cr = CrBit::Raw(cr);
+        self
+    }
+
+    /// Whether the frame is a command or a response, following the C/R table like
+    /// [`FrameBuilder::cr_command`] and [`FrameBuilder::cr_response`]
+    pub fn command(self, command: bool) -> Self {
+        match command {
+            true => self.cr_command(),
+            false => self.cr_response(),
+        }
+    }
+
+    /// Set the C/R bit of a command, following the C/R table
+    pub fn cr_command(mut self) -> Self {
+        self.cr = CrBit::Command;
+        self
+    }
+
+    /// Set the C/R bit of a response, following the C/R table
+    pub fn cr_response(mut self) -> Self {
+        self.cr = CrBit::Response;
+        self
+    }
+
+    /// Whether the sender started the session, for [`FrameBuilder::cr_command`] and
+    /// [`FrameBuilder::cr_response`]
+    pub fn initiator(mut self, is_initiator: bool) -> Self {
+        self.is_initiator = is_initiator;
+        self
+    }
+
+    pub fn pf(mut self, pf: bool) -> Self {
+        self.pf = pf;
+        self
+    }
+
+    pub fn payload(mut self, payload: Vec<u8>) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    /// Same as [`FrameBuilder::payload`]
+    pub fn content(self, content: Vec<u8>) -> Self {
+        self.payload(content)
+    }
+
+    /// Maximum frame size N1 the payload must fit in
+    pub fn max_frame_size(mut self, n1: u16) -> Self {
+        self.max_frame_size = n1;
+        self
+    }
+
+    /// Build the frame, failing on a missing DLCI or frame type, a DLCI above 63
+    /// or a payload longer than the maximum frame size
+    pub fn build(self) -> Result<Frame, GsmError> {
+        let dlci = self
+            .dlci
+            .ok_or_else(|| GsmError::ParseFrameError("missing DLCI".into()))?;
+        if dlci > MAX_DLCI {
+            return Err(GsmError::ParseFrameError(format!(
+                "DLCI {} above {}",
+                dlci, MAX_DLCI
+            )));
+        }
+        let frame_type = self
+            .frame_type
+            .ok_or_else(|| GsmError::ParseFrameError("missing frame type".into()))?;
+        if self.payload.len() > self.max_frame_size as usize {
+            return Err(GsmError::FrameTooLong(self.payload.len()));
+        }
+        let cr = match self.cr {
+            CrBit::Raw(cr) => cr,
+            CrBit::Command => self.is_initiator,
+            CrBit::Response => !self.is_initiator,
+        };
+        Ok(Frame::new(
+            Address::new_address(cr, true, dlci),
+            Control::new_control(frame_type, self.pf),
+            self.payload,
+        ))
+    }
+}
+
+/// Length Indicator octets of a length, and how many are used
+fn length_indicator(length: u16) -> ([u8; 2], usize) {
+    if length > MAX_SHORT_LENGTH {
+        ([(length << 1) as u8 & !EA, (length >> 7) as u8], 2)
+    } else {
+        ([((length as u8) << 1) | EA, 0], 1)
+    }
+}
+
+/// Fields of a basic option frame preceding its content
+struct BasicHeader {
+    address: Address,
+    control: Control,
+    length: u16,
+    credit: Option<u8>,
+    /// Offset of the content from the start of the parsed slice
+    content_start: usize,
+}
+
+impl BasicHeader {
+    /// Parse the header of the first frame of a slice, skipping what precedes its flag
+    fn parse(buf: &[u8], framing: Framing) -> Result<Self, ParseError> {
+        let start = buf
+            .iter()
+            .position(|&b| b == FLAG)
+            .ok_or(ParseError::Incomplete)?;
+        let mut pos = start + 1;
+        if buf.get(pos) == Some(&FLAG) {
+            return Err(ParseError::BadFlag(FLAG));
+        }
+        let header = buf.get(pos..pos + 3).ok_or(ParseError::Incomplete)?;
+        let (address, control, length_lo) = (header[0], header[1], header[2]);
+        pos += 3;
+        let length = if length_lo & EA == EA {
+            (length_lo >> 1) as u16
         } else {
-            data.push(((self.length as u8) << 1) | 1);
+            let length_hi = *buf.get(pos).ok_or(ParseError::Incomplete)?;
+            pos += 1;
+            ((length_hi as u16) << 7) | (length_lo >> 1) as u16
         };
-        data.extend_from_slice(&self.content);
-        data.push(self.try_fcs()?);
-        data.push(FLAG);
-        Ok(data)
+        // A corrupted length would wait for octets that never come
+        if length > framing.max_length {
+            return Err(ParseError::UnsupportedLength);
+        }
+        let credit = match framing.has_credit(address, control) {
+            true => {
+                let credit = *buf.get(pos).ok_or(ParseError::Incomplete)?;
+                pos += 1;
+                Some(credit)
+            }
+            false => None,
+        };
+        Ok(BasicHeader {
+            address,
+            control,
+            length,
+            credit,
+            content_start: pos,
+        })
+    }
+
+    /// Octets from the start of the parsed slice to the closing flag included
+    fn frame_end(&self) -> usize {
+        self.content_start + self.length as usize + 2
+    }
+}
+
+/// A [`Frame`] whose content borrows the buffer it was parsed from
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct FrameRef<'a> {
+    pub address: Address,
+    pub control: Control,
+    pub length: u16,
+    pub content: &'a [u8],
+    /// Credits granted to the receiver, see [`Frame::credit`]
+    pub credit: Option<u8>,
+}
+
+impl<'a> FrameRef<'a> {
+    /// Parse a basic option frame from a slice without copying its content.
+    ///
+    /// Returns the frame and the number of octets it spans, like [`Frame::parse`].
+    pub fn parse(buf: &'a [u8]) -> Result<(Self, usize), ParseError> {
+        Self::parse_with(buf, Framing::default())
+    }
+
+    /// Parse a basic option frame from a slice with the given framing, without copying
+    /// its content.
+    ///
+    /// The transparency of `framing` is ignored: the octets of advanced option frames
+    /// are escaped, [`Frame::parse_with`] unescapes them into an owned frame.
+    pub fn parse_with(buf: &'a [u8], framing: Framing) -> Result<(Self, usize), ParseError> {
+        let header = BasicHeader::parse(buf, framing)?;
+        let mut pos = header.content_start;
+        let content = buf
+            .get(pos..pos + header.length as usize)
+            .ok_or(ParseError::Incomplete)?;
+        pos += header.length as usize;
+        let trailer = buf.get(pos..pos + 2).ok_or(ParseError::Incomplete)?;
+        let (fcs, flag) = (trailer[0], trailer[1]);
+        if flag != FLAG {
+            return Err(ParseError::BadFlag(flag));
+        }
+        let frame = FrameRef {
+            address: header.address,
+            control: header.control,
+            length: header.length,
+            content,
+            credit: header.credit,
+        };
+        let expected = framing
+            .fcs
+            .compute(&frame, MuxMode::Basic)
+            .map_err(|_| ParseError::UnknownControl(frame.control))?;
+        if expected != fcs {
+            return Err(ParseError::BadFcs { expected, got: fcs });
+        }
+        Ok((frame, pos + 2))
+    }
+
+    /// Calculate the Frame Check Sequence (FCS) of the frame in the given mode
+    pub fn try_fcs_with_mode(&self, mode: MuxMode) -> Result<u8, GsmError> {
+        FcsAlgorithm::Standard.compute(self, mode)
+    }
+
+    /// Whether the frame is on the control channel, DLCI 0
+    pub fn is_control_channel(&self) -> bool {
+        self.address.get_dlci() == 0
+    }
+
+    /// Copy the content into an owned [`Frame`]
+    pub fn to_frame(&self) -> Frame {
+        Frame {
+            address: self.address,
+            control: self.control,
+            length: self.length,
+            content: self.content.to_vec(),
+            credit: self.credit,
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for Frame {
+    type Error = GsmError;
+
+    /// Parse a basic option frame spanning the whole slice, flags included
+    fn try_from(bytes: &[u8]) -> Result<Self, GsmError> {
+        if let Some(&first) = bytes.first().filter(|&&b| b != FLAG) {
+            return Err(GsmError::InvalidFlag(first));
+        }
+        let hex = || {
+            bytes
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        match Frame::parse(&mut bytes.iter().copied()) {
+            Ok((frame, len)) if len == bytes.len() => Ok(frame),
+            Ok((_, len)) => Err(GsmError::ParseFrameError(format!(
+                "{} trailing octets: {}",
+                bytes.len() - len,
+                hex()
+            ))),
+            Err(ParseError::BadFcs { expected, got }) => Err(GsmError::FcsError { expected, got }),
+            Err(ParseError::BadFlag(flag)) => Err(GsmError::InvalidFlag(flag)),
+            Err(e) => Err(GsmError::ParseFrameError(format!("{}: {}", e, hex()))),
+        }
     }
 }
 
@@ -360,19 +1209,283 @@ mod tests {
     #[test]
     fn frame_fcs_works() {
         // Frame with UI frame type
-        let frame = Frame::new(7, 239, 4, vec![0x41, 0x54, 0xD, 0xA]);
+        let frame = Frame::new(7, 239, vec![0x41, 0x54, 0xD, 0xA]);
         assert_eq!(frame.try_fcs().unwrap(), 0x39);
         // Frame with UIH frame type
         let addr = Address::new_address(true, true, 0x0F);
         let ctrl = Control::new_control(FrameType::UIH, true);
-        let len = 0x0A;
-        let frame = Frame::new(addr, ctrl, len, vec![0x41, 0x54, 0xD, 0xA]);
+        let frame = Frame::new(addr, ctrl, vec![0x41, 0x54, 0xD, 0xA]).with_length(0x0A);
         assert_eq!(frame.try_fcs().unwrap(), 0x23);
     }
 
+    #[test]
+    fn builder_derives_the_length() {
+        let frame = Frame::builder()
+            .dlci(2)
+            .frame_type(FrameType::UIH)
+            .command(true)
+            .pf(true)
+            .content(b"AT\r".to_vec())
+            .build()
+            .unwrap();
+        assert_eq!(frame.address, 0x0B);
+        assert_eq!(frame.control, 0xFF);
+        assert_eq!(frame.length, 3);
+        assert_eq!(frame.content, b"AT\r");
+        assert_eq!(
+            frame,
+            Frame::new(
+                Address::new_address(true, true, 2),
+                Control::new_control(FrameType::UIH, true),
+                b"AT\r".to_vec()
+            )
+        );
+
+        let frame = Frame::builder()
+            .dlci(0)
+            .frame_type(FrameType::UA)
+            .cr(false)
+            .build()
+            .unwrap();
+        assert_eq!(
+            (frame.address, frame.control, frame.length),
+            (0x01, 0x63, 0)
+        );
+        // A response of the responder carries C/R=1
+        let frame = Frame::builder()
+            .dlci(0)
+            .frame_type(FrameType::UA)
+            .initiator(false)
+            .command(false)
+            .build()
+            .unwrap();
+        assert!(frame.address.get_cr());
+    }
+
+    #[test]
+    fn builder_rejects_invalid_frames() {
+        let uih = || Frame::builder().dlci(3).frame_type(FrameType::UIH);
+        assert_eq!(uih().dlci(63).build().unwrap().address.get_dlci(), 63);
+        for builder in [
+            uih().dlci(64),
+            uih().dlci(80),
+            Frame::builder().frame_type(FrameType::UIH),
+            Frame::builder().dlci(3),
+        ] {
+            assert!(matches!(builder.build(), Err(GsmError::ParseFrameError(_))));
+        }
+        let content = vec![0x41; 32];
+        assert!(uih().payload(content.clone()).build().is_ok());
+        assert!(matches!(
+            uih().max_frame_size(31).payload(content.clone()).build(),
+            Err(GsmError::FrameTooLong(32))
+        ));
+        let frame = uih().max_frame_size(32).payload(content).build().unwrap();
+        assert_eq!(frame.length, 32);
+        assert!(matches!(
+            uih().payload(vec![0; MAX_LENGTH as usize + 1]).build(),
+            Err(GsmError::FrameTooLong(_))
+        ));
+    }
+
+    #[test]
+    fn batch_matches_individual_frames() {
+        let frames: Vec<Frame> = (0..4u8)
+            .map(|dlci| {
+                Frame::builder()
+                    .dlci(dlci)
+                    .frame_type(FrameType::SABM)
+                    .pf(true)
+                    .payload(vec![FLAG, ADVANCED_FLAG, dlci])
+                    .build()
+                    .unwrap()
+            })
+            .collect();
+        for transparency in [TransparencyMode::Basic, TransparencyMode::Advanced] {
+            let framing = Framing::from(transparency);
+            let single: Vec<Vec<u8>> = frames
+                .iter()
+                .map(|frame| frame.try_to_bytes_with(framing).unwrap())
+                .collect();
+            let batch = serialize_batch(&frames, framing.into()).unwrap();
+            assert_eq!(batch, single.concat());
+
+            let codec = FrameCodec {
+                framing,
+                share_flags: true,
+            };
+            if transparency == TransparencyMode::Basic {
+                // The basic option parser would take the shared flag as the closing one
+                assert!(matches!(
+                    serialize_batch(&frames, codec),
+                    Err(GsmError::InvalidConfig(_))
+                ));
+                continue;
+            }
+            // Every frame after the first one drops its opening flag
+            let shared = serialize_batch(&frames, codec).unwrap();
+            let mut expected = single[0].clone();
+            for bytes in &single[1..] {
+                expected.extend_from_slice(&bytes[1..]);
+            }
+            assert_eq!(shared, expected);
+            assert_eq!(shared.len(), batch.len() - 3);
+        }
+        assert_eq!(serialize_batch(&[], FrameCodec::default()).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn command_and_response_follow_the_cr_table() {
+        let sabm = Control::new_control(FrameType::SABM, true);
+        let ua = Control::new_control(FrameType::UA, true);
+        // Initiator: commands C/R=1, responses C/R=0
+        assert_eq!(Frame::command(1, sabm, vec![], true).address, 0x07);
+        assert_eq!(Frame::response(1, ua, vec![], true).address, 0x05);
+        // Responder: commands C/R=0, responses C/R=1
+        assert_eq!(Frame::command(1, sabm, vec![], false).address, 0x05);
+        assert_eq!(Frame::response(1, ua, vec![], false).address, 0x07);
+        let uih = Frame::command(
+            2,
+            Control::new_control(FrameType::UIH, false),
+            b"AT".to_vec(),
+            true,
+        );
+        assert_eq!((uih.address, uih.length), (0x0B, 2));
+
+        // The builder follows the same table
+        let builder = |is_initiator| {
+            Frame::builder()
+                .dlci(1)
+                .frame_type(FrameType::SABM)
+                .pf(true)
+                .initiator(is_initiator)
+        };
+        for is_initiator in [true, false] {
+            assert_eq!(
+                builder(is_initiator).cr_command().build().unwrap(),
+                Frame::command(1, sabm, vec![], is_initiator)
+            );
+            assert_eq!(
+                builder(is_initiator).cr_response().build().unwrap(),
+                Frame::response(1, sabm, vec![], is_initiator)
+            );
+        }
+        assert_eq!(builder(false).cr(true).build().unwrap().address, 0x07);
+
+        assert!(FrameType::SABM.cr(true));
+        assert!(!FrameType::UA.cr(true));
+        assert!(!FrameType::UIH.cr(false));
+        assert!(FrameType::DM.cr(false));
+    }
+
+    #[test]
+    fn length_must_match_the_content() {
+        let frame = Frame::try_new(0x07, 0xEF, 2, b"AT".to_vec()).unwrap();
+        assert_eq!(frame, Frame::new(0x07, 0xEF, b"AT".to_vec()));
+        assert_eq!(frame.length, 2);
+        // A forced length is never serialized
+        assert!(matches!(
+            frame.with_length(3).try_to_bytes(),
+            Err(GsmError::ParseFrameError(_))
+        ));
+        for length in [1, 3] {
+            assert!(matches!(
+                Frame::try_new(0x07, 0xEF, length, b"AT".to_vec()),
+                Err(GsmError::ParseFrameError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn credit_octet_follows_the_length() {
+        let framing = Framing {
+            flow_control: FlowControl::CreditBased,
+            ..Default::default()
+        };
+        let frame = Frame::new(0x07, 0xEF, b"OK".to_vec()).with_credit(5);
+        assert_eq!(frame.control, 0xFF);
+        let bytes = frame.try_to_bytes_with(framing).unwrap();
+        assert_eq!(bytes[..5], [FLAG, 0x07, 0xFF, 0x05, 0x05]);
+        assert_eq!(bytes[5..7], *b"OK");
+        // The FCS of UIH frames doesn't cover the credit
+        assert_eq!(
+            bytes[7],
+            Frame::new(0x07, 0xFF, b"OK".to_vec()).try_fcs().unwrap()
+        );
+        let (parsed, len) = Frame::parse_with(&mut bytes.iter().copied(), framing).unwrap();
+        assert_eq!((parsed, len), (frame.clone(), bytes.len()));
+
+        let advanced = Framing {
+            transparency: TransparencyMode::Advanced,
+            ..framing
+        };
+        let bytes = frame.try_to_bytes_with(advanced).unwrap();
+        assert_eq!(bytes[..4], [ADVANCED_FLAG, 0x07, 0xFF, 0x05]);
+        let (parsed, _) = Frame::parse_with(&mut bytes.iter().copied(), advanced).unwrap();
+        assert_eq!(parsed, frame);
+
+        // Without credit based flow control, or on the control channel, P/F carries no credit
+        let plain = Frame::new(0x07, 0xFF, b"OK".to_vec());
+        let bytes = plain.try_to_bytes().unwrap();
+        let (parsed, _) =
+            Frame::parse_with(&mut bytes.iter().copied(), Framing::default()).unwrap();
+        assert_eq!(parsed.credit, None);
+        let control = Frame::new(0x03, 0xFF, vec![0xE3, 0x01]);
+        let bytes = control.try_to_bytes().unwrap();
+        let (parsed, _) = Frame::parse_with(&mut bytes.iter().copied(), framing).unwrap();
+        assert_eq!(parsed, control);
+    }
+
+    #[test]
+    fn static_crc_table_matches_fresh_crc() {
+        let fixtures = [
+            Frame::new(7, 239, vec![0x41, 0x54, 0xD, 0xA]),
+            Frame::new(0x3F, 0xEF, vec![0x41, 0x54, 0xD, 0xA]).with_length(10),
+            Frame::new(0x07, 0x13, vec![0x41, 0x54]),
+            Frame::new(0x03, 0x3F, vec![]),
+            Frame::new(0x95, 0xFF, vec![ADVANCED_FLAG]),
+            Frame::new(0x07, 0x03, vec![0x55; 200]),
+        ];
+        for frame in &fixtures {
+            for mode in [MuxMode::Basic, MuxMode::Advanced] {
+                let crc = Crc::<u8>::new(&crc::CRC_8_ROHC);
+                let mut digest = crc.digest();
+                digest.update(&[frame.address, frame.control]);
+                if mode.has_length_indicator() {
+                    digest.update(&frame.length_bytes());
+                }
+                if frame.control.get_frame_type().unwrap() == FrameType::UI {
+                    digest.update(&frame.content);
+                }
+                assert_eq!(frame.try_fcs_with_mode(mode).unwrap(), !digest.finalize());
+            }
+        }
+    }
+
+    #[test]
+    fn frame_fcs_depends_on_mode() {
+        let addr = Address::new_address(true, true, 0x01);
+        let ctrl = Control::new_control(FrameType::UIH, false);
+        let frame = Frame::new(addr, ctrl, vec![0x41, 0x54, 0xD, 0xA]);
+        let basic = frame.try_fcs_with_mode(MuxMode::Basic).unwrap();
+        let advanced = frame.try_fcs_with_mode(MuxMode::Advanced).unwrap();
+        assert_eq!(basic, frame.try_fcs().unwrap());
+        assert_ne!(basic, advanced);
+        assert_eq!(
+            advanced,
+            frame.try_fcs_with_mode(MuxMode::ErrorRecovery).unwrap()
+        );
+        // Content doesn't change the FCS of UIH frames in either mode
+        let other = Frame::new(addr, ctrl, vec![0; 4]);
+        assert_eq!(
+            other.try_fcs_with_mode(MuxMode::Advanced).unwrap(),
+            advanced
+        );
+    }
+
     #[test]
     fn frame_parse_works() {
-        let frame = Frame::new(7, 239, 4, vec![0x41, 0x54, 0xD, 0xA]);
+        let frame = Frame::new(7, 239, vec![0x41, 0x54, 0xD, 0xA]);
         let frame_bytes = frame.try_to_bytes().unwrap();
         dbg!(frame_bytes.clone());
         let mut iter = frame_bytes.into_iter();
@@ -380,4 +1493,276 @@ mod tests {
         assert_eq!(parsed_frame, frame);
         assert_eq!(len, 10);
     }
+
+    #[test]
+    fn frame_parse_two_octet_length() {
+        let content: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let addr = Address::new_address(true, true, 0x01);
+        let ctrl = Control::new_control(FrameType::UIH, false);
+        let frame = Frame::new(addr, ctrl, content);
+        let frame_bytes = frame.try_to_bytes().unwrap();
+        assert_eq!(&frame_bytes[3..5], &[0x58, 0x02]);
+        let mut iter = frame_bytes.into_iter();
+        let (parsed_frame, len) = Frame::parse(&mut iter).unwrap();
+        assert_eq!(parsed_frame, frame);
+        assert_eq!(len, 307);
+    }
+
+    #[test]
+    fn frame_round_trip_200_bytes() {
+        let addr = Address::new_address(true, true, 0x02);
+        let ctrl = Control::new_control(FrameType::UI, false);
+        let frame = Frame::new(addr, ctrl, vec![0xF9; 200]);
+        // 200 = 0b1_1001000: low 7 bits with EA cleared, then the high bits
+        assert_eq!(frame.length_bytes(), vec![0x90, 0x01]);
+        let frame_bytes = frame.try_to_bytes().unwrap();
+        assert_eq!(frame_bytes.len(), 207);
+        let (parsed_frame, len) = Frame::parse(&mut frame_bytes.into_iter()).unwrap();
+        assert_eq!(parsed_frame, frame);
+        assert_eq!(len, 207);
+    }
+
+    #[test]
+    fn frame_length_encoding_boundaries() {
+        let addr = Address::new_address(true, true, 0x01);
+        let ctrl = Control::new_control(FrameType::UIH, false);
+        for length in [127u16, 128, 255, 1000] {
+            let frame = Frame::new(addr, ctrl, vec![0x55; length as usize]);
+            let frame_bytes = frame.try_to_bytes().unwrap();
+            // EA is set only when the length fits in one octet
+            assert_eq!(frame_bytes[3] & EA == EA, length <= MAX_SHORT_LENGTH);
+            let (parsed_frame, len) = Frame::parse(&mut frame_bytes.clone().into_iter()).unwrap();
+            assert_eq!(parsed_frame, frame);
+            assert_eq!(len, frame_bytes.len());
+        }
+    }
+
+    #[test]
+    fn frame_length_bytes_match_header() {
+        let addr = Address::new_address(true, true, 0x01);
+        let ctrl = Control::new_control(FrameType::UIH, false);
+        for length in [127u16, 128, 255, 256, MAX_LENGTH] {
+            let frame = Frame::new(addr, ctrl, vec![0xAA; length as usize]);
+            let length_bytes = frame.length_bytes();
+            let frame_bytes = frame.try_to_bytes().unwrap();
+            assert_eq!(&frame_bytes[3..3 + length_bytes.len()], &length_bytes[..]);
+            let (parsed_frame, _) = Frame::parse(&mut frame_bytes.into_iter()).unwrap();
+            assert_eq!(parsed_frame.length, length);
+        }
+        let frame = Frame::new(addr, ctrl, vec![]).with_length(MAX_LENGTH);
+        assert_eq!(frame.length_bytes(), vec![0xFE, 0xFF]);
+        let frame = Frame::new(addr, ctrl, vec![]).with_length(MAX_LENGTH + 1);
+        assert!(frame.try_to_bytes().is_err());
+    }
+
+    #[test]
+    fn frame_advanced_transparency() {
+        let addr = Address::new_address(true, true, 0x01);
+        let ctrl = Control::new_control(FrameType::UIH, false);
+        let content = vec![0x41, ADVANCED_FLAG, 0x42, CONTROL_ESCAPE, 0x11];
+        let frame = Frame::new(addr, ctrl, content);
+        let frame_bytes = frame.try_to_bytes_with(TransparencyMode::Advanced).unwrap();
+        let fcs = frame.try_fcs_with_mode(MuxMode::Advanced).unwrap();
+        let mut expected = vec![ADVANCED_FLAG, addr, ctrl];
+        expected.extend_from_slice(&[0x41, 0x7D, 0x5E, 0x42, 0x7D, 0x5D, 0x7D, 0x31]);
+        expected.extend_from_slice(&[fcs, ADVANCED_FLAG]);
+        assert_eq!(frame_bytes, expected);
+        // Only the opening and closing flags remain on the wire
+        assert_eq!(
+            frame_bytes.iter().filter(|&&b| b == ADVANCED_FLAG).count(),
+            2
+        );
+
+        let mut iter = frame_bytes.clone().into_iter();
+        let (parsed_frame, len) = Frame::parse_with(&mut iter, TransparencyMode::Advanced).unwrap();
+        assert_eq!(parsed_frame, frame);
+        assert_eq!(len, frame_bytes.len() - 1);
+    }
+
+    #[test]
+    fn frame_advanced_rejects_bad_fcs() {
+        let addr = Address::new_address(true, true, 0x01);
+        let ctrl = Control::new_control(FrameType::UI, false);
+        let frame = Frame::new(addr, ctrl, vec![0x41, 0x42]);
+        let mut frame_bytes = frame.try_to_bytes_with(TransparencyMode::Advanced).unwrap();
+        // UI frames cover the information field
+        frame_bytes[3] = 0x43;
+        let mut iter = frame_bytes.into_iter();
+        assert!(matches!(
+            Frame::parse_with(&mut iter, TransparencyMode::Advanced),
+            Err(ParseError::BadFcs { .. })
+        ));
+    }
+
+    #[test]
+    fn frame_parse_truncated_slice_stops_at_header() {
+        // Length 100 but only 3 content octets available
+        let bytes = [FLAG, 0x07, 0xEF, 0xC9, 0x01, 0x02, 0x03];
+        let mut iter = bytes.iter().copied();
+        assert_eq!(Frame::parse(&mut iter), Err(ParseError::Incomplete));
+        // No content octet was pulled from the slice
+        assert_eq!(iter.len(), 3);
+    }
+
+    #[test]
+    fn frame_parse_errors() {
+        // UIH on DLCI 0 with "AT", FCS 0xF2
+        let good = [FLAG, 0x03, 0xEF, 0x05, b'A', b'T', 0xF2, FLAG];
+        let parse = |bytes: &[u8]| Frame::parse(&mut bytes.iter().copied()).map(|(f, _)| f);
+        assert!(parse(&good).is_ok());
+        assert!(Frame::parse_opt(&mut good.iter().copied()).is_some());
+        assert_eq!(parse(&good[..5]), Err(ParseError::Incomplete));
+        assert_eq!(parse(&good[..7]), Err(ParseError::Incomplete));
+        assert_eq!(parse(&[0x01, 0x02]), Err(ParseError::Incomplete));
+
+        let mut bad_fcs = good;
+        bad_fcs[6] = 0xF3;
+        assert_eq!(
+            parse(&bad_fcs),
+            Err(ParseError::BadFcs {
+                expected: 0xF2,
+                got: 0xF3
+            })
+        );
+        assert_eq!(Frame::parse_opt(&mut bad_fcs.iter().copied()), None);
+
+        let mut bad_flag = good;
+        bad_flag[7] = 0x00;
+        assert_eq!(parse(&bad_flag), Err(ParseError::BadFlag(0x00)));
+
+        let mut unknown = good;
+        unknown[2] = 0x00;
+        assert_eq!(parse(&unknown), Err(ParseError::UnknownControl(0x00)));
+
+        let advanced = |bytes: &[u8]| {
+            Frame::parse_with(&mut bytes.iter().copied(), TransparencyMode::Advanced)
+                .map(|(f, _)| f)
+        };
+        assert_eq!(
+            advanced(&[ADVANCED_FLAG, 0x03, ADVANCED_FLAG]),
+            Err(ParseError::BadFlag(ADVANCED_FLAG))
+        );
+        let mut oversized = vec![ADVANCED_FLAG, 0x03, 0xEF];
+        oversized.extend(std::iter::repeat_n(0x41, MAX_LENGTH as usize + 1));
+        oversized.extend([0x00, ADVANCED_FLAG]);
+        assert_eq!(advanced(&oversized), Err(ParseError::UnsupportedLength));
+    }
+
+    #[test]
+    fn fcs_algorithm_detection() {
+        let frames = [
+            Frame::new(0x03, 0x73, vec![]),
+            Frame::new(0x07, 0xEF, vec![0xC3, 0x01]),
+            Frame::new(0x07, 0xEF, vec![0x41, 0x54, 0x0D]),
+        ];
+        for transparency in [TransparencyMode::Basic, TransparencyMode::Advanced] {
+            for fcs in FcsAlgorithm::ALL {
+                let framing = Framing {
+                    transparency,
+                    fcs,
+                    ..Default::default()
+                };
+                let mut stream = vec![0x00, 0x41];
+                for frame in &frames {
+                    stream.extend(frame.try_to_bytes_with(framing).unwrap());
+                }
+                assert_eq!(FcsAlgorithm::detect(&stream, transparency), Some(fcs));
+                let (frame, _) =
+                    Frame::parse_with(&mut stream[2..].iter().copied(), framing).unwrap();
+                assert_eq!(frame, frames[0]);
+            }
+        }
+        assert_eq!(
+            FcsAlgorithm::detect(
+                &[FLAG, 0x03, 0x73, 0x01, 0x00, FLAG],
+                TransparencyMode::Basic
+            ),
+            None
+        );
+
+        // An empty UA validates under the standard FCS and the full frame one alike
+        let ua = frames[0].try_to_bytes().unwrap();
+        assert_eq!(
+            FcsAlgorithm::candidates(&ua, TransparencyMode::Basic),
+            vec![FcsAlgorithm::Standard, FcsAlgorithm::FullFrame]
+        );
+    }
+
+    #[test]
+    fn frame_try_from_slice() {
+        let good = [FLAG, 0x03, 0xEF, 0x05, b'A', b'T', 0xF2, FLAG];
+        let frame = Frame::try_from(&good[..]).unwrap();
+        assert_eq!(frame.content, b"AT");
+        assert_eq!(frame.try_to_bytes().unwrap(), good);
+
+        let mut bad_fcs = good;
+        bad_fcs[6] = 0xF3;
+        assert!(matches!(
+            Frame::try_from(&bad_fcs[..]),
+            Err(GsmError::FcsError {
+                expected: 0xF2,
+                got: 0xF3
+            })
+        ));
+        assert!(matches!(
+            Frame::try_from(&good[1..]),
+            Err(GsmError::InvalidFlag(0x03))
+        ));
+        let mut bad_flag = good;
+        bad_flag[7] = 0x00;
+        assert!(matches!(
+            Frame::try_from(&bad_flag[..]),
+            Err(GsmError::InvalidFlag(0x00))
+        ));
+        let Err(GsmError::ParseFrameError(message)) = Frame::try_from(&good[..5]) else {
+            panic!("truncated frame parsed");
+        };
+        assert_eq!(message, "incomplete frame: F9 03 EF 05 41");
+        assert!(matches!(
+            Frame::try_from(&[&good[..], &[0x00]].concat()[..]),
+            Err(GsmError::ParseFrameError(_))
+        ));
+        assert!(matches!(
+            Frame::new(0x03, 0xEF, vec![])
+                .with_length(MAX_LENGTH + 1)
+                .try_to_bytes(),
+            Err(GsmError::FrameTooLong(_))
+        ));
+    }
+
+    #[test]
+    fn frame_ref_parses_without_copying() {
+        let good = [0x00, FLAG, 0x03, 0xEF, 0x05, b'A', b'T', 0xF2, FLAG];
+        let (frame, len) = FrameRef::parse(&good).unwrap();
+        assert_eq!(frame.content, b"AT");
+        assert!(std::ptr::eq(frame.content.as_ptr(), &good[5]));
+        let (owned, owned_len) = Frame::parse(&mut good.iter().copied()).unwrap();
+        assert_eq!((frame.to_frame(), len), (owned.clone(), owned_len));
+        assert_eq!(owned.as_frame_ref(), frame);
+
+        let ui = Frame::new(7, 239, vec![0x41, 0x54, 0xD, 0xA]);
+        let bytes = ui.try_to_bytes().unwrap();
+        assert_eq!(FrameRef::parse(&bytes).unwrap().0.to_frame(), ui);
+
+        // Same failures as Frame::parse
+        for bytes in [&good[..4], &good[..8], &[0x01, 0x02][..]] {
+            assert_eq!(FrameRef::parse(bytes), Err(ParseError::Incomplete));
+        }
+        assert_eq!(
+            FrameRef::parse(&[FLAG, FLAG]),
+            Err(ParseError::BadFlag(FLAG))
+        );
+        let mut bad_fcs = good;
+        bad_fcs[7] = 0xF3;
+        assert_eq!(
+            FrameRef::parse(&bad_fcs),
+            Err(ParseError::BadFcs {
+                expected: 0xF2,
+                got: 0xF3
+            })
+        );
+        let mut bad_flag = good;
+        bad_flag[8] = 0x00;
+        assert_eq!(FrameRef::parse(&bad_flag), Err(ParseError::BadFlag(0x00)));
+    }
 }
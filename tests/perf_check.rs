@@ -0,0 +1,467 @@
+//! Performance gate: times the frame decode, encode and dispatch paths with fixed
+//! iteration counts, and compares them with the baselines of `perf_baselines.toml`.
+//!
+//! Ignored by default, the timings only mean something in release builds:
+//!
+//! ```text
+//! cargo test --release --test perf-check -- --ignored
+//! ```
+//!
+//! With `PERF_CHECK_REGENERATE=1` the measurements replace the baselines of the
+//! current architecture instead of being checked against them.
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    hint::black_box,
+    io::{self, ErrorKind, Read, Write},
+    time::{Duration, Instant},
+};
+
+use gsm0710::{
+    buffer::ReceiveBuffer, mcc::MccType, stats::DropStats, Address, AddressImpl, Control,
+    ControlImpl, Frame, FrameType, Framing, Muxer, MuxerConfig,
+};
+use serde::{Deserialize, Serialize};
+
+const BASELINES_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/perf_baselines.toml");
+const REGENERATE_VAR: &str = "PERF_CHECK_REGENERATE";
+/// Slowdown tolerated over the baseline of an architecture that doesn't set its own
+const DEFAULT_TOLERANCE: f64 = 3.0;
+/// Timed runs of each scenario, the fastest one is kept
+const SAMPLES: usize = 5;
+/// Frames of the decode and encode scenarios, with the default N1 of 31 octets
+const BATCH_FRAMES: usize = 64;
+const PAYLOAD_LENGTH: usize = 31;
+/// Octets handed to the receive buffer at a time in the chopped reads scenario
+const CHOP_LENGTH: usize = 7;
+const DISPATCH_CHANNELS: u8 = 60;
+/// Type octet of a TEST command, with the C/R bit set
+const TEST_COMMAND: u8 = 0x23;
+
+/// Baselines of an architecture
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Baseline {
+    /// Slowdown over the baseline tolerated before a scenario fails
+    tolerance: f64,
+    /// Nanoseconds per iteration of each scenario
+    ns_per_iter: BTreeMap<String, f64>,
+}
+
+/// Baselines by architecture, as in [`std::env::consts::ARCH`]
+type Baselines = BTreeMap<String, Baseline>;
+
+fn parse_baselines(toml: &str) -> Result<Baselines, toml::de::Error> {
+    toml::from_str(toml)
+}
+
+/// Replace the baselines of `arch` with `measured`, keeping its tolerance
+fn regenerate(baselines: &mut Baselines, arch: &str, measured: &[(&str, f64)]) {
+    let tolerance = baselines
+        .get(arch)
+        .map_or(DEFAULT_TOLERANCE, |baseline| baseline.tolerance);
+    let ns_per_iter = measured
+        .iter()
+        .map(|&(scenario, ns)| (scenario.to_string(), ns.round()))
+        .collect();
+    baselines.insert(
+        arch.to_string(),
+        Baseline {
+            tolerance,
+            ns_per_iter,
+        },
+    );
+}
+
+fn render_baselines(baselines: &Baselines) -> String {
+    let header = concat!(
+        "# Baselines of the perf-check test, in nanoseconds per iteration of release builds.\n",
+        "# Regenerate those of the current architecture with PERF_CHECK_REGENERATE=1.\n\n",
+    );
+    header.to_string() + &toml::to_string_pretty(baselines).expect("baselines serialize")
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Status {
+    Ok,
+    /// Slower than the baseline times the tolerance
+    Slower,
+    /// Without a baseline on this architecture, it can't fail
+    NoBaseline,
+}
+
+#[derive(Debug)]
+struct Row {
+    scenario: String,
+    baseline: Option<f64>,
+    measured: f64,
+    status: Status,
+}
+
+/// Outcome of the comparison of the measurements with the baselines of an architecture
+#[derive(Debug)]
+struct Report {
+    arch: String,
+    tolerance: f64,
+    rows: Vec<Row>,
+}
+
+impl Report {
+    fn compare(baselines: &Baselines, arch: &str, measured: &[(&str, f64)]) -> Self {
+        let baseline = baselines.get(arch);
+        let tolerance = baseline.map_or(DEFAULT_TOLERANCE, |baseline| baseline.tolerance);
+        let rows = measured
+            .iter()
+            .map(|&(scenario, measured)| {
+                let baseline = baseline.and_then(|baseline| baseline.ns_per_iter.get(scenario));
+                let status = match baseline {
+                    None => Status::NoBaseline,
+                    Some(&baseline) if measured > baseline * tolerance => Status::Slower,
+                    Some(_) => Status::Ok,
+                };
+                Row {
+                    scenario: scenario.to_string(),
+                    baseline: baseline.copied(),
+                    measured,
+                    status,
+                }
+            })
+            .collect();
+        Report {
+            arch: arch.to_string(),
+            tolerance,
+            rows,
+        }
+    }
+
+    fn passed(&self) -> bool {
+        self.rows.iter().all(|row| row.status != Status::Slower)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "perf-check on {} (tolerance {:.1}x), ns per iteration:",
+            self.arch, self.tolerance
+        )?;
+        writeln!(
+            f,
+            "  {:<22} {:>12} {:>12} {:>8}  status",
+            "scenario", "baseline", "measured", "ratio"
+        )?;
+        for row in &self.rows {
+            let (baseline, ratio) = match row.baseline {
+                Some(baseline) => (
+                    format!("{:.0}", baseline),
+                    format!("{:.2}x", row.measured / baseline),
+                ),
+                None => ("-".to_string(), "-".to_string()),
+            };
+            let status = match row.status {
+                Status::Ok => "ok",
+                Status::Slower => "SLOWER",
+                Status::NoBaseline => "no baseline",
+            };
+            writeln!(
+                f,
+                "  {:<22} {:>12} {:>12.0} {:>8}  {}",
+                row.scenario, baseline, row.measured, ratio, status
+            )?;
+        }
+        if self.rows.iter().any(|row| row.status == Status::NoBaseline) {
+            writeln!(
+                f,
+                "Record the missing baselines with {}=1 on this architecture",
+                REGENERATE_VAR
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Nanoseconds per iteration of `run`, the fastest of [`SAMPLES`] runs of `iterations`
+fn measure(iterations: u32, mut run: impl FnMut()) -> f64 {
+    (0..SAMPLES)
+        .map(|_| {
+            let start = Instant::now();
+            for _ in 0..iterations {
+                run();
+            }
+            start.elapsed().as_nanos() as f64 / iterations as f64
+        })
+        .fold(f64::INFINITY, f64::min)
+}
+
+fn data_frame(dlci: u8) -> Frame {
+    Frame::new(
+        Address::new_address(true, true, dlci),
+        Control::new_control(FrameType::UIH, false),
+        vec![0x55; PAYLOAD_LENGTH],
+    )
+}
+
+/// Serial stream of [`BATCH_FRAMES`] data frames over 4 channels
+fn batch() -> Vec<u8> {
+    (0..BATCH_FRAMES)
+        .flat_map(|i| data_frame(1 + i as u8 % 4).try_to_bytes().unwrap())
+        .collect()
+}
+
+fn decode_throughput() -> f64 {
+    let stream = batch();
+    let mut buffer = ReceiveBuffer::new(stream.len());
+    let mut drops = DropStats::default();
+    measure(2000, || {
+        buffer.push(&stream, &mut drops);
+        while let Some(frame) = buffer.pop_frame_bytes(Framing::default(), &mut drops) {
+            black_box(frame.content);
+        }
+    })
+}
+
+fn chopped_reads() -> f64 {
+    let stream = batch();
+    let mut buffer = ReceiveBuffer::new(stream.len());
+    let mut drops = DropStats::default();
+    measure(2000, || {
+        for chunk in stream.chunks(CHOP_LENGTH) {
+            buffer.push(chunk, &mut drops);
+            while let Some(frame) = buffer.pop_frame_bytes(Framing::default(), &mut drops) {
+                black_box(frame.content);
+            }
+        }
+    })
+}
+
+fn tx_encode() -> f64 {
+    let payload = vec![0x55; PAYLOAD_LENGTH];
+    measure(2000, || {
+        for i in 0..BATCH_FRAMES {
+            let frame = Frame::builder()
+                .dlci(1 + i as u8 % 4)
+                .frame_type(FrameType::UIH)
+                .payload(payload.clone())
+                .build()
+                .unwrap();
+            black_box(frame.try_to_bytes_with(Framing::default()).unwrap());
+        }
+    })
+}
+
+/// Modem end of the dispatch scenario: accepts every channel, and answers each TEST
+/// command with a data frame on every open channel followed by the echo
+struct Responder {
+    buffer: ReceiveBuffer,
+    drops: DropStats,
+    open: Vec<u8>,
+    burst: Vec<u8>,
+    to_mux: VecDeque<u8>,
+}
+
+impl Responder {
+    fn new() -> Self {
+        Responder {
+            buffer: ReceiveBuffer::new(4096),
+            drops: DropStats::default(),
+            open: Vec::new(),
+            burst: Vec::new(),
+            to_mux: VecDeque::new(),
+        }
+    }
+
+    fn reply(&mut self, frame: Frame) {
+        self.to_mux.extend(frame.try_to_bytes().unwrap());
+    }
+}
+
+impl Write for Responder {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buffer.push(data, &mut self.drops);
+        while let Some(frame) = self.buffer.next_frame(Framing::default(), &mut self.drops) {
+            let dlci = frame.address.get_dlci();
+            match frame.control.get_frame_type() {
+                Ok(FrameType::SABM) => {
+                    self.open.push(dlci);
+                    self.reply(Frame::new(
+                        Address::new_address(true, true, dlci),
+                        Control::new_control(FrameType::UA, true),
+                        vec![],
+                    ));
+                }
+                // The echo of a TEST command clears the C/R bit of its type octet
+                Ok(FrameType::UIH) if dlci == 0 && frame.content.first() == Some(&TEST_COMMAND) => {
+                    if self.burst.is_empty() {
+                        self.burst = self
+                            .open
+                            .iter()
+                            .flat_map(|&dlci| data_frame(dlci).try_to_bytes().unwrap())
+                            .collect();
+                    }
+                    self.to_mux.extend(&self.burst);
+                    let mut echo = frame.content;
+                    echo[0] = MccType::Test.octet();
+                    self.reply(Frame::new(
+                        Address::new_address(true, true, 0),
+                        Control::new_control(FrameType::UIH, false),
+                        echo,
+                    ));
+                }
+                _ => {}
+            }
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for Responder {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.to_mux.is_empty() {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        let len = buf.len().min(self.to_mux.len());
+        for (dst, src) in buf.iter_mut().zip(self.to_mux.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
+
+fn dispatch_60_channels() -> f64 {
+    let config = MuxerConfig {
+        channels: DISPATCH_CHANNELS + 1,
+        negotiate: false,
+        ..Default::default()
+    };
+    let mut muxer = Muxer::new(Responder::new(), config).unwrap();
+    for dlci in 1..=DISPATCH_CHANNELS {
+        muxer.open_channel(dlci).unwrap();
+    }
+    measure(200, || {
+        muxer
+            .ping(b"perf".to_vec(), Duration::from_secs(1))
+            .unwrap();
+    })
+}
+
+fn run_scenarios() -> Vec<(&'static str, f64)> {
+    vec![
+        ("decode_throughput", decode_throughput()),
+        ("chopped_reads", chopped_reads()),
+        ("tx_encode", tx_encode()),
+        ("dispatch_60_channels", dispatch_60_channels()),
+    ]
+}
+
+#[test]
+#[ignore = "timings, run with --release -- --ignored"]
+fn perf_check() {
+    if cfg!(debug_assertions) {
+        panic!("the baselines are release timings, run with --release");
+    }
+    let arch = std::env::consts::ARCH;
+    let mut baselines = parse_baselines(&std::fs::read_to_string(BASELINES_PATH).unwrap())
+        .expect("baselines parse");
+    let measured = run_scenarios();
+    if std::env::var_os(REGENERATE_VAR).is_some() {
+        regenerate(&mut baselines, arch, &measured);
+        std::fs::write(BASELINES_PATH, render_baselines(&baselines)).unwrap();
+        println!("Recorded the {} baselines in {}", arch, BASELINES_PATH);
+        return;
+    }
+    let report = Report::compare(&baselines, arch, &measured);
+    println!("{}", report);
+    assert!(
+        report.passed(),
+        "scenarios slower than baseline\n{}",
+        report
+    );
+}
+
+fn baselines() -> Baselines {
+    parse_baselines(
+        r#"
+        [x86_64]
+        tolerance = 2.0
+
+        [x86_64.ns_per_iter]
+        decode_throughput = 1000.0
+        tx_encode = 500.0
+        "#,
+    )
+    .unwrap()
+}
+
+#[test]
+fn checked_in_baselines_cover_every_scenario() {
+    let baselines = parse_baselines(&std::fs::read_to_string(BASELINES_PATH).unwrap()).unwrap();
+    let x86_64 = &baselines["x86_64"];
+    for scenario in [
+        "decode_throughput",
+        "chopped_reads",
+        "tx_encode",
+        "dispatch_60_channels",
+    ] {
+        assert!(x86_64.ns_per_iter.contains_key(scenario), "{}", scenario);
+    }
+}
+
+#[test]
+fn scenarios_slower_than_the_tolerance_fail() {
+    let report = Report::compare(
+        &baselines(),
+        "x86_64",
+        &[
+            ("decode_throughput", 1999.0),
+            ("tx_encode", 1001.0),
+            ("chopped_reads", 10.0),
+        ],
+    );
+    let statuses: Vec<_> = report.rows.iter().map(|row| row.status).collect();
+    assert_eq!(statuses, [Status::Ok, Status::Slower, Status::NoBaseline]);
+    assert!(!report.passed());
+}
+
+#[test]
+fn architectures_without_baselines_pass() {
+    let report = Report::compare(&baselines(), "aarch64", &[("decode_throughput", 1e9)]);
+    assert_eq!(report.tolerance, DEFAULT_TOLERANCE);
+    assert_eq!(report.rows[0].status, Status::NoBaseline);
+    assert!(report.passed());
+}
+
+#[test]
+fn report_lists_every_scenario() {
+    let report = Report::compare(
+        &baselines(),
+        "x86_64",
+        &[("decode_throughput", 800.0), ("tx_encode", 1500.0)],
+    );
+    assert_eq!(
+        report.to_string(),
+        "perf-check on x86_64 (tolerance 2.0x), ns per iteration:\n  \
+         scenario                   baseline     measured    ratio  status\n  \
+         decode_throughput              1000          800    0.80x  ok\n  \
+         tx_encode                       500         1500    3.00x  SLOWER\n"
+    );
+}
+
+#[test]
+fn regenerating_keeps_the_tolerance_and_other_architectures() {
+    let mut baselines = baselines();
+    regenerate(&mut baselines, "aarch64", &[("tx_encode", 812.4)]);
+    regenerate(&mut baselines, "x86_64", &[("tx_encode", 400.6)]);
+
+    let baselines = parse_baselines(&render_baselines(&baselines)).unwrap();
+    assert_eq!(baselines["aarch64"].tolerance, DEFAULT_TOLERANCE);
+    assert_eq!(baselines["aarch64"].ns_per_iter["tx_encode"], 812.0);
+    assert_eq!(baselines["x86_64"].tolerance, 2.0);
+    assert_eq!(
+        baselines["x86_64"].ns_per_iter,
+        BTreeMap::from([("tx_encode".to_string(), 401.0)])
+    );
+}